@@ -0,0 +1,60 @@
+// Compiles the GLSL shaders in src/shader into SPIR-V that the binary embeds,
+// so it works from any directory. shaderc is only linked into the binary with the shader-hot-reload feature
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[path = "src/shader/compiler.rs"]
+mod compiler;
+#[path = "src/shader/variant.rs"]
+mod variant;
+
+const SHADER_DIR: &str = "src/shader";
+const INCLUDE_DIR: &str = "src/shader/include";
+
+fn main() {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    // covers the includes as well
+    println!("cargo:rerun-if-changed={}", SHADER_DIR);
+    let mut sources = vec![];
+    find_sources(Path::new(SHADER_DIR), &mut sources);
+    sources.sort();
+
+    let mut failed = false;
+    let mut embedded = String::from("&[\n");
+    for path in sources {
+        match compiler::compile_variants(&path, Path::new(INCLUDE_DIR)) {
+            Ok(variants) => {
+                for (name, binary) in variants {
+                    let out = out_dir.join(format!("{}.spv", name));
+                    fs::write(&out, binary).unwrap_or_else(|e| panic!("Failed to write {}: {}", out.display(), e));
+                    writeln!(embedded, "    ({:?}, include_bytes!({:?})),", name, out).unwrap();
+                }
+            }
+            Err(e) => {
+                // the diagnostics are "<file>:<line>: error: ...", relative to the package root
+                eprintln!("{}", e);
+                failed = true;
+            }
+        }
+    }
+    // all diagnostics are reported before failing
+    if failed {
+        process::exit(1);
+    }
+    embedded.push(']');
+    fs::write(out_dir.join("shaders.rs"), embedded).unwrap();
+}
+
+fn find_sources(dir: &Path, sources: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).unwrap_or_else(|e| panic!("Failed to read {}: {}", dir.display(), e)) {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            find_sources(&path, sources);
+        } else if compiler::shader_kind(&path).is_some() {
+            sources.push(path);
+        }
+    }
+}
@@ -1,18 +1,37 @@
-use crate::camera::CameraState;
+use crate::camera::{Camera, CameraState};
 use crate::renderer::render::RenderingState;
+use crate::lighting::{Light, LightKind};
 use crate::model::SimpleVertex;
 use crate::texture::Texture;
-use crate::{renderer, editor, event, model, scene};
-use crate::scene::manager::{Manager, NUM_ROWS, NUM_INSTANCES_PER_ROW, INSTANCE_DISPLACEMENT};
+use crate::shader::{self, ShaderWatcher};
+use crate::{cli, renderer, editor, event, model, point_cloud};
+use crate::scene::component::{MeshRenderer, Name};
+use crate::scene::description::{CameraDescription, SceneDescription};
+use crate::scene::manager::{Manager, Object, Transform};
+use crate::scene::picking::{Hit, Ray};
 use cgmath::prelude::*;
-use cgmath::{Deg, Quaternion, Vector3, Vector4};
+use cgmath::{Matrix4, Vector3, Vector4};
 use iced_wgpu::wgpu;
+use iced_winit::winit::dpi::PhysicalSize;
 use iced_winit::winit::event_loop::EventLoop;
 use iced_winit::winit::window::{Window, WindowBuilder};
-use ordered_float::OrderedFloat;
-use cgmath::num_traits::Pow;
+use anyhow::Context;
+use iced_winit::Clipboard;
+use image::RgbaImage;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
 
-const MODELS: [&str; 3] = ["resources/penguin.obj", "resources/cube.obj", "resources/sphere.obj"];
+// both are in the resources directory
+const BOUNDING_SPHERE_MODEL: &str = "sphere.obj";
+// built in, the viewer can't draw selections without it
+const BOUNDING_SPHERE_OBJ: &[u8] = include_bytes!("../resources/sphere.obj");
+const DEFAULT_SCENE: &str = "default.scene.json";
+const SAVED_SCENE: &str = "saved.scene.json";
+const DEFAULT_OFFSCREEN_SIZE: (u32, u32) = (800, 600);
+// for models opened without a scene
+const DEFAULT_LIGHT_POSITION: [f32; 3] = [2.0, 2.0, 2.0];
 
 pub struct IndexDriver {
     current_index: usize,
@@ -31,128 +50,355 @@ impl IndexDriver {
 }
 
 pub struct App {
-    pub window: Window,
+    window: Option<Window>,
     pub resized: bool,
     pub rendering: RenderingState,
     pub camera_state: CameraState,
     pub scene_manager: Manager,
     pub model_loader: model::Loader,
+    pub point_cloud_loader: point_cloud::Loader,
+    pub selection: Option<Hit>,
+    // every model instance gets a bounding sphere instance of this model
+    bounding_model_id: usize,
+    // only windows started with a shader directory reload shaders
+    shader_watcher: Option<ShaderWatcher>,
+    // by shader source, shown in the gui until the source is fixed
+    shader_errors: BTreeMap<PathBuf, String>,
 }
 
 impl App {
-    pub fn run() {
+    pub fn run(options: cli::Options) {
         let event_loop = EventLoop::new();
-        let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+        let instance = wgpu::Instance::new(options.backends);
         let window = {
             let mut builder = WindowBuilder::new();
             builder = builder.with_title("scene-viewer");
+            if let Some((width, height)) = options.window_size {
+                builder = builder.with_inner_size(PhysicalSize::new(width, height));
+            }
             builder.build(&event_loop).expect("Could not build window")
         };
         let surface = unsafe { instance.create_surface(&window) };
         let rendering = RenderingState::new(
             &instance,
-            surface,
+            Some(surface),
             window.inner_size(),
             window.scale_factor(),
+            options.present_mode,
+            options.force_fallback_adapter,
+        );
+        let shader_dir = options.shader_dir.clone();
+        let mut app = App::new(Some(window), rendering, options).unwrap_or_else(|e| {
+            eprintln!("{:?}", e);
+            std::process::exit(1);
+        });
+        app.shader_watcher = shader_dir.map(ShaderWatcher::new);
+
+        event_loop.run(move |event, _, control_flow| {
+            event::processor::process_events(&mut app, &event, control_flow)
+        })
+    }
+
+    /// Renders the scene once without a window, the camera pose from the options overrides the one from scene files
+    pub fn render_offscreen(options: cli::Options) -> anyhow::Result<RgbaImage> {
+        let instance = wgpu::Instance::new(options.backends);
+        let (width, height) = options.window_size.unwrap_or(DEFAULT_OFFSCREEN_SIZE);
+        let rendering = RenderingState::new(
+            &instance,
+            None,
+            PhysicalSize::new(width, height),
+            1.0,
+            options.present_mode,
+            options.force_fallback_adapter,
         );
+        let camera = options.camera;
+        let mut app = App::new(None, rendering, options)?;
+        if let Some(camera) = camera {
+            app.camera_state.camera = Camera::new(camera.position, camera.yaw, camera.pitch);
+        }
+        // applies the scene background color
+        app.rendering.update_gui(&mut Clipboard::unconnected());
+        app.update(Duration::ZERO);
+        // octree nodes are loaded in the background, a thumbnail should have all of them
+        while app.rendering.is_streaming() {
+            thread::sleep(Duration::from_millis(10));
+            app.update(Duration::ZERO);
+        }
+        Ok(app.rendering.render_to_image())
+    }
+
+    fn new(window: Option<Window>, mut rendering: RenderingState, options: cli::Options) -> anyhow::Result<App> {
+        rendering.show_gui = options.gui;
         let camera_state = CameraState::new(rendering.surface_config.width, rendering.surface_config.height);
+        let mut scene_manager = Manager::new();
+        let mut model_loader = model::Loader::new();
+        let bounding_model_id = scene_manager.add_model(
+            model_loader.load_primitive(BOUNDING_SPHERE_MODEL, BOUNDING_SPHERE_OBJ)?,
+        );
+        rendering.init_bounding_sphere(scene_manager.get_model(bounding_model_id));
         let mut app = App {
             window,
             rendering,
             camera_state,
             resized: false,
-            scene_manager: Manager::new(),
-            model_loader: model::Loader::new(),
+            scene_manager,
+            model_loader,
+            point_cloud_loader: point_cloud::Loader::new(),
+            selection: None,
+            bounding_model_id,
+            shader_watcher: None,
+            shader_errors: BTreeMap::new(),
         };
-        app.add_objects();
+        let paths = if options.paths.is_empty() {
+            let default_scene = options.resources.join(DEFAULT_SCENE);
+            if default_scene.is_file() {
+                vec![default_scene]
+            } else {
+                log::warn!("No default scene at {}, starting with an empty scene", default_scene.display());
+                vec![]
+            }
+        } else {
+            options.paths
+        };
+        for path in paths.iter() {
+            app.open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        }
+        if app.scene_manager.get_light_ids().is_empty() {
+            app.scene_manager.add_light(Light::point(DEFAULT_LIGHT_POSITION.into(), Vector3::new(1.0, 1.0, 1.0)));
+        }
+        app.show_shadow_settings();
+        if let Some(point_budget) = options.point_budget {
+            app.rendering.gui.program_state.queue_message(editor::Message::ChangePointBudget(point_budget));
+        }
+        Ok(app)
+    }
 
-        event_loop.run(move |event, _, control_flow| {
-            event::processor::process_events(&mut app, &event, control_flow)
-        })
+    /// Only the offscreen mode works without a window
+    pub fn window(&self) -> &Window {
+        self.window.as_ref().expect("The app has no window")
     }
 
-    fn add_objects(&mut self) {
-        self.scene_manager.add_model(self.model_loader.load(MODELS[0]).unwrap());
-        self.scene_manager.add_model(self.model_loader.load(MODELS[1]).unwrap());
-        let bounding_model_id = self.scene_manager.add_model(self.model_loader.load_primitive(MODELS[2]).unwrap());
-        let mut i: i32 = -1;
-        let bounding_sphere = self.scene_manager.get_model(bounding_model_id);
-        self.rendering.init_bounding_sphere(&bounding_sphere);
-        for model_id in self.scene_manager.get_model_ids().iter() {
-            if *model_id == bounding_model_id {
-                continue;
-            }
-            i += 1;
+    /// Picks the loader by the file extension, directories are opened as octrees
+    pub fn open<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            return self.add_octree(path).map(|_| ());
+        }
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_lowercase());
+        let result = match extension.as_deref() {
+            Some("obj") => self.add_obj(path),
+            Some("gltf" | "glb") => self.add_gltf(path),
+            Some("json") => self.load_scene(path),
+            Some("ply" | "las" | "laz" | "xyz" | "txt" | "pts" | "csv") => self.add_point_cloud(path).map(|_| ()),
+            _ => anyhow::bail!("Unsupported file type: {}", path.display()),
+        };
+        self.report_loader_warnings();
+        result
+    }
 
-            let new_instances = self.create_instances(i, *model_id);
-            let new_bounding_spheres = self.create_bounding_sphere_instances(*model_id, bounding_model_id);
+    // models open with default materials and textures when theirs are broken or the adapter can't sample them,
+    // the user should still know
+    fn report_loader_warnings(&mut self) {
+        let mut warnings = self.model_loader.take_warnings();
+        warnings.extend(self.rendering.take_texture_warnings());
+        if warnings.is_empty() {
+            return;
+        }
+        for warning in warnings.iter() {
+            log::warn!("{}", warning);
+        }
+        self.rendering
+            .gui
+            .program_state
+            .queue_message(editor::Message::DebugInfo(warnings.join("\n")));
+    }
 
-            let model = self.scene_manager.get_model(*model_id);
-            self.rendering.init_model(&model);
-            self.rendering.add_instances(&model, &self.scene_manager.get_objects_by_ids(&new_instances));
-            self.rendering.add_bounding_sphere_instances(bounding_model_id, &self.scene_manager.get_objects_by_ids(&new_bounding_spheres));
+    /// Adds models and objects of the scene file to the current ones,
+    /// its camera, lights and background replace the current ones
+    pub fn load_scene<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let scene = SceneDescription::load(path)?;
+        for model_id in self.scene_manager.populate(&scene, &mut self.model_loader)? {
+            self.init_model(model_id);
+        }
+        // scenes without lights keep the current ones
+        if !scene.lights.is_empty() {
+            for light_id in self.scene_manager.get_light_ids() {
+                self.scene_manager.remove_light(light_id);
+            }
+            for light in scene.lights.iter() {
+                self.scene_manager.add_light(*light);
+            }
+            self.show_shadow_settings();
         }
+        self.camera_state.camera = Camera::new(scene.camera.position, scene.camera.yaw, scene.camera.pitch);
+        let [r, g, b, a] = scene.background_color;
+        self.rendering
+            .gui
+            .program_state
+            .queue_message(editor::Message::SetBackgroundColor(iced::Color::from_rgba(r, g, b, a)));
+        Ok(())
+    }
+
+    pub fn save_scene<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
+        let (models, objects) = self.scene_manager.describe(&[self.bounding_model_id]);
+        let camera = &self.camera_state.camera;
+        let background_color = self.rendering.gui.program_state.program().background_color();
+        let scene = SceneDescription {
+            models,
+            objects,
+            lights: self.scene_manager.get_lights(),
+            camera: CameraDescription {
+                position: camera.position,
+                yaw: camera.yaw.into(),
+                pitch: camera.pitch.into(),
+            },
+            background_color: [background_color.r, background_color.g, background_color.b, background_color.a],
+        };
+        scene.save(path)
     }
 
-    fn create_instances(&mut self, i: i32, model_id: usize) ->Vec<usize> {
-        let mut transforms = (0..NUM_ROWS)
-            .flat_map(|z| {
-                (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-                    let position = Vector3 {
-                        x: (x * 6) as f32,
-                        y: 0.0,
-                        // * i * 30 just to move the second model next to the first model to showcase
-                        z: (z * 6 + i as u32 * 30) as f32,
-                    } - INSTANCE_DISPLACEMENT;
-                    let rotation = Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0));
-                    // let rotation = if position.is_zero() {
-                    //     this is needed so an object at (0, 0, 0) won't get scaled to zero
-                    //     as Quaternions can effect scale if they're not created correctly
-                    //     Quaternion::from_axis_angle(Vector3::unit_z(), Deg(0.0))
-                    // } else {
-                    //     Quaternion::from_axis_angle(position.clone().normalize(), Deg(45.0))
-                    // };
-
-                    scene::manager::Transform {
-                        position,
-                        rotation,
-                        scale: Vector3::new(1.0, 1.0, 1.0),
-                    }
-                })
-            })
-            .collect::<Vec<_>>();
-        let mut ids: Vec<usize> = vec![];
-        for transform in transforms.iter_mut() {
-            ids.push(self.scene_manager.create_object(model_id.clone(), transform.clone()));
+    /// Saves the scene into the working directory
+    pub fn quick_save_scene(&mut self) {
+        let message = match self.save_scene(SAVED_SCENE) {
+            Ok(()) => format!("scene saved to {}", SAVED_SCENE),
+            Err(e) => format!("failed to save the scene: {:?}", e),
+        };
+        self.rendering
+            .gui
+            .program_state
+            .queue_message(editor::Message::DebugInfo(message));
+    }
+
+    /// Adds all meshes of a gltf scene and places their instances at node transforms
+    pub fn add_gltf<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let gltf_scene = self.model_loader.load_gltf(path)?;
+        let mut model_ids = vec![];
+        for model in gltf_scene.models {
+            model_ids.push(self.scene_manager.add_model(model));
+        }
+        for (model_index, transform, name) in gltf_scene.objects {
+            let object_id = self.scene_manager.create_object(model_ids[model_index], transform);
+            if let Some(name) = name {
+                self.scene_manager.add_component(object_id, Name(name))?;
+            }
+        }
+        for model_id in model_ids {
+            self.init_model(model_id);
         }
-        ids
+        Ok(())
+    }
+
+    /// Adds the model with one instance at the origin
+    pub fn add_obj<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        let model_id = self.scene_manager.add_model(self.model_loader.load(path)?);
+        self.scene_manager.create_object(model_id, Transform::default());
+        self.init_model(model_id);
+        Ok(())
+    }
+
+    // uploads a new model with all of its instances and adds their bounding spheres
+    fn init_model(&mut self, model_id: usize) {
+        let worlds: Vec<Matrix4<f32>> = self.scene_manager
+            .get_model_instances(model_id)
+            .iter()
+            .map(|object| object.world_matrix())
+            .collect();
+        let sphere_worlds = self.create_bounding_sphere_instances(model_id);
+
+        let model = self.scene_manager.get_model(model_id);
+        self.rendering.init_model(model);
+        self.rendering.add_instances(model, &worlds);
+        self.rendering.add_bounding_sphere_instances(self.bounding_model_id, &sphere_worlds);
+    }
+
+    /// Point clouds are kept only on the gpu, returns the point cloud id
+    pub fn add_point_cloud<P: AsRef<Path>>(&mut self, path: P) -> anyhow::Result<usize> {
+        let point_cloud = self.point_cloud_loader.load(path)?;
+        self.rendering.add_point_cloud(&point_cloud);
+        Ok(point_cloud.id)
+    }
+
+    /// Opens an octree made by `pointz convert-octree`, returns the point cloud id
+    pub fn add_octree<P: AsRef<Path>>(&mut self, directory: P) -> anyhow::Result<usize> {
+        let octree = self.point_cloud_loader.load_octree(directory)?;
+        let id = octree.id;
+        self.rendering.add_octree(octree);
+        Ok(id)
     }
 
-    fn create_bounding_sphere_instances(&mut self, model_id: usize, bounding_model_id: usize) ->Vec<usize> {
+    // every instance gets a child sphere, so the sphere follows it when it moves, returns world matrices of the spheres
+    fn create_bounding_sphere_instances(&mut self, model_id: usize) -> Vec<Matrix4<f32>> {
         let model = self.scene_manager.get_model(model_id);
-        let radius = calc_bounding_sphere_radius(model);
+        let radius = model.calc_bounding_sphere_radius();
+        let object_ids: Vec<usize> = self.scene_manager
+            .get_model_instances(model_id)
+            .iter()
+            .map(|object| object.id)
+            .collect();
+        let mut worlds = vec![];
+        for object_id in object_ids {
+            let sphere_id = self.scene_manager.create_bounding_volume(object_id, self.bounding_model_id, radius);
+            worlds.push(self.scene_manager.get_object(sphere_id).unwrap().world_matrix());
+        }
+        worlds
+    }
+
+    /// Removes the object together with all of its descendants
+    pub fn remove_object(&mut self, object_id: usize) {
+        let mut object_ids = self.scene_manager.descendants(object_id);
+        object_ids.insert(0, object_id);
+        if self.selection.is_some_and(|hit| object_ids.contains(&hit.object_id)) {
+            self.select(None);
+        }
+        // children first, so none of them is left as a root
+        for object_id in object_ids.into_iter().rev() {
+            let mesh_renderer = self.scene_manager.get_component::<MeshRenderer>(object_id).copied();
+            if self.scene_manager.remove_object(object_id).is_none() {
+                continue;
+            }
+            if let Some(mesh_renderer) = mesh_renderer {
+                let (model_id, instance_id) = (mesh_renderer.model_id(), mesh_renderer.instance_id());
+                let moved = self.scene_manager.get_model_instance(model_id, instance_id).map(Object::world_matrix);
+                self.rendering.remove_instance(model_id, instance_id, moved.as_ref());
+            }
+        }
+    }
 
-        let mut transforms = vec![];
-        for object in self.scene_manager.get_model_instances(model_id) {
-            let mut transform = object.transform.clone();
-            transform.scale *= radius;
-            transforms.push(transform);
+    pub fn remove_model(&mut self, model_id: usize) {
+        let selected_model_id = self.selection
+            .and_then(|hit| self.scene_manager.get_component::<MeshRenderer>(hit.object_id))
+            .map(MeshRenderer::model_id);
+        if selected_model_id == Some(model_id) {
+            self.select(None);
         }
-        let mut ids: Vec<usize> = vec![];
-        for transform in transforms {
-            ids.push(self.scene_manager.create_object(bounding_model_id, transform));
+        if !self.scene_manager.get_model_ids().contains(&model_id) {
+            return;
+        }
+        // bounding spheres and other children of the instances go with them
+        let child_ids: Vec<usize> = self.scene_manager
+            .get_model_instances(model_id)
+            .iter()
+            .flat_map(|object| object.children().to_vec())
+            .collect();
+        for child_id in child_ids {
+            self.remove_object(child_id);
+        }
+        if self.scene_manager.remove_model(model_id).is_some() {
+            self.rendering.remove_model(model_id);
         }
-        ids
     }
 
     pub fn resize(&mut self) {
-        let new_size = self.window.inner_size();
+        let new_size = self.window().inner_size();
         self.camera_state
             .projection
             .resize(new_size.width, new_size.height);
         self.rendering.viewport = iced_wgpu::Viewport::with_physical_size(
             iced_winit::Size::new(new_size.width, new_size.height),
-            self.window.scale_factor(),
+            self.window().scale_factor(),
         );
         self.rendering.surface_config.width = new_size.width;
         self.rendering.surface_config.height = new_size.height;
@@ -162,10 +408,12 @@ impl App {
             &self.rendering.device,
             &self.rendering.queue,
         );
-        self.rendering.surface.configure(&self.rendering.device, &self.rendering.surface_config);
+        if let Some(surface) = &self.rendering.surface {
+            surface.configure(&self.rendering.device, &self.rendering.surface_config);
+        }
     }
 
-    pub fn update(&mut self, dt: std::time::Duration) {
+    pub fn update(&mut self, dt: Duration) {
         self.camera_state
             .camera_controller
             .update_camera(&mut self.camera_state.camera, dt);
@@ -178,13 +426,30 @@ impl App {
             0,
             bytemuck::cast_slice(&[self.rendering.uniforms]),
         );
+        self.rendering
+            .update_octrees(&self.camera_state.camera, &self.camera_state.projection);
+        self.apply_shadow_settings();
+        self.apply_selection_action();
+        for object_id in self.scene_manager.update_world_transforms() {
+            if let Some(mesh_renderer) = self.scene_manager.get_component::<MeshRenderer>(object_id) {
+                let world = self.scene_manager.get_object(object_id).unwrap().world_matrix();
+                self.rendering.update_instance(mesh_renderer.model_id(), mesh_renderer.instance_id(), &world);
+            }
+        }
+        // after the world transforms, lights follow their objects
+        if self.scene_manager.take_lights_dirty() {
+            self.rendering.set_lights(&self.scene_manager.get_lights());
+        }
+        self.rendering
+            .update_shadows(&self.camera_state.camera, &self.camera_state.projection);
 
-        // todo disabled rotation because models and bounding spheres are all in the schene_manager and I try to rotate all object
-        // todo and in fact models and spheres are in different renderers
-        // for (_, object) in self.scene_manager.get_objects().iter_mut() {
-        //     object.transform.rotation = Quaternion::from_angle_y(Rad(0.03)) * object.transform.rotation;
-        //     self.rendering.update_object(object);
-        // }
+        let (visible_instances, culled_instances) = self
+            .rendering
+            .cull_instances(&self.camera_state.camera, &self.camera_state.projection);
+        self.rendering
+            .gui
+            .program_state
+            .queue_message(editor::Message::UpdateCulling(visible_instances, culled_instances));
 
         self.rendering.gui.fps_meter.push(dt);
         self.rendering
@@ -195,6 +460,101 @@ impl App {
             ));
     }
 
+    /// Lists the lights that can cast shadows in the gui, the gui owns the settings until the lights are replaced
+    fn show_shadow_settings(&mut self) {
+        let mut settings = vec![];
+        for light_id in self.scene_manager.get_light_ids() {
+            let light = self.scene_manager.get_light(light_id).unwrap();
+            let kind = match light.kind {
+                LightKind::Point => continue,
+                LightKind::Directional => "directional",
+                LightKind::Spot { .. } => "spot",
+            };
+            settings.push(editor::ShadowSettings {
+                light_id,
+                label: format!("{} light {}", kind, light_id),
+                shadow: light.shadow,
+            });
+        }
+        self.rendering
+            .gui
+            .program_state
+            .queue_message(editor::Message::SetShadowSettings(settings));
+    }
+
+    /// Copies shadow settings edited in the gui to the lights
+    fn apply_shadow_settings(&mut self) {
+        let settings = self.rendering.gui.program_state.program().shadow_settings();
+        for settings in settings.iter() {
+            match self.scene_manager.get_light(settings.light_id) {
+                Some(light) if light.shadow != settings.shadow => {
+                    let light = Light {
+                        shadow: settings.shadow,
+                        ..*light
+                    };
+                    self.scene_manager.set_light(settings.light_id, light);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Removes or detaches the selected object when it was asked for in the gui
+    fn apply_selection_action(&mut self) {
+        let action = match self.rendering.gui.program_state.program().selection_action() {
+            Some(action) => action,
+            None => return,
+        };
+        self.rendering.gui.program_state.queue_message(editor::Message::ClearSelectionAction);
+        let (object_id, model_id) = match self.selection {
+            Some(hit) => match self.scene_manager.get_component::<MeshRenderer>(hit.object_id) {
+                Some(mesh_renderer) => (hit.object_id, mesh_renderer.model_id()),
+                None => return,
+            },
+            None => return,
+        };
+        match action {
+            editor::SelectionAction::RemoveObject => self.remove_object(object_id),
+            editor::SelectionAction::RemoveModel => self.remove_model(model_id),
+            editor::SelectionAction::Detach => {
+                // the world matrix stays the same, only removing the old parent won't take the object along
+                if let Err(e) = self.scene_manager.set_parent(object_id, None) {
+                    log::warn!("Failed to detach object {}: {}", object_id, e);
+                }
+            }
+        }
+    }
+
+    /// Recompiles changed shaders and rebuilds the pipelines using them,
+    /// broken shaders keep their old pipelines and their diagnostics are shown in the gui
+    pub fn reload_changed_shaders(&mut self) {
+        let (changed, shader_dir) = match self.shader_watcher.as_mut() {
+            Some(watcher) => (watcher.poll(), watcher.dir().to_path_buf()),
+            None => return,
+        };
+        if changed.is_empty() {
+            return;
+        }
+        for path in changed {
+            let result = shader::compile_shader(&path, &shader_dir)
+                .and_then(|_| self.rendering.reload_shaders(&[shader::shader_name(&path)]));
+            match result {
+                Ok(()) => {
+                    log::info!("Reloaded {}", path.display());
+                    self.shader_errors.remove(&path);
+                }
+                Err(e) => {
+                    self.shader_errors.insert(path, format!("{:#}", e));
+                }
+            }
+        }
+        let errors: Vec<&str> = self.shader_errors.values().map(String::as_str).collect();
+        self.rendering
+            .gui
+            .program_state
+            .queue_message(editor::Message::ShaderErrors(errors.join("\n")));
+    }
+
     pub fn process_mouse_move(&mut self, delta: &(f64, f64)) {
         self.rendering
             .gui
@@ -221,6 +581,10 @@ impl App {
 
         let end = start + self.camera_state.projection.zfar * ray_world;
 
+        let ray = Ray::new(start.truncate(), ray_world.truncate());
+        let hit = self.scene_manager.raycast(&ray);
+        self.select(hit);
+
         self.rendering.add_line(
             SimpleVertex {
                 position: [start.x, start.y, start.z],
@@ -240,6 +604,53 @@ impl App {
             )));
     }
 
+    fn select(&mut self, selection: Option<Hit>) {
+        self.selection = selection;
+        // objects whose boxes touch the selected one, e.g. to see what's stacked on it
+        let overlapping: Vec<String> = match selection.and_then(|hit| self.scene_manager.get_world_bounds(hit.object_id)) {
+            Some(bounds) => self
+                .scene_manager
+                .query_aabb(&bounds)
+                .into_iter()
+                .filter(|object_id| Some(*object_id) != selection.map(|hit| hit.object_id))
+                .map(|object_id| format!("#{}", object_id))
+                .collect(),
+            None => vec![],
+        };
+        let description = match selection {
+            Some(hit) => {
+                let mesh_renderer = self.scene_manager.get_component::<MeshRenderer>(hit.object_id).unwrap();
+                let model = self.scene_manager.get_model(mesh_renderer.model_id());
+                let label = match self.scene_manager.get_component::<Name>(hit.object_id) {
+                    Some(name) => &name.0,
+                    None => &model.label,
+                };
+                let mut description = format!(
+                    "selected {} #{}, point ({:.2}, {:.2}, {:.2}), normal ({:.2}, {:.2}, {:.2}), mesh {} triangle {}",
+                    label,
+                    hit.object_id,
+                    hit.point.x,
+                    hit.point.y,
+                    hit.point.z,
+                    hit.normal.x,
+                    hit.normal.y,
+                    hit.normal.z,
+                    model.meshes[hit.mesh_index].name,
+                    hit.triangle_index,
+                );
+                if !overlapping.is_empty() {
+                    description += &format!(", overlaps {}", overlapping.join(", "));
+                }
+                description
+            }
+            None => String::new(),
+        };
+        self.rendering
+            .gui
+            .program_state
+            .queue_message(editor::Message::UpdateSelection(description));
+    }
+
     fn get_normalized_click_coords(&self) -> Vector4<f32> {
         Vector4::new(
             (2.0 * self.rendering.gui.cursor_position.x as f32)
@@ -253,21 +664,8 @@ impl App {
     }
 
     pub fn render(&mut self) {
-        self.rendering.render(&self.window);
-    }
-}
-
-// todo move
-fn calc_bounding_sphere_radius(model: &model::Model) -> f32 {
-    let mut lengths: Vec<OrderedFloat<f32>> = vec![];
-    for mesh in model.meshes.iter() {
-        lengths = mesh.vertices.iter().map(|vertex| {
-            // todo move to a math lib? or it already exists?
-            // we measure the distance between the model space 0,0,0 and a vertex, so vertex vector will always be the same as it's coords
-            let length: f32 = vertex.position.x.pow(2) + vertex.position.y.pow(2) + vertex.position.z.pow(2);
-            OrderedFloat(length.sqrt())
-        }).collect();
+        if let Some(window) = &self.window {
+            self.rendering.render(window);
+        }
     }
-    let max = lengths.iter().max().unwrap().into_inner();
-    max
 }
@@ -1,3 +1,4 @@
+use crate::scene::bvh::Aabb;
 use cgmath::*;
 use iced_winit::winit;
 use iced_winit::winit::dpi::PhysicalPosition;
@@ -5,7 +6,7 @@ use std::f32::consts::FRAC_PI_2;
 use std::time::Duration;
 use winit::event::*;
 
-#[cfg_attr(rustfmt, rustfmt_skip)]
+#[rustfmt::skip]
 pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
     1.0, 0.0, 0.0, 0.0,
     0.0, 1.0, 0.0, 0.0,
@@ -45,7 +46,7 @@ impl Camera {
 pub struct Projection {
     aspect: f32,
     fovy: Rad<f32>,
-    znear: f32,
+    pub znear: f32,
     pub zfar: f32,
 }
 
@@ -63,9 +64,61 @@ impl Projection {
         self.aspect = width as f32 / height as f32;
     }
 
+    /// The same projection cut to a part of its depth range, e.g. for shadow cascades
+    pub fn with_depth_range(&self, znear: f32, zfar: f32) -> Projection {
+        Projection { znear, zfar, ..*self }
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
     }
+
+    /// How many pixels an object of size 1 takes at distance 1, for screen space level of detail
+    pub fn calc_pixels_per_unit(&self, viewport_height: u32) -> f32 {
+        viewport_height as f32 / (2.0 * (self.fovy.0 / 2.0).tan())
+    }
+}
+
+/// The 6 planes of a view projection, normals point inside
+pub struct Frustum {
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    pub fn from_matrix(view_proj: &Matrix4<f32>) -> Frustum {
+        let rows = [view_proj.row(0), view_proj.row(1), view_proj.row(2), view_proj.row(3)];
+        // wgpu clip space depth is 0..1, so the near plane is just z >= 0
+        let planes = [
+            rows[3] + rows[0],
+            rows[3] - rows[0],
+            rows[3] + rows[1],
+            rows[3] - rows[1],
+            rows[2],
+            rows[3] - rows[2],
+        ];
+        Frustum {
+            planes: planes.map(|plane| plane / plane.truncate().magnitude()),
+        }
+    }
+
+    /// Conservative test, boxes near frustum corners can pass without being visible
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner furthest along the plane normal
+            let corner = Vector3::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+
+    pub fn intersects_sphere(&self, center: Vector3<f32>, radius: f32) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.truncate().dot(center) + plane.w >= -radius)
+    }
 }
 
 #[derive(Debug)]
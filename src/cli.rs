@@ -0,0 +1,252 @@
+use crate::scene::description::CameraDescription;
+use anyhow::*;
+use cgmath::{Deg, Point3};
+use iced_wgpu::wgpu;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage:
+  pointz [options] [paths...]
+  pointz convert-octree <point cloud> <output directory>
+  pointz pack-textures <model.obj> <output directory>
+
+pack-textures copies the model with its textures converted to block compressed
+KTX2 files with mipmaps, its MTL files are rewritten to use them.
+
+Paths can be models (.obj, .gltf, .glb), point clouds (.ply, .las, .xyz, .txt, .pts, .csv),
+octree directories made by convert-octree or scene files (.json).
+The default scene from the resources directory is opened when no paths are given.
+
+Options:
+  --resources <dir>        directory with the default scene, defaults to $POINTZ_RESOURCES
+                           or the first resources directory next to the executable or above it
+  --size <width>x<height>  window size in physical pixels
+  --vsync, --no-vsync      shortcuts for --present-mode auto-vsync and auto-no-vsync
+  --present-mode <mode>    fifo, fifo-relaxed, mailbox, immediate, auto-vsync or auto-no-vsync
+  --backend <backend>      vulkan, metal, dx12, dx11, gl, primary or all
+  --no-gui                 don't draw the gui
+  --output <file.png>      render one frame without a window into a png and exit
+  --camera <x,y,z,yaw,pitch>
+                           camera position and rotation in degrees, overrides scene files
+  --software               use a software adapter, e.g. on servers without a gpu
+  --point-budget <points>  max number of octree points drawn per frame, defaults to 5000000
+  --shader-dir <dir>       compile shaders from this directory instead of using the built in ones
+                           and reload them when they change, e.g. src/shader,
+                           needs a build with the shader-hot-reload feature
+  -h, --help               print this message";
+
+const RESOURCES_DIR: &str = "resources";
+const RESOURCES_ENV: &str = "POINTZ_RESOURCES";
+
+pub enum Command {
+    View(Options),
+    ConvertOctree { input: PathBuf, output: PathBuf },
+    PackTextures { input: PathBuf, output: PathBuf },
+    Help,
+}
+
+pub struct Options {
+    pub paths: Vec<PathBuf>,
+    pub resources: PathBuf,
+    pub window_size: Option<(u32, u32)>,
+    pub present_mode: wgpu::PresentMode,
+    pub backends: wgpu::Backends,
+    pub force_fallback_adapter: bool,
+    pub gui: bool,
+    /// Offscreen mode when set
+    pub output: Option<PathBuf>,
+    pub camera: Option<CameraDescription>,
+    /// Octree points drawn per frame, the gui default when not set
+    pub point_budget: Option<usize>,
+    /// Overrides the embedded shaders during development
+    pub shader_dir: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            paths: vec![],
+            resources: default_resources(),
+            window_size: None,
+            present_mode: wgpu::PresentMode::Fifo,
+            backends: wgpu::Backends::PRIMARY,
+            force_fallback_adapter: false,
+            gui: true,
+            output: None,
+            camera: None,
+            point_budget: None,
+            shader_dir: None,
+        }
+    }
+}
+
+/// The working directory is searched last, when the executable isn't in a package or a checkout
+fn default_resources() -> PathBuf {
+    if let Some(dir) = std::env::var_os(RESOURCES_ENV) {
+        return PathBuf::from(dir);
+    }
+    std::env::current_exe()
+        .ok()
+        .and_then(|exe| find_resources(exe.parent()?))
+        .unwrap_or_else(|| PathBuf::from(RESOURCES_DIR))
+}
+
+// cargo puts the executable a few levels below the repository, in target/<profile>
+fn find_resources(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().map(|dir| dir.join(RESOURCES_DIR)).find(|dir| dir.is_dir())
+}
+
+/// Parses arguments without the program name
+pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Command> {
+    let mut args = args.into_iter().peekable();
+    if args.peek().map(String::as_str) == Some("convert-octree") {
+        args.next();
+        let (Some(input), Some(output), None) = (args.next(), args.next(), args.next()) else {
+            bail!("convert-octree expects a point cloud and an output directory");
+        };
+        return Ok(Command::ConvertOctree {
+            input: PathBuf::from(input),
+            output: PathBuf::from(output),
+        });
+    }
+    if args.peek().map(String::as_str) == Some("pack-textures") {
+        args.next();
+        let (Some(input), Some(output), None) = (args.next(), args.next(), args.next()) else {
+            bail!("pack-textures expects an obj model and an output directory");
+        };
+        return Ok(Command::PackTextures {
+            input: PathBuf::from(input),
+            output: PathBuf::from(output),
+        });
+    }
+
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or_else(|| anyhow!("{} expects a value", name));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--resources" => options.resources = PathBuf::from(value("--resources")?),
+            "--size" => options.window_size = Some(parse_size(&value("--size")?)?),
+            "--vsync" => options.present_mode = wgpu::PresentMode::AutoVsync,
+            "--no-vsync" => options.present_mode = wgpu::PresentMode::AutoNoVsync,
+            "--present-mode" => options.present_mode = parse_present_mode(&value("--present-mode")?)?,
+            "--backend" => options.backends = parse_backends(&value("--backend")?)?,
+            "--no-gui" => options.gui = false,
+            "--output" => options.output = Some(PathBuf::from(value("--output")?)),
+            "--camera" => options.camera = Some(parse_camera(&value("--camera")?)?),
+            "--software" => options.force_fallback_adapter = true,
+            "--point-budget" => options.point_budget = Some(parse_point_budget(&value("--point-budget")?)?),
+            "--shader-dir" => options.shader_dir = Some(PathBuf::from(value("--shader-dir")?)),
+            // everything after it is a path, even if it starts with a dash
+            "--" => options.paths.extend(args.by_ref().map(PathBuf::from)),
+            _ if arg.starts_with('-') => bail!("Unknown option {}", arg),
+            _ => options.paths.push(PathBuf::from(arg)),
+        }
+    }
+    Ok(Command::View(options))
+}
+
+fn parse_size(size: &str) -> Result<(u32, u32)> {
+    let parsed = size
+        .split_once('x')
+        .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)));
+    match parsed {
+        Some((width, height)) if width > 0 && height > 0 => Ok((width, height)),
+        _ => bail!("Invalid window size {}, expected <width>x<height>, e.g. 1280x720", size),
+    }
+}
+
+fn parse_point_budget(budget: &str) -> Result<usize> {
+    match budget.parse() {
+        std::result::Result::Ok(budget) if budget > 0 => Ok(budget),
+        _ => bail!("Invalid point budget {}, expected a positive number of points", budget),
+    }
+}
+
+fn parse_camera(camera: &str) -> Result<CameraDescription> {
+    let values: Vec<f32> = camera
+        .split(',')
+        .map(|value| value.trim().parse())
+        .collect::<std::result::Result<_, _>>()
+        .with_context(|| format!("Invalid camera {}", camera))?;
+    let [x, y, z, yaw, pitch] = values[..] else {
+        bail!("Invalid camera {}, expected x,y,z,yaw,pitch", camera);
+    };
+    Ok(CameraDescription {
+        position: Point3::new(x, y, z),
+        yaw: Deg(yaw),
+        pitch: Deg(pitch),
+    })
+}
+
+fn parse_present_mode(mode: &str) -> Result<wgpu::PresentMode> {
+    Ok(match mode {
+        "fifo" => wgpu::PresentMode::Fifo,
+        "fifo-relaxed" => wgpu::PresentMode::FifoRelaxed,
+        "mailbox" => wgpu::PresentMode::Mailbox,
+        "immediate" => wgpu::PresentMode::Immediate,
+        "auto-vsync" => wgpu::PresentMode::AutoVsync,
+        "auto-no-vsync" => wgpu::PresentMode::AutoNoVsync,
+        _ => bail!("Unknown present mode {}", mode),
+    })
+}
+
+fn parse_backends(backend: &str) -> Result<wgpu::Backends> {
+    Ok(match backend {
+        "vulkan" => wgpu::Backends::VULKAN,
+        "metal" => wgpu::Backends::METAL,
+        "dx12" => wgpu::Backends::DX12,
+        "dx11" => wgpu::Backends::DX11,
+        "gl" => wgpu::Backends::GL,
+        "primary" => wgpu::Backends::PRIMARY,
+        "all" => wgpu::Backends::all(),
+        _ => bail!("Unknown backend {}", backend),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_view(args: &[&str]) -> Options {
+        match parse(args.iter().map(|arg| arg.to_string())).unwrap() {
+            Command::View(options) => options,
+            _ => panic!("not a view command"),
+        }
+    }
+
+    #[test]
+    fn parses_view_options() {
+        let options = parse_view(&[
+            "--size", "320x240", "--camera", "1,2,3,-90,10", "--point-budget", "1000000", "--no-gui", "a.obj", "--", "-b.ply",
+        ]);
+        assert_eq!(options.window_size, Some((320, 240)));
+        assert_eq!(options.point_budget, Some(1_000_000));
+        let camera = options.camera.unwrap();
+        assert_eq!(camera.position, Point3::new(1.0, 2.0, 3.0));
+        assert_eq!((camera.yaw, camera.pitch), (Deg(-90.0), Deg(10.0)));
+        assert!(!options.gui);
+        assert_eq!(options.paths, vec![PathBuf::from("a.obj"), PathBuf::from("-b.ply")]);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(["--size".to_string(), "320".to_string()]).is_err());
+        assert!(parse(["--camera".to_string(), "1,2,3".to_string()]).is_err());
+        assert!(parse(["--unknown".to_string()]).is_err());
+        assert!(parse(["--output".to_string()]).is_err());
+        assert!(parse(["--point-budget".to_string(), "0".to_string()]).is_err());
+        assert!(parse(["convert-octree".to_string(), "in.ply".to_string()]).is_err());
+    }
+
+    #[test]
+    fn finds_resources_above_the_executable() {
+        let root = std::env::temp_dir().join(format!("pointz-resources-{}", std::process::id()));
+        let exe_dir = root.join("target").join("debug");
+        std::fs::create_dir_all(&exe_dir).unwrap();
+        std::fs::create_dir_all(root.join(RESOURCES_DIR)).unwrap();
+        let found = find_resources(&exe_dir);
+        std::fs::remove_dir_all(&root).unwrap();
+        assert_eq!(found, Some(root.join(RESOURCES_DIR)));
+    }
+}
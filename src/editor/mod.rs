@@ -1,15 +1,17 @@
+use crate::lighting::Shadow;
+use crate::renderer::point_cloud::{DEFAULT_POINT_BUDGET, DEFAULT_POINT_SIZE};
 use crate::widgets::fps;
 
 use iced::alignment;
 use iced_wgpu::{Backend, Renderer, Settings, wgpu};
-use iced::widget::{button, column, horizontal_space, vertical_space, row, text};
+use iced::widget::{button, checkbox, column, horizontal_space, vertical_space, row, slider, text, Column};
 use iced_winit::{Color, Command, Element, Length, Program, program, winit, Debug, Size};
 use iced_winit::winit::dpi::PhysicalPosition;
 use winit::dpi::PhysicalSize;
 
-pub struct GUI {
+pub struct Gui {
     pub renderer: Renderer,
-    pub program_state: program::State<GUIState>,
+    pub program_state: program::State<GuiState>,
     // todo keep a list of widgets, come up with a normal design
     pub(crate) fps_meter: fps::Meter,
     pub cursor_position: PhysicalPosition<f64>,
@@ -17,18 +19,17 @@ pub struct GUI {
 
 }
 
-impl GUI {
-    pub fn new(device: &wgpu::Device, scale_factor: f64, size: PhysicalSize<u32>, texture_format: wgpu::TextureFormat) -> GUI {
+impl Gui {
+    pub fn new(device: &wgpu::Device, size: PhysicalSize<u32>, texture_format: wgpu::TextureFormat) -> Gui {
         let mut renderer = Renderer::new(Backend::new(device, Settings::default(), texture_format));
         let mut debug = Debug::new();
         let program_state = program::State::new(
-            GUIState::new(),
+            GuiState::new(),
             Size::new(size.width as f32, size.height as f32),
-            // conversion::cursor_position(PhysicalPosition::new(-1.0, -1.0), scale_factor),
             &mut renderer,
             &mut debug,
         );
-        GUI {
+        Gui {
             renderer,
             program_state,
             fps_meter: fps::Meter::new(),
@@ -38,36 +39,141 @@ impl GUI {
     }
 }
 
-pub struct GUIState {
+// the shadow resolution button cycles through them
+const SHADOW_RESOLUTIONS: [u32; 4] = [512, 1024, 2048, 4096];
+
+/// Shadow settings of a directional or spot light, the app applies the edited ones to the light
+#[derive(Debug, Clone)]
+pub struct ShadowSettings {
+    pub light_id: usize,
+    pub label: String,
+    pub shadow: Option<Shadow>,
+}
+
+/// What the selection buttons ask the app to do with the selected object
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectionAction {
+    RemoveObject,
+    /// Removes all instances of the model of the object
+    RemoveModel,
+    /// Makes the object a root that stays where it is
+    Detach,
+}
+
+pub struct GuiState {
     background_color: Color,
     // buttons: [State; 1],
     fps: i32,
+    // model instances drawn and skipped by frustum culling in the last frame
+    visible_instances: usize,
+    culled_instances: usize,
     debug_info: String,
+    selection_info: String,
+    point_size: f32,
+    /// Max number of octree points drawn per frame
+    point_budget: usize,
+    /// Models are colored by their level of detail
+    lod_colors: bool,
+    /// Diagnostics of shaders that failed to reload, empty when all of them work
+    shader_errors: String,
+    shadow_settings: Vec<ShadowSettings>,
+    /// Requested in the gui, the app clears it once it is applied to the selection
+    selection_action: Option<SelectionAction>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     ChangeBackgroundColor,
+    SetBackgroundColor(Color),
     UpdateFps(i32),
+    /// Visible and culled model instances
+    UpdateCulling(usize, usize),
     DebugInfo(String),
+    UpdateSelection(String),
+    ChangePointSize(f32),
+    ChangePointBudget(usize),
+    ToggleLodColors(bool),
+    ShaderErrors(String),
+    SetShadowSettings(Vec<ShadowSettings>),
+    ToggleShadow(usize, bool),
+    ChangeShadowBias(usize, f32),
+    ChangeShadowResolution(usize),
+    ApplyToSelection(SelectionAction),
+    ClearSelectionAction,
 }
 
-impl GUIState {
-    pub fn new() -> GUIState {
-        GUIState {
+impl GuiState {
+    pub fn new() -> GuiState {
+        GuiState {
             background_color: Color::BLACK,
             // buttons: Default::default(),
             fps: 0,
+            visible_instances: 0,
+            culled_instances: 0,
             debug_info: "".to_string(),
+            selection_info: "".to_string(),
+            point_size: DEFAULT_POINT_SIZE,
+            point_budget: DEFAULT_POINT_BUDGET,
+            lod_colors: false,
+            shader_errors: "".to_string(),
+            shadow_settings: vec![],
+            selection_action: None,
         }
     }
 
     pub fn background_color(&self) -> Color {
         self.background_color
     }
+
+    pub fn point_size(&self) -> f32 {
+        self.point_size
+    }
+
+    pub fn point_budget(&self) -> usize {
+        self.point_budget
+    }
+
+    pub fn lod_colors(&self) -> bool {
+        self.lod_colors
+    }
+
+    pub fn shadow_settings(&self) -> &[ShadowSettings] {
+        &self.shadow_settings
+    }
+
+    pub fn selection_action(&self) -> Option<SelectionAction> {
+        self.selection_action
+    }
+
+    fn shadow_mut(&mut self, light_id: usize) -> Option<&mut Shadow> {
+        self.shadow_settings
+            .iter_mut()
+            .find(|settings| settings.light_id == light_id)
+            .and_then(|settings| settings.shadow.as_mut())
+    }
+
+    fn shadow_row(settings: &ShadowSettings) -> Element<'_, Message, Renderer> {
+        let light_id = settings.light_id;
+        let mut shadow_row = row![
+            text(format!("{} shadows", settings.label)).style(Color::from([1.0, 1.0, 1.0])),
+            checkbox("", settings.shadow.is_some(), move |enabled| Message::ToggleShadow(light_id, enabled)),
+        ]
+            .spacing(5);
+        if let Some(shadow) = settings.shadow {
+            shadow_row = shadow_row
+                .push(text(format!("bias {:.3}", shadow.bias)).style(Color::from([1.0, 1.0, 1.0])))
+                .push(
+                    slider(0.0..=0.2, shadow.bias, move |bias| Message::ChangeShadowBias(light_id, bias))
+                        .step(0.005)
+                        .width(Length::Fixed(100.0)),
+                )
+                .push(button(text(format!("{}px", shadow.resolution))).on_press(Message::ChangeShadowResolution(light_id)));
+        }
+        shadow_row.into()
+    }
 }
 
-impl Program for GUIState {
+impl Program for GuiState {
     type Renderer = Renderer;
     type Message = Message;
 
@@ -80,29 +186,96 @@ impl Program for GUIState {
                     Color::BLACK
                 };
             }
+            Message::SetBackgroundColor(color) => {
+                self.background_color = color;
+            }
             Message::UpdateFps(val) => {
                 self.fps = val;
             }
+            Message::UpdateCulling(visible, culled) => {
+                self.visible_instances = visible;
+                self.culled_instances = culled;
+            }
             Message::DebugInfo(s) => {
                 self.debug_info = s;
             }
+            Message::UpdateSelection(s) => {
+                self.selection_info = s;
+            }
+            Message::ChangePointSize(size) => {
+                self.point_size = size;
+            }
+            Message::ChangePointBudget(budget) => {
+                self.point_budget = budget;
+            }
+            Message::ToggleLodColors(enabled) => {
+                self.lod_colors = enabled;
+            }
+            Message::ShaderErrors(errors) => {
+                self.shader_errors = errors;
+            }
+            Message::SetShadowSettings(settings) => {
+                self.shadow_settings = settings;
+            }
+            Message::ToggleShadow(light_id, enabled) => {
+                if let Some(settings) = self.shadow_settings.iter_mut().find(|settings| settings.light_id == light_id) {
+                    settings.shadow = enabled.then(Shadow::default);
+                }
+            }
+            Message::ChangeShadowBias(light_id, bias) => {
+                if let Some(shadow) = self.shadow_mut(light_id) {
+                    shadow.bias = bias;
+                }
+            }
+            Message::ChangeShadowResolution(light_id) => {
+                if let Some(shadow) = self.shadow_mut(light_id) {
+                    shadow.resolution = SHADOW_RESOLUTIONS
+                        .into_iter()
+                        .find(|resolution| *resolution > shadow.resolution)
+                        .unwrap_or(SHADOW_RESOLUTIONS[0]);
+                }
+            }
+            Message::ApplyToSelection(action) => {
+                self.selection_action = Some(action);
+            }
+            Message::ClearSelectionAction => {
+                self.selection_action = None;
+            }
         }
         Command::none()
     }
 
-    fn view(&self) -> Element<Message, Renderer> {
+    fn view(&self) -> Element<'_, Message, Renderer> {
+        let mut selection_row = row![text(self.selection_info.clone()).style(Color::from([1.0, 1.0, 1.0]))].spacing(5);
+        if !self.selection_info.is_empty() {
+            selection_row = selection_row
+                .push(button("Remove object").on_press(Message::ApplyToSelection(SelectionAction::RemoveObject)))
+                .push(button("Remove model").on_press(Message::ApplyToSelection(SelectionAction::RemoveModel)))
+                .push(button("Detach").on_press(Message::ApplyToSelection(SelectionAction::Detach)));
+        }
         column![
             row![
-                // text("1").style(Color::from([1.0, 1.0, 1.0])),
+                selection_row,
                 horizontal_space(Length::Fill),
+                text(format!("{} visible, {} culled", self.visible_instances, self.culled_instances))
+                    .style(Color::from([1.0, 1.0, 1.0])),
                 text(self.fps.to_string()).style(Color::from([1.0, 1.0, 1.0])),
             ],
+            text(self.shader_errors.clone()).style(Color::from([1.0, 0.3, 0.3])),
+            Column::with_children(self.shadow_settings.iter().map(GuiState::shadow_row).collect()).spacing(5),
             vertical_space(Length::Fill),
             row![
                 text(self.debug_info.clone())
                     .style(Color::from([1.0, 1.0, 1.0]))
                     .vertical_alignment(alignment::Vertical::Center),
                 horizontal_space(Length::Fill),
+                text(format!("point size {:.0}", self.point_size)).style(Color::from([1.0, 1.0, 1.0])),
+                slider(1.0..=20.0, self.point_size, Message::ChangePointSize).width(Length::Fixed(150.0)),
+                text(format!("point budget {:.1}M", self.point_budget as f32 / 1e6)).style(Color::from([1.0, 1.0, 1.0])),
+                // in millions of points
+                slider(1.0..=50.0, self.point_budget as f32 / 1e6, |millions| Message::ChangePointBudget((millions * 1e6) as usize))
+                    .width(Length::Fixed(150.0)),
+                checkbox("LOD colors", self.lod_colors, Message::ToggleLodColors),
                 button("Change background").on_press(Message::ChangeBackgroundColor),
             ]
         ]
@@ -1,6 +1,5 @@
 use crate::app::App;
 
-use iced::theme::Theme;
 use iced_winit::winit::event::{
     DeviceEvent,
     ElementState,
@@ -8,10 +7,11 @@ use iced_winit::winit::event::{
     KeyboardInput,
     ModifiersState,
     MouseButton,
+    VirtualKeyCode,
     WindowEvent,
 };
 use iced_winit::winit::event_loop::ControlFlow;
-use iced_winit::{Clipboard, conversion, renderer};
+use iced_winit::{Clipboard, conversion};
 
 use std::env;
 
@@ -31,6 +31,9 @@ pub fn process_events(app: &mut App, event: &Event<()>, control_flow: &mut Contr
                         },
                     ..
                 } => {
+                    if *key == VirtualKeyCode::F5 && *state == ElementState::Pressed {
+                        app.quick_save_scene();
+                    }
                     app.camera_state
                         .camera_controller
                         .process_keyboard(*key, *state);
@@ -44,16 +47,14 @@ pub fn process_events(app: &mut App, event: &Event<()>, control_flow: &mut Contr
                     ..
                 } => {
                     app.camera_state.camera_mode = *state == ElementState::Pressed;
-                    app.window.set_cursor_visible(!app.camera_state.camera_mode);
+                    app.window().set_cursor_visible(!app.camera_state.camera_mode);
                 }
                 WindowEvent::MouseInput {
                     button: MouseButton::Left,
-                    state,
+                    state: ElementState::Pressed,
                     ..
                 } => {
-                    if *state == ElementState::Pressed {
-                        app.process_left_click();
-                    }
+                    app.process_left_click();
                 }
                 WindowEvent::CursorMoved { position, .. } => {
                     if app.camera_state.camera_mode {
@@ -61,7 +62,7 @@ pub fn process_events(app: &mut App, event: &Event<()>, control_flow: &mut Contr
                         // when calling set_cursor_position
                         if env::consts::OS != "macos" {
                             // make cursor stay at the same place on camera movement
-                            app.window
+                            app.window()
                                 .set_cursor_position(app.rendering.gui.cursor_position)
                                 .unwrap();
                         }
@@ -81,27 +82,15 @@ pub fn process_events(app: &mut App, event: &Event<()>, control_flow: &mut Contr
                 _ => {}
             }
             if let Some(event) =
-                conversion::window_event(&event, app.window.scale_factor(), modifiers)
+                conversion::window_event(event, app.window().scale_factor(), modifiers)
             {
                 app.rendering.gui.program_state.queue_event(event);
             }
         }
         Event::MainEventsCleared => {
-            if !app.rendering.gui.program_state.is_queue_empty() {
-                let _ = app.rendering.gui.program_state.update(
-                    app.rendering.viewport.logical_size(),
-                    conversion::cursor_position(
-                        app.rendering.gui.cursor_position,
-                        app.rendering.viewport.scale_factor(),
-                    ),
-                    &mut app.rendering.gui.renderer,
-                    &Theme::default(),
-                    &renderer::Style::default(),
-                    &mut Clipboard::connect(&app.window),
-                    &mut app.rendering.gui.debug,
-                );
-            }
-            app.window.request_redraw();
+            app.reload_changed_shaders();
+            app.rendering.update_gui(&mut Clipboard::connect(app.window()));
+            app.window().request_redraw();
         }
         Event::RedrawRequested(_) => {
             let now = std::time::Instant::now();
@@ -115,34 +104,34 @@ pub fn process_events(app: &mut App, event: &Event<()>, control_flow: &mut Contr
             }
             app.render();
         }
-        Event::DeviceEvent { event, .. } => match event {
-            DeviceEvent::MouseMotion { delta } => {
-                app.process_mouse_move(delta);
-                if app
-                    .camera_state
-                    .cursor_watcher
-                    .last_frames_cursor_deltas
-                    .len()
-                    > KEEP_CURSOR_POS_FOR_NUM_FRAMES
-                {
-                    app.camera_state
-                        .cursor_watcher
-                        .last_frames_cursor_deltas
-                        .drain(..1);
-                }
+        Event::DeviceEvent {
+            event: DeviceEvent::MouseMotion { delta },
+            ..
+        } => {
+            app.process_mouse_move(delta);
+            if app
+                .camera_state
+                .cursor_watcher
+                .last_frames_cursor_deltas
+                .len()
+                > KEEP_CURSOR_POS_FOR_NUM_FRAMES
+            {
                 app.camera_state
                     .cursor_watcher
                     .last_frames_cursor_deltas
-                    .push(*delta);
-                if app.camera_state.camera_mode {
-                    let (mouse_dx, mouse_dy) = app.camera_state.cursor_watcher.get_avg_cursor_pos();
-                    app.camera_state
-                        .camera_controller
-                        .process_mouse(mouse_dx / 2.0, mouse_dy / 2.0);
-                }
+                    .drain(..1);
             }
-            _ => {}
-        },
+            app.camera_state
+                .cursor_watcher
+                .last_frames_cursor_deltas
+                .push(*delta);
+            if app.camera_state.camera_mode {
+                let (mouse_dx, mouse_dy) = app.camera_state.cursor_watcher.get_avg_cursor_pos();
+                app.camera_state
+                    .camera_controller
+                    .process_mouse(mouse_dx / 2.0, mouse_dy / 2.0);
+            }
+        }
         _ => {}
     };
 }
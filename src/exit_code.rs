@@ -0,0 +1,4 @@
+//! Exit codes that scripts and the integration tests check for
+
+/// No adapter supports the requested backends, the same code as automake's skipped tests
+pub const NO_ADAPTER: i32 = 77;
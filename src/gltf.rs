@@ -0,0 +1,727 @@
+use crate::app::IndexDriver;
+use crate::json::{self, Value};
+use crate::model::{self, AlphaMode, Material, Mesh, Model, ModelSource, ModelVertex, TextureFilter};
+use crate::scene::bvh::Bvh;
+use crate::scene::manager::Transform;
+use crate::simplify;
+use crate::texture::{Texture, TextureType};
+use anyhow::*;
+use cgmath::{Matrix4, Quaternion, SquareMatrix, Vector2, Vector3, Zero};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+const GLB_MAGIC: &[u8; 4] = b"glTF";
+const GLB_CHUNK_JSON: u32 = 0x4E4F534A;
+const GLB_CHUNK_BIN: u32 = 0x004E4942;
+
+const MODE_TRIANGLES: usize = 4;
+
+const COMPONENT_BYTE: usize = 5120;
+const COMPONENT_UNSIGNED_BYTE: usize = 5121;
+const COMPONENT_SHORT: usize = 5122;
+const COMPONENT_UNSIGNED_SHORT: usize = 5123;
+const COMPONENT_UNSIGNED_INT: usize = 5125;
+const COMPONENT_FLOAT: usize = 5126;
+
+const FILTER_NEAREST: usize = 9728;
+const FILTER_LINEAR: usize = 9729;
+const FILTER_NEAREST_MIPMAP_NEAREST: usize = 9984;
+const FILTER_LINEAR_MIPMAP_NEAREST: usize = 9985;
+const FILTER_NEAREST_MIPMAP_LINEAR: usize = 9986;
+const FILTER_LINEAR_MIPMAP_LINEAR: usize = 9987;
+
+/// Every glTF mesh becomes a model, every node that references a mesh becomes an object
+pub struct GltfScene {
+    pub models: Vec<Model>,
+    /// index in `models`, the node's world transform and its name
+    pub objects: Vec<(usize, Transform, Option<String>)>,
+}
+
+pub fn load(path: &Path, index_driver: &mut IndexDriver) -> Result<GltfScene> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let (document, bin) = if bytes.starts_with(GLB_MAGIC) {
+        parse_glb(&bytes)?
+    } else {
+        (json::parse(std::str::from_utf8(&bytes)?)?, None)
+    };
+    let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("gltf");
+    let loader = GltfLoader {
+        buffers: load_buffers(&document, path.parent().unwrap(), bin)?,
+        document: &document,
+        base_dir: path.parent().unwrap(),
+    };
+
+    let materials = loader.load_materials(file_name)?;
+    let mut models = vec![];
+    for (mesh_index, mesh) in array(&document, "meshes").iter().enumerate() {
+        let label = match mesh.get("name").and_then(Value::as_str) {
+            Some(name) => format!("{}:{}", file_name, name),
+            None => format!("{}:{}", file_name, mesh_index),
+        };
+        let source = ModelSource {
+            path: path.to_path_buf(),
+            mesh: Some(mesh_index),
+        };
+        models.push(loader.load_mesh(mesh, &materials, label, source, index_driver.next_id())?);
+    }
+
+    let objects = loader.collect_objects(loader.root_nodes()?)?;
+    Ok(GltfScene { models, objects })
+}
+
+struct GltfLoader<'a> {
+    document: &'a Value,
+    buffers: Vec<Vec<u8>>,
+    base_dir: &'a Path,
+}
+
+impl<'a> GltfLoader<'a> {
+    fn load_materials(&self, file_name: &str) -> Result<Vec<Material>> {
+        let mut images: HashMap<(usize, TextureType), Texture> = HashMap::new();
+        let mut materials = vec![];
+        for (material_index, material) in array(self.document, "materials").iter().enumerate() {
+            let name = match material.get("name").and_then(Value::as_str) {
+                Some(name) => name.to_string(),
+                None => format!("{}:material{}", file_name, material_index),
+            };
+            let pbr = material.get("pbrMetallicRoughness");
+            let texture_index = |parent: Option<&Value>, key: &str| {
+                parent.and_then(|parent| parent.get(key)).and_then(|texture| texture.get("index")).and_then(Value::as_usize)
+            };
+            let base_color_texture = texture_index(pbr, "baseColorTexture");
+            let diffuse_texture = match base_color_texture {
+                Some(index) => self.load_texture(index, TextureType::Diffuse, &mut images)?,
+                None => model::white_texture(&format!("{}_diffuse", name), TextureType::Diffuse),
+            };
+            let normal_texture = match texture_index(Some(material), "normalTexture") {
+                Some(index) => Some(self.load_texture(index, TextureType::Normal, &mut images)?),
+                None => None,
+            };
+            let has_normal_map = normal_texture.is_some();
+            let normal_texture = normal_texture.unwrap_or_else(|| model::flat_normal_texture(&name));
+            let mut result = Material::new(&name, diffuse_texture, normal_texture, has_normal_map);
+            if let Some(index) = texture_index(pbr, "metallicRoughnessTexture") {
+                result.metallic_roughness_texture = self.load_texture(index, TextureType::Data, &mut images)?;
+            }
+            if let Some(index) = texture_index(Some(material), "emissiveTexture") {
+                result.emissive_texture = self.load_texture(index, TextureType::Diffuse, &mut images)?;
+            }
+            let factor = |parent: Option<&Value>, key: &str, default: f32| {
+                parent.and_then(|parent| parent.get(key)).and_then(Value::as_f32).unwrap_or(default)
+            };
+            result.base_color_factor = pbr
+                .and_then(|pbr| pbr.get("baseColorFactor"))
+                .and_then(Value::as_f32_array::<4>)
+                .unwrap_or([1.0; 4]);
+            result.metallic_factor = factor(pbr, "metallicFactor", 1.0);
+            result.roughness_factor = factor(pbr, "roughnessFactor", 1.0);
+            result.normal_scale = factor(material.get("normalTexture"), "scale", 1.0);
+            result.emissive_factor = material.get("emissiveFactor").and_then(Value::as_f32_array::<3>).unwrap_or([0.0; 3]);
+            let extensions = material.get("extensions");
+            result.ior = factor(extensions.and_then(|extensions| extensions.get("KHR_materials_ior")), "ior", model::DEFAULT_IOR);
+            if let Some(specular) = extensions.and_then(|extensions| extensions.get("KHR_materials_specular")) {
+                let color = specular.get("specularColorFactor").and_then(Value::as_f32_array::<3>).unwrap_or([1.0; 3]);
+                result.specular_factor = color.map(|c| c * factor(Some(specular), "specularFactor", 1.0));
+            }
+            result.alpha_mode = match material.get("alphaMode").and_then(Value::as_str) {
+                Some("MASK") => AlphaMode::Mask(factor(Some(material), "alphaCutoff", 0.5)),
+                Some("BLEND") => AlphaMode::Blend,
+                _ => AlphaMode::Opaque,
+            };
+            // one sampler for all maps, the base color one is what the filtering is the most visible on
+            if let Some(index) = base_color_texture {
+                result.texture_filter = self.texture_filter(index);
+            }
+            materials.push(result);
+        }
+        Ok(materials)
+    }
+
+    fn texture_filter(&self, texture_index: usize) -> TextureFilter {
+        let sampler = array(self.document, "textures")
+            .get(texture_index)
+            .and_then(|texture| texture.get("sampler"))
+            .and_then(Value::as_usize)
+            .and_then(|sampler| array(self.document, "samplers").get(sampler));
+        let filter = |key: &str| sampler.and_then(|sampler| sampler.get(key)).and_then(Value::as_usize);
+        match (filter("magFilter"), filter("minFilter")) {
+            (Some(FILTER_NEAREST), _) => TextureFilter::Nearest,
+            // without mipmaps or only the nearest level of them
+            (_, Some(FILTER_NEAREST | FILTER_LINEAR | FILTER_NEAREST_MIPMAP_NEAREST | FILTER_LINEAR_MIPMAP_NEAREST)) => TextureFilter::Bilinear,
+            (_, Some(FILTER_NEAREST_MIPMAP_LINEAR | FILTER_LINEAR_MIPMAP_LINEAR)) => TextureFilter::Trilinear,
+            // the sampler leaves it to the renderer
+            _ => TextureFilter::default(),
+        }
+    }
+
+    fn load_texture(&self, texture_index: usize, type_: TextureType, images: &mut HashMap<(usize, TextureType), Texture>) -> Result<Texture> {
+        let texture = get(self.document, "textures", texture_index)?;
+        let image_index = texture.get("source").and_then(Value::as_usize).ok_or_else(|| anyhow!("Texture {} has no source", texture_index))?;
+        if let Some(texture) = images.get(&(image_index, type_)) {
+            return Ok(texture.clone());
+        }
+        let image = get(self.document, "images", image_index)?;
+        let (bytes, label) = match (image.get("uri").and_then(Value::as_str), image.get("bufferView").and_then(Value::as_usize)) {
+            (Some(uri), _) => (read_uri(uri, self.base_dir)?, uri.to_string()),
+            (None, Some(view)) => (self.read_buffer_view(view)?.to_vec(), format!("image{}", image_index)),
+            _ => bail!("Image {} has neither uri nor bufferView", image_index),
+        };
+        let texture = Texture::from_bytes(&bytes, &label, type_)
+            .with_context(|| format!("Failed to decode image {}", label))?;
+        images.insert((image_index, type_), texture.clone());
+        Ok(texture)
+    }
+
+    fn load_mesh(
+        &self,
+        mesh: &Value,
+        materials: &[Material],
+        label: String,
+        source: ModelSource,
+        id: usize,
+    ) -> Result<Model> {
+        let mut meshes = vec![];
+        let mut model_materials: Vec<Material> = vec![];
+        // gltf material index or None for the default material -> index in model_materials
+        let mut material_ids: HashMap<Option<usize>, usize> = HashMap::new();
+        for (primitive_index, primitive) in mesh.get("primitives").and_then(Value::as_array).into_iter().flatten().enumerate() {
+            let mode = primitive.get("mode").and_then(Value::as_usize).unwrap_or(MODE_TRIANGLES);
+            if mode != MODE_TRIANGLES {
+                bail!("{}: only triangle primitives are supported, got mode {}", label, mode);
+            }
+            let material = primitive.get("material").and_then(Value::as_usize);
+            let material_id = match material_ids.get(&material) {
+                Some(id) => *id,
+                None => {
+                    model_materials.push(match material {
+                        Some(index) => materials.get(index).ok_or_else(|| anyhow!("Material {} doesn't exist", index))?.clone(),
+                        None => default_material(),
+                    });
+                    material_ids.insert(material, model_materials.len() - 1);
+                    model_materials.len() - 1
+                }
+            };
+            meshes.push(self.load_primitive(primitive, format!("{}:{}", label, primitive_index), material_id)?);
+        }
+        Ok(Model {
+            id,
+            label,
+            meshes,
+            materials: model_materials,
+            source,
+        })
+    }
+
+    fn load_primitive(&self, primitive: &Value, name: String, material_id: usize) -> Result<Mesh> {
+        let attributes = primitive.get("attributes").ok_or_else(|| anyhow!("{} has no attributes", name))?;
+        let attribute = |name: &str| attributes.get(name).and_then(Value::as_usize);
+        let positions = self.read_floats(attribute("POSITION").ok_or_else(|| anyhow!("{} has no positions", name))?, 3)?;
+        let count = positions.len() / 3;
+        let normals = match attribute("NORMAL") {
+            Some(accessor) => Some(self.read_floats(accessor, 3)?),
+            None => None,
+        };
+        let tex_coords = match attribute("TEXCOORD_0") {
+            Some(accessor) => Some(self.read_floats(accessor, 2)?),
+            None => None,
+        };
+        let tangents = match attribute("TANGENT") {
+            // tangents of meshes without normals must be ignored, the normals are generated
+            Some(accessor) if normals.is_some() => Some(self.read_floats(accessor, 4)?),
+            _ => None,
+        };
+
+        let mut vertices = Vec::with_capacity(count);
+        for i in 0..count {
+            let normal = match &normals {
+                Some(normals) => Vector3::new(normals[i * 3], normals[i * 3 + 1], normals[i * 3 + 2]),
+                // calc_flat_normals sets it below
+                None => Vector3::zero(),
+            };
+            let (tangent, bitangent) = match &tangents {
+                // w is the handedness of the tangent space
+                Some(tangents) => {
+                    let tangent = Vector3::new(tangents[i * 4], tangents[i * 4 + 1], tangents[i * 4 + 2]);
+                    (tangent, normal.cross(tangent) * tangents[i * 4 + 3])
+                }
+                None => (Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0)),
+            };
+            vertices.push(ModelVertex {
+                position: Vector3::new(positions[i * 3], positions[i * 3 + 1], positions[i * 3 + 2]),
+                tex_coords: match &tex_coords {
+                    Some(tex_coords) => Vector2::new(tex_coords[i * 2], tex_coords[i * 2 + 1]),
+                    None => Vector2::new(0.0, 0.0),
+                },
+                normal,
+                tangent,
+                bitangent,
+            });
+        }
+
+        let indices = match primitive.get("indices").and_then(Value::as_usize) {
+            Some(accessor) => self.read_indices(accessor)?,
+            None => (0..count as u32).collect(),
+        };
+        if let Some(index) = indices.iter().find(|index| **index as usize >= count) {
+            bail!("{}: index {} is out of bounds", name, index);
+        }
+        // the spec asks for flat normals when a mesh has none
+        let (mut vertices, indices) = match normals {
+            Some(_) => (vertices, indices),
+            None => model::calc_flat_normals(&vertices, &indices),
+        };
+        if tangents.is_none() {
+            model::calc_tangents(&mut vertices, &indices);
+        }
+        let positions: Vec<Vector3<f32>> = vertices.iter().map(|vertex| vertex.position).collect();
+        let bvh = Bvh::from_triangles(&positions, &indices);
+        let lods = simplify::generate_lods(&vertices, &indices);
+        Ok(Mesh {
+            name,
+            vertices,
+            indices,
+            material_id,
+            has_tex_coords: tex_coords.is_some(),
+            bvh,
+            lods,
+        })
+    }
+
+    fn root_nodes(&self) -> Result<Vec<usize>> {
+        let scene_index = self.document.get("scene").and_then(Value::as_usize).unwrap_or(0);
+        if let Some(scene) = self.document.get("scenes").and_then(Value::as_array).and_then(|scenes| scenes.get(scene_index)) {
+            return Ok(usize_array(scene.get("nodes")));
+        }
+        // no scenes, every node that is not a child of another node is a root
+        let nodes = array(self.document, "nodes");
+        let children: Vec<usize> = nodes.iter().flat_map(|node| usize_array(node.get("children"))).collect();
+        Ok((0..nodes.len()).filter(|node| !children.contains(node)).collect())
+    }
+
+    // walks the node trees without recursion, nodes must not be shared or form cycles
+    fn collect_objects(&self, roots: Vec<usize>) -> Result<Vec<(usize, Transform, Option<String>)>> {
+        let mut objects = vec![];
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(usize, Matrix4<f32>)> = roots.into_iter().rev().map(|root| (root, Matrix4::identity())).collect();
+        while let Some((node_index, parent)) = stack.pop() {
+            if !visited.insert(node_index) {
+                bail!("Node {} has more than one parent or is its own ancestor", node_index);
+            }
+            let node = get(self.document, "nodes", node_index)?;
+            let world = parent * node_matrix(node)?;
+            if let Some(mesh) = node.get("mesh").and_then(Value::as_usize) {
+                let name = node.get("name").and_then(Value::as_str).map(str::to_string);
+                objects.push((mesh, Transform::from_matrix(&world), name));
+            }
+            stack.extend(usize_array(node.get("children")).into_iter().rev().map(|child| (child, world)));
+        }
+        Ok(objects)
+    }
+
+    /// Reads an accessor of float or normalized integer components
+    fn read_floats(&self, accessor_index: usize, components: usize) -> Result<Vec<f32>> {
+        let accessor = self.read_accessor(accessor_index)?;
+        if accessor.components != components {
+            bail!("Accessor {} has {} components, expected {}", accessor_index, accessor.components, components);
+        }
+        let normalized = accessor.value.get("normalized").and_then(Value::as_bool).unwrap_or(false);
+        let mut result = Vec::with_capacity(accessor.count * components);
+        accessor.for_each_component(|bytes| {
+            result.push(match (accessor.component_type, normalized) {
+                (COMPONENT_FLOAT, _) => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                (COMPONENT_UNSIGNED_BYTE, true) => bytes[0] as f32 / 255.0,
+                (COMPONENT_UNSIGNED_SHORT, true) => u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 65535.0,
+                (COMPONENT_BYTE, true) => (bytes[0] as i8 as f32 / 127.0).max(-1.0),
+                (COMPONENT_SHORT, true) => (i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32767.0).max(-1.0),
+                (COMPONENT_UNSIGNED_BYTE, false) => bytes[0] as f32,
+                (COMPONENT_UNSIGNED_SHORT, false) => u16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+                (COMPONENT_BYTE, false) => bytes[0] as i8 as f32,
+                (COMPONENT_SHORT, false) => i16::from_le_bytes([bytes[0], bytes[1]]) as f32,
+                _ => f32::NAN,
+            });
+        })?;
+        if result.iter().any(|value| value.is_nan()) {
+            bail!("Accessor {} has unsupported component type {}", accessor_index, accessor.component_type);
+        }
+        Ok(result)
+    }
+
+    fn read_indices(&self, accessor_index: usize) -> Result<Vec<u32>> {
+        let accessor = self.read_accessor(accessor_index)?;
+        let mut result = Vec::with_capacity(accessor.count);
+        let component_type = accessor.component_type;
+        if ![COMPONENT_UNSIGNED_BYTE, COMPONENT_UNSIGNED_SHORT, COMPONENT_UNSIGNED_INT].contains(&component_type) {
+            bail!("Accessor {} has unsupported index type {}", accessor_index, component_type);
+        }
+        accessor.for_each_component(|bytes| {
+            result.push(match component_type {
+                COMPONENT_UNSIGNED_BYTE => bytes[0] as u32,
+                COMPONENT_UNSIGNED_SHORT => u16::from_le_bytes([bytes[0], bytes[1]]) as u32,
+                _ => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            });
+        })?;
+        Ok(result)
+    }
+
+    fn read_accessor(&self, accessor_index: usize) -> Result<Accessor<'_>> {
+        let value = get(self.document, "accessors", accessor_index)?;
+        if value.get("sparse").is_some() {
+            bail!("Sparse accessors are not supported");
+        }
+        let component_type = value.get("componentType").and_then(Value::as_usize).ok_or_else(|| anyhow!("Accessor {} has no componentType", accessor_index))?;
+        let count = value.get("count").and_then(Value::as_usize).ok_or_else(|| anyhow!("Accessor {} has no count", accessor_index))?;
+        let components = match value.get("type").and_then(Value::as_str) {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            Some("MAT4") => 16,
+            other => bail!("Accessor {} has unsupported type {:?}", accessor_index, other),
+        };
+        let component_size = match component_type {
+            COMPONENT_BYTE | COMPONENT_UNSIGNED_BYTE => 1,
+            COMPONENT_SHORT | COMPONENT_UNSIGNED_SHORT => 2,
+            _ => 4,
+        };
+        let (data, stride) = match value.get("bufferView").and_then(Value::as_usize) {
+            Some(view_index) => {
+                let view = get(self.document, "bufferViews", view_index)?;
+                let stride = view.get("byteStride").and_then(Value::as_usize).unwrap_or(components * component_size);
+                let offset = value.get("byteOffset").and_then(Value::as_usize).unwrap_or(0);
+                let data = self.read_buffer_view(view_index)?;
+                if offset > data.len() {
+                    bail!("Accessor {} is out of its buffer view", accessor_index);
+                }
+                (&data[offset..], stride)
+            }
+            // accessors without buffer views are filled with zeros
+            None => (&[][..], components * component_size),
+        };
+        Ok(Accessor {
+            value,
+            data,
+            component_type,
+            component_size,
+            components,
+            count,
+            stride,
+        })
+    }
+
+    fn read_buffer_view(&self, view_index: usize) -> Result<&[u8]> {
+        let view = get(self.document, "bufferViews", view_index)?;
+        let buffer_index = view.get("buffer").and_then(Value::as_usize).ok_or_else(|| anyhow!("Buffer view {} has no buffer", view_index))?;
+        let buffer = self.buffers.get(buffer_index).ok_or_else(|| anyhow!("Buffer {} doesn't exist", buffer_index))?;
+        let offset = view.get("byteOffset").and_then(Value::as_usize).unwrap_or(0);
+        let length = view.get("byteLength").and_then(Value::as_usize).ok_or_else(|| anyhow!("Buffer view {} has no byteLength", view_index))?;
+        buffer.get(offset..offset + length).ok_or_else(|| anyhow!("Buffer view {} is out of its buffer", view_index))
+    }
+}
+
+struct Accessor<'a> {
+    value: &'a Value,
+    data: &'a [u8],
+    component_type: usize,
+    component_size: usize,
+    components: usize,
+    count: usize,
+    stride: usize,
+}
+
+impl<'a> Accessor<'a> {
+    fn for_each_component<F: FnMut(&[u8])>(&self, mut f: F) -> Result<()> {
+        if self.data.is_empty() {
+            let zeros = [0u8; 4];
+            for _ in 0..self.count * self.components {
+                f(&zeros[..self.component_size]);
+            }
+            return Ok(());
+        }
+        let element_size = self.components * self.component_size;
+        if self.count > 0 && (self.count - 1) * self.stride + element_size > self.data.len() {
+            bail!("Accessor data is out of its buffer view");
+        }
+        for i in 0..self.count {
+            let element = &self.data[i * self.stride..i * self.stride + element_size];
+            for component in element.chunks_exact(self.component_size) {
+                f(component);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_glb(bytes: &[u8]) -> Result<(Value, Option<Vec<u8>>)> {
+    let read_u32 = |offset: usize| -> Result<u32> {
+        let bytes = bytes.get(offset..offset + 4).ok_or_else(|| anyhow!("Unexpected end of glb file"))?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let version = read_u32(4)?;
+    if version != 2 {
+        bail!("Unsupported glb version {}", version);
+    }
+    let length = (read_u32(8)? as usize).min(bytes.len());
+    let mut document = None;
+    let mut bin = None;
+    let mut offset = 12;
+    while offset + 8 <= length {
+        let chunk_length = read_u32(offset)? as usize;
+        let chunk_type = read_u32(offset + 4)?;
+        let chunk = bytes.get(offset + 8..offset + 8 + chunk_length).ok_or_else(|| anyhow!("Unexpected end of glb file"))?;
+        match chunk_type {
+            GLB_CHUNK_JSON => document = Some(json::parse(std::str::from_utf8(chunk)?)?),
+            GLB_CHUNK_BIN if bin.is_none() => bin = Some(chunk.to_vec()),
+            // unknown chunks must be ignored
+            _ => {}
+        }
+        // chunks are 4 bytes aligned
+        offset += 8 + chunk_length.div_ceil(4) * 4;
+    }
+    Ok((document.ok_or_else(|| anyhow!("glb file has no json chunk"))?, bin))
+}
+
+fn load_buffers(document: &Value, base_dir: &Path, mut bin: Option<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+    let mut buffers = vec![];
+    for (i, buffer) in array(document, "buffers").iter().enumerate() {
+        let data = match buffer.get("uri").and_then(Value::as_str) {
+            Some(uri) => read_uri(uri, base_dir)?,
+            // only the first buffer can reference the glb binary chunk
+            None if i == 0 => bin.take().ok_or_else(|| anyhow!("Buffer 0 has no uri and there is no glb binary chunk"))?,
+            None => bail!("Buffer {} has no uri", i),
+        };
+        let length = buffer.get("byteLength").and_then(Value::as_usize).unwrap_or(data.len());
+        if data.len() < length {
+            bail!("Buffer {} is shorter than its byteLength", i);
+        }
+        buffers.push(data);
+    }
+    Ok(buffers)
+}
+
+/// Reads a base64 data uri or a file relative to the gltf file
+fn read_uri(uri: &str, base_dir: &Path) -> Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let (header, payload) = data.split_once(',').ok_or_else(|| anyhow!("Invalid data uri"))?;
+        if !header.ends_with(";base64") {
+            bail!("Only base64 data uris are supported");
+        }
+        return decode_base64(payload);
+    }
+    let path = base_dir.join(percent_decode(uri));
+    fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+fn decode_base64(input: &str) -> Result<Vec<u8>> {
+    let mut result = Vec::with_capacity(input.len() * 3 / 4);
+    let mut accumulator: u32 = 0;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' => break,
+            b'\n' | b'\r' | b' ' => continue,
+            _ => bail!("Invalid base64 character '{}'", c as char),
+        };
+        accumulator = (accumulator << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((accumulator >> bits) as u8);
+        }
+    }
+    Ok(result)
+}
+
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                result.push(byte);
+                i += 3;
+            }
+            None => {
+                result.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+fn node_matrix(node: &Value) -> Result<Matrix4<f32>> {
+    if let Some(matrix) = node.get("matrix") {
+        let m = matrix.as_f32_array::<16>().ok_or_else(|| anyhow!("Invalid node matrix"))?;
+        // gltf matrices are column major, same as cgmath
+        return Ok(Matrix4::new(
+            m[0], m[1], m[2], m[3],
+            m[4], m[5], m[6], m[7],
+            m[8], m[9], m[10], m[11],
+            m[12], m[13], m[14], m[15],
+        ));
+    }
+    let translation = node.get("translation").and_then(Value::as_f32_array::<3>).unwrap_or([0.0; 3]);
+    let rotation = node.get("rotation").and_then(Value::as_f32_array::<4>).unwrap_or([0.0, 0.0, 0.0, 1.0]);
+    let scale = node.get("scale").and_then(Value::as_f32_array::<3>).unwrap_or([1.0; 3]);
+    Ok(Transform {
+        position: translation.into(),
+        // gltf stores quaternions as x, y, z, w
+        rotation: Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]),
+        scale: scale.into(),
+    }.calc_matrix())
+}
+
+// the gltf default material is a white metal
+fn default_material() -> Material {
+    let mut material = Material::new(
+        "default",
+        model::white_texture("default_diffuse", TextureType::Diffuse),
+        model::flat_normal_texture("default"),
+        false,
+    );
+    material.metallic_factor = 1.0;
+    material.roughness_factor = 1.0;
+    material
+}
+
+fn array<'a>(document: &'a Value, key: &str) -> &'a [Value] {
+    document.get(key).and_then(Value::as_array).map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn get<'a>(document: &'a Value, key: &str, index: usize) -> Result<&'a Value> {
+    array(document, key).get(index).ok_or_else(|| anyhow!("{}[{}] doesn't exist", key, index))
+}
+
+fn usize_array(value: Option<&Value>) -> Vec<usize> {
+    value.and_then(Value::as_array).into_iter().flatten().filter_map(Value::as_usize).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::InnerSpace;
+    use std::path::PathBuf;
+
+    // a 2x2 square in the xy plane, 4 positions and 6 u16 indices in square.bin
+    const SQUARE: &str = r#"
+        "buffers": [{"uri": "square.bin", "byteLength": 60}],
+        "bufferViews": [
+            {"buffer": 0, "byteOffset": 0, "byteLength": 48},
+            {"buffer": 0, "byteOffset": 48, "byteLength": 12}
+        ],
+        "accessors": [
+            {"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3"},
+            {"bufferView": 1, "componentType": 5123, "count": 6, "type": "SCALAR"}
+        ]"#;
+
+    // writes the gltf file and square.bin into a directory of the test
+    fn write_gltf(test: &str, json: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pointz-gltf-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut bin = vec![];
+        for value in [-1.0f32, -1.0, 0.0, 1.0, -1.0, 0.0, 1.0, 1.0, 0.0, -1.0, 1.0, 0.0] {
+            bin.extend(value.to_le_bytes());
+        }
+        for index in [0u16, 1, 2, 0, 2, 3] {
+            bin.extend(index.to_le_bytes());
+        }
+        fs::write(dir.join("square.bin"), bin).unwrap();
+        let path = dir.join("scene.gltf");
+        fs::write(&path, format!("{{{},{}}}", SQUARE, json)).unwrap();
+        path
+    }
+
+    fn load_gltf(test: &str, json: &str) -> Result<GltfScene> {
+        let path = write_gltf(test, json);
+        let scene = load(&path, &mut IndexDriver::new());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        scene
+    }
+
+    #[test]
+    fn meshes_without_normals_get_flat_ones() {
+        let scene = load_gltf("flat", r#"
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1}]}],
+            "nodes": [{"mesh": 0}]"#).unwrap();
+        let mesh = &scene.models[0].meshes[0];
+        assert_eq!(mesh.vertices.len(), 6);
+        assert_eq!(mesh.indices, [0, 1, 2, 3, 4, 5]);
+        for vertex in mesh.vertices.iter() {
+            assert_eq!(vertex.normal, Vector3::unit_z());
+            assert!(vertex.tangent.dot(vertex.normal).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn nodes_become_objects_with_world_transforms() {
+        let scene = load_gltf("nodes", r#"
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1}]}],
+            "nodes": [
+                {"translation": [1, 0, 0], "scale": [2, 2, 2], "children": [1, 2]},
+                {"mesh": 0, "name": "first", "translation": [0, 1, 0]},
+                {"mesh": 0, "matrix": [1, 0, 0, 0, 0, 1, 0, 0, 0, 0, 1, 0, 0, 0, 3, 1]}
+            ],
+            "scenes": [{"nodes": [0]}]"#).unwrap();
+        let objects: Vec<(usize, Vector3<f32>, Option<String>)> = scene.objects
+            .into_iter()
+            .map(|(mesh, transform, name)| (mesh, transform.position, name))
+            .collect();
+        assert_eq!(objects, [
+            (0, Vector3::new(1.0, 2.0, 0.0), Some("first".to_string())),
+            (0, Vector3::new(1.0, 0.0, 6.0), None),
+        ]);
+    }
+
+    #[test]
+    fn rejects_node_cycles() {
+        let error = load_gltf("cycle", r#"
+            "meshes": [{"primitives": [{"attributes": {"POSITION": 0}, "indices": 1}]}],
+            "nodes": [{"children": [1]}, {"mesh": 0, "children": [0]}],
+            "scenes": [{"nodes": [0]}]"#).err().unwrap();
+        assert!(error.to_string().contains("Node 0"), "{}", error);
+        assert!(load_gltf("shared", r#"
+            "nodes": [{"children": [2]}, {"children": [2]}, {}],
+            "scenes": [{"nodes": [0, 1]}]"#).is_err());
+    }
+
+    #[test]
+    fn reads_the_normal_texture_scale() {
+        let path = write_gltf("normal-scale", r#"
+            "images": [{"uri": "normal%20map.png"}],
+            "textures": [{"source": 0}],
+            "materials": [
+                {"normalTexture": {"index": 0, "scale": 0.25}},
+                {"normalTexture": {"index": 0}}
+            ],
+            "meshes": [{"primitives": [
+                {"attributes": {"POSITION": 0}, "indices": 1, "material": 0},
+                {"attributes": {"POSITION": 0}, "indices": 1, "material": 1}
+            ]}]"#);
+        let dir = path.parent().unwrap().to_path_buf();
+        image::RgbaImage::from_pixel(2, 2, image::Rgba([128, 128, 255, 255])).save(dir.join("normal map.png")).unwrap();
+        let scene = load(&path, &mut IndexDriver::new());
+        fs::remove_dir_all(&dir).unwrap();
+        let materials = &scene.unwrap().models[0].materials;
+        assert!(materials.iter().all(|material| material.has_normal_map));
+        assert_eq!(materials.iter().map(|material| material.normal_scale).collect::<Vec<_>>(), [0.25, 1.0]);
+    }
+
+    #[test]
+    fn decodes_data_uris() {
+        assert_eq!(read_uri("data:application/octet-stream;base64,cG9p\nbnR6", Path::new(".")).unwrap(), b"pointz");
+        assert_eq!(decode_base64("AAE=").unwrap(), [0, 1]);
+        assert!(read_uri("data:text/plain,pointz", Path::new(".")).is_err());
+        assert_eq!(percent_decode("a%20b%2Fc%zz"), "a b/c%zz");
+    }
+}
@@ -0,0 +1,428 @@
+use anyhow::*;
+use std::collections::BTreeMap;
+
+/// Minimal JSON document model, enough for glTF files and our own metadata files
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(BTreeMap<String, Value>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(map) => map.get(key),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        self.as_f64().map(|n| n as f32)
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::Array(array) => Some(array),
+            _ => None,
+        }
+    }
+
+    /// Reads an array of numbers of exactly N elements, e.g. a vector or a matrix
+    pub fn as_f32_array<const N: usize>(&self) -> Option<[f32; N]> {
+        let array = self.as_array()?;
+        if array.len() != N {
+            return None;
+        }
+        let mut result = [0.0; N];
+        for (i, value) in array.iter().enumerate() {
+            result[i] = value.as_f32()?;
+        }
+        Some(result)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(n: f32) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::String(s)
+    }
+}
+
+impl<T: Into<Value>, const N: usize> From<[T; N]> for Value {
+    fn from(array: [T; N]) -> Self {
+        Value::Array(array.into_iter().map(Into::into).collect())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(array: Vec<Value>) -> Self {
+        Value::Array(array)
+    }
+}
+
+/// Builds an object from key value pairs, e.g. `object([("a", 1.0.into())])`
+pub fn object<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    Value::Object(entries.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+/// Serializes the value with 2 spaces indentation
+pub fn to_string_pretty(value: &Value) -> String {
+    let mut result = String::new();
+    write_value(value, 0, &mut result);
+    result.push('\n');
+    result
+}
+
+fn write_value(value: &Value, indent: usize, out: &mut String) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        // json has no infinities and nans
+        Value::Number(n) if !n.is_finite() => out.push_str("null"),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::String(s) => write_string(s, out),
+        Value::Array(array) if array.is_empty() => out.push_str("[]"),
+        Value::Array(array) => {
+            // arrays of numbers (vectors, matrices) are kept on one line
+            if array.iter().all(|value| matches!(value, Value::Number(_))) {
+                out.push('[');
+                for (i, value) in array.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(", ");
+                    }
+                    write_value(value, indent, out);
+                }
+                out.push(']');
+                return;
+            }
+            out.push_str("[\n");
+            for (i, value) in array.iter().enumerate() {
+                out.push_str(&"  ".repeat(indent + 1));
+                write_value(value, indent + 1, out);
+                out.push_str(if i + 1 < array.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&"  ".repeat(indent));
+            out.push(']');
+        }
+        Value::Object(map) if map.is_empty() => out.push_str("{}"),
+        Value::Object(map) => {
+            out.push_str("{\n");
+            for (i, (key, value)) in map.iter().enumerate() {
+                out.push_str(&"  ".repeat(indent + 1));
+                write_string(key, out);
+                out.push_str(": ");
+                write_value(value, indent + 1, out);
+                out.push_str(if i + 1 < map.len() { ",\n" } else { "\n" });
+            }
+            out.push_str(&"  ".repeat(indent));
+            out.push('}');
+        }
+    }
+}
+
+fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+// nesting limit of arrays and objects, the parser recurses and would overflow the stack on hostile files
+const MAX_DEPTH: usize = 128;
+
+pub fn parse(source: &str) -> Result<Value> {
+    let mut parser = Parser {
+        bytes: source.as_bytes(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    if parser.position != parser.bytes.len() {
+        bail!("Unexpected trailing characters at {}", parser.position);
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // arrays and objects the parser is inside of
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{' | b'[') => {
+                if self.depth == MAX_DEPTH {
+                    bail!("More than {} nested arrays and objects at {}", MAX_DEPTH, self.position);
+                }
+                self.depth += 1;
+                let value = if self.peek() == Some(b'{') { self.parse_object() } else { self.parse_array() };
+                self.depth -= 1;
+                value
+            }
+            Some(b'"') => Ok(Value::String(self.parse_string()?)),
+            Some(b't') => self.parse_literal("true", Value::Bool(true)),
+            Some(b'f') => self.parse_literal("false", Value::Bool(false)),
+            Some(b'n') => self.parse_literal("null", Value::Null),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(c) => bail!("Unexpected character '{}' at {}", c as char, self.position),
+            None => bail!("Unexpected end of json"),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value> {
+        self.expect(b'{')?;
+        let mut map = BTreeMap::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.position += 1;
+            return Ok(Value::Object(map));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            let value = self.parse_value()?;
+            map.insert(key, value);
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b'}') => return Ok(Value::Object(map)),
+                _ => bail!("Expected ',' or '}}' at {}", self.position),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value> {
+        self.expect(b'[')?;
+        let mut array = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.position += 1;
+            return Ok(Value::Array(array));
+        }
+        loop {
+            array.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => continue,
+                Some(b']') => return Ok(Value::Array(array)),
+                _ => bail!("Expected ',' or ']' at {}", self.position),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect(b'"')?;
+        let mut result = String::new();
+        loop {
+            let start = self.position;
+            while let Some(c) = self.peek() {
+                if c == b'"' || c == b'\\' {
+                    break;
+                }
+                self.position += 1;
+            }
+            result.push_str(std::str::from_utf8(&self.bytes[start..self.position])?);
+            match self.next() {
+                Some(b'"') => return Ok(result),
+                Some(b'\\') => result.push(self.parse_escape()?),
+                _ => bail!("Unterminated string"),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char> {
+        Ok(match self.next() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+                let high = self.parse_hex()?;
+                if (0xD800..0xDC00).contains(&high) {
+                    // surrogate pair
+                    self.expect(b'\\')?;
+                    self.expect(b'u')?;
+                    let low = self.parse_hex()?;
+                    let code = 0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                    char::from_u32(code).ok_or_else(|| anyhow!("Invalid unicode escape"))?
+                } else {
+                    char::from_u32(high).ok_or_else(|| anyhow!("Invalid unicode escape"))?
+                }
+            }
+            _ => bail!("Invalid escape sequence at {}", self.position),
+        })
+    }
+
+    fn parse_hex(&mut self) -> Result<u32> {
+        let end = self.position + 4;
+        if end > self.bytes.len() {
+            bail!("Unexpected end of json");
+        }
+        let hex = std::str::from_utf8(&self.bytes[self.position..end])?;
+        self.position = end;
+        Ok(u32::from_str_radix(hex, 16)?)
+    }
+
+    fn parse_number(&mut self) -> Result<Value> {
+        let start = self.position;
+        while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+            self.position += 1;
+        }
+        let number = std::str::from_utf8(&self.bytes[start..self.position])?;
+        Ok(Value::Number(number.parse().with_context(|| format!("Invalid number {}", number))?))
+    }
+
+    fn parse_literal(&mut self, literal: &str, value: Value) -> Result<Value> {
+        if self.bytes[self.position..].starts_with(literal.as_bytes()) {
+            self.position += literal.len();
+            Ok(value)
+        } else {
+            bail!("Unexpected token at {}", self.position)
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => bail!("Expected '{}' at {}", expected as char, self.position),
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\n' | b'\r' | b'\t') = self.peek() {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.peek();
+        self.position += 1;
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values() {
+        let value = parse(r#" {"a": [1, -2.5e1, true, null], "b": {"c": "x\"\u00e9\ud83d\ude00"}, "d": []} "#).unwrap();
+        assert_eq!(value.get("a").unwrap().as_array().unwrap(), &[1.0.into(), (-25.0).into(), true.into(), Value::Null]);
+        assert_eq!(value.get("b").unwrap().get("c").unwrap().as_str(), Some("x\"\u{e9}\u{1f600}"));
+        assert_eq!(value.get("d").unwrap().as_array().unwrap().len(), 0);
+        assert_eq!(parse("3").unwrap().as_usize(), Some(3));
+        assert_eq!(parse("3.5").unwrap().as_usize(), None);
+    }
+
+    #[test]
+    fn writes_what_it_parses() {
+        let value = object([
+            ("name", "a \"quoted\"\n\u{1}name".into()),
+            ("matrix", [1.0, 0.5, -2.0].into()),
+            ("nested", vec![object([("flag", false.into())]), Value::Null].into()),
+            ("empty", object([])),
+        ]);
+        assert_eq!(parse(&to_string_pretty(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn rejects_broken_json() {
+        for source in ["", "{", "[1,]", "{\"a\" 1}", "[1] 2", "\"unterminated", "tru", "\"\\u12\"", "1e"] {
+            assert!(parse(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&nested(1_000_000)).is_err());
+    }
+}
@@ -1,28 +1,121 @@
-#[repr(C)]
+use cgmath::{Angle, Deg, InnerSpace, Vector3};
+use std::ops::Range;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LightKind {
+    Point,
+    /// Lights the whole scene from `direction`, the position is only used for the gizmo
+    Directional,
+    /// Full intensity inside the inner angle, fading out towards the outer one
+    Spot { inner_angle: Deg<f32>, outer_angle: Deg<f32> },
+}
+
+/// Shadow map settings of a light, only directional and spot lights cast shadows
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Shadow {
+    /// World space distance surfaces are pushed along their normals before the depth test, against shadow acne
+    pub bias: f32,
+    /// Size of the square shadow map, directional lights have one per cascade
+    pub resolution: u32,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Shadow {
+            bias: 0.05,
+            resolution: 2048,
+        }
+    }
+}
+
+/// A light component of a scene object, see scene::manager::Manager::add_light
 #[derive(Debug, Copy, Clone)]
 pub struct Light {
-    position: cgmath::Vector3<f32>,
-    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
-    _padding: u32,
-    color: cgmath::Vector3<f32>,
-    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
-    _padding2: u32,
+    pub kind: LightKind,
+    pub position: Vector3<f32>,
+    /// Where the light shines to, unused by point lights
+    pub direction: Vector3<f32>,
+    pub color: Vector3<f32>,
+    pub intensity: f32,
+    /// Point and spot lights fade out until this distance, 0 means they reach everything
+    pub range: f32,
+    /// None when the light doesn't cast shadows
+    pub shadow: Option<Shadow>,
 }
 
-unsafe impl bytemuck::Zeroable for Light {}
-unsafe impl bytemuck::Pod for Light {}
-
 impl Light {
-    pub fn new(position: cgmath::Vector3<f32>, color: cgmath::Vector3<f32>) -> Self {
+    pub fn point(position: Vector3<f32>, color: Vector3<f32>) -> Self {
         Light {
+            kind: LightKind::Point,
             position,
-            _padding: 0,
+            direction: Vector3::new(0.0, -1.0, 0.0),
             color,
-            _padding2: 0,
+            intensity: 1.0,
+            range: 0.0,
+            shadow: None,
+        }
+    }
+
+    pub fn casts_shadows(&self) -> bool {
+        self.shadow.is_some() && self.kind != LightKind::Point
+    }
+
+    /// Falls back to straight down for a zero direction
+    pub fn normalized_direction(&self) -> Vector3<f32> {
+        if self.direction.magnitude2() > 0.0 {
+            self.direction.normalize()
+        } else {
+            Vector3::new(0.0, -1.0, 0.0)
+        }
+    }
+
+    /// `shadow_maps` are the indices of the light's shadow maps, see renderer::shadow::ShadowMaps
+    pub fn to_raw(self, shadow_maps: Option<Range<usize>>) -> RawLight {
+        let (kind, inner_cos, outer_cos) = match self.kind {
+            LightKind::Point => (0, 0.0, 0.0),
+            LightKind::Directional => (1, 0.0, 0.0),
+            LightKind::Spot { inner_angle, outer_angle } => {
+                (2, inner_angle.cos(), outer_angle.cos())
+            }
+        };
+        let (first_shadow, num_of_shadows) = match shadow_maps {
+            Some(shadow_maps) => (shadow_maps.start as i32, shadow_maps.len() as u32),
+            None => (-1, 0),
+        };
+        RawLight {
+            position: self.position.into(),
+            kind,
+            direction: self.normalized_direction().into(),
+            range: self.range,
+            color: self.color.into(),
+            intensity: self.intensity,
+            inner_cos,
+            outer_cos,
+            first_shadow,
+            num_of_shadows,
         }
     }
 }
 
+/// Light in the std430 layout of include/light.glsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct RawLight {
+    position: [f32; 3],
+    kind: u32,
+    direction: [f32; 3],
+    range: f32,
+    color: [f32; 3],
+    intensity: f32,
+    inner_cos: f32,
+    outer_cos: f32,
+    first_shadow: i32,
+    num_of_shadows: u32,
+}
+
+unsafe impl bytemuck::Zeroable for RawLight {}
+unsafe impl bytemuck::Pod for RawLight {}
+
 // pub trait DrawLight<'a, 'b>
 // where
 //     'b: 'a,
@@ -1,16 +1,25 @@
 extern crate log;
 
+use anyhow::Context;
 use app::App;
+use std::path::Path;
 
 mod app;
 mod camera;
+mod cli;
 mod editor;
 mod event;
+mod exit_code;
+mod gltf;
+mod json;
 mod lighting;
 mod model;
+mod octree;
+mod point_cloud;
 mod renderer;
 mod scene;
 mod shader;
+mod simplify;
 mod texture;
 mod widgets;
 
@@ -18,7 +27,64 @@ mod widgets;
 // todo you can load models in parallel, check learn-wgpu
 // todo move to glam
 pub fn main() {
-    // todo define a ROOT const
-    shader::compile_shaders("src/shader");
-    App::run();
+    let command = match cli::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+    match command {
+        cli::Command::Help => println!("{}", cli::USAGE),
+        cli::Command::ConvertOctree { input, output } => {
+            if let Err(e) = convert_octree(&input, &output) {
+                eprintln!("{:?}", e);
+                std::process::exit(1);
+            }
+        }
+        cli::Command::PackTextures { input, output } => {
+            match texture::pack_obj_textures(&input, &output) {
+                Ok(count) => println!("Packed {} textures of {} into {}", count, input.display(), output.display()),
+                Err(e) => {
+                    eprintln!("{:?}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        cli::Command::View(options) => {
+            if let Some(shader_dir) = &options.shader_dir {
+                compile_shaders(shader_dir);
+            }
+            match options.output.clone() {
+                Some(output) => {
+                    if let Err(e) = render_to_file(options, &output) {
+                        eprintln!("{:?}", e);
+                        std::process::exit(1);
+                    }
+                }
+                None => App::run(options),
+            }
+        }
+    }
+}
+
+// there are no pipelines to fall back to yet, so errors are fatal here unlike during hot reloads
+fn compile_shaders(dir: &Path) {
+    if let Err(e) = shader::compile_shaders(dir) {
+        eprintln!("{:?}", e);
+        std::process::exit(1);
+    }
+}
+
+fn render_to_file(options: cli::Options, output: &Path) -> anyhow::Result<()> {
+    App::render_offscreen(options)?
+        .save(output)
+        .with_context(|| format!("Failed to write {}", output.display()))
+}
+
+/// Prepares huge clouds for streaming
+fn convert_octree(input: &Path, output: &Path) -> anyhow::Result<()> {
+    let (num_of_points, num_of_nodes) = octree::convert(input, output)?;
+    println!("Converted {} points into {} octree nodes in {}", num_of_points, num_of_nodes, output.display());
+    Ok(())
 }
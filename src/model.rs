@@ -1,11 +1,20 @@
+use std::collections::HashMap;
 use std::mem;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use anyhow::*;
-use cgmath::{Vector2, Vector3, Zero};
+use cgmath::{InnerSpace, Vector2, Vector3, Zero};
 use iced_wgpu::wgpu;
 use tobj::LoadOptions;
 use crate::app::IndexDriver;
-use crate::texture;
+use crate::scene::bvh::{Aabb, Bvh};
+use crate::texture::TextureType;
+use crate::{gltf, simplify, texture};
+
+// name of the material meshes get when their file has none for them
+const DEFAULT_MATERIAL: &str = "default";
+
+/// Index of refraction of common dielectrics, it gives a reflectance of 4% at normal incidence
+pub const DEFAULT_IOR: f32 = 1.5;
 
 // todo move to render?
 pub trait Vertex {
@@ -40,26 +49,160 @@ pub struct Model {
     pub label: String,
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    pub source: ModelSource,
+}
+
+/// Where the model was loaded from, it's what scene files store instead of the geometry
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelSource {
+    pub path: PathBuf,
+    /// Index of the mesh for gltf files, they have one model per mesh
+    pub mesh: Option<usize>,
+}
+
+impl Model {
+    /// Radius of a sphere around the model space origin that contains all vertices
+    pub fn calc_bounding_sphere_radius(&self) -> f32 {
+        // we measure the distance between the model space 0,0,0 and a vertex, so vertex vector will always be the same as it's coords
+        self.meshes
+            .iter()
+            .flat_map(|mesh| mesh.vertices.iter())
+            .map(|vertex| vertex.position.magnitude())
+            .fold(0.0, f32::max)
+    }
+
+    pub fn calc_aabb(&self) -> Aabb {
+        Aabb::from_points(self.meshes.iter().flat_map(|mesh| mesh.vertices.iter().map(|vertex| &vertex.position)))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AlphaMode {
+    Opaque,
+    /// Fragments with alpha below the cutoff are discarded
+    Mask(f32),
+    /// Blended over what's behind, drawn after opaque meshes
+    Blend,
 }
 
+/// How the textures of a material are sampled, all of them have mipmaps
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TextureFilter {
+    /// Sharp texels, for pixel art
+    Nearest,
+    /// Linear within the nearest mip level
+    Bilinear,
+    /// Linear between mip levels too
+    Trilinear,
+    /// Trilinear with up to the given number of samples along the view, a power of 2 up to 16.
+    /// It keeps textures at grazing angles sharp
+    Anisotropic(u8),
+}
+
+impl Default for TextureFilter {
+    fn default() -> Self {
+        TextureFilter::Anisotropic(8)
+    }
+}
+
+/// Metallic-roughness material like the one of gltf, every texture is multiplied by its factor,
+/// missing textures are white placeholders so the factors are used as they are
+#[derive(Clone)]
 pub struct Material {
     pub name: String,
+    /// Base color
     pub diffuse_texture: texture::Texture,
     pub normal_texture: texture::Texture,
+    /// False when normal_texture is a flat placeholder, shaders then skip sampling it
+    pub has_normal_map: bool,
+    /// Scales x and y of the normal map, like the scale of a gltf normalTexture
+    pub normal_scale: f32,
+    /// Roughness in the green channel and metallic in the blue one
+    pub metallic_roughness_texture: texture::Texture,
+    pub emissive_texture: texture::Texture,
+    pub base_color_factor: [f32; 4],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub emissive_factor: [f32; 3],
+    /// Scales the reflectance of non-metals given by the ior
+    pub specular_factor: [f32; 3],
+    pub ior: f32,
+    pub alpha_mode: AlphaMode,
+    pub texture_filter: TextureFilter,
 }
 
 impl Material {
+    /// A rough white dielectric with the given maps
     pub fn new(
         name: &str,
         diffuse_texture: texture::Texture,
         normal_texture: texture::Texture,
+        has_normal_map: bool,
     ) -> Material {
         Material {
             name: String::from(name),
             diffuse_texture,
             normal_texture,
+            has_normal_map,
+            normal_scale: 1.0,
+            metallic_roughness_texture: white_texture(&format!("{}_metallic_roughness", name), TextureType::Data),
+            emissive_texture: white_texture(&format!("{}_emissive", name), TextureType::Diffuse),
+            base_color_factor: [1.0; 4],
+            metallic_factor: 0.0,
+            roughness_factor: 0.5,
+            emissive_factor: [0.0; 3],
+            specular_factor: [1.0; 3],
+            ior: DEFAULT_IOR,
+            alpha_mode: AlphaMode::Opaque,
+            texture_filter: TextureFilter::default(),
         }
     }
+
+    pub fn to_raw(&self) -> RawMaterial {
+        let alpha_cutoff = match self.alpha_mode {
+            AlphaMode::Mask(cutoff) => cutoff,
+            _ => 0.0,
+        };
+        RawMaterial {
+            base_color: self.base_color_factor,
+            emissive: self.emissive_factor,
+            metallic: self.metallic_factor,
+            specular: self.specular_factor,
+            roughness: self.roughness_factor,
+            ior: self.ior,
+            alpha_cutoff,
+            normal_scale: self.normal_scale,
+            _padding: 0.0,
+        }
+    }
+}
+
+/// Material factors in the std140 layout of shader.frag
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct RawMaterial {
+    base_color: [f32; 4],
+    emissive: [f32; 3],
+    metallic: f32,
+    specular: [f32; 3],
+    roughness: f32,
+    ior: f32,
+    alpha_cutoff: f32,
+    normal_scale: f32,
+    _padding: f32,
+}
+
+unsafe impl bytemuck::Pod for RawMaterial {}
+
+unsafe impl bytemuck::Zeroable for RawMaterial {}
+
+pub fn white_texture(label: &str, type_: TextureType) -> texture::Texture {
+    texture::Texture::from_color([255; 4], label, type_)
+}
+
+/// Normal map placeholder pointing straight out of the surface
+pub fn flat_normal_texture(name: &str) -> texture::Texture {
+    texture::Texture::from_color([128, 128, 255, 255], &format!("{}_normal", name), TextureType::Normal)
 }
 
 pub struct Mesh {
@@ -67,6 +210,23 @@ pub struct Mesh {
     pub vertices: Vec<ModelVertex>,
     pub indices: Vec<u32>,
     pub material_id: usize,
+    /// Without them every vertex has zero texture coordinates
+    pub has_tex_coords: bool,
+    /// Triangles bvh in model space, it's empty for line primitives
+    pub bvh: Bvh,
+    /// Simplified index lists over the same vertices, coarser ones last, see simplify::generate_lods
+    pub lods: Vec<Vec<u32>>,
+}
+
+impl Mesh {
+    pub fn get_triangle(&self, triangle_index: usize) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        let triangle = &self.indices[triangle_index * 3..triangle_index * 3 + 3];
+        (
+            self.vertices[triangle[0] as usize].position,
+            self.vertices[triangle[1] as usize].position,
+            self.vertices[triangle[2] as usize].position,
+        )
+    }
 }
 
 #[repr(C)]
@@ -133,15 +293,23 @@ impl Vertex for ModelVertex {
 
 pub struct Loader {
     index_driver: IndexDriver,
+    // problems that didn't stop loading, like missing textures
+    warnings: Vec<String>,
 }
 
 impl Loader {
     pub fn new() -> Self {
         Self {
             index_driver: IndexDriver::new(),
+            warnings: vec![],
         }
     }
 
+    /// Returns problems of the files loaded since the last call, their models were loaded with defaults
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
     pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<Model> {
         let (obj_models, obj_materials) = tobj::load_obj(
             path.as_ref(),
@@ -153,32 +321,20 @@ impl Loader {
             },
         )?;
 
-        // We're assuming that the texture files are stored with the obj file
-        let containing_folder = path.as_ref().parent().unwrap();
-
-        let mut materials = Vec::new();
-        for mat in obj_materials.unwrap() {
-            let diffuse_path = mat.diffuse_texture;
-            let diffuse_texture =
-                texture::Texture::load(containing_folder.join(diffuse_path), false)?;
-
-            let normal_path = mat.normal_texture;
-            let normal_texture = texture::Texture::load(containing_folder.join(normal_path), true)?;
-
-            materials.push(Material::new(&mat.name, diffuse_texture, normal_texture));
-        }
+        let mut materials = self.load_obj_materials(path.as_ref(), obj_materials);
 
         let mut meshes = Vec::new();
         for m in obj_models {
             let mut vertices = Vec::new();
             for i in 0..m.mesh.positions.len() / 3 {
-                let tex_coords: Vector2<f32> = if m.mesh.texcoords.len() == 0 {
+                let tex_coords: Vector2<f32> = if m.mesh.texcoords.is_empty() {
                     Vector2::new(0.0, 0.0)
                 } else {
                     Vector2::new(m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1])
                 };
-                let normal: Vector3<f32> = if m.mesh.normals.len() == 0 {
-                    Vector3::new(1.0, 1.0, 1.0)
+                // calculated after all vertices are there
+                let normal: Vector3<f32> = if m.mesh.normals.is_empty() {
+                    Vector3::zero()
                 } else {
                     Vector3::new(
                         m.mesh.normals[i * 3],
@@ -195,70 +351,28 @@ impl Loader {
                         .into(),
                     // tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]].into(),
                     tex_coords,
-                    // normal: [
-                    //     m.mesh.normals[i * 3],
-                    //     m.mesh.normals[i * 3 + 1],
-                    //     m.mesh.normals[i * 3 + 2],
-                    // ]
-                    //     .into(),
                     normal,
                     tangent: [0.0; 3].into(),
                     bitangent: [0.0; 3].into(),
                 });
             }
 
-            let indices = &m.mesh.indices;
-
-            // Calculate tangents and bitangets. We're going to
-            // use the triangles, so we need to loop through the
-            // indices in chunks of 3
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0 = v0.position;
-                let pos1 = v1.position;
-                let pos2 = v2.position;
-
-                let uv0 = v0.tex_coords;
-                let uv1 = v1.tex_coords;
-                let uv2 = v2.tex_coords;
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                // This will give us a direction to calculate the
-                // tangent and bitangent
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                // Luckily, the place I found this equation provided
-                // the solution!
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
-
-                // We'll use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent = tangent;
-                vertices[c[1] as usize].tangent = tangent;
-                vertices[c[2] as usize].tangent = tangent;
-
-                vertices[c[0] as usize].bitangent = bitangent;
-                vertices[c[1] as usize].bitangent = bitangent;
-                vertices[c[2] as usize].bitangent = bitangent;
+            if m.mesh.normals.is_empty() {
+                calc_smooth_normals(&mut vertices, &m.mesh.indices);
             }
+            calc_tangents(&mut vertices, &m.mesh.indices);
 
+            let positions: Vec<Vector3<f32>> = vertices.iter().map(|vertex| vertex.position).collect();
+            let bvh = Bvh::from_triangles(&positions, &m.mesh.indices);
+            let lods = simplify::generate_lods(&vertices, &m.mesh.indices);
             meshes.push(Mesh {
                 name: m.name,
                 vertices,
                 indices: m.mesh.indices,
-                material_id: m.mesh.material_id.unwrap_or(0),
+                material_id: default_material_id(m.mesh.material_id, &mut materials),
+                has_tex_coords: !m.mesh.texcoords.is_empty(),
+                bvh,
+                lods,
             });
         }
 
@@ -267,29 +381,27 @@ impl Loader {
             label: String::from(path.as_ref().file_name().unwrap().to_str().unwrap()),
             meshes,
             materials,
+            source: ModelSource {
+                path: path.as_ref().to_path_buf(),
+                mesh: None,
+            },
         })
     }
 
-    pub fn load_primitive<P: AsRef<Path>>(&mut self, path: P) -> Result<Model> {
-        let (obj_models, obj_materials) = tobj::load_obj(
-            path.as_ref(),
+    /// Loads a .gltf or .glb file, every gltf mesh becomes a separate model
+    pub fn load_gltf<P: AsRef<Path>>(&mut self, path: P) -> Result<gltf::GltfScene> {
+        gltf::load(path.as_ref(), &mut self.index_driver)
+    }
+
+    /// Loads a helper model built into the binary, its meshes get the default material
+    pub fn load_primitive(&mut self, label: &str, obj: &[u8]) -> Result<Model> {
+        let (obj_models, _) = tobj::load_obj_buf(
+            &mut &obj[..],
             &LoadOptions::default(),
+            |_| std::result::Result::Ok(Default::default()),
         )?;
 
-        // We're assuming that the texture files are stored with the obj file
-        let containing_folder = path.as_ref().parent().unwrap();
-
-        let mut materials = Vec::new();
-        for mat in obj_materials.unwrap() {
-            let diffuse_path = mat.diffuse_texture;
-            let diffuse_texture =
-                texture::Texture::load(containing_folder.join(diffuse_path), false)?;
-
-            let normal_path = mat.normal_texture;
-            let normal_texture = texture::Texture::load(containing_folder.join(normal_path), true)?;
-
-            materials.push(Material::new(&mat.name, diffuse_texture, normal_texture));
-        }
+        let mut materials = vec![];
 
         let mut meshes = Vec::new();
         for m in obj_models {
@@ -314,15 +426,194 @@ impl Loader {
                 name: m.name,
                 vertices,
                 indices: m.mesh.indices,
-                material_id: m.mesh.material_id.unwrap_or(0),
+                material_id: default_material_id(m.mesh.material_id, &mut materials),
+                has_tex_coords: false,
+                bvh: Bvh::default(),
+                // lines can't be simplified
+                lods: vec![],
             });
         }
 
         Ok(Model {
             id: self.index_driver.next_id(),
-            label: String::from(path.as_ref().file_name().unwrap().to_str().unwrap()),
+            label: String::from(label),
             meshes,
             materials,
+            source: ModelSource {
+                path: PathBuf::from(label),
+                mesh: None,
+            },
         })
     }
+
+    /// A missing or broken MTL file leaves the model without materials, meshes then get the default one
+    fn load_obj_materials(&mut self, path: &Path, obj_materials: Result<Vec<tobj::Material>, tobj::LoadError>) -> Vec<Material> {
+        let obj_materials = obj_materials.unwrap_or_else(|e| {
+            self.warnings.push(format!("{}: failed to load materials, {}", path.display(), e));
+            vec![]
+        });
+        // We're assuming that the texture files are stored with the obj file
+        let containing_folder = path.parent().unwrap();
+        obj_materials.iter().map(|mat| self.load_obj_material(mat, containing_folder)).collect()
+    }
+
+    /// Converts a Blinn-Phong MTL material. Parameters of the PBR extension of MTL (Pr, Pm, map_Ke) are used
+    /// when they are there, otherwise roughness comes from the specular exponent
+    fn load_obj_material(&mut self, mat: &tobj::Material, folder: &Path) -> Material {
+        let normal_texture = self.load_obj_texture(mat, &mat.normal_texture, folder, TextureType::Normal);
+        let has_normal_map = normal_texture.is_some();
+        let normal_texture = normal_texture.unwrap_or_else(|| flat_normal_texture(&mat.name));
+        // Blender writes Kd next to map_Kd although it only uses the map, so Kd is only the fallback
+        let (diffuse_texture, [r, g, b]) = match self.load_obj_texture(mat, &mat.diffuse_texture, folder, TextureType::Diffuse) {
+            Some(diffuse_texture) => (diffuse_texture, [1.0; 3]),
+            None => (white_texture(&format!("{}_diffuse", mat.name), TextureType::Diffuse), mat.diffuse),
+        };
+        let mut material = Material::new(&mat.name, diffuse_texture, normal_texture, has_normal_map);
+        material.base_color_factor = [r, g, b, mat.dissolve];
+        material.metallic_factor = mtl_floats(mat, "Pm").map_or(0.0, |[metallic]| metallic);
+        // the inverse of Blender's export, Ns = (1 - roughness)^2 * 1000
+        material.roughness_factor = mtl_floats(mat, "Pr")
+            .map_or_else(|| 1.0 - (mat.shininess.clamp(0.0, 1000.0) / 1000.0).sqrt(), |[roughness]| roughness);
+        material.emissive_factor = mtl_floats(mat, "Ke").unwrap_or([0.0; 3]);
+        let emissive_path = mat.unknown_param.get("map_Ke").map(String::as_str).unwrap_or_default();
+        if let Some(emissive_texture) = self.load_obj_texture(mat, emissive_path, folder, TextureType::Diffuse) {
+            material.emissive_texture = emissive_texture;
+            if !mat.unknown_param.contains_key("Ke") {
+                material.emissive_factor = [1.0; 3];
+            }
+        }
+        // Blender writes its principled specular as Ks, the default 0.5 keeps the reflectance of the ior
+        material.specular_factor = mat.specular.map(|specular| specular * 2.0);
+        // Ni 1 means no reflections at all, Blender writes it when the ior wasn't set
+        material.ior = if mat.optical_density > 1.0 { mat.optical_density } else { DEFAULT_IOR };
+        if mat.dissolve < 1.0 {
+            material.alpha_mode = AlphaMode::Blend;
+        }
+        material
+    }
+
+    /// None when the material has no such map or it can't be loaded, the caller falls back to a default texture
+    fn load_obj_texture(&mut self, mat: &tobj::Material, file: &str, folder: &Path, type_: TextureType) -> Option<texture::Texture> {
+        if file.is_empty() {
+            return None;
+        }
+        let texture = texture::Texture::load(folder.join(file), type_);
+        if let Err(e) = &texture {
+            self.warnings.push(format!("Material {}: failed to load {}, {:#}", mat.name, file, e));
+        }
+        texture.ok()
+    }
+}
+
+/// Meshes without a material or with a broken reference get the default one, it's added on first use
+fn default_material_id(material_id: Option<usize>, materials: &mut Vec<Material>) -> usize {
+    if let Some(material_id) = material_id.filter(|material_id| *material_id < materials.len()) {
+        return material_id;
+    }
+    if let Some(default_id) = materials.iter().position(|material| material.name == DEFAULT_MATERIAL) {
+        return default_id;
+    }
+    materials.push(Material::new(
+        DEFAULT_MATERIAL,
+        white_texture(&format!("{}_diffuse", DEFAULT_MATERIAL), TextureType::Diffuse),
+        flat_normal_texture(DEFAULT_MATERIAL),
+        false,
+    ));
+    materials.len() - 1
+}
+
+/// Parameters tobj doesn't know, like "Ke 0.1 0.1 0.1"
+fn mtl_floats<const N: usize>(mat: &tobj::Material, key: &str) -> Option<[f32; N]> {
+    let values: Vec<f32> = mat.unknown_param.get(key)?.split_whitespace().filter_map(|value| value.parse().ok()).collect();
+    values.try_into().ok()
+}
+
+/// Calculates tangents and bitangents from texture coordinates, averaged over the triangles of every vertex.
+/// Tangents are made perpendicular to normals, bitangents follow the handedness of the uv mapping
+pub fn calc_tangents(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let mut tangents = vec![Vector3::zero(); vertices.len()];
+    let mut bitangents = vec![Vector3::zero(); vertices.len()];
+    for c in indices.chunks_exact(3) {
+        let v0 = vertices[c[0] as usize];
+        let v1 = vertices[c[1] as usize];
+        let v2 = vertices[c[2] as usize];
+
+        // Calculate the edges of the triangle
+        let delta_pos1 = v1.position - v0.position;
+        let delta_pos2 = v2.position - v0.position;
+        let delta_uv1 = v1.tex_coords - v0.tex_coords;
+        let delta_uv2 = v2.tex_coords - v0.tex_coords;
+
+        // Solving the following system of equations will
+        // give us the tangent and bitangent.
+        //     delta_pos1 = delta_uv1.x * T + delta_uv1.y * B
+        //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+        let determinant = delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x;
+        // all three vertices share a uv or lie on a line in uv space, the triangle has no tangent space
+        if determinant.abs() < f32::EPSILON {
+            continue;
+        }
+        // not normalized, so bigger triangles weigh more
+        let r = 1.0 / determinant;
+        let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+        let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * r;
+        for index in c {
+            tangents[*index as usize] += tangent;
+            bitangents[*index as usize] += bitangent;
+        }
+    }
+
+    for (vertex, (tangent, bitangent)) in vertices.iter_mut().zip(tangents.into_iter().zip(bitangents)) {
+        let normal = vertex.normal.normalize();
+        // Gram-Schmidt, vertices without uvs get any tangent perpendicular to the normal
+        let mut tangent = tangent - normal * normal.dot(tangent);
+        if tangent.magnitude2() < f32::EPSILON {
+            tangent = any_perpendicular(normal);
+        }
+        let tangent = tangent.normalize();
+        // mirrored uvs flip the bitangent
+        let handedness = if normal.cross(tangent).dot(bitangent) < 0.0 { -1.0 } else { 1.0 };
+        vertex.tangent = tangent;
+        vertex.bitangent = normal.cross(tangent) * handedness;
+    }
+}
+
+fn any_perpendicular(vector: Vector3<f32>) -> Vector3<f32> {
+    let axis = if vector.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+    vector.cross(axis)
+}
+
+/// Normals for meshes that come without them, the face normals around every position are averaged
+/// weighted by area. Vertices at the same position share the normal, so uv seams stay smooth
+pub fn calc_smooth_normals(vertices: &mut [ModelVertex], indices: &[u32]) {
+    let position_key = |position: Vector3<f32>| [position.x.to_bits(), position.y.to_bits(), position.z.to_bits()];
+    let mut normals: HashMap<[u32; 3], Vector3<f32>> = HashMap::new();
+    for c in indices.chunks_exact(3) {
+        let [p0, p1, p2] = [c[0], c[1], c[2]].map(|index| vertices[index as usize].position);
+        // the length of the cross product is twice the area of the triangle
+        let face_normal = (p1 - p0).cross(p2 - p0);
+        for position in [p0, p1, p2] {
+            *normals.entry(position_key(position)).or_insert_with(Vector3::zero) += face_normal;
+        }
+    }
+    for vertex in vertices.iter_mut() {
+        let normal = normals.get(&position_key(vertex.position)).copied().unwrap_or_else(Vector3::zero);
+        // points and lines have no faces, they get any unit normal
+        vertex.normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { Vector3::unit_z() };
+    }
+}
+
+/// Face normals for meshes that must not be smoothed, like gltf meshes without normals.
+/// Every triangle gets its own vertices, so the result has as many vertices as indices
+pub fn calc_flat_normals(vertices: &[ModelVertex], indices: &[u32]) -> (Vec<ModelVertex>, Vec<u32>) {
+    let mut flat_vertices = Vec::with_capacity(indices.len());
+    for c in indices.chunks_exact(3) {
+        let [v0, v1, v2] = [c[0], c[1], c[2]].map(|index| vertices[index as usize]);
+        let normal = (v1.position - v0.position).cross(v2.position - v0.position);
+        // degenerate triangles have no direction
+        let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { Vector3::unit_z() };
+        flat_vertices.extend([v0, v1, v2].map(|vertex| ModelVertex { normal, ..vertex }));
+    }
+    let flat_indices = (0..flat_vertices.len() as u32).collect();
+    (flat_vertices, flat_indices)
 }
@@ -0,0 +1,564 @@
+use crate::camera::Frustum;
+use crate::json;
+use crate::point_cloud::{self, PointVertex};
+use crate::scene::bvh::Aabb;
+use anyhow::*;
+use cgmath::{InnerSpace, Vector3};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+const METADATA_FILE: &str = "octree.json";
+const NODES_DIR: &str = "nodes";
+// points of nodes that are not split yet, the directory is removed once the conversion is done
+const BUCKETS_DIR: &str = "buckets";
+const ROOT_NODE_NAME: &str = "r";
+const VERSION: usize = 1;
+// nodes with fewer points keep all of them and are not split any further
+const MAX_NODE_POINTS: usize = 20_000;
+// every node keeps at most one point per cell of a grid with this many cells along a side
+const SAMPLING_GRID_SIZE: f32 = 128.0;
+// buckets with more points are split on disk, it's 32mb of points and 8mb of indices in memory
+const MAX_IN_MEMORY_POINTS: usize = 2 * 1024 * 1024;
+// protects from endless splitting of many points at the same position
+const MAX_LEVEL: usize = 20;
+// nodes smaller than this on screen are not drawn, their parents already have enough points
+const MIN_NODE_PIXEL_SIZE: f32 = 150.0;
+
+pub struct OctreeNode {
+    /// "r" for the root, every child adds its octant index 0-7, e.g. "r05"
+    pub name: String,
+    pub bounds: Aabb,
+    pub num_of_points: usize,
+    /// Indices in Octree::nodes
+    pub children: Vec<usize>,
+}
+
+/// Point cloud converted into an octree where every node holds a sparse sample of its area
+/// and children add more detail. Only the hierarchy is kept in memory, node points are read on demand
+pub struct Octree {
+    pub id: usize,
+    pub label: String,
+    pub directory: PathBuf,
+    /// The root is always the first one
+    pub nodes: Vec<OctreeNode>,
+}
+
+impl Octree {
+    pub fn open<P: AsRef<Path>>(directory: P, id: usize) -> Result<Octree> {
+        let directory = directory.as_ref();
+        let metadata_path = directory.join(METADATA_FILE);
+        let source = fs::read_to_string(&metadata_path)
+            .with_context(|| format!("Failed to read {}", metadata_path.display()))?;
+        let metadata = json::parse(&source)?;
+        let version = metadata.get("version").and_then(|version| version.as_usize());
+        if version != Some(VERSION) {
+            bail!("Unsupported octree version {:?} in {}", version, metadata_path.display());
+        }
+        let mut nodes = vec![];
+        for node in metadata.get("nodes").and_then(|nodes| nodes.as_array()).into_iter().flatten() {
+            nodes.push(read_node_metadata(node).ok_or_else(|| anyhow!("Invalid octree node {:?}", node))?);
+        }
+        if nodes.is_empty() {
+            bail!("Octree {} has no nodes", directory.display());
+        }
+        if nodes.iter().flat_map(|node| node.children.iter()).any(|child| *child >= nodes.len()) {
+            bail!("Octree {} has invalid child indices", directory.display());
+        }
+        Ok(Octree {
+            id,
+            label: metadata
+                .get("label")
+                .and_then(|label| label.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| directory.display().to_string()),
+            directory: directory.to_path_buf(),
+            nodes,
+        })
+    }
+
+    pub fn node_path(&self, node_index: usize) -> PathBuf {
+        node_path(&self.directory, &self.nodes[node_index].name)
+    }
+
+    /// Picks the nodes to draw, the ones taking more screen space go first until the point budget is spent.
+    /// A node is picked only together with its parent, so coarse levels are always there while details load
+    pub fn select_nodes(
+        &self,
+        frustum: &Frustum,
+        camera_position: Vector3<f32>,
+        pixels_per_unit: f32,
+        point_budget: usize,
+    ) -> Vec<usize> {
+        let mut selected = vec![];
+        if !frustum.intersects_aabb(&self.nodes[0].bounds) {
+            return selected;
+        }
+        let mut num_of_points = 0;
+        let mut candidates = BinaryHeap::from([Candidate {
+            node_index: 0,
+            priority: f32::INFINITY,
+        }]);
+        while let Some(candidate) = candidates.pop() {
+            let node = &self.nodes[candidate.node_index];
+            if num_of_points + node.num_of_points > point_budget {
+                break;
+            }
+            num_of_points += node.num_of_points;
+            selected.push(candidate.node_index);
+            for child_index in node.children.iter() {
+                let child = &self.nodes[*child_index];
+                if !frustum.intersects_aabb(&child.bounds) {
+                    continue;
+                }
+                let pixel_size = calc_pixel_size(&child.bounds, camera_position, pixels_per_unit);
+                if pixel_size >= MIN_NODE_PIXEL_SIZE {
+                    candidates.push(Candidate {
+                        node_index: *child_index,
+                        priority: pixel_size,
+                    });
+                }
+            }
+        }
+        selected
+    }
+}
+
+struct Candidate {
+    node_index: usize,
+    priority: f32,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.total_cmp(&other.priority)
+    }
+}
+
+// approximate size of the node's bounding sphere on screen
+fn calc_pixel_size(bounds: &Aabb, camera_position: Vector3<f32>, pixels_per_unit: f32) -> f32 {
+    let radius = (bounds.max - bounds.min).magnitude() / 2.0;
+    let distance = (bounds.centroid() - camera_position).magnitude();
+    if distance <= radius {
+        return f32::INFINITY;
+    }
+    radius / distance * pixels_per_unit
+}
+
+fn read_node_metadata(node: &json::Value) -> Option<OctreeNode> {
+    let bounds = node.get("bounds")?.as_f32_array::<6>()?;
+    let mut children = vec![];
+    for child in node.get("children")?.as_array()? {
+        children.push(child.as_usize()?);
+    }
+    Some(OctreeNode {
+        name: node.get("name")?.as_str()?.to_string(),
+        bounds: Aabb {
+            min: Vector3::new(bounds[0], bounds[1], bounds[2]),
+            max: Vector3::new(bounds[3], bounds[4], bounds[5]),
+        },
+        num_of_points: node.get("num_of_points")?.as_usize()?,
+        children,
+    })
+}
+
+fn node_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(NODES_DIR).join(format!("{}.bin", name))
+}
+
+/// Node files are raw PointVertex arrays
+pub fn read_node_points(path: &Path) -> Result<Vec<PointVertex>> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let vertex_size = std::mem::size_of::<PointVertex>();
+    if bytes.len() % vertex_size != 0 {
+        bail!("Corrupted octree node {}", path.display());
+    }
+    Ok(bytes
+        .chunks_exact(vertex_size)
+        .map(bytemuck::pod_read_unaligned::<PointVertex>)
+        .collect())
+}
+
+/// Offline conversion of a point cloud file into an octree directory, returns the number of points and nodes.
+/// The file is read twice, once for the bounds and once to put the points into a bucket file of the root.
+/// Buckets too large for memory are split on disk into buckets of the children, so any cloud fits
+pub fn convert(input: &Path, directory: &Path) -> Result<(usize, usize)> {
+    convert_with(input, directory, MAX_IN_MEMORY_POINTS)
+}
+
+fn convert_with(input: &Path, directory: &Path, max_in_memory_points: usize) -> Result<(usize, usize)> {
+    let mut bounds = Aabb::empty();
+    let mut num_of_points = 0;
+    point_cloud::read_points(input, &mut |batch| {
+        for point in batch {
+            bounds.grow(Vector3::from(point.position));
+        }
+        num_of_points += batch.len();
+        Ok(())
+    })?;
+    if num_of_points == 0 {
+        bail!("Point cloud {} is empty", input.display());
+    }
+    for dir in [NODES_DIR, BUCKETS_DIR] {
+        fs::create_dir_all(directory.join(dir)).with_context(|| format!("Failed to create {}", directory.display()))?;
+    }
+    // nodes are cubes, so the sampling grid cells are cubes too
+    let size = bounds.max - bounds.min;
+    let side = size.x.max(size.y).max(size.z).max(f32::EPSILON);
+    bounds.max = bounds.min + Vector3::new(side, side, side);
+
+    let root_bucket = bucket_path(directory, ROOT_NODE_NAME);
+    let mut writer = BufWriter::new(create_file(&root_bucket)?);
+    // the file could change between the passes, the bucket has what was actually read
+    let mut num_of_points = 0;
+    let offset = point_cloud::read_points(input, &mut |batch| {
+        num_of_points += batch.len();
+        Ok(writer.write_all(bytemuck::cast_slice(batch))?)
+    })?;
+    writer.flush()?;
+    drop(writer);
+
+    let mut converter = Converter {
+        directory,
+        root_spacing: side / SAMPLING_GRID_SIZE,
+        max_in_memory_points,
+        nodes: vec![],
+    };
+    converter.build_bucket(String::from(ROOT_NODE_NAME), bounds, num_of_points, 0)?;
+    // every bucket is removed once it's split
+    fs::remove_dir(directory.join(BUCKETS_DIR))?;
+
+    let nodes: Vec<json::Value> = converter
+        .nodes
+        .iter()
+        .map(|node| {
+            json::object([
+                ("name", node.name.as_str().into()),
+                (
+                    "bounds",
+                    [node.bounds.min.x, node.bounds.min.y, node.bounds.min.z, node.bounds.max.x, node.bounds.max.y, node.bounds.max.z].into(),
+                ),
+                ("num_of_points", node.num_of_points.into()),
+                ("children", node.children.iter().map(|child| (*child).into()).collect::<Vec<_>>().into()),
+            ])
+        })
+        .collect();
+    let label = input.file_name().map_or_else(|| input.display().to_string(), |name| name.to_string_lossy().into_owned());
+    // the viewer doesn't need the offset, it's kept for tools that place the cloud in its original coordinates
+    let metadata = json::object([
+        ("version", VERSION.into()),
+        ("label", label.into()),
+        ("offset", [offset.x, offset.y, offset.z].into()),
+        ("nodes", nodes.into()),
+    ]);
+    let metadata_path = directory.join(METADATA_FILE);
+    fs::write(&metadata_path, json::to_string_pretty(&metadata))
+        .with_context(|| format!("Failed to write {}", metadata_path.display()))?;
+    Ok((num_of_points, converter.nodes.len()))
+}
+
+fn bucket_path(directory: &Path, name: &str) -> PathBuf {
+    directory.join(BUCKETS_DIR).join(format!("{}.bin", name))
+}
+
+fn create_file(path: &Path) -> Result<fs::File> {
+    fs::File::create(path).with_context(|| format!("Failed to create {}", path.display()))
+}
+
+struct Converter<'a> {
+    directory: &'a Path,
+    root_spacing: f32,
+    max_in_memory_points: usize,
+    nodes: Vec<OctreeNode>,
+}
+
+impl<'a> Converter<'a> {
+    // builds the subtree of the points in the bucket file of the node, the file is removed
+    fn build_bucket(&mut self, name: String, bounds: Aabb, num_of_points: usize, level: usize) -> Result<usize> {
+        let bucket = bucket_path(self.directory, &name);
+        if num_of_points <= self.max_in_memory_points {
+            let points = read_node_points(&bucket)?;
+            fs::remove_file(&bucket)?;
+            let mut indices: Vec<u32> = (0..points.len() as u32).collect();
+            return self.build_node(&points, name, bounds, &mut indices, level);
+        }
+        let node_index = self.nodes.len();
+        self.nodes.push(OctreeNode {
+            name: name.clone(),
+            bounds,
+            num_of_points,
+            children: vec![],
+        });
+        let path = node_path(self.directory, &name);
+        if level >= MAX_LEVEL {
+            // too many points at the same position to split, the node keeps all of them
+            fs::rename(&bucket, &path).with_context(|| format!("Failed to write {}", path.display()))?;
+            return Ok(node_index);
+        }
+
+        // the first point of every occupied grid cell stays in the node, the others go to the child buckets
+        let spacing = self.root_spacing / (1 << level) as f32;
+        let center = bounds.centroid();
+        let mut occupied_cells = HashSet::new();
+        let mut node_writer = BufWriter::new(create_file(&path)?);
+        let mut child_writers: Vec<Option<BufWriter<fs::File>>> = (0..8).map(|_| None).collect();
+        let mut child_counts = [0; 8];
+        let mut num_sampled = 0;
+        let mut reader = BufReader::new(fs::File::open(&bucket).with_context(|| format!("Failed to open {}", bucket.display()))?);
+        let mut bytes = [0u8; std::mem::size_of::<PointVertex>()];
+        for _ in 0..num_of_points {
+            reader.read_exact(&mut bytes).with_context(|| format!("Failed to read {}", bucket.display()))?;
+            let position = bytemuck::pod_read_unaligned::<PointVertex>(&bytes).position;
+            let cell = (
+                ((position[0] - bounds.min.x) / spacing) as i32,
+                ((position[1] - bounds.min.y) / spacing) as i32,
+                ((position[2] - bounds.min.z) / spacing) as i32,
+            );
+            if occupied_cells.insert(cell) {
+                node_writer.write_all(&bytes)?;
+                num_sampled += 1;
+                continue;
+            }
+            let octant = calc_octant(position, center);
+            let writer = match &mut child_writers[octant] {
+                Some(writer) => writer,
+                writer => writer.insert(BufWriter::new(create_file(&bucket_path(self.directory, &format!("{}{}", name, octant)))?)),
+            };
+            writer.write_all(&bytes)?;
+            child_counts[octant] += 1;
+        }
+        node_writer.flush()?;
+        for writer in child_writers.iter_mut().flatten() {
+            writer.flush()?;
+        }
+        drop(child_writers);
+        fs::remove_file(&bucket)?;
+        self.nodes[node_index].num_of_points = num_sampled;
+
+        let mut children = vec![];
+        for (octant, count) in child_counts.into_iter().enumerate().filter(|(_, count)| *count > 0) {
+            let child_bounds = calc_child_bounds(&bounds, octant);
+            children.push(self.build_bucket(format!("{}{}", name, octant), child_bounds, count, level + 1)?);
+        }
+        self.nodes[node_index].children = children;
+        Ok(node_index)
+    }
+
+    fn build_node(&mut self, points: &[PointVertex], name: String, bounds: Aabb, indices: &mut [u32], level: usize) -> Result<usize> {
+        let num_of_points = if indices.len() <= MAX_NODE_POINTS || level >= MAX_LEVEL {
+            indices.len()
+        } else {
+            self.sample(points, &bounds, indices, level)
+        };
+        let (sampled, rest) = indices.split_at_mut(num_of_points);
+        let node_points: Vec<PointVertex> = sampled.iter().map(|i| points[*i as usize]).collect();
+        let path = node_path(self.directory, &name);
+        fs::write(&path, bytemuck::cast_slice(&node_points)).with_context(|| format!("Failed to write {}", path.display()))?;
+
+        let node_index = self.nodes.len();
+        self.nodes.push(OctreeNode {
+            name: name.clone(),
+            bounds,
+            num_of_points,
+            children: vec![],
+        });
+        if rest.is_empty() {
+            return Ok(node_index);
+        }
+
+        let center = bounds.centroid();
+        let get_octant = |i: &u32| calc_octant(points[*i as usize].position, center);
+        rest.sort_unstable_by_key(get_octant);
+        let mut children = vec![];
+        let mut start = 0;
+        while start < rest.len() {
+            let octant = get_octant(&rest[start]);
+            let end = start + rest[start..].iter().take_while(|i| get_octant(i) == octant).count();
+            let child_bounds = calc_child_bounds(&bounds, octant);
+            let child_name = format!("{}{}", name, octant);
+            children.push(self.build_node(points, child_name, child_bounds, &mut rest[start..end], level + 1)?);
+            start = end;
+        }
+        self.nodes[node_index].children = children;
+        Ok(node_index)
+    }
+
+    // moves the first point of every occupied grid cell to the beginning, returns the number of such points
+    fn sample(&self, points: &[PointVertex], bounds: &Aabb, indices: &mut [u32], level: usize) -> usize {
+        let spacing = self.root_spacing / (1 << level) as f32;
+        let mut occupied_cells = HashSet::new();
+        let mut num_sampled = 0;
+        for i in 0..indices.len() {
+            let position = points[indices[i] as usize].position;
+            let cell = (
+                ((position[0] - bounds.min.x) / spacing) as i32,
+                ((position[1] - bounds.min.y) / spacing) as i32,
+                ((position[2] - bounds.min.z) / spacing) as i32,
+            );
+            if occupied_cells.insert(cell) {
+                indices.swap(i, num_sampled);
+                num_sampled += 1;
+            }
+        }
+        num_sampled
+    }
+}
+
+// bit 0 is x, bit 1 is y, bit 2 is z
+fn calc_octant(position: [f32; 3], center: Vector3<f32>) -> usize {
+    (position[0] >= center.x) as usize | ((position[1] >= center.y) as usize) << 1 | ((position[2] >= center.z) as usize) << 2
+}
+
+fn calc_child_bounds(bounds: &Aabb, octant: usize) -> Aabb {
+    let center = bounds.centroid();
+    let mut child = *bounds;
+    if octant & 1 == 0 { child.max.x = center.x } else { child.min.x = center.x }
+    if octant & 2 == 0 { child.max.y = center.y } else { child.min.y = center.y }
+    if octant & 4 == 0 { child.max.z = center.z } else { child.min.z = center.z }
+    child
+}
+
+pub struct NodeRequest {
+    pub octree_id: usize,
+    pub node_index: usize,
+    pub path: PathBuf,
+}
+
+pub struct LoadedNode {
+    pub octree_id: usize,
+    pub node_index: usize,
+    pub points: Result<Vec<PointVertex>>,
+}
+
+/// Reads node files on a background thread, so disk access never blocks a frame
+pub struct NodeStreamer {
+    requests: Sender<NodeRequest>,
+    loaded: Receiver<LoadedNode>,
+}
+
+impl NodeStreamer {
+    pub fn new() -> NodeStreamer {
+        let (requests, request_receiver) = mpsc::channel::<NodeRequest>();
+        let (loaded_sender, loaded) = mpsc::channel();
+        thread::Builder::new()
+            .name(String::from("octree node loader"))
+            .spawn(move || {
+                // stops when the streamer is dropped
+                for request in request_receiver {
+                    let loaded = LoadedNode {
+                        octree_id: request.octree_id,
+                        node_index: request.node_index,
+                        points: read_node_points(&request.path),
+                    };
+                    if loaded_sender.send(loaded).is_err() {
+                        break;
+                    }
+                }
+            })
+            .expect("Failed to spawn the octree node loader");
+        NodeStreamer { requests, loaded }
+    }
+
+    pub fn request(&self, request: NodeRequest) {
+        // the loader thread lives as long as the streamer, so it can't fail
+        self.requests.send(request).unwrap();
+    }
+
+    pub fn try_recv(&self) -> Option<LoadedNode> {
+        self.loaded.try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a 200x200 grid, denser than the sampling grid of the root
+    fn write_grid_cloud(dir: &Path) -> PathBuf {
+        fs::create_dir_all(dir).unwrap();
+        let mut lines = String::new();
+        for x in 0..200 {
+            for z in 0..200 {
+                lines += &format!("{} {} {}\n", x, (x + z) % 3, z);
+            }
+        }
+        let path = dir.join("grid.xyz");
+        fs::write(&path, lines).unwrap();
+        path
+    }
+
+    // checks the hierarchy and returns the sorted positions of all node points
+    fn read_octree(directory: &Path) -> (Octree, Vec<[u32; 3]>) {
+        let octree = Octree::open(directory, 0).unwrap();
+        let mut positions = vec![];
+        for (node_index, node) in octree.nodes.iter().enumerate() {
+            let points = read_node_points(&octree.node_path(node_index)).unwrap();
+            assert_eq!(points.len(), node.num_of_points);
+            for point in points.iter() {
+                let position = Vector3::from(point.position);
+                for axis in 0..3 {
+                    assert!(position[axis] >= node.bounds.min[axis] - 1e-4 && position[axis] <= node.bounds.max[axis] + 1e-4);
+                }
+                positions.push(point.position.map(f32::to_bits));
+            }
+            for child in node.children.iter() {
+                assert!(*child > node_index);
+                assert_eq!(octree.nodes[*child].name.len(), node.name.len() + 1);
+                assert!(octree.nodes[*child].name.starts_with(&node.name));
+            }
+        }
+        positions.sort_unstable();
+        (octree, positions)
+    }
+
+    #[test]
+    fn converts_in_memory_and_on_disk_into_the_same_points() {
+        let dir = std::env::temp_dir().join(format!("pointz-octree-convert-{}", std::process::id()));
+        let input = write_grid_cloud(&dir);
+        let (in_memory, on_disk) = (dir.join("in-memory"), dir.join("on-disk"));
+        let converted_in_memory = convert_with(&input, &in_memory, usize::MAX).unwrap();
+        // the root and some of its children are too large, they're split through buckets
+        let converted_on_disk = convert_with(&input, &on_disk, 5_000).unwrap();
+        let (octree, in_memory_positions) = read_octree(&in_memory);
+        let (_, on_disk_positions) = read_octree(&on_disk);
+        let buckets_left = on_disk.join(BUCKETS_DIR).exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(converted_in_memory.0, 40_000);
+        assert_eq!(converted_on_disk.0, 40_000);
+        assert!(converted_on_disk.1 > 1);
+        assert_eq!(octree.label, "grid.xyz");
+        assert_eq!(in_memory_positions.len(), 40_000);
+        assert_eq!(in_memory_positions, on_disk_positions);
+        assert!(!buckets_left);
+    }
+
+    #[test]
+    fn rejects_empty_clouds() {
+        let dir = std::env::temp_dir().join(format!("pointz-octree-empty-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("empty.xyz");
+        fs::write(&input, "").unwrap();
+        let result = convert(&input, &dir.join("octree"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+}
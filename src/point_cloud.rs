@@ -0,0 +1,646 @@
+use crate::app::IndexDriver;
+use crate::model::Vertex;
+use crate::octree::Octree;
+use anyhow::*;
+use cgmath::Vector3;
+use iced_wgpu::wgpu;
+use std::fs;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::Path;
+
+const LAS_SIGNATURE: &[u8; 4] = b"LASF";
+const DEFAULT_COLOR: [u8; 4] = [255, 255, 255, 255];
+// readers pass points on in batches of this many
+const BATCH_SIZE: usize = 65_536;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct PointVertex {
+    pub position: [f32; 3],
+    /// sRGB, converted to linear in the shader
+    pub color: [u8; 4],
+}
+
+unsafe impl bytemuck::Pod for PointVertex {}
+
+unsafe impl bytemuck::Zeroable for PointVertex {}
+
+impl PointVertex {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array!(
+        0 => Float32x3,
+        1 => Unorm8x4,
+    );
+}
+
+impl Vertex for PointVertex {
+    // one instance per point, the shader expands every instance into a screen space quad
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<PointVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBUTES,
+        }
+    }
+}
+
+pub struct PointCloud {
+    pub id: usize,
+    pub label: String,
+    pub points: Vec<PointVertex>,
+}
+
+pub struct Loader {
+    index_driver: IndexDriver,
+}
+
+impl Loader {
+    pub fn new() -> Self {
+        Self {
+            index_driver: IndexDriver::new(),
+        }
+    }
+
+    /// Picks the format by the file extension: .ply, .las or .xyz/.txt/.pts/.csv
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<PointCloud> {
+        let path = path.as_ref();
+        let mut points = vec![];
+        read_points(path, &mut |batch| {
+            points.extend_from_slice(batch);
+            Ok(())
+        })?;
+        Ok(PointCloud {
+            id: self.index_driver.next_id(),
+            label: String::from(path.file_name().unwrap().to_str().unwrap()),
+            points,
+        })
+    }
+
+    /// Opens an octree directory made by `pointz convert-octree`, points are streamed while rendering
+    pub fn load_octree<P: AsRef<Path>>(&mut self, directory: P) -> Result<Octree> {
+        Octree::open(directory, self.index_driver.next_id())
+    }
+}
+
+/// Reads the file in batches of points, so clouds larger than the memory can be converted.
+/// Georeferenced clouds are moved close to the origin to keep f32 precision,
+/// returns the original position of the cloud's (0, 0, 0)
+pub fn read_points(path: &Path, sink: &mut dyn FnMut(&[PointVertex]) -> Result<()>) -> Result<Vector3<f64>> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let open = || -> Result<BufReader<fs::File>> {
+        Ok(BufReader::new(fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?))
+    };
+    match extension.as_deref() {
+        Some("ply") => stream_ply(open()?, sink).map(|_| Vector3::new(0.0, 0.0, 0.0)),
+        Some("las") => stream_las(open()?, sink),
+        Some("laz") => bail!("Compressed LAZ files are not supported, decompress {} to LAS first", path.display()),
+        Some("xyz" | "txt" | "pts" | "csv") => stream_xyz(open()?, sink).map(|_| Vector3::new(0.0, 0.0, 0.0)),
+        _ => bail!("Unsupported point cloud format: {}", path.display()),
+    }
+}
+
+// collects points and passes them on when there are BATCH_SIZE of them
+struct Batcher<'a> {
+    points: Vec<PointVertex>,
+    sink: &'a mut dyn FnMut(&[PointVertex]) -> Result<()>,
+}
+
+impl<'a> Batcher<'a> {
+    fn new(sink: &'a mut dyn FnMut(&[PointVertex]) -> Result<()>) -> Batcher<'a> {
+        Batcher {
+            points: Vec::with_capacity(BATCH_SIZE),
+            sink,
+        }
+    }
+
+    fn push(&mut self, point: PointVertex) -> Result<()> {
+        self.points.push(point);
+        if self.points.len() == BATCH_SIZE {
+            (self.sink)(&self.points)?;
+            self.points.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.points.is_empty() {
+            (self.sink)(&self.points)?;
+        }
+        Ok(())
+    }
+}
+
+/// Text file with one point per line: `x y z` or `x y z r g b`, values separated by spaces or commas.
+/// Colors can be either 0-255 or 0-1, lines that don't start with 3 numbers (headers, point counts) are skipped
+fn stream_xyz<R: BufRead + Seek>(mut reader: R, sink: &mut dyn FnMut(&[PointVertex]) -> Result<()>) -> Result<()> {
+    // the first pass finds out if every point has a color and which range the colors are in
+    let mut has_colors = true;
+    let mut max_color: f32 = 0.0;
+    for_each_xyz_line(&mut reader, |_, color| {
+        match color {
+            Some(color) => max_color = color.iter().fold(max_color, |max, c| max.max(*c)),
+            None => has_colors = false,
+        }
+        Ok(())
+    })?;
+    reader.rewind()?;
+
+    let scale = if max_color > 1.0 { 1.0 / 255.0 } else { 1.0 };
+    let mut batcher = Batcher::new(sink);
+    for_each_xyz_line(&mut reader, |position, color| {
+        batcher.push(PointVertex {
+            position,
+            color: match color {
+                Some(color) if has_colors => to_color(color.map(|c| c * scale)),
+                _ => DEFAULT_COLOR,
+            },
+        })
+    })?;
+    batcher.finish()
+}
+
+// calls f with the position and the color of every point line
+fn for_each_xyz_line<R: BufRead, F: FnMut([f32; 3], Option<[f32; 3]>) -> Result<()>>(reader: &mut R, mut f: F) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let values: Vec<f64> = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|value| !value.is_empty())
+            .map_while(|value| value.parse().ok())
+            .collect();
+        if values.len() < 3 {
+            continue;
+        }
+        // pts files have intensity between position and color
+        let color = match values.len() {
+            6 => Some([values[3], values[4], values[5]]),
+            7.. => Some([values[4], values[5], values[6]]),
+            _ => None,
+        };
+        f([values[0] as f32, values[1] as f32, values[2] as f32], color.map(|color| color.map(|c| c as f32)))?;
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Copy, Clone)]
+enum PlyType {
+    Int8,
+    Uint8,
+    Int16,
+    Uint16,
+    Int32,
+    Uint32,
+    Float32,
+    Float64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<PlyType> {
+        Ok(match name {
+            "char" | "int8" => PlyType::Int8,
+            "uchar" | "uint8" => PlyType::Uint8,
+            "short" | "int16" => PlyType::Int16,
+            "ushort" | "uint16" => PlyType::Uint16,
+            "int" | "int32" => PlyType::Int32,
+            "uint" | "uint32" => PlyType::Uint32,
+            "float" | "float32" => PlyType::Float32,
+            "double" | "float64" => PlyType::Float64,
+            _ => bail!("Unknown ply property type {}", name),
+        })
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            PlyType::Int8 | PlyType::Uint8 => 1,
+            PlyType::Int16 | PlyType::Uint16 => 2,
+            PlyType::Int32 | PlyType::Uint32 | PlyType::Float32 => 4,
+            PlyType::Float64 => 8,
+        }
+    }
+
+    /// Colors stored as integers are normalized by the type max value
+    fn color_scale(&self) -> f32 {
+        match self {
+            PlyType::Uint8 => 1.0 / 255.0,
+            PlyType::Uint16 => 1.0 / 65535.0,
+            _ => 1.0,
+        }
+    }
+
+    fn read(&self, bytes: &[u8], format: PlyFormat) -> f64 {
+        macro_rules! read {
+            ($t:ty) => {{
+                let mut buffer = [0u8; std::mem::size_of::<$t>()];
+                buffer.copy_from_slice(&bytes[..std::mem::size_of::<$t>()]);
+                match format {
+                    PlyFormat::BinaryBigEndian => <$t>::from_be_bytes(buffer) as f64,
+                    _ => <$t>::from_le_bytes(buffer) as f64,
+                }
+            }};
+        }
+        match self {
+            PlyType::Int8 => read!(i8),
+            PlyType::Uint8 => read!(u8),
+            PlyType::Int16 => read!(i16),
+            PlyType::Uint16 => read!(u16),
+            PlyType::Int32 => read!(i32),
+            PlyType::Uint32 => read!(u32),
+            PlyType::Float32 => read!(f32),
+            PlyType::Float64 => read!(f64),
+        }
+    }
+}
+
+struct PlyProperty {
+    name: String,
+    type_: PlyType,
+    // list properties store the type of their length
+    list_count_type: Option<PlyType>,
+}
+
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+fn stream_ply<R: BufRead>(mut reader: R, sink: &mut dyn FnMut(&[PointVertex]) -> Result<()>) -> Result<()> {
+    let (format, elements) = read_ply_header(&mut reader)?;
+    let mut batcher = Batcher::new(sink);
+    let mut line = String::new();
+    for element in elements.iter() {
+        let is_vertex = element.name == "vertex";
+        let property = |name: &str| element.properties.iter().position(|property| property.name == name);
+        let positions = [property("x"), property("y"), property("z")];
+        let colors = [
+            property("red").or_else(|| property("diffuse_red")),
+            property("green").or_else(|| property("diffuse_green")),
+            property("blue").or_else(|| property("diffuse_blue")),
+        ];
+        if is_vertex && positions.iter().any(Option::is_none) {
+            bail!("Ply vertex element has no x, y, z properties");
+        }
+        let mut values = vec![0.0; element.properties.len()];
+        for _ in 0..element.count {
+            match format {
+                PlyFormat::Ascii => {
+                    line.clear();
+                    if reader.read_line(&mut line).context("Ascii ply body is not valid utf-8")? == 0 {
+                        bail!("Unexpected end of ply file");
+                    }
+                    let mut tokens = line.split_whitespace();
+                    for (i, property) in element.properties.iter().enumerate() {
+                        let mut token = || -> Result<f64> {
+                            Ok(tokens.next().ok_or_else(|| anyhow!("Not enough values in ply line"))?.parse()?)
+                        };
+                        values[i] = token()?;
+                        if property.list_count_type.is_some() {
+                            for _ in 0..values[i] as usize {
+                                token()?;
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    for (i, property) in element.properties.iter().enumerate() {
+                        let mut read = |type_: PlyType| -> Result<f64> {
+                            let mut buffer = [0u8; 8];
+                            reader
+                                .read_exact(&mut buffer[..type_.size()])
+                                .map_err(|_| anyhow!("Unexpected end of ply file"))?;
+                            Ok(type_.read(&buffer, format))
+                        };
+                        match property.list_count_type {
+                            Some(count_type) => {
+                                let count = read(count_type)? as usize;
+                                for _ in 0..count {
+                                    read(property.type_)?;
+                                }
+                            }
+                            None => values[i] = read(property.type_)?,
+                        }
+                    }
+                }
+            }
+            if !is_vertex {
+                continue;
+            }
+            let mut point = PointVertex {
+                position: positions.map(|i| values[i.unwrap()] as f32),
+                color: DEFAULT_COLOR,
+            };
+            if let [Some(r), Some(g), Some(b)] = colors {
+                point.color = to_color([r, g, b].map(|i| {
+                    values[i] as f32 * element.properties[i].type_.color_scale()
+                }));
+            }
+            batcher.push(point)?;
+        }
+        if is_vertex {
+            return batcher.finish();
+        }
+    }
+    bail!("Ply file has no vertex element")
+}
+
+// leaves the reader at the beginning of the body
+fn read_ply_header<R: BufRead>(reader: &mut R) -> Result<(PlyFormat, Vec<PlyElement>)> {
+    let mut format = None;
+    let mut elements: Vec<PlyElement> = vec![];
+    let mut first_line = true;
+    let mut bytes = vec![];
+    loop {
+        bytes.clear();
+        if reader.read_until(b'\n', &mut bytes)? == 0 || bytes.last() != Some(&b'\n') {
+            bail!("Ply header has no end_header");
+        }
+        let line = std::str::from_utf8(&bytes)?.trim();
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if first_line {
+            if line != "ply" {
+                bail!("Not a ply file");
+            }
+            first_line = false;
+            continue;
+        }
+        match tokens.as_slice() {
+            ["format", "ascii", _] => format = Some(PlyFormat::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(PlyFormat::BinaryLittleEndian),
+            ["format", "binary_big_endian", _] => format = Some(PlyFormat::BinaryBigEndian),
+            ["element", name, count] => elements.push(PlyElement {
+                name: name.to_string(),
+                count: count.parse()?,
+                properties: vec![],
+            }),
+            ["property", "list", count_type, type_, name] => {
+                elements.last_mut().ok_or_else(|| anyhow!("Ply property before element"))?.properties.push(PlyProperty {
+                    name: name.to_string(),
+                    type_: PlyType::parse(type_)?,
+                    list_count_type: Some(PlyType::parse(count_type)?),
+                });
+            }
+            ["property", type_, name] => {
+                elements.last_mut().ok_or_else(|| anyhow!("Ply property before element"))?.properties.push(PlyProperty {
+                    name: name.to_string(),
+                    type_: PlyType::parse(type_)?,
+                    list_count_type: None,
+                });
+            }
+            ["end_header"] => break,
+            // comments, obj_info and unknown lines
+            _ => {}
+        }
+    }
+    Ok((format.ok_or_else(|| anyhow!("Ply header has no format"))?, elements))
+}
+
+/// LAS 1.0-1.4, uncompressed. LAS files are Z up, points are converted to Y up and moved to the origin
+fn stream_las<R: BufRead + Seek>(mut reader: R, sink: &mut dyn FnMut(&[PointVertex]) -> Result<()>) -> Result<Vector3<f64>> {
+    let file_length = reader.seek(SeekFrom::End(0))?;
+    reader.rewind()?;
+    // the 1.4 header is the longest one
+    let mut bytes = vec![0u8; file_length.min(375) as usize];
+    reader.read_exact(&mut bytes)?;
+    if !bytes.starts_with(LAS_SIGNATURE) || bytes.len() < 227 {
+        bail!("Not a las file");
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let f64_at = |offset: usize| f64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+
+    let version_minor = bytes[25];
+    let point_data_offset = u32_at(96) as u64;
+    let point_format = bytes[104];
+    if point_format & 0xC0 != 0 {
+        bail!("Compressed LAZ point data is not supported");
+    }
+    let record_length = u16_at(105) as usize;
+    let mut count = u32_at(107) as u64;
+    if count == 0 && version_minor >= 4 && bytes.len() >= 255 {
+        count = u64::from_le_bytes(bytes[247..255].try_into().unwrap());
+    }
+    let scale = [f64_at(131), f64_at(139), f64_at(147)];
+    let offset = [f64_at(155), f64_at(163), f64_at(171)];
+    let min = [f64_at(187), f64_at(203), f64_at(219)];
+    let max = [f64_at(179), f64_at(195), f64_at(211)];
+    let center = [0, 1, 2].map(|i| (min[i] + max[i]) * 0.5);
+
+    let color_offset = match point_format {
+        2 => Some(20),
+        3 | 5 => Some(28),
+        7 | 8 | 10 => Some(30),
+        0 | 1 | 4 | 6 | 9 => None,
+        _ => bail!("Unsupported las point format {}", point_format),
+    };
+    if record_length < color_offset.map_or(20, |color_offset| color_offset + 6) {
+        bail!("Las point record length {} is too small for point format {}", record_length, point_format);
+    }
+    // the counts come from the file, a broken header must not overflow or make us read past the end
+    count
+        .checked_mul(record_length as u64)
+        .and_then(|length| length.checked_add(point_data_offset))
+        .filter(|data_end| *data_end <= file_length)
+        .ok_or_else(|| anyhow!("Las file is shorter than its header says, {} points of {} bytes", count, record_length))?;
+
+    // colors are usually 16 bit, but some writers store 8 bit values, the first pass finds out
+    let mut record = vec![0u8; record_length];
+    let mut max_color: u16 = 0;
+    let mut max_intensity: u16 = 1;
+    reader.seek(SeekFrom::Start(point_data_offset))?;
+    for _ in 0..count {
+        reader.read_exact(&mut record)?;
+        max_intensity = max_intensity.max(u16::from_le_bytes([record[12], record[13]]));
+        if let Some(color_offset) = color_offset {
+            for i in 0..3 {
+                max_color = max_color.max(u16::from_le_bytes([record[color_offset + i * 2], record[color_offset + i * 2 + 1]]));
+            }
+        }
+    }
+    let color_scale = if max_color > 255 { 1.0 / 65535.0 } else { 1.0 / 255.0 };
+
+    reader.seek(SeekFrom::Start(point_data_offset))?;
+    let mut batcher = Batcher::new(sink);
+    for _ in 0..count {
+        reader.read_exact(&mut record)?;
+        let coordinate = |i: usize| {
+            let value = i32::from_le_bytes(record[i * 4..i * 4 + 4].try_into().unwrap());
+            (value as f64 * scale[i] + offset[i] - center[i]) as f32
+        };
+        let (x, y, z) = (coordinate(0), coordinate(1), coordinate(2));
+        let color = match color_offset {
+            Some(color_offset) => [0, 1, 2].map(|i| {
+                u16::from_le_bytes([record[color_offset + i * 2], record[color_offset + i * 2 + 1]]) as f32 * color_scale
+            }),
+            None => [u16::from_le_bytes([record[12], record[13]]) as f32 / max_intensity as f32; 3],
+        };
+        batcher.push(PointVertex {
+            position: [x, z, -y],
+            color: to_color(color),
+        })?;
+    }
+    batcher.finish()?;
+    Ok(Vector3::new(center[0], center[2], -center[1]))
+}
+
+fn to_color(color: [f32; 3]) -> [u8; 4] {
+    let [r, g, b] = color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+    [r, g, b, 255]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read_las(bytes: &[u8]) -> Result<(Vec<PointVertex>, Vector3<f64>)> {
+        let mut points = vec![];
+        let offset = stream_las(Cursor::new(bytes), &mut |batch| {
+            points.extend_from_slice(batch);
+            Ok(())
+        })?;
+        Ok((points, offset))
+    }
+
+    fn read_ply(bytes: &[u8]) -> Result<Vec<PointVertex>> {
+        let mut points = vec![];
+        stream_ply(bytes, &mut |batch| {
+            points.extend_from_slice(batch);
+            Ok(())
+        })?;
+        Ok(points)
+    }
+
+    fn read_xyz(text: &str) -> Result<Vec<PointVertex>> {
+        let mut points = vec![];
+        stream_xyz(Cursor::new(text.as_bytes()), &mut |batch| {
+            points.extend_from_slice(batch);
+            Ok(())
+        })?;
+        Ok(points)
+    }
+
+    // header of a las 1.2 file with point format 2, 26 byte records follow it
+    fn las_header(count: u32, record_length: u16) -> Vec<u8> {
+        let mut header = vec![0u8; 227];
+        header[..4].copy_from_slice(LAS_SIGNATURE);
+        header[24] = 1;
+        header[25] = 2;
+        header[96..100].copy_from_slice(&227u32.to_le_bytes());
+        header[104] = 2;
+        header[105..107].copy_from_slice(&record_length.to_le_bytes());
+        header[107..111].copy_from_slice(&count.to_le_bytes());
+        for (offset, value) in [
+            // scale
+            (131, 0.01), (139, 0.01), (147, 0.01),
+            // offset
+            (155, 100.0), (163, 200.0), (171, 0.0),
+            // max and min of x, y and z
+            (179, 102.0), (187, 100.0), (195, 200.0), (203, 200.0), (211, 1.0), (219, 1.0),
+        ] {
+            header[offset..offset + 8].copy_from_slice(&f64::to_le_bytes(value));
+        }
+        header
+    }
+
+    fn las_record(position: [i32; 3], color: [u16; 3]) -> Vec<u8> {
+        let mut record = vec![0u8; 26];
+        for i in 0..3 {
+            record[i * 4..i * 4 + 4].copy_from_slice(&position[i].to_le_bytes());
+            record[20 + i * 2..22 + i * 2].copy_from_slice(&color[i].to_le_bytes());
+        }
+        record
+    }
+
+    #[test]
+    fn reads_las_points_y_up_around_the_center() {
+        let mut bytes = las_header(2, 26);
+        bytes.extend(las_record([0, 0, 100], [65535, 0, 0]));
+        bytes.extend(las_record([200, 0, 100], [0, 0, 65535]));
+        let (points, center) = read_las(&bytes).unwrap();
+        assert_eq!(center, Vector3::new(101.0, 1.0, -200.0));
+        assert_eq!(points.iter().map(|point| point.position).collect::<Vec<_>>(), [[-1.0, 0.0, 0.0], [1.0, 0.0, 0.0]]);
+        assert_eq!(points[0].color, [255, 0, 0, 255]);
+        assert_eq!(points[1].color, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn rejects_las_files_shorter_than_their_header() {
+        let mut bytes = las_header(2, 26);
+        bytes.extend(las_record([0, 0, 0], [0, 0, 0]));
+        assert!(read_las(&bytes).is_err());
+        // count * record_length overflows on 32 bit targets and is far past the end everywhere
+        let bytes = las_header(u32::MAX, u16::MAX);
+        assert!(read_las(&bytes).is_err());
+        assert!(read_las(&las_header(1, 10)).is_err());
+        assert!(read_las(b"LASF").is_err());
+    }
+
+    #[test]
+    fn reads_ascii_and_binary_ply() {
+        let ascii = b"ply\nformat ascii 1.0\ncomment made by hand\nelement vertex 2\nproperty float x\nproperty float y\nproperty float z\n\
+            property uchar red\nproperty uchar green\nproperty uchar blue\nelement face 0\nproperty list uchar int vertex_indices\nend_header\n\
+            1 2 3 255 0 0\n-1 -2 -3 0 255 0\n";
+        let points = read_ply(ascii).unwrap();
+        assert_eq!(points.iter().map(|point| point.position).collect::<Vec<_>>(), [[1.0, 2.0, 3.0], [-1.0, -2.0, -3.0]]);
+        assert_eq!(points[1].color, [0, 255, 0, 255]);
+
+        // a list property before the position, the big endian body has one point
+        let mut binary = b"ply\nformat binary_big_endian 1.0\nelement vertex 1\nproperty list uchar short extra\n\
+            property double x\nproperty double y\nproperty double z\nend_header\n".to_vec();
+        binary.extend([2, 0, 1, 0, 2]);
+        for value in [0.5f64, 1.5, -2.0] {
+            binary.extend(value.to_be_bytes());
+        }
+        let points = read_ply(&binary).unwrap();
+        assert_eq!(points[0].position, [0.5, 1.5, -2.0]);
+        assert_eq!(points[0].color, DEFAULT_COLOR);
+    }
+
+    #[test]
+    fn rejects_broken_ply() {
+        assert!(read_ply(b"obj\nend_header\n").is_err());
+        assert!(read_ply(b"ply\nelement vertex 1\nproperty float x\nend_header\n").is_err());
+        assert!(read_ply(b"ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nend_header\n1\n").is_err());
+        // far more points than the body has
+        assert!(read_ply(b"ply\nformat binary_little_endian 1.0\nelement vertex 4000000000\n\
+            property float x\nproperty float y\nproperty float z\nend_header\n").is_err());
+    }
+
+    #[test]
+    fn reads_xyz_with_and_without_colors() {
+        let points = read_xyz("2\n1 2 3 255 128 0\n4,5,6,0,0,255\n").unwrap();
+        assert_eq!(points.iter().map(|point| point.position).collect::<Vec<_>>(), [[1.0, 2.0, 3.0], [4.0, 5.0, 6.0]]);
+        assert_eq!(points[0].color, [255, 128, 0, 255]);
+
+        // pts files have intensity before the color, colors between 0 and 1 are scaled up
+        let points = read_xyz("1 2 3 7 1 0.5 0\n").unwrap();
+        assert_eq!(points[0].color, [255, 128, 0, 255]);
+
+        let points = read_xyz("x y z\n1 2 3 1 0 0\n4 5 6\n").unwrap();
+        assert_eq!(points.len(), 2);
+        assert!(points.iter().all(|point| point.color == DEFAULT_COLOR));
+    }
+
+    #[test]
+    fn passes_points_on_in_batches() {
+        let text: String = (0..BATCH_SIZE + 1).map(|i| format!("{} 0 0\n", i)).collect();
+        let mut batches = vec![];
+        stream_xyz(Cursor::new(text.as_bytes()), &mut |batch| {
+            batches.push(batch.len());
+            Ok(())
+        }).unwrap();
+        assert_eq!(batches, [BATCH_SIZE, 1]);
+    }
+}
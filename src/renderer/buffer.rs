@@ -86,10 +86,29 @@ impl<T: bytemuck::Pod + Copy + 'static> DynamicBuffer<T> {
                 0,
                 (self.len * std::mem::size_of::<T>()) as u64,
             );
+            queue.submit(std::iter::once(encoder.finish()));
             self.buffer = buffer;
         }
         queue.write_buffer(&self.buffer, (self.len * std::mem::size_of::<T>()) as u64, bytemuck::cast_slice(data));
-        self.len = self.len + data.len();
+        self.len += data.len();
+    }
+
+    /// Overwrites already appended elements starting at `index`
+    pub fn write(&self, queue: &wgpu::Queue, index: usize, data: &[T]) {
+        assert!(index + data.len() <= self.len, "DynamicBuffer write out of bounds");
+        queue.write_buffer(&self.buffer, (index * std::mem::size_of::<T>()) as u64, bytemuck::cast_slice(data));
+    }
+
+    /// Forgets elements after `len`, the memory stays allocated and will be reused by the next append
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len {
+            self.len = len;
+        }
+    }
+
+    /// Elements that fit without growing, the buffer is replaced when it grows
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
 
     pub fn get_buffer(&self) -> &wgpu::Buffer {
@@ -1,4 +1,3 @@
-use std::fs;
 use crate::renderer::render::Drawer;
 use crate::model::{SimpleVertex, Vertex};
 use iced_wgpu::wgpu;
@@ -7,11 +6,14 @@ use crate::renderer::render;
 
 pub struct DebugDrawer {
     render_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
     vertex_buff: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
 }
 
 impl DebugDrawer {
+    pub const SHADERS: [&'static str; 2] = ["line.vert", "line.frag"];
+
     pub fn new(device: &wgpu::Device, uniform_buffer: &wgpu::Buffer) -> DebugDrawer {
         let debug_uniform_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -35,30 +37,13 @@ impl DebugDrawer {
             }],
             label: Some("debug_uniform_bind_group"),
         });
-        let debug_render_pipeline = {
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("debug pipeline"),
-                bind_group_layouts: &[&debug_uniform_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-            let vs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("line.vert"),
-                source: wgpu::util::make_spirv(&fs::read("src/shader/spv/line.vert.spv").unwrap()),
-            });
-            let fs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("line.frag"),
-                source: wgpu::util::make_spirv(&fs::read("src/shader/spv/line.frag.spv").unwrap()),
-            });
-            render::build_render_pipeline(
-                device,
-                &layout,
-                vs_module,
-                fs_module,
-                SimpleVertex::desc(),
-                wgpu::PrimitiveTopology::LineList,
-                "debug_render_pipeline",
-            )
-        };
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("debug pipeline"),
+            bind_group_layouts: &[&debug_uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = DebugDrawer::create_render_pipeline(device, &pipeline_layout)
+            .unwrap_or_else(|e| panic!("{:?}", e));
         let vertex_buff = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Vertex Buffer"),
             contents: bytemuck::cast_slice(&[
@@ -72,12 +57,31 @@ impl DebugDrawer {
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
         });
         DebugDrawer {
-            render_pipeline: debug_render_pipeline,
+            render_pipeline,
+            pipeline_layout,
             vertex_buff,
             uniform_bind_group,
         }
     }
 
+    /// Rebuilds the pipeline from the compiled shaders, the old one stays on errors
+    pub fn reload_pipeline(&mut self, device: &wgpu::Device) -> anyhow::Result<()> {
+        self.render_pipeline = DebugDrawer::create_render_pipeline(device, &self.pipeline_layout)?;
+        Ok(())
+    }
+
+    fn create_render_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout) -> anyhow::Result<wgpu::RenderPipeline> {
+        render::create_render_pipeline(
+            device,
+            layout,
+            DebugDrawer::SHADERS,
+            &[],
+            &[SimpleVertex::desc()],
+            wgpu::PrimitiveTopology::LineList,
+            "debug_render_pipeline",
+        )
+    }
+
     pub fn add_line(&mut self, start: SimpleVertex, end: SimpleVertex, queue: &wgpu::Queue) {
         queue.write_buffer(&self.vertex_buff, 0, bytemuck::cast_slice(&[start, end]));
     }
@@ -0,0 +1,212 @@
+use crate::renderer::render;
+use iced_wgpu::wgpu;
+use std::ops::Range;
+
+// threads of a workgroup in draw_args.comp
+const WORKGROUP_SIZE: u32 = 64;
+const INITIAL_DRAWS: usize = 64;
+const INITIAL_VISIBILITIES: usize = 16;
+// wgpu::util::DrawIndexedIndirect
+const DRAW_ARGS_SIZE: usize = 5 * std::mem::size_of::<u32>();
+// Params in draw_args.comp, padded to the 16 bytes of a uniform
+const PARAMS_SIZE: usize = 4 * std::mem::size_of::<u32>();
+
+/// A mesh drawn with the visible instances of its model, see Draw in draw_args.comp
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct RawDraw {
+    pub index_count: u32,
+    pub first_index: u32,
+    pub base_vertex: i32,
+    /// Where the visible instances of the model at the level of detail start in the visible instance buffer
+    pub first_instance: u32,
+    /// Index of the visible instance count of the model at the level of detail of the draw
+    pub visibility: u32,
+}
+
+unsafe impl bytemuck::Pod for RawDraw {}
+unsafe impl bytemuck::Zeroable for RawDraw {}
+
+/// Draw arguments of all meshes of a drawer. A compute pass fills instance counts of the indirect args
+/// after culling, so draws with the same pipeline and material go in one multi draw call.
+/// Without indirect first instance support the draws are made one by one from the cpu copy
+pub struct DrawCommands {
+    // None when draws are made on the cpu
+    pipeline: Option<wgpu::ComputePipeline>,
+    pipeline_layout: wgpu::PipelineLayout,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    draw_buffer: wgpu::Buffer,
+    visible_count_buffer: wgpu::Buffer,
+    args_buffer: wgpu::Buffer,
+    // number of draws, the storage buffers are bigger
+    params_buffer: wgpu::Buffer,
+    draw_capacity: usize,
+    visibility_capacity: usize,
+    draws: Vec<RawDraw>,
+    visible_counts: Vec<u32>,
+    multi_draw: bool,
+}
+
+impl DrawCommands {
+    pub const SHADER: &'static str = "draw_args.comp";
+
+    pub fn new(device: &wgpu::Device) -> DrawCommands {
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let params_entry = wgpu::BindGroupLayoutEntry {
+            binding: 3,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[storage_entry(0, true), storage_entry(1, true), storage_entry(2, false), params_entry],
+            label: Some("draw_args_bind_group_layout"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("draw args pipeline"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let draw_buffer = create_buffer(device, "draw buffer", INITIAL_DRAWS * std::mem::size_of::<RawDraw>(), wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+        let visible_count_buffer = create_buffer(device, "visible count buffer", INITIAL_VISIBILITIES * std::mem::size_of::<u32>(), wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+        let args_buffer = create_buffer(device, "draw args buffer", INITIAL_DRAWS * DRAW_ARGS_SIZE, wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT);
+        let params_buffer = create_buffer(device, "draw args params buffer", PARAMS_SIZE, wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST);
+        let bind_group = create_bind_group(device, &bind_group_layout, [&draw_buffer, &visible_count_buffer, &args_buffer, &params_buffer]);
+        // the instance attributes have to start at the first visible instance of the model
+        let pipeline = if device.features().contains(wgpu::Features::INDIRECT_FIRST_INSTANCE) {
+            render::create_compute_pipeline(device, &pipeline_layout, DrawCommands::SHADER, "draw_args_pipeline")
+                .map_err(|e| log::error!("{:?}", e))
+                .ok()
+        } else {
+            None
+        };
+        DrawCommands {
+            pipeline,
+            pipeline_layout,
+            bind_group_layout,
+            bind_group,
+            draw_buffer,
+            visible_count_buffer,
+            args_buffer,
+            params_buffer,
+            draw_capacity: INITIAL_DRAWS,
+            visibility_capacity: INITIAL_VISIBILITIES,
+            draws: vec![],
+            visible_counts: vec![],
+            multi_draw: device.features().contains(wgpu::Features::MULTI_DRAW_INDIRECT),
+        }
+    }
+
+    /// Rebuilds the compute pipeline from the compiled shader, the old one stays on errors
+    pub fn reload_pipeline(&mut self, device: &wgpu::Device) -> anyhow::Result<()> {
+        if self.pipeline.is_some() {
+            self.pipeline = Some(render::create_compute_pipeline(device, &self.pipeline_layout, DrawCommands::SHADER, "draw_args_pipeline")?);
+        }
+        Ok(())
+    }
+
+    /// Replaces all draws, nothing is visible until the next set_visible_counts
+    pub fn set_draws(&mut self, draws: Vec<RawDraw>, num_of_visibilities: usize, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if draws.len() > self.draw_capacity || num_of_visibilities > self.visibility_capacity {
+            self.draw_capacity = self.draw_capacity.max(draws.len() * 2);
+            self.visibility_capacity = self.visibility_capacity.max(num_of_visibilities * 2);
+            self.draw_buffer = create_buffer(device, "draw buffer", self.draw_capacity * std::mem::size_of::<RawDraw>(), wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+            self.visible_count_buffer = create_buffer(device, "visible count buffer", self.visibility_capacity * std::mem::size_of::<u32>(), wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST);
+            self.args_buffer = create_buffer(device, "draw args buffer", self.draw_capacity * DRAW_ARGS_SIZE, wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::INDIRECT);
+            self.bind_group = create_bind_group(device, &self.bind_group_layout, [&self.draw_buffer, &self.visible_count_buffer, &self.args_buffer, &self.params_buffer]);
+        }
+        if !draws.is_empty() {
+            queue.write_buffer(&self.draw_buffer, 0, bytemuck::cast_slice(&draws));
+        }
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[draws.len() as u32, 0, 0, 0]));
+        self.draws = draws;
+        self.set_visible_counts(vec![0; num_of_visibilities], queue);
+    }
+
+    /// Numbers of visible instances by the visibility index of the draws
+    pub fn set_visible_counts(&mut self, visible_counts: Vec<u32>, queue: &wgpu::Queue) {
+        if !visible_counts.is_empty() {
+            queue.write_buffer(&self.visible_count_buffer, 0, bytemuck::cast_slice(&visible_counts));
+        }
+        self.visible_counts = visible_counts;
+    }
+
+    /// Fills the indirect args, it has to be recorded before the passes that draw
+    pub fn dispatch(&self, encoder: &mut wgpu::CommandEncoder) {
+        let pipeline = match &self.pipeline {
+            Some(pipeline) if !self.draws.is_empty() => pipeline,
+            _ => return,
+        };
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("draw args pass"),
+        });
+        compute_pass.set_pipeline(pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
+        compute_pass.dispatch_workgroups((self.draws.len() as u32).div_ceil(WORKGROUP_SIZE), 1, 1);
+    }
+
+    /// Draws a range of draws that share the pipeline and bind groups already set on the pass
+    pub fn draw<'a: 'b, 'b>(&'a self, render_pass: &'b mut wgpu::RenderPass<'a>, draws: Range<u32>) {
+        if self.pipeline.is_none() {
+            for draw in self.draws[draws.start as usize..draws.end as usize].iter() {
+                let num_of_visible = self.visible_counts[draw.visibility as usize];
+                if num_of_visible > 0 {
+                    render_pass.draw_indexed(
+                        draw.first_index..draw.first_index + draw.index_count,
+                        draw.base_vertex,
+                        draw.first_instance..draw.first_instance + num_of_visible,
+                    );
+                }
+            }
+        } else if self.multi_draw {
+            render_pass.multi_draw_indexed_indirect(&self.args_buffer, args_offset(draws.start), draws.end - draws.start);
+        } else {
+            for draw in draws {
+                render_pass.draw_indexed_indirect(&self.args_buffer, args_offset(draw));
+            }
+        }
+    }
+}
+
+fn args_offset(draw: u32) -> wgpu::BufferAddress {
+    (draw as usize * DRAW_ARGS_SIZE) as wgpu::BufferAddress
+}
+
+fn create_buffer(device: &wgpu::Device, label: &str, size: usize, usage: wgpu::BufferUsages) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: size as u64,
+        usage,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, buffers: [&wgpu::Buffer; 4]) -> wgpu::BindGroup {
+    let entries: Vec<wgpu::BindGroupEntry> = buffers
+        .iter()
+        .enumerate()
+        .map(|(binding, buffer)| wgpu::BindGroupEntry {
+            binding: binding as u32,
+            resource: buffer.as_entire_binding(),
+        })
+        .collect();
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &entries,
+        label: Some("draw_args_bind_group"),
+    })
+}
@@ -0,0 +1,196 @@
+use crate::lighting::{Light, RawLight};
+use crate::model::{SimpleVertex, Vertex};
+use crate::renderer::render::{self, Drawer};
+use iced_wgpu::wgpu;
+use iced_wgpu::wgpu::util::DeviceExt;
+use std::ops::Range;
+
+/// The buffer has a fixed size, so bind groups made from it never have to be recreated
+pub const MAX_LIGHTS: usize = 64;
+// light_count padded to the 16 byte alignment of the light array in std430
+const HEADER_SIZE: usize = 16;
+
+// an octahedron is enough to see where a light is
+const GIZMO_VERTICES: [SimpleVertex; 6] = [
+    SimpleVertex { position: [1.0, 0.0, 0.0] },
+    SimpleVertex { position: [-1.0, 0.0, 0.0] },
+    SimpleVertex { position: [0.0, 1.0, 0.0] },
+    SimpleVertex { position: [0.0, -1.0, 0.0] },
+    SimpleVertex { position: [0.0, 0.0, 1.0] },
+    SimpleVertex { position: [0.0, 0.0, -1.0] },
+];
+const GIZMO_INDICES: [u16; 24] = [0, 2, 4, 0, 5, 2, 0, 4, 3, 0, 3, 5, 1, 4, 2, 1, 2, 5, 1, 3, 4, 1, 5, 3];
+
+/// Storage buffer with all lights of the scene, see include/light.glsl.
+/// Lit drawers and the gizmo drawer make their bind groups from it
+pub struct LightBuffer {
+    buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    num_of_lights: usize,
+}
+
+impl LightBuffer {
+    pub fn new(device: &wgpu::Device) -> LightBuffer {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("light buffer"),
+            size: (HEADER_SIZE + MAX_LIGHTS * std::mem::size_of::<RawLight>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            // zeroed, so there are no lights until the first write
+            mapped_at_creation: false,
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("light_bind_group_layout"),
+        });
+        LightBuffer {
+            buffer,
+            bind_group_layout,
+            num_of_lights: 0,
+        }
+    }
+
+    pub fn create_bind_group(&self, device: &wgpu::Device) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: self.buffer.as_entire_binding(),
+            }],
+            label: Some("light_bind_group"),
+        })
+    }
+
+    /// Replaces all lights, the ones over MAX_LIGHTS are dropped.
+    /// `shadow_maps` has the shadow map indices of every light, see shadow::ShadowMaps::set_lights
+    pub fn write(&mut self, lights: &[Light], shadow_maps: &[Option<Range<usize>>], queue: &wgpu::Queue) {
+        if lights.len() > MAX_LIGHTS {
+            log::warn!("{} lights in the scene, only the first {} are used", lights.len(), MAX_LIGHTS);
+        }
+        let raw_lights: Vec<RawLight> = lights
+            .iter()
+            .zip(shadow_maps.iter())
+            .take(MAX_LIGHTS)
+            .map(|(light, shadow_maps)| light.to_raw(shadow_maps.clone()))
+            .collect();
+        self.num_of_lights = raw_lights.len();
+        let header = [self.num_of_lights as u32, 0, 0, 0];
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&header));
+        if !raw_lights.is_empty() {
+            queue.write_buffer(&self.buffer, HEADER_SIZE as u64, bytemuck::cast_slice(&raw_lights));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_of_lights
+    }
+}
+
+/// Draws a small octahedron in the color of every light
+pub struct LightDrawer {
+    render_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    uniform_bind_group: wgpu::BindGroup,
+    light_bind_group: wgpu::BindGroup,
+    num_of_lights: u32,
+}
+
+impl LightDrawer {
+    pub const SHADERS: [&'static str; 2] = ["light.vert", "light.frag"];
+
+    pub fn new(device: &wgpu::Device, uniform_buffer: &wgpu::Buffer, lights: &LightBuffer) -> LightDrawer {
+        let uniform_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("light_gizmo_uniform_bind_group_layout"),
+        });
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("light_gizmo_uniform_bind_group"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("light pipeline"),
+            bind_group_layouts: &[&uniform_bind_group_layout, &lights.bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = LightDrawer::create_render_pipeline(device, &pipeline_layout)
+            .unwrap_or_else(|e| panic!("{:?}", e));
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("light gizmo vertex buffer"),
+            contents: bytemuck::cast_slice(&GIZMO_VERTICES),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("light gizmo index buffer"),
+            contents: bytemuck::cast_slice(&GIZMO_INDICES),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+        LightDrawer {
+            render_pipeline,
+            pipeline_layout,
+            vertex_buffer,
+            index_buffer,
+            uniform_bind_group,
+            light_bind_group: lights.create_bind_group(device),
+            num_of_lights: 0,
+        }
+    }
+
+    /// Rebuilds the pipeline from the compiled shaders, the old one stays on errors
+    pub fn reload_pipeline(&mut self, device: &wgpu::Device) -> anyhow::Result<()> {
+        self.render_pipeline = LightDrawer::create_render_pipeline(device, &self.pipeline_layout)?;
+        Ok(())
+    }
+
+    fn create_render_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout) -> anyhow::Result<wgpu::RenderPipeline> {
+        render::create_render_pipeline(
+            device,
+            layout,
+            LightDrawer::SHADERS,
+            &[],
+            &[SimpleVertex::desc()],
+            wgpu::PrimitiveTopology::TriangleList,
+            "light_render_pipeline",
+        )
+    }
+
+    pub fn set_num_of_lights(&mut self, num_of_lights: usize) {
+        self.num_of_lights = num_of_lights as u32;
+    }
+}
+
+impl Drawer for LightDrawer {
+    fn draw<'a: 'b, 'b>(&'a self, render_pass: &'b mut wgpu::RenderPass<'a>) {
+        if self.num_of_lights == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.light_bind_group, &[]);
+        render_pass.draw_indexed(0..GIZMO_INDICES.len() as u32, 0, 0..self.num_of_lights);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::model::{self, ModelVertex};
+use iced_wgpu::wgpu;
+use std::collections::HashMap;
+use std::iter;
+use std::ops::Range;
+
+// a new pool fits a few small meshes before the first reallocation
+const INITIAL_VERTICES: usize = 1024;
+const INITIAL_INDICES: usize = 4096;
+
+/// Where a mesh lies in the pool, indices are relative to base_vertex.
+/// The indices of its levels of detail follow the full mesh
+pub struct MeshRange {
+    pub base_vertex: u32,
+    num_vertices: u32,
+    first_index: u32,
+    num_indices: u32,
+    // from first_index, the full mesh first
+    lods: Vec<Range<u32>>,
+}
+
+impl MeshRange {
+    pub fn num_of_lods(&self) -> usize {
+        self.lods.len()
+    }
+
+    /// Indices of a level of detail, meshes with fewer levels use their coarsest one
+    pub fn lod(&self, lod: usize) -> Range<u32> {
+        let range = &self.lods[lod.min(self.lods.len() - 1)];
+        self.first_index + range.start..self.first_index + range.end
+    }
+}
+
+/// Vertices and indices of all meshes of a drawer in one vertex and one index buffer,
+/// so they are bound once per pass instead of once per mesh
+pub struct MeshPool {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    vertex_capacity: usize,
+    index_capacity: usize,
+    // meshes are packed from the start, new ones go after the last one
+    num_vertices: usize,
+    num_indices: usize,
+    ranges: HashMap<usize, MeshRange>,
+}
+
+impl MeshPool {
+    pub fn new(device: &wgpu::Device) -> MeshPool {
+        MeshPool {
+            vertex_buffer: create_vertex_buffer(device, INITIAL_VERTICES),
+            index_buffer: create_index_buffer(device, INITIAL_INDICES),
+            vertex_capacity: INITIAL_VERTICES,
+            index_capacity: INITIAL_INDICES,
+            num_vertices: 0,
+            num_indices: 0,
+            ranges: HashMap::new(),
+        }
+    }
+
+    /// Uploads meshes by their ids, the buffers grow at most once for all of them
+    pub fn add(&mut self, meshes: &[(usize, &model::Mesh)], device: &wgpu::Device, queue: &wgpu::Queue) {
+        let num_vertices = self.num_vertices + meshes.iter().map(|(_, mesh)| mesh.vertices.len()).sum::<usize>();
+        let num_indices = self.num_indices + meshes.iter().map(|(_, mesh)| lod_indices(mesh).map(Vec::len).sum::<usize>()).sum::<usize>();
+        if num_vertices > self.vertex_capacity || num_indices > self.index_capacity {
+            self.reallocate(
+                self.vertex_capacity.max(num_vertices * 2),
+                self.index_capacity.max(num_indices * 2),
+                device,
+                queue,
+            );
+        }
+        for (mesh_id, mesh) in meshes {
+            let mut range = MeshRange {
+                base_vertex: self.num_vertices as u32,
+                num_vertices: mesh.vertices.len() as u32,
+                first_index: self.num_indices as u32,
+                num_indices: 0,
+                lods: vec![],
+            };
+            queue.write_buffer(&self.vertex_buffer, vertex_offset(range.base_vertex), bytemuck::cast_slice(&mesh.vertices));
+            for indices in lod_indices(mesh) {
+                queue.write_buffer(&self.index_buffer, index_offset(range.first_index + range.num_indices), bytemuck::cast_slice(indices));
+                range.lods.push(range.num_indices..range.num_indices + indices.len() as u32);
+                range.num_indices += indices.len() as u32;
+            }
+            self.num_vertices += mesh.vertices.len();
+            self.num_indices += range.num_indices as usize;
+            self.ranges.insert(*mesh_id, range);
+        }
+    }
+
+    /// Frees the meshes and moves the rest together, so the freed space is used by the next meshes
+    pub fn remove(&mut self, mesh_ids: &[usize], device: &wgpu::Device, queue: &wgpu::Queue) {
+        for mesh_id in mesh_ids {
+            self.ranges.remove(mesh_id);
+        }
+        self.reallocate(self.vertex_capacity, self.index_capacity, device, queue);
+    }
+
+    pub fn get(&self, mesh_id: usize) -> &MeshRange {
+        self.ranges.get(&mesh_id).unwrap()
+    }
+
+    pub fn vertex_buffer(&self) -> &wgpu::Buffer {
+        &self.vertex_buffer
+    }
+
+    pub fn index_buffer(&self) -> &wgpu::Buffer {
+        &self.index_buffer
+    }
+
+    // copies the meshes into new buffers one after another, the old buffers are dropped after the copy
+    fn reallocate(&mut self, vertex_capacity: usize, index_capacity: usize, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let vertex_buffer = create_vertex_buffer(device, vertex_capacity);
+        let index_buffer = create_index_buffer(device, index_capacity);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("mesh pool encoder"),
+        });
+        let (mut num_vertices, mut num_indices) = (0, 0);
+        for range in self.ranges.values_mut() {
+            encoder.copy_buffer_to_buffer(
+                &self.vertex_buffer,
+                vertex_offset(range.base_vertex),
+                &vertex_buffer,
+                vertex_offset(num_vertices),
+                vertex_offset(range.num_vertices),
+            );
+            encoder.copy_buffer_to_buffer(
+                &self.index_buffer,
+                index_offset(range.first_index),
+                &index_buffer,
+                index_offset(num_indices),
+                index_offset(range.num_indices),
+            );
+            range.base_vertex = num_vertices;
+            range.first_index = num_indices;
+            num_vertices += range.num_vertices;
+            num_indices += range.num_indices;
+        }
+        queue.submit(iter::once(encoder.finish()));
+        self.vertex_buffer = vertex_buffer;
+        self.index_buffer = index_buffer;
+        self.vertex_capacity = vertex_capacity;
+        self.index_capacity = index_capacity;
+        self.num_vertices = num_vertices as usize;
+        self.num_indices = num_indices as usize;
+    }
+}
+
+fn lod_indices(mesh: &model::Mesh) -> impl Iterator<Item = &Vec<u32>> {
+    iter::once(&mesh.indices).chain(mesh.lods.iter())
+}
+
+fn vertex_offset(vertex: u32) -> wgpu::BufferAddress {
+    (vertex as usize * std::mem::size_of::<ModelVertex>()) as wgpu::BufferAddress
+}
+
+fn index_offset(index: u32) -> wgpu::BufferAddress {
+    (index as usize * std::mem::size_of::<u32>()) as wgpu::BufferAddress
+}
+
+fn create_vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("mesh pool vertex buffer"),
+        size: (capacity * std::mem::size_of::<ModelVertex>()) as u64,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_index_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("mesh pool index buffer"),
+        size: (capacity * std::mem::size_of::<u32>()) as u64,
+        usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    })
+}
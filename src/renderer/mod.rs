@@ -1,4 +1,9 @@
 mod debug;
+mod indirect;
+pub mod light;
+mod mesh_pool;
 pub mod model;
+pub mod point_cloud;
 pub mod render;
+pub mod shadow;
 mod buffer;
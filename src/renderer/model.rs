@@ -1,210 +1,496 @@
+use crate::camera::Frustum;
 use crate::renderer::render;
-use crate::lighting::Light;
 use crate::texture::TextureType;
-use crate::{model, texture};
+use crate::{model, shader, texture};
 use crate::model::{ModelVertex, Vertex};
 use crate::app::IndexDriver;
-use crate::scene::manager::{Object, RawTransform};
+use crate::scene::manager::RawTransform;
 use crate::renderer::render::{InternalModel, InternalMesh};
 use iced_wgpu::wgpu;
 use iced_wgpu::wgpu::util::DeviceExt;
+use bytemuck::Zeroable;
+use cgmath::{InnerSpace, Matrix4, Vector3};
 use std::ops::Range;
 use std::collections::HashMap;
-use std::fs;
-use std::num::NonZeroU32;
+use std::num::{NonZeroU32, NonZeroU8};
 use crate::renderer::buffer::DynamicBuffer;
+use crate::renderer::indirect::{DrawCommands, RawDraw};
+use crate::renderer::light::LightBuffer;
+use crate::renderer::mesh_pool::MeshPool;
+use crate::renderer::shadow::ShadowMaps;
+
+// instances a new drawer has room for before its buffers grow
+const INITIAL_INSTANCES: usize = 16;
+// instances whose bounding sphere is at least this many pixels across are drawn in full detail,
+// every halving of the size switches to the next level
+const FULL_DETAIL_PIXELS: f32 = 256.0;
+
+/// Visible instance with the level of detail it's drawn with, see include/visible_instances.glsl
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct RawVisibleInstance {
+    instance: u32,
+    lod: u32,
+}
+
+unsafe impl bytemuck::Pod for RawVisibleInstance {}
+unsafe impl bytemuck::Zeroable for RawVisibleInstance {}
+
+impl RawVisibleInstance {
+    const ATTRIBUTES: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![5 => Uint32, 6 => Uint32];
+
+    /// Read per instance in the second vertex buffer, after the vertices
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<RawVisibleInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &RawVisibleInstance::ATTRIBUTES,
+        }
+    }
+}
+
+// draws with the same pipeline and material, one multi draw call when the device supports it
+struct DrawBatch {
+    defines: Vec<&'static str>,
+    material_id: Option<usize>,
+    draws: Range<u32>,
+}
 
 pub struct ModelDrawer {
     index_driver: IndexDriver,
-    render_pipeline: wgpu::RenderPipeline,
+    /// By shader permutation defines, created for the first mesh that needs them
+    render_pipelines: HashMap<Vec<&'static str>, wgpu::RenderPipeline>,
+    // kept to build pipelines for new permutations and to rebuild them when shaders change
+    pipeline_layout: wgpu::PipelineLayout,
+    primitive_topology: wgpu::PrimitiveTopology,
     light_bind_group: wgpu::BindGroup,
+    shadow_bind_group: wgpu::BindGroup,
+    /// Pipelines are built with the SHADOWS permutation while any light casts shadows
+    shadows: bool,
+    /// Debug mode, pipelines are built with the LOD_COLORS permutation
+    lod_colors: bool,
     models: HashMap<usize, InternalModel>,
     material_bind_group_registry: HashMap<usize, wgpu::BindGroup>,
-    uniform_bind_group_registry: HashMap<usize, wgpu::BindGroup>,
-    vertex_buffer_registry: HashMap<usize, wgpu::Buffer>,
-    index_buffer_registry: HashMap<usize, wgpu::Buffer>,
-    instance_buffer_registry: HashMap<usize, DynamicBuffer<RawTransform>>,
+    /// Vertices and indices of all meshes
+    meshes: MeshPool,
+    /// Instances of all models, each model has a range from its first_instance
+    instance_buffer: DynamicBuffer<RawTransform>,
+    /// Instances that passed frustum culling, rewritten every frame. Every level of detail
+    /// of a model has room for all of its instances from the model's first_visible
+    visible_buffer: wgpu::Buffer,
+    visible_capacity: usize,
+    /// Every slot of the instance buffer at the full level of detail, depth passes draw them instead of the visible ones
+    all_instances_buffer: wgpu::Buffer,
+    /// Camera uniforms and instances for every draw
+    uniform_bind_group: wgpu::BindGroup,
+    draw_commands: DrawCommands,
+    /// In drawing order, blended meshes last
+    batches: Vec<DrawBatch>,
     uniform_bind_group_layout: wgpu::BindGroupLayout,
     texture_bind_group_layout: wgpu::BindGroupLayout,
+    /// Textures drawn as placeholders since the last take_warnings, the adapter can't sample them
+    warnings: Vec<String>,
 }
 
 impl ModelDrawer {
-    pub fn new(device: &wgpu::Device, primitive_topology: wgpu::PrimitiveTopology) -> ModelDrawer {
+    pub const SHADERS: [&'static str; 2] = ["shader.vert", "shader.frag"];
+
+    pub fn new(
+        device: &wgpu::Device,
+        uniform_buffer: &wgpu::Buffer,
+        primitive_topology: wgpu::PrimitiveTopology,
+        lights: &LightBuffer,
+        shadow_maps: &ShadowMaps,
+    ) -> ModelDrawer {
         let uniform_bind_group_layout = <ModelDrawer>::create_uniform_bind_group_layout(device);
         let texture_bind_group_layout = <ModelDrawer>::create_texture_bind_group_layout(device);
-        let light_bind_group_layout = <ModelDrawer>::create_light_bind_group_layout(device);
-        let render_pipeline = {
-            let render_pipeline_layout =
-                device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: &[
-                        &uniform_bind_group_layout,
-                        &texture_bind_group_layout,
-                        &light_bind_group_layout,
-                    ],
-                    label: Some("model_drawer"),
-                    push_constant_ranges: &[],
-                });
-            let vs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("shader.vert"),
-                source: wgpu::util::make_spirv(&fs::read("src/shader/spv/shader.vert.spv").unwrap()),
-            });
-            let fs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-                label: Some("shader.frag"),
-                source: wgpu::util::make_spirv(&fs::read("src/shader/spv/shader.frag.spv").unwrap()),
-            });
-            render::build_render_pipeline(
-                &device,
-                &render_pipeline_layout,
-                vs_module,
-                fs_module,
-                ModelVertex::desc(),
-                primitive_topology,
-                "model_render_pipeline",
-            )
-        };
-        let light = Light::new((2.0, 2.0, 2.0).into(), (1.0, 1.0, 1.0).into());
-        // We'll want to update our lights position, so we use COPY_DST
-        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            contents: bytemuck::cast_slice(&[light]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            label: Some("light buffer"),
-        });
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.as_entire_binding(),
-            }],
-            label: None,
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[
+                &uniform_bind_group_layout,
+                &texture_bind_group_layout,
+                &lights.bind_group_layout,
+                &shadow_maps.bind_group_layout,
+            ],
+            label: Some("model_drawer"),
+            push_constant_ranges: &[],
         });
-        // // this pipeline just renders light model
-        // let light_render_pipeline = {
-        //     let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-        //         label: Some("light pipeline"),
-        //         bind_group_layouts: &[&uniform_bind_group_layout, &light_bind_group_layout],
-        //         push_constant_ranges: &[],
-        //     });
-        //     let vs_module =
-        //         device.create_shader_module(wgpu::include_spirv!("../shader/spv/light.vert.spv"));
-        //     let fs_module =
-        //         device.create_shader_module(wgpu::include_spirv!("../shader/spv/light.frag.spv"));
-        //     build_render_pipeline(&device, &layout, vs_module, fs_module)
-        // };
+        let light_bind_group = lights.create_bind_group(device);
+        let shadow_bind_group = shadow_maps.create_bind_group(device);
+        let instance_buffer = DynamicBuffer::with_capacity(device, INITIAL_INSTANCES, wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC);
+        let visible_buffer = create_visible_buffer(device, INITIAL_INSTANCES);
+        let all_instances_buffer = create_all_instances_buffer(device, INITIAL_INSTANCES);
+        let uniform_bind_group = <ModelDrawer>::create_uniform_bind_group(
+            device,
+            &uniform_bind_group_layout,
+            uniform_buffer,
+            &instance_buffer,
+        );
         ModelDrawer {
             index_driver: IndexDriver::new(),
-            render_pipeline,
+            warnings: vec![],
+            render_pipelines: HashMap::new(),
+            pipeline_layout,
+            primitive_topology,
             light_bind_group,
+            shadow_bind_group,
+            shadows: false,
+            lod_colors: false,
             models: HashMap::new(),
             material_bind_group_registry: HashMap::new(),
-            uniform_bind_group_registry: HashMap::new(),
-            vertex_buffer_registry: HashMap::new(),
-            index_buffer_registry: HashMap::new(),
-            instance_buffer_registry: HashMap::new(),
+            meshes: MeshPool::new(device),
+            instance_buffer,
+            visible_buffer,
+            visible_capacity: INITIAL_INSTANCES,
+            all_instances_buffer,
+            uniform_bind_group,
+            draw_commands: DrawCommands::new(device),
+            batches: vec![],
             uniform_bind_group_layout,
             texture_bind_group_layout,
         }
     }
 
+    /// Rebuilds the pipelines of all permutations from the compiled shaders, the old ones stay on errors
+    pub fn reload_pipeline(&mut self, device: &wgpu::Device) -> anyhow::Result<()> {
+        let mut render_pipelines = HashMap::new();
+        for defines in self.render_pipelines.keys() {
+            render_pipelines.insert(defines.clone(), self.create_render_pipeline(device, defines)?);
+        }
+        self.render_pipelines = render_pipelines;
+        self.draw_commands.reload_pipeline(device)
+    }
+
+    /// Switches all pipelines to the permutation with or without shadows
+    pub fn set_shadows(&mut self, shadows: bool, device: &wgpu::Device) {
+        if self.shadows == shadows {
+            return;
+        }
+        self.shadows = shadows;
+        if let Err(e) = self.reload_pipeline(device) {
+            log::error!("{:?}", e);
+        }
+    }
+
+    /// Colors meshes by the level of detail they are drawn with instead of shading them
+    pub fn set_lod_colors(&mut self, lod_colors: bool, device: &wgpu::Device) {
+        if self.lod_colors == lod_colors {
+            return;
+        }
+        self.lod_colors = lod_colors;
+        if let Err(e) = self.reload_pipeline(device) {
+            log::error!("{:?}", e);
+        }
+    }
+
+    fn create_render_pipeline(&self, device: &wgpu::Device, defines: &[&str]) -> anyhow::Result<wgpu::RenderPipeline> {
+        let mut defines = defines.to_vec();
+        if self.shadows {
+            defines.push(shader::SHADOWS);
+        }
+        if self.lod_colors {
+            defines.push(shader::LOD_COLORS);
+        }
+        render::create_render_pipeline(
+            device,
+            &self.pipeline_layout,
+            ModelDrawer::SHADERS,
+            &defines,
+            &[ModelVertex::desc(), RawVisibleInstance::desc()],
+            self.primitive_topology,
+            "model_render_pipeline",
+        )
+    }
+
+    /// Picks the shader permutation by what the mesh and its material provide
+    fn mesh_defines(mesh: &model::Mesh, material: Option<&model::Material>) -> Vec<&'static str> {
+        let mut defines = vec![];
+        if material.is_some_and(|material| material.has_normal_map) {
+            defines.push(shader::HAS_NORMAL_MAP);
+        }
+        if mesh.has_tex_coords {
+            defines.push(shader::HAS_TEXCOORDS);
+        }
+        if material.is_some_and(|material| material.alpha_mode == model::AlphaMode::Blend) {
+            defines.push(shader::ALPHA_BLEND);
+        }
+        defines
+    }
+
+    /// Returns the problems with textures of the models initialized since the last call
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.warnings)
+    }
+
     pub fn init_model(
         &mut self,
         model: &model::Model,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
-        uniform_buffer: &wgpu::Buffer,
     ) {
         let mut internal_meshes: Vec<InternalMesh> = vec![];
-        let material_ids = self.create_material_bind_groups(model, &device, &queue);
+        let mut pooled_meshes = vec![];
+        let material_ids = self.create_material_bind_groups(model, device, queue);
         for mesh in model.meshes.iter() {
             let mesh_id = self.index_driver.next_id();
-            self.index_buffer_registry
-                .insert(mesh_id, self.create_mesh_index_buffer(&mesh, device));
-            self.vertex_buffer_registry
-                .insert(mesh_id, self.create_vertex_buffer(mesh, device));
-            // todo do I need it? or I can return as it was
-            let material_id = if material_ids.len() == 0 {
-                None
-            } else {
-                Some(material_ids[mesh.material_id])
-            };
+            pooled_meshes.push((mesh_id, mesh));
+            let material_id = material_ids.get(mesh.material_id).copied();
+            let defines = ModelDrawer::mesh_defines(mesh, model.materials.get(mesh.material_id));
+            if !self.render_pipelines.contains_key(&defines) {
+                // the mesh isn't drawn without its pipeline
+                match self.create_render_pipeline(device, &defines) {
+                    Ok(render_pipeline) => {
+                        self.render_pipelines.insert(defines.clone(), render_pipeline);
+                    }
+                    Err(e) => log::error!("{:?}", e),
+                }
+            }
             internal_meshes.push(InternalMesh {
-                count: mesh.indices.len(),
                 id: mesh_id,
                 material_id,
+                defines,
             });
         }
-        let instance_buffer = self.create_instance_buffer(&vec![], device, queue);
-        self.instance_buffer_registry
-            .insert(model.id, instance_buffer);
-        self.uniform_bind_group_registry.insert(
-            model.id,
-            self.create_model_uniform_bind_group(model.id, device, uniform_buffer),
-        );
+        self.meshes.add(&pooled_meshes, device, queue);
+        let num_of_lods = internal_meshes
+            .iter()
+            .map(|internal_mesh| self.meshes.get(internal_mesh.id).num_of_lods())
+            .max()
+            .unwrap_or(1);
+        // after the slots of the other models, it gets its own when the first instances are added
+        let first_instance = self.models
+            .values()
+            .map(|internal_model| internal_model.first_instance + internal_model.instance_capacity)
+            .max()
+            .unwrap_or(0);
         self.models.insert(model.id, InternalModel {
-            id: model.id,
             num_of_instances: 0,
+            first_instance,
+            instance_capacity: 0,
+            num_of_lods,
+            first_visible: 0,
+            instance_data: vec![],
             internal_meshes,
+            bounding_radius: model.calc_bounding_sphere_radius(),
+            instance_bounds: vec![],
+            num_of_visible: 0,
         });
+        self.rebuild_draws(device, queue);
     }
 
-    // todo maybe I should pass encoder here, so that if I add a lot of instances I will be able to call submit() once when all instances are added
-    // todo potentially slow place
+    /// Writes only the new instances while they fit into the model's slots,
+    /// otherwise the instances of all models are packed again with room for twice as many
     pub fn add_instances(
         &mut self,
         model_id: usize,
-        objects: &Vec<&Object>,
+        worlds: &[Matrix4<f32>],
         device: &wgpu::Device,
         uniform_buffer: &wgpu::Buffer,
         queue: &wgpu::Queue
     ) {
-        let instance_buffer = self.instance_buffer_registry.get_mut(&model_id).unwrap();
-        let instance_data = objects
-            .iter()
-            .map(|object| object.get_raw_transform())
-            .collect::<Vec<_>>();
-        let encoder = device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
-        instance_buffer.append(device, encoder, queue, bytemuck::cast_slice(&instance_data));
-        *self.uniform_bind_group_registry.get_mut(&model_id).unwrap() = self.create_model_uniform_bind_group(model_id, device, uniform_buffer);
         let model = self.models.get_mut(&model_id).unwrap();
-        model.num_of_instances += objects.len();
+        let first_new = model.num_of_instances;
+        model.num_of_instances += worlds.len();
+        model.instance_data.extend(worlds.iter().map(|world| RawTransform::new(*world)));
+        let bounding_radius = model.bounding_radius;
+        model.instance_bounds.extend(worlds.iter().map(|world| bounding_sphere(world, bounding_radius)));
+        if model.num_of_instances <= model.instance_capacity {
+            self.instance_buffer.write(queue, model.first_instance + first_new, &model.instance_data[first_new..]);
+            return;
+        }
+        model.instance_capacity = model.num_of_instances * 2;
+        self.pack_instances(device, queue, uniform_buffer);
+        self.rebuild_draws(device, queue);
     }
 
-    fn create_instance_buffer(
-        &mut self,
-        objects: &Vec<&Object>,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue
-    ) -> DynamicBuffer<RawTransform> {
-        let instance_data = objects
-            .iter()
-            .map(|object| object.get_raw_transform())
-            .collect::<Vec<_>>();
-        // todo 4, change or comment
-        let mut instance_buffer: DynamicBuffer<RawTransform> = DynamicBuffer::with_capacity(device, 4 + instance_data.len() * 2, wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC);
-        let encoder = device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
-        instance_buffer.append(
-            device,
-            encoder,
-            queue,
-            bytemuck::cast_slice(&instance_data)
-        );
-        instance_buffer
+    pub fn update_instance(&mut self, model_id: usize, instance_id: usize, world: &Matrix4<f32>, queue: &wgpu::Queue) {
+        let model = self.models.get_mut(&model_id).unwrap();
+        model.instance_bounds[instance_id] = bounding_sphere(world, model.bounding_radius);
+        let transform = RawTransform::new(*world);
+        model.instance_data[instance_id] = transform;
+        self.instance_buffer.write(queue, model.first_instance + instance_id, &[transform]);
     }
 
-    pub fn update_object(&mut self, object: &Object, queue: &wgpu::Queue) {
-        let transform = vec![object.get_raw_transform()];
-        let bytes: &[u8] = bytemuck::cast_slice(&transform);
-        let offset = (object.instance_id * bytes.len()) as u64;
-        queue.write_buffer(
-            self.instance_buffer_registry.get(&object.model_id).unwrap().get_buffer(),
-            offset,
-            bytes,
-        );
+    /// Drops the last instance slot of the model. If the last instance was swapped into the removed
+    /// instance's slot its world matrix has to be passed as `moved`, so it's written to the new offset
+    pub fn remove_instance(&mut self, model_id: usize, instance_id: usize, moved: Option<&Matrix4<f32>>, queue: &wgpu::Queue) {
+        let model = self.models.get_mut(&model_id).unwrap();
+        model.num_of_instances -= 1;
+        model.instance_data.truncate(model.num_of_instances);
+        model.instance_bounds.truncate(model.num_of_instances);
+        if let Some(moved) = moved {
+            let transform = RawTransform::new(*moved);
+            model.instance_data[instance_id] = transform;
+            model.instance_bounds[instance_id] = bounding_sphere(moved, model.bounding_radius);
+            self.instance_buffer.write(queue, model.first_instance + instance_id, &[transform]);
+        }
+    }
+
+    /// The slots of the model's instances stay unused until the instances are packed again
+    pub fn remove_model(&mut self, model_id: usize, device: &wgpu::Device, queue: &wgpu::Queue) {
+        if let Some(model) = self.models.remove(&model_id) {
+            let mesh_ids: Vec<usize> = model.internal_meshes.iter().map(|internal_mesh| internal_mesh.id).collect();
+            self.meshes.remove(&mesh_ids, device, queue);
+            for internal_mesh in model.internal_meshes.iter() {
+                if let Some(material_id) = internal_mesh.material_id {
+                    self.material_bind_group_registry.remove(&material_id);
+                }
+            }
+            self.rebuild_draws(device, queue);
+        }
+    }
+
+    pub fn has_model(&self, model_id: usize) -> bool {
+        self.models.contains_key(&model_id)
+    }
+
+    // models in the order of their instance ranges and of the model indices of the draws
+    fn sorted_model_ids(&self) -> Vec<usize> {
+        let mut model_ids: Vec<usize> = self.models.keys().copied().collect();
+        model_ids.sort_unstable();
+        model_ids
+    }
+
+    /// Uploads the instances of all models one model after another, each followed by its free slots.
+    /// Slots of removed models are reused, the draws have to be rebuilt after it
+    fn pack_instances(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, uniform_buffer: &wgpu::Buffer) {
+        let mut instance_data = vec![];
+        for model_id in self.sorted_model_ids() {
+            let internal_model = self.models.get_mut(&model_id).unwrap();
+            internal_model.first_instance = instance_data.len();
+            instance_data.extend_from_slice(&internal_model.instance_data);
+            instance_data.resize(internal_model.first_instance + internal_model.instance_capacity, RawTransform::zeroed());
+        }
+        let capacity = self.instance_buffer.capacity();
+        let encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+        self.instance_buffer.truncate(0);
+        self.instance_buffer.append(device, encoder, queue, &instance_data);
+        if self.instance_buffer.capacity() != capacity {
+            self.all_instances_buffer = create_all_instances_buffer(device, self.instance_buffer.capacity());
+            self.uniform_bind_group = <ModelDrawer>::create_uniform_bind_group(
+                device,
+                &self.uniform_bind_group_layout,
+                uniform_buffer,
+                &self.instance_buffer,
+            );
+        }
+    }
+
+    /// Lists the draws of all meshes, it's done after a model was added or removed or its instance slots changed.
+    /// Nothing is visible until the next culling
+    fn rebuild_draws(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let model_ids = self.sorted_model_ids();
+        let mut draws = vec![];
+        // a visible instance count for every level of every model
+        let mut num_of_visibilities = 0;
+        let mut visible_capacity = 0;
+        for model_id in model_ids.iter() {
+            let internal_model = self.models.get_mut(model_id).unwrap();
+            internal_model.first_visible = visible_capacity;
+            internal_model.num_of_visible = 0;
+            for lod in 0..internal_model.num_of_lods {
+                for internal_mesh in internal_model.internal_meshes.iter() {
+                    let range = self.meshes.get(internal_mesh.id);
+                    let indices = range.lod(lod);
+                    let draw = RawDraw {
+                        index_count: indices.end - indices.start,
+                        first_index: indices.start,
+                        base_vertex: range.base_vertex as i32,
+                        first_instance: (internal_model.first_visible + lod * internal_model.instance_capacity) as u32,
+                        visibility: (num_of_visibilities + lod) as u32,
+                    };
+                    draws.push((internal_mesh.defines.clone(), internal_mesh.material_id, draw));
+                }
+            }
+            num_of_visibilities += internal_model.num_of_lods;
+            visible_capacity += internal_model.num_of_lods * internal_model.instance_capacity;
+        }
+        // blended meshes go last, over everything opaque
+        draws.sort_by_key(|(defines, material_id, _)| (defines.contains(&shader::ALPHA_BLEND), defines.clone(), *material_id));
+        self.batches.clear();
+        for (i, (defines, material_id, _)) in draws.iter().enumerate() {
+            match self.batches.last_mut() {
+                Some(batch) if batch.defines == *defines && batch.material_id == *material_id => batch.draws.end += 1,
+                _ => self.batches.push(DrawBatch {
+                    defines: defines.clone(),
+                    material_id: *material_id,
+                    draws: i as u32..i as u32 + 1,
+                }),
+            }
+        }
+        self.draw_commands.set_draws(draws.into_iter().map(|(_, _, draw)| draw).collect(), num_of_visibilities, device, queue);
+        if visible_capacity > self.visible_capacity {
+            self.visible_capacity = visible_capacity * 2;
+            self.visible_buffer = create_visible_buffer(device, self.visible_capacity);
+        }
+    }
+
+    /// Tests the bounding sphere of every instance against the frustum and writes the visible ones
+    /// for drawing, each with a level of detail by its size on the screen.
+    /// Returns the numbers of visible and culled instances
+    pub fn cull(&mut self, frustum: &Frustum, camera_position: Vector3<f32>, pixels_per_unit: f32, queue: &wgpu::Queue) -> (usize, usize) {
+        let (mut num_of_visible, mut num_of_culled) = (0, 0);
+        let mut visible: Vec<RawVisibleInstance> = vec![];
+        let mut visible_counts = vec![];
+        for model_id in self.sorted_model_ids() {
+            let internal_model = self.models.get_mut(&model_id).unwrap();
+            let mut lods: Vec<Vec<RawVisibleInstance>> = vec![vec![]; internal_model.num_of_lods];
+            for (instance_id, (center, radius)) in internal_model.instance_bounds.iter().enumerate() {
+                if !frustum.intersects_sphere(*center, *radius) {
+                    continue;
+                }
+                let lod = select_lod(*center, *radius, camera_position, pixels_per_unit, internal_model.num_of_lods);
+                lods[lod].push(RawVisibleInstance {
+                    instance: (internal_model.first_instance + instance_id) as u32,
+                    lod: lod as u32,
+                });
+            }
+            internal_model.num_of_visible = 0;
+            for (lod, instances) in lods.into_iter().enumerate() {
+                // every level is listed over its own range
+                visible.resize(internal_model.first_visible + lod * internal_model.instance_capacity, RawVisibleInstance::default());
+                internal_model.num_of_visible += instances.len();
+                visible_counts.push(instances.len() as u32);
+                visible.extend(instances);
+            }
+            num_of_visible += internal_model.num_of_visible;
+            num_of_culled += internal_model.num_of_instances - internal_model.num_of_visible;
+        }
+        if !visible.is_empty() {
+            queue.write_buffer(&self.visible_buffer, 0, bytemuck::cast_slice(&visible));
+        }
+        self.draw_commands.set_visible_counts(visible_counts, queue);
+        (num_of_visible, num_of_culled)
+    }
+
+    /// Fills the draw args with the instances left after culling, before any pass draws the models
+    pub fn prepare_draws(&self, encoder: &mut wgpu::CommandEncoder) {
+        self.draw_commands.dispatch(encoder);
+    }
+
+    /// Instances and the camera uniforms of a model are in bind group 0, other pipelines can draw the models with it
+    pub fn uniform_bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.uniform_bind_group_layout
+    }
+
+    /// Draws every mesh with all of its instances and only its vertices bound, for depth passes with their own pipeline
+    pub fn draw_depth<'a: 'b, 'b>(&'a self, render_pass: &'b mut wgpu::RenderPass<'a>) {
+        render_pass.set_vertex_buffer(0, self.meshes.vertex_buffer().slice(..));
+        render_pass.set_vertex_buffer(1, self.all_instances_buffer.slice(..));
+        render_pass.set_index_buffer(self.meshes.index_buffer().slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        for internal_model in self.models.values().filter(|internal_model| internal_model.num_of_instances > 0) {
+            let first_instance = internal_model.first_instance as u32;
+            for internal_mesh in internal_model.internal_meshes.iter() {
+                let range = self.meshes.get(internal_mesh.id);
+                render_pass.draw_indexed(
+                    range.lod(0),
+                    range.base_vertex as i32,
+                    first_instance..first_instance + internal_model.num_of_instances as u32,
+                );
+            }
+        }
     }
 
     /// Returns ordered material ids, meshes will take actual id by index using it's mesh.material_id
@@ -225,22 +511,6 @@ impl ModelDrawer {
         ids
     }
 
-    fn create_mesh_index_buffer(&self, mesh: &model::Mesh, device: &wgpu::Device) -> wgpu::Buffer {
-        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            contents: bytemuck::cast_slice(&mesh.indices),
-            usage: wgpu::BufferUsages::INDEX,
-            label: Some("index buffer"),
-        })
-    }
-
-    fn create_vertex_buffer(&self, mesh: &model::Mesh, device: &wgpu::Device) -> wgpu::Buffer {
-        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            contents: bytemuck::cast_slice(&mesh.vertices),
-            usage: wgpu::BufferUsages::VERTEX,
-            label: Some("vertex buffer"),
-        })
-    }
-
     fn create_material_bind_group(
         &mut self,
         material: &model::Material,
@@ -248,10 +518,17 @@ impl ModelDrawer {
         queue: &wgpu::Queue,
     ) -> wgpu::BindGroup {
         let layout = &self.texture_bind_group_layout;
-        let diffuse_view = create_view(&material.diffuse_texture, device, queue);
-        let normal_view = create_view(&material.normal_texture, device, queue);
-        let diffuse_sampler = self.create_sampler(device);
-        let normal_sampler = self.create_sampler(device);
+        let diffuse_view = create_view(&material.diffuse_texture, device, queue, &mut self.warnings);
+        let normal_view = create_view(&material.normal_texture, device, queue, &mut self.warnings);
+        let metallic_roughness_view = create_view(&material.metallic_roughness_texture, device, queue, &mut self.warnings);
+        let emissive_view = create_view(&material.emissive_texture, device, queue, &mut self.warnings);
+        // the maps of a material are filtered the same way
+        let sampler = self.create_sampler(device, material.texture_filter);
+        let material_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("material buffer"),
+            contents: bytemuck::cast_slice(&[material.to_raw()]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
         device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
             entries: &[
@@ -261,7 +538,7 @@ impl ModelDrawer {
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_sampler),
+                    resource: wgpu::BindingResource::Sampler(&sampler),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
@@ -269,40 +546,61 @@ impl ModelDrawer {
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_sampler),
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: material_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&metallic_roughness_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&emissive_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
                 },
             ],
             label: Some(&material.name),
         })
     }
 
-    fn create_sampler(&self, device: &wgpu::Device) -> wgpu::Sampler {
+    fn create_sampler(&self, device: &wgpu::Device, texture_filter: model::TextureFilter) -> wgpu::Sampler {
+        let (filter, mipmap_filter, anisotropy_clamp) = match texture_filter {
+            model::TextureFilter::Nearest => (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest, None),
+            model::TextureFilter::Bilinear => (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest, None),
+            model::TextureFilter::Trilinear => (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, None),
+            // wgpu falls back to trilinear on devices without anisotropic filtering
+            model::TextureFilter::Anisotropic(samples) => (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear, NonZeroU8::new(samples)),
+        };
         device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
-            // it had filtering, but there was an error non-filterable float texture so I disabled it
-            // mag_filter: wgpu::FilterMode::Linear,
-            // min_filter: wgpu::FilterMode::Nearest,
-            // mipmap_filter: wgpu::FilterMode::Nearest,
+            mag_filter: filter,
+            min_filter: filter,
+            mipmap_filter,
+            anisotropy_clamp,
             ..Default::default()
         })
     }
 
-    // pub fn remove_model_instance(&mut self, model_batch: &model::ModelBatch) {
-    //     let model_id = model_batch.model.get_id().to_string();
-    //     self.bind_group_registry.remove(&model_id);
-    // }
-
-    fn create_model_uniform_bind_group(
-        &self,
-        model_id: usize,
+    fn create_uniform_bind_group(
         device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
         uniform_buffer: &wgpu::Buffer,
+        instance_buffer: &DynamicBuffer<RawTransform>,
     ) -> wgpu::BindGroup {
-        let instance_buffer = self.instance_buffer_registry.get(&model_id).unwrap();
         device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &self.uniform_bind_group_layout,
+            layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
@@ -353,7 +651,7 @@ impl ModelDrawer {
                     visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Texture {
                         multisampled: false,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         view_dimension: wgpu::TextureViewDimension::D2,
                     },
                     count: None,
@@ -361,10 +659,7 @@ impl ModelDrawer {
                 wgpu::BindGroupLayoutEntry {
                     binding: 1,
                     visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler {
-                        // todo why 0?
-                        0: wgpu::SamplerBindingType::NonFiltering,
-                    },
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
                 // normal map
@@ -373,7 +668,7 @@ impl ModelDrawer {
                     visibility: wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Texture {
                         multisampled: false,
-                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         view_dimension: wgpu::TextureViewDimension::D2,
                     },
                     count: None,
@@ -381,84 +676,146 @@ impl ModelDrawer {
                 wgpu::BindGroupLayoutEntry {
                     binding: 3,
                     visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Sampler {
-                        0: wgpu::SamplerBindingType::NonFiltering,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // material factors, see model::RawMaterial
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
                     },
                     count: None,
                 },
+                // metallic roughness map, then emissive map
+                ModelDrawer::texture_layout_entry(5),
+                ModelDrawer::sampler_layout_entry(6),
+                ModelDrawer::texture_layout_entry(7),
+                ModelDrawer::sampler_layout_entry(8),
             ],
             label: Some("texture_bind_group_layout"),
         })
     }
 
-    fn create_light_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
-        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }],
-            label: None,
-        })
-    }
-
-    fn draw_model_instanced<'a: 'b, 'b>(
-        &'a self,
-        render_pass: &'b mut wgpu::RenderPass<'a>,
-        internal_model: &InternalModel,
-    ) {
-        for internal_mesh in internal_model.internal_meshes.iter() {
-            self.draw_mesh_instanced(
-                render_pass,
-                internal_mesh,
-                self.uniform_bind_group_registry.get(&internal_model.id).unwrap(),
-                0..internal_model.num_of_instances as u32,
-            );
+    fn texture_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                view_dimension: wgpu::TextureViewDimension::D2,
+            },
+            count: None,
         }
     }
 
-    fn draw_mesh_instanced<'a: 'b, 'b>(
-        &'a self,
-        render_pass: &'b mut wgpu::RenderPass<'a>,
-        internal_mesh: &InternalMesh,
-        uniform_bind_group: &'a wgpu::BindGroup,
-        instances: Range<u32>,
-    ) {
-        let vertex_buffer = self.vertex_buffer_registry.get(&internal_mesh.id).unwrap();
-        let index_buffer = self.index_buffer_registry.get(&internal_mesh.id).unwrap();
-        if let Some(material_id) = internal_mesh.material_id {
-            let material_bind_group = self
-                .material_bind_group_registry
-                .get(&material_id).unwrap();
-            render_pass.set_bind_group(1, material_bind_group, &[]);
+    fn sampler_layout_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
+        wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
         }
-        render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
-        render_pass.set_index_buffer(index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-        render_pass.set_bind_group(0, uniform_bind_group, &[]);
-        render_pass.set_bind_group(2, &self.light_bind_group, &[]);
-        render_pass.draw_indexed(0..internal_mesh.count as u32, 0, instances);
     }
 }
 
 impl render::Drawer for ModelDrawer {
     fn draw<'a: 'b, 'b>(&'a self, render_pass: &'b mut wgpu::RenderPass<'a>) {
-        render_pass.set_pipeline(&self.render_pipeline);
-        for (_, internal_model) in self.models.iter() {
-            self.draw_model_instanced(render_pass, internal_model);
+        if self.batches.is_empty() {
+            return;
+        }
+        render_pass.set_vertex_buffer(0, self.meshes.vertex_buffer().slice(..));
+        render_pass.set_vertex_buffer(1, self.visible_buffer.slice(..));
+        render_pass.set_index_buffer(self.meshes.index_buffer().slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        render_pass.set_bind_group(2, &self.light_bind_group, &[]);
+        render_pass.set_bind_group(3, &self.shadow_bind_group, &[]);
+        for batch in self.batches.iter() {
+            // the meshes aren't drawn when their pipeline failed to build
+            let render_pipeline = match self.render_pipelines.get(&batch.defines) {
+                Some(render_pipeline) => render_pipeline,
+                None => continue,
+            };
+            render_pass.set_pipeline(render_pipeline);
+            if let Some(material_id) = batch.material_id {
+                render_pass.set_bind_group(1, self.material_bind_group_registry.get(&material_id).unwrap(), &[]);
+            }
+            self.draw_commands.draw(render_pass, batch.draws.clone());
         }
     }
 }
 
+fn create_visible_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("visible instance buffer"),
+        size: (capacity * std::mem::size_of::<RawVisibleInstance>()) as u64,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_all_instances_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    let instances: Vec<RawVisibleInstance> = (0..capacity as u32)
+        .map(|instance| RawVisibleInstance { instance, lod: 0 })
+        .collect();
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("all instance buffer"),
+        contents: bytemuck::cast_slice(&instances),
+        usage: wgpu::BufferUsages::VERTEX,
+    })
+}
+
+// the full mesh up close, then one level for every halving of the bounding sphere on the screen
+fn select_lod(center: Vector3<f32>, radius: f32, camera_position: Vector3<f32>, pixels_per_unit: f32, num_of_lods: usize) -> usize {
+    let distance = (center - camera_position).magnitude();
+    if distance <= radius {
+        return 0;
+    }
+    let pixels = 2.0 * radius * pixels_per_unit / distance;
+    let lod = (FULL_DETAIL_PIXELS / pixels).log2().floor().max(0.0) as usize;
+    lod.min(num_of_lods - 1)
+}
+
+// around the model space origin, so only the position and the largest scale of the transform matter
+fn bounding_sphere(matrix: &Matrix4<f32>, bounding_radius: f32) -> (Vector3<f32>, f32) {
+    let scale = matrix.x.truncate().magnitude().max(matrix.y.truncate().magnitude()).max(matrix.z.truncate().magnitude());
+    (matrix.w.truncate(), bounding_radius * scale)
+}
+
+/// Compressed textures in formats the adapter can't sample are decoded on the cpu. The ones without
+/// a cpu decoder, e.g. BC6H, BC7 and ASTC, are drawn as placeholders and get a warning
 pub fn create_view(
     texture: &texture::Texture,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
+    warnings: &mut Vec<String>,
 ) -> wgpu::TextureView {
+    if let Some(compressed) = texture.compressed.as_ref() {
+        let format = compressed_format(compressed.format, texture.type_);
+        let (block_width, block_height) = compressed.format.block_dimensions();
+        // wgpu wants whole blocks in the largest level
+        if device.features().contains(format.describe().required_features)
+            && texture.dimensions.0.is_multiple_of(block_width)
+            && texture.dimensions.1.is_multiple_of(block_height)
+        {
+            return create_compressed_view(texture, compressed, format, device, queue);
+        }
+        return match texture.decompress() {
+            Ok(decompressed) => create_view(&decompressed, device, queue, warnings),
+            Err(e) => {
+                warnings.push(format!("{:#}, it's drawn as a placeholder", e));
+                let placeholder = match texture.type_ {
+                    TextureType::Normal => model::flat_normal_texture(&texture.label),
+                    type_ => model::white_texture(&texture.label, type_),
+                };
+                create_view(&placeholder, device, queue, warnings)
+            }
+        };
+    }
     let wgpu_texture = device.create_texture(&wgpu::TextureDescriptor {
         label: Some(&texture.label),
         size: wgpu::Extent3d {
@@ -466,33 +823,128 @@ pub fn create_view(
             height: texture.dimensions.1,
             depth_or_array_layers: 1,
         },
-        mip_level_count: 1,
+        mip_level_count: texture.mip_level_count(),
         sample_count: 1,
         dimension: wgpu::TextureDimension::D2,
         format: match texture.type_ {
-            TextureType::Normal => wgpu::TextureFormat::Rgba8Unorm,
+            TextureType::Normal | TextureType::Data => wgpu::TextureFormat::Rgba8Unorm,
             TextureType::Diffuse => wgpu::TextureFormat::Rgba8UnormSrgb,
             TextureType::Depth => texture::DEPTH_FORMAT,
         },
         usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
     });
     if let Some(rgba_image) = texture.rgba_image.as_ref() {
+        let mip_chain = texture.mip_chain();
+        for (mip_level, image) in std::iter::once(rgba_image).chain(mip_chain.iter()).enumerate() {
+            let (width, height) = image.dimensions();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &wgpu_texture,
+                    mip_level: mip_level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::default(),
+                },
+                image,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(4 * width),
+                    rows_per_image: NonZeroU32::new(height),
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+    }
+    wgpu_texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
+
+fn compressed_format(format: texture::BlockFormat, type_: TextureType) -> wgpu::TextureFormat {
+    let srgb = type_ == TextureType::Diffuse;
+    let astc_channel = if srgb { wgpu::AstcChannel::UnormSrgb } else { wgpu::AstcChannel::Unorm };
+    match (format, srgb) {
+        (texture::BlockFormat::Bc1, false) => wgpu::TextureFormat::Bc1RgbaUnorm,
+        (texture::BlockFormat::Bc1, true) => wgpu::TextureFormat::Bc1RgbaUnormSrgb,
+        (texture::BlockFormat::Bc2, false) => wgpu::TextureFormat::Bc2RgbaUnorm,
+        (texture::BlockFormat::Bc2, true) => wgpu::TextureFormat::Bc2RgbaUnormSrgb,
+        (texture::BlockFormat::Bc3, false) => wgpu::TextureFormat::Bc3RgbaUnorm,
+        (texture::BlockFormat::Bc3, true) => wgpu::TextureFormat::Bc3RgbaUnormSrgb,
+        // one and two channel formats and hdr colors have no srgb variant
+        (texture::BlockFormat::Bc4, _) => wgpu::TextureFormat::Bc4RUnorm,
+        (texture::BlockFormat::Bc5, _) => wgpu::TextureFormat::Bc5RgUnorm,
+        (texture::BlockFormat::Bc6h, _) => wgpu::TextureFormat::Bc6hRgbUfloat,
+        (texture::BlockFormat::Bc7, false) => wgpu::TextureFormat::Bc7RgbaUnorm,
+        (texture::BlockFormat::Bc7, true) => wgpu::TextureFormat::Bc7RgbaUnormSrgb,
+        (texture::BlockFormat::Astc(width, height), _) => wgpu::TextureFormat::Astc {
+            block: astc_block(width, height),
+            channel: astc_channel,
+        },
+    }
+}
+
+fn astc_block(width: u8, height: u8) -> wgpu::AstcBlock {
+    match (width, height) {
+        (5, 4) => wgpu::AstcBlock::B5x4,
+        (5, 5) => wgpu::AstcBlock::B5x5,
+        (6, 5) => wgpu::AstcBlock::B6x5,
+        (6, 6) => wgpu::AstcBlock::B6x6,
+        (8, 5) => wgpu::AstcBlock::B8x5,
+        (8, 6) => wgpu::AstcBlock::B8x6,
+        (8, 8) => wgpu::AstcBlock::B8x8,
+        (10, 5) => wgpu::AstcBlock::B10x5,
+        (10, 6) => wgpu::AstcBlock::B10x6,
+        (10, 8) => wgpu::AstcBlock::B10x8,
+        (10, 10) => wgpu::AstcBlock::B10x10,
+        (12, 10) => wgpu::AstcBlock::B12x10,
+        (12, 12) => wgpu::AstcBlock::B12x12,
+        _ => wgpu::AstcBlock::B4x4,
+    }
+}
+
+// levels are uploaded as they are stored, mipmaps can't be generated for block formats
+fn create_compressed_view(
+    texture: &texture::Texture,
+    compressed: &texture::CompressedImage,
+    format: wgpu::TextureFormat,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+) -> wgpu::TextureView {
+    let wgpu_texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(&texture.label),
+        size: wgpu::Extent3d {
+            width: texture.dimensions.0,
+            height: texture.dimensions.1,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: texture.mip_level_count(),
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+    });
+    let (block_width, block_height) = compressed.format.block_dimensions();
+    for (mip_level, level) in compressed.levels.iter().enumerate() {
+        // small levels still take whole blocks
+        let blocks_wide = (texture.dimensions.0 >> mip_level).max(1).div_ceil(block_width);
+        let blocks_high = (texture.dimensions.1 >> mip_level).max(1).div_ceil(block_height);
         queue.write_texture(
             wgpu::ImageCopyTexture {
                 texture: &wgpu_texture,
-                mip_level: 0,
+                mip_level: mip_level as u32,
                 origin: wgpu::Origin3d::ZERO,
                 aspect: wgpu::TextureAspect::default(),
             },
-            rgba_image,
+            level,
             wgpu::ImageDataLayout {
                 offset: 0,
-                bytes_per_row: NonZeroU32::new(4 * texture.dimensions.0),
-                rows_per_image: NonZeroU32::new(texture.dimensions.1),
+                bytes_per_row: NonZeroU32::new(blocks_wide * compressed.format.block_bytes() as u32),
+                rows_per_image: None,
             },
             wgpu::Extent3d {
-                width: texture.dimensions.0,
-                height: texture.dimensions.1,
+                width: blocks_wide * block_width,
+                height: blocks_high * block_height,
                 depth_or_array_layers: 1,
             },
         );
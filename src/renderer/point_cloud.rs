@@ -0,0 +1,338 @@
+use crate::camera::Frustum;
+use crate::model::Vertex;
+use crate::octree::{NodeRequest, NodeStreamer, Octree};
+use crate::point_cloud::{PointCloud, PointVertex};
+use crate::renderer::render;
+use cgmath::Vector3;
+use iced_wgpu::wgpu;
+use iced_wgpu::wgpu::util::DeviceExt;
+use std::collections::{HashMap, HashSet};
+
+// default wgpu limits allow 256mb buffers, big clouds are split into several buffers
+const MAX_POINTS_PER_BUFFER: usize = 4 * 1024 * 1024;
+pub const DEFAULT_POINT_SIZE: f32 = 3.0;
+/// Max number of octree points drawn per frame, shared by all octrees
+pub const DEFAULT_POINT_BUDGET: usize = 5_000_000;
+// loaded octree nodes that are not visible anymore stay on the gpu until there are this many budgets of points
+const CACHED_POINT_BUDGETS: usize = 4;
+// more requests make the loader thread work on nodes that may already be out of view when they're read
+const MAX_PENDING_NODES: usize = 16;
+
+#[repr(C)]
+#[derive(Copy, Clone, PartialEq)]
+pub struct PointSettings {
+    viewport_size: [f32; 2],
+    point_size: f32,
+    // Due to uniforms requiring 16 byte (4 float) spacing, we need to use a padding field here
+    _padding: f32,
+}
+
+unsafe impl bytemuck::Pod for PointSettings {}
+unsafe impl bytemuck::Zeroable for PointSettings {}
+
+struct InternalPointCloud {
+    // vertex buffer and number of points in it
+    chunks: Vec<(wgpu::Buffer, u32)>,
+}
+
+struct InternalNode {
+    buffer: wgpu::Buffer,
+    num_of_points: u32,
+    last_visible_frame: u64,
+}
+
+struct InternalOctree {
+    octree: Octree,
+    // only loaded nodes are here, the key is the node index
+    nodes: HashMap<usize, InternalNode>,
+    pending: HashSet<usize>,
+    // nodes that failed to load are not requested again
+    failed: HashSet<usize>,
+    visible: Vec<usize>,
+}
+
+pub struct PointCloudDrawer {
+    render_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    uniform_bind_group: wgpu::BindGroup,
+    settings_buffer: wgpu::Buffer,
+    settings: PointSettings,
+    point_clouds: HashMap<usize, InternalPointCloud>,
+    octrees: HashMap<usize, InternalOctree>,
+    streamer: NodeStreamer,
+    point_budget: usize,
+    frame: u64,
+}
+
+impl PointCloudDrawer {
+    pub const SHADERS: [&'static str; 2] = ["point.vert", "point.frag"];
+
+    pub fn new(device: &wgpu::Device, uniform_buffer: &wgpu::Buffer, viewport_size: (u32, u32)) -> PointCloudDrawer {
+        let settings = PointSettings {
+            viewport_size: [viewport_size.0 as f32, viewport_size.1 as f32],
+            point_size: DEFAULT_POINT_SIZE,
+            _padding: 0.0,
+        };
+        let settings_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            contents: bytemuck::cast_slice(&[settings]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            label: Some("point settings buffer"),
+        });
+        let uniform_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("point_uniform_bind_group_layout"),
+        });
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("point_uniform_bind_group"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("point cloud pipeline"),
+            bind_group_layouts: &[&uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = PointCloudDrawer::create_render_pipeline(device, &pipeline_layout)
+            .unwrap_or_else(|e| panic!("{:?}", e));
+        PointCloudDrawer {
+            render_pipeline,
+            pipeline_layout,
+            uniform_bind_group,
+            settings_buffer,
+            settings,
+            point_clouds: HashMap::new(),
+            octrees: HashMap::new(),
+            streamer: NodeStreamer::new(),
+            point_budget: DEFAULT_POINT_BUDGET,
+            frame: 0,
+        }
+    }
+
+    /// Rebuilds the pipeline from the compiled shaders, the old one stays on errors
+    pub fn reload_pipeline(&mut self, device: &wgpu::Device) -> anyhow::Result<()> {
+        self.render_pipeline = PointCloudDrawer::create_render_pipeline(device, &self.pipeline_layout)?;
+        Ok(())
+    }
+
+    fn create_render_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout) -> anyhow::Result<wgpu::RenderPipeline> {
+        render::create_render_pipeline(
+            device,
+            layout,
+            PointCloudDrawer::SHADERS,
+            &[],
+            &[PointVertex::desc()],
+            wgpu::PrimitiveTopology::TriangleList,
+            "point_cloud_render_pipeline",
+        )
+    }
+
+    pub fn add_point_cloud(&mut self, point_cloud: &PointCloud, device: &wgpu::Device) {
+        let chunks = point_cloud
+            .points
+            .chunks(MAX_POINTS_PER_BUFFER)
+            .map(|points| {
+                let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    contents: bytemuck::cast_slice(points),
+                    usage: wgpu::BufferUsages::VERTEX,
+                    label: Some(&point_cloud.label),
+                });
+                (buffer, points.len() as u32)
+            })
+            .collect();
+        self.point_clouds.insert(point_cloud.id, InternalPointCloud { chunks });
+    }
+
+    pub fn add_octree(&mut self, octree: Octree) {
+        self.octrees.insert(
+            octree.id,
+            InternalOctree {
+                octree,
+                nodes: HashMap::new(),
+                pending: HashSet::new(),
+                failed: HashSet::new(),
+                visible: vec![],
+            },
+        );
+    }
+
+    pub fn set_point_budget(&mut self, point_budget: usize) {
+        self.point_budget = point_budget;
+    }
+
+    /// Picks visible octree nodes, uploads the ones the loader thread has read since the last frame,
+    /// requests missing ones and frees the least recently visible nodes when the cache is full.
+    /// Visible nodes that are not loaded yet are skipped while drawing, their parents are drawn instead
+    pub fn update_octrees(
+        &mut self,
+        frustum: &Frustum,
+        camera_position: Vector3<f32>,
+        pixels_per_unit: f32,
+        device: &wgpu::Device,
+    ) {
+        if self.octrees.is_empty() {
+            return;
+        }
+        self.frame += 1;
+
+        while let Some(loaded) = self.streamer.try_recv() {
+            // the octree could be removed while its node was loading
+            let Some(internal_octree) = self.octrees.get_mut(&loaded.octree_id) else {
+                continue;
+            };
+            internal_octree.pending.remove(&loaded.node_index);
+            match loaded.points {
+                Ok(points) if !points.is_empty() => {
+                    let octree = &internal_octree.octree;
+                    let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        contents: bytemuck::cast_slice(&points),
+                        usage: wgpu::BufferUsages::VERTEX,
+                        label: Some(&format!("{}:{}", octree.label, octree.nodes[loaded.node_index].name)),
+                    });
+                    internal_octree.nodes.insert(
+                        loaded.node_index,
+                        InternalNode {
+                            buffer,
+                            num_of_points: points.len() as u32,
+                            last_visible_frame: self.frame,
+                        },
+                    );
+                }
+                Ok(_) => {
+                    internal_octree.failed.insert(loaded.node_index);
+                }
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    internal_octree.failed.insert(loaded.node_index);
+                }
+            }
+        }
+
+        let point_budget = self.point_budget / self.octrees.len();
+        let mut num_of_pending: usize = self.octrees.values().map(|internal_octree| internal_octree.pending.len()).sum();
+        for internal_octree in self.octrees.values_mut() {
+            internal_octree.visible =
+                internal_octree.octree.select_nodes(frustum, camera_position, pixels_per_unit, point_budget);
+            for node_index in internal_octree.visible.iter() {
+                if let Some(node) = internal_octree.nodes.get_mut(node_index) {
+                    node.last_visible_frame = self.frame;
+                } else if num_of_pending < MAX_PENDING_NODES
+                    && !internal_octree.pending.contains(node_index)
+                    && !internal_octree.failed.contains(node_index)
+                {
+                    internal_octree.pending.insert(*node_index);
+                    num_of_pending += 1;
+                    self.streamer.request(NodeRequest {
+                        octree_id: internal_octree.octree.id,
+                        node_index: *node_index,
+                        path: internal_octree.octree.node_path(*node_index),
+                    });
+                }
+            }
+        }
+        self.evict_nodes();
+    }
+
+    pub fn has_pending_nodes(&self) -> bool {
+        self.octrees.values().any(|internal_octree| !internal_octree.pending.is_empty())
+    }
+
+    fn evict_nodes(&mut self) {
+        let max_cached_points = CACHED_POINT_BUDGETS * self.point_budget;
+        let mut num_of_cached_points: usize = self
+            .octrees
+            .values()
+            .flat_map(|internal_octree| internal_octree.nodes.values())
+            .map(|node| node.num_of_points as usize)
+            .sum();
+        if num_of_cached_points <= max_cached_points {
+            return;
+        }
+        let mut candidates: Vec<(u64, usize, usize)> = self
+            .octrees
+            .iter()
+            .flat_map(|(octree_id, internal_octree)| {
+                internal_octree
+                    .nodes
+                    .iter()
+                    .filter(|(_, node)| node.last_visible_frame < self.frame)
+                    .map(|(node_index, node)| (node.last_visible_frame, *octree_id, *node_index))
+            })
+            .collect();
+        candidates.sort_unstable();
+        for (_, octree_id, node_index) in candidates {
+            if num_of_cached_points <= max_cached_points {
+                break;
+            }
+            if let Some(node) = self.octrees.get_mut(&octree_id).unwrap().nodes.remove(&node_index) {
+                num_of_cached_points -= node.num_of_points as usize;
+            }
+        }
+    }
+
+    /// Writes the settings only if they changed, so it can be called every frame
+    pub fn update_settings(&mut self, viewport_size: (u32, u32), point_size: f32, queue: &wgpu::Queue) {
+        let settings = PointSettings {
+            viewport_size: [viewport_size.0 as f32, viewport_size.1 as f32],
+            point_size,
+            _padding: 0.0,
+        };
+        if settings != self.settings {
+            self.settings = settings;
+            queue.write_buffer(&self.settings_buffer, 0, bytemuck::cast_slice(&[settings]));
+        }
+    }
+}
+
+impl render::Drawer for PointCloudDrawer {
+    fn draw<'a: 'b, 'b>(&'a self, render_pass: &'b mut wgpu::RenderPass<'a>) {
+        if self.point_clouds.is_empty() && self.octrees.is_empty() {
+            return;
+        }
+        render_pass.set_pipeline(&self.render_pipeline);
+        render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+        for internal_point_cloud in self.point_clouds.values() {
+            for (vertex_buffer, num_of_points) in internal_point_cloud.chunks.iter() {
+                render_pass.set_vertex_buffer(0, vertex_buffer.slice(..));
+                render_pass.draw(0..6, 0..*num_of_points);
+            }
+        }
+        for internal_octree in self.octrees.values() {
+            for node_index in internal_octree.visible.iter() {
+                if let Some(node) = internal_octree.nodes.get(node_index) {
+                    render_pass.set_vertex_buffer(0, node.buffer.slice(..));
+                    render_pass.draw(0..6, 0..node.num_of_points);
+                }
+            }
+        }
+    }
+}
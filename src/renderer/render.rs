@@ -1,79 +1,132 @@
 use crate::renderer::buffer::Uniforms;
 use crate::renderer::debug::DebugDrawer;
+use crate::renderer::indirect::DrawCommands;
+use crate::renderer::light::{LightBuffer, LightDrawer};
 use crate::renderer::model::ModelDrawer;
+use crate::renderer::point_cloud::PointCloudDrawer;
+use crate::renderer::shadow::{ShadowMaps, ShadowPass};
 use crate::model::{SimpleVertex, Model};
+use crate::camera::{Camera, Frustum, Projection};
+use crate::lighting::Light;
+use crate::octree::Octree;
+use crate::point_cloud::PointCloud;
+use cgmath::{EuclideanSpace, Matrix4, Vector3};
 use crate::texture::Texture;
-use crate::{renderer, model, texture};
-use crate::editor::GUI;
-use crate::scene::manager::Object;
+use crate::{exit_code, renderer, model, shader, texture};
+use crate::editor::Gui;
+use crate::scene::manager::RawTransform;
 use iced_wgpu::wgpu;
 use iced_wgpu::wgpu::util::DeviceExt;
-use iced_winit::futures;
+use iced::theme::Theme;
+use iced_winit::{conversion, futures, Clipboard};
 use iced_winit::winit::dpi::PhysicalSize;
 use iced_winit::winit::window::Window;
+use image::RgbaImage;
 use std::iter;
+use std::num::NonZeroU32;
 use std::time::Instant;
 use iced_wgpu::wgpu::CompositeAlphaMode;
 // todo wgpu must be only inside the renderer, but that's not for sure
 
+// the same as the surface format, pipelines are built for it
+const OFFSCREEN_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Bgra8UnormSrgb;
+
 pub trait Drawer {
     fn draw<'a: 'b, 'b>(&'a self, render_pass: &'b mut wgpu::RenderPass<'a>);
 }
 
 pub struct InternalMesh {
+    /// Finds the range of the mesh in the mesh pool
     pub id: usize,
-    pub count: usize,
     pub material_id: Option<usize>,
+    /// Shader permutation the mesh is drawn with
+    pub defines: Vec<&'static str>,
 }
 
 pub struct InternalModel {
-    pub id: usize,
     pub num_of_instances: usize,
+    /// Where the instances of the model start in the instance buffer shared by all models
+    pub first_instance: usize,
+    /// Slots reserved from first_instance, instances are added in place until the model outgrows them
+    pub instance_capacity: usize,
+    /// Transforms by instance id, the shared instance buffer is rebuilt from them
+    pub instance_data: Vec<RawTransform>,
     pub internal_meshes: Vec<InternalMesh>,
+    /// Radius of the model space bounding sphere around the origin
+    pub bounding_radius: f32,
+    /// World space bounding spheres by instance id, centers and radii
+    pub instance_bounds: Vec<(Vector3<f32>, f32)>,
+    /// Levels of detail of the mesh with the most of them, the other meshes repeat their coarsest one
+    pub num_of_lods: usize,
+    /// Where the visible instances of every level of detail start in the visible instance buffer,
+    /// each level has room for instance_capacity instances
+    pub first_visible: usize,
+    /// Instances that passed frustum culling at all levels of detail
+    pub num_of_visible: usize,
 }
 
 pub struct RenderingState {
-    pub gui: GUI,
+    pub gui: Gui,
     pub viewport: iced_wgpu::Viewport,
     pub surface_config: wgpu::SurfaceConfiguration,
-    pub surface: wgpu::Surface,
+    /// None when rendering offscreen, surface_config then describes the offscreen target
+    pub surface: Option<wgpu::Surface>,
     pub queue: wgpu::Queue,
     pub device: wgpu::Device,
     pub uniforms: Uniforms,
     pub uniform_buffer: wgpu::Buffer,
     pub last_render_time: Instant,
+    pub show_gui: bool,
+    lights: LightBuffer,
+    shadow_maps: ShadowMaps,
     model_drawer: ModelDrawer,
+    shadow_pass: ShadowPass,
+    /// Light gizmos are drawn together with the gui
+    light_drawer: LightDrawer,
     debug_drawer: DebugDrawer,
     bounding_spheres_drawer: Option<ModelDrawer>,
+    point_cloud_drawer: PointCloudDrawer,
     pub depth_texture_view: wgpu::TextureView,
 }
 
 impl RenderingState {
     pub fn new(
         instance: &wgpu::Instance,
-        surface: wgpu::Surface,
+        surface: Option<wgpu::Surface>,
         size: PhysicalSize<u32>,
         scale_factor: f64,
+        present_mode: wgpu::PresentMode,
+        force_fallback_adapter: bool,
     ) -> RenderingState {
         let (texture_format, (device, queue)) = futures::executor::block_on(async {
             let adapter = instance
                 .request_adapter(&wgpu::RequestAdapterOptions {
                     power_preference: wgpu::PowerPreference::default(),
-                    force_fallback_adapter: false,
-                    compatible_surface: Some(&surface),
+                    force_fallback_adapter,
+                    compatible_surface: surface.as_ref(),
                 })
                 .await
-                .expect("Request adapter");
+                .unwrap_or_else(|| {
+                    eprintln!("No graphics adapter found for the requested backends");
+                    std::process::exit(exit_code::NO_ADAPTER);
+                });
 
             (
-                surface
-                    .get_supported_formats(&adapter)[0],
+                match &surface {
+                    Some(surface) => surface.get_supported_formats(&adapter)[0],
+                    None => OFFSCREEN_FORMAT,
+                },
                     // .expect("Get preferred format"),
                 adapter
                     .request_device(
                         &wgpu::DeviceDescriptor {
                             label: Some("device descriptor, I guess I have only one device"),
-                            features: wgpu::Features::empty(),
+                            // compressed textures are decoded on the cpu and models are drawn one by one without them
+                            features: adapter.features()
+                                & (wgpu::Features::TEXTURE_COMPRESSION_BC
+                                    | wgpu::Features::TEXTURE_COMPRESSION_ASTC_LDR
+                                    | wgpu::Features::INDIRECT_FIRST_INSTANCE
+                                    | wgpu::Features::MULTI_DRAW_INDIRECT),
                             limits: wgpu::Limits::default(),
                         },
                         None,
@@ -87,10 +140,12 @@ impl RenderingState {
             format: wgpu::TextureFormat::Bgra8UnormSrgb,
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode,
             alpha_mode: CompositeAlphaMode::Auto,
         };
-        surface.configure(&device, &surface_config);
+        if let Some(surface) = &surface {
+            surface.configure(&device, &surface_config);
+        }
         let depth_texture = Texture::create_depth_texture(&surface_config, "depth_texture");
         let depth_texture_view = renderer::model::create_depth_view(&depth_texture, &device, &queue);
         let uniforms = Uniforms::new();
@@ -102,13 +157,18 @@ impl RenderingState {
             label: Some("uniform buffer"),
         });
 
-        let model_drawer = ModelDrawer::new(&device, wgpu::PrimitiveTopology::TriangleList);
+        let lights = LightBuffer::new(&device);
+        let shadow_maps = ShadowMaps::new(&device);
+        let model_drawer = ModelDrawer::new(&device, &uniform_buffer, wgpu::PrimitiveTopology::TriangleList, &lights, &shadow_maps);
+        let shadow_pass = ShadowPass::new(&device, &model_drawer);
+        let light_drawer = LightDrawer::new(&device, &uniform_buffer, &lights);
         let debug_drawer = DebugDrawer::new(&device, &uniform_buffer);
+        let point_cloud_drawer = PointCloudDrawer::new(&device, &uniform_buffer, (size.width, size.height));
         let viewport = iced_wgpu::Viewport::with_physical_size(
             iced::Size::new(size.width, size.height),
             scale_factor,
         );
-        let gui = GUI::new(&device, scale_factor, size, texture_format);
+        let gui = Gui::new(&device, size, texture_format);
 
         RenderingState {
             gui,
@@ -118,11 +178,17 @@ impl RenderingState {
             queue,
             device,
             last_render_time: std::time::Instant::now(),
+            show_gui: true,
             uniforms,
             uniform_buffer,
+            lights,
+            shadow_maps,
             model_drawer,
+            shadow_pass,
+            light_drawer,
             debug_drawer,
             bounding_spheres_drawer: None,
+            point_cloud_drawer,
             depth_texture_view,
         }
     }
@@ -133,27 +199,35 @@ impl RenderingState {
             model,
             &self.device,
             &self.queue,
-            &self.uniform_buffer,
         )
     }
 
+    /// Textures drawn as placeholders since the last call, see model::create_view
+    pub fn take_texture_warnings(&mut self) -> Vec<String> {
+        let mut warnings = self.model_drawer.take_warnings();
+        if let Some(bounding_spheres_drawer) = self.bounding_spheres_drawer.as_mut() {
+            warnings.extend(bounding_spheres_drawer.take_warnings());
+        }
+        warnings
+    }
+
     pub fn init_bounding_sphere_model(&mut self, model: &Model) {
         self.bounding_spheres_drawer.as_mut().unwrap().init_model(
             model,
             &self.device,
             &self.queue,
-            &self.uniform_buffer,
         )
     }
 
+    /// `worlds` are the world matrices of the new instances, in the order of their instance ids
     pub fn add_instances(
         &mut self,
         model: &model::Model,
-        instances: &Vec<&Object>,
+        worlds: &[Matrix4<f32>],
     ) {
         self.model_drawer.add_instances(
             model.id,
-            instances,
+            worlds,
             &self.device,
             &self.uniform_buffer,
             &self.queue
@@ -163,26 +237,119 @@ impl RenderingState {
     pub fn init_bounding_sphere(&mut self, model: &Model) {
         match &mut self.bounding_spheres_drawer {
             None => {
-                self.bounding_spheres_drawer = Some(ModelDrawer::new(&self.device, wgpu::PrimitiveTopology::LineList));
+                self.bounding_spheres_drawer = Some(ModelDrawer::new(
+                    &self.device,
+                    &self.uniform_buffer,
+                    wgpu::PrimitiveTopology::LineList,
+                    &self.lights,
+                    &self.shadow_maps,
+                ));
                 self.init_bounding_sphere_model(model);
             },
             _ => {panic!("Bounding sphere already initialized")}
         }
     }
 
-    pub fn add_bounding_sphere_instances(&mut self, bounding_model_id: usize, sphere_instances: &Vec<&Object>) {
-        self.bounding_spheres_drawer.as_mut().unwrap().add_instances(bounding_model_id,sphere_instances, &self.device, &self.uniform_buffer, &self.queue);
+    pub fn add_bounding_sphere_instances(&mut self, bounding_model_id: usize, sphere_worlds: &[Matrix4<f32>]) {
+        self.bounding_spheres_drawer.as_mut().unwrap().add_instances(bounding_model_id, sphere_worlds, &self.device, &self.uniform_buffer, &self.queue);
+    }
+
+    /// Replaces all lights of the scene, it's cheap enough to call whenever any of them changes
+    pub fn set_lights(&mut self, lights: &[Light]) {
+        let shadow_maps = self.shadow_maps.set_lights(lights);
+        self.lights.write(lights, &shadow_maps, &self.queue);
+        self.light_drawer.set_num_of_lights(self.lights.len());
+        self.model_drawer.set_shadows(!self.shadow_maps.is_empty(), &self.device);
+    }
+
+    /// Fits shadow maps to the view, it has to be called after the camera moved
+    pub fn update_shadows(&mut self, camera: &Camera, projection: &Projection) {
+        self.shadow_maps.update(camera, projection, &self.queue);
+    }
+
+    /// Leaves only instances inside the view of the current uniforms for drawing and picks their levels of detail,
+    /// returns the numbers of visible and culled model instances
+    pub fn cull_instances(&mut self, camera: &Camera, projection: &Projection) -> (usize, usize) {
+        let frustum = Frustum::from_matrix(&self.uniforms.view_proj);
+        let camera_position = camera.position.to_vec();
+        let pixels_per_unit = projection.calc_pixels_per_unit(self.surface_config.height);
+        if let Some(bounding_spheres_drawer) = self.bounding_spheres_drawer.as_mut() {
+            bounding_spheres_drawer.cull(&frustum, camera_position, pixels_per_unit, &self.queue);
+        }
+        self.model_drawer.cull(&frustum, camera_position, pixels_per_unit, &self.queue)
     }
 
     // todo add update all method?
 
-    pub fn update_object(&mut self, object: &Object) {
-        self.model_drawer.update_object(object, &self.queue);
+    pub fn update_instance(&mut self, model_id: usize, instance_id: usize, world: &Matrix4<f32>) {
+        get_drawer_mut(&mut self.model_drawer, &mut self.bounding_spheres_drawer, model_id)
+            .update_instance(model_id, instance_id, world, &self.queue);
+    }
+
+    /// `moved` is the world matrix of the instance that took the removed instance's slot, if any
+    pub fn remove_instance(&mut self, model_id: usize, instance_id: usize, moved: Option<&Matrix4<f32>>) {
+        get_drawer_mut(&mut self.model_drawer, &mut self.bounding_spheres_drawer, model_id)
+            .remove_instance(model_id, instance_id, moved, &self.queue);
+    }
+
+    pub fn remove_model(&mut self, model_id: usize) {
+        get_drawer_mut(&mut self.model_drawer, &mut self.bounding_spheres_drawer, model_id)
+            .remove_model(model_id, &self.device, &self.queue);
+    }
+
+    /// Rebuilds the pipelines that use any of the changed shaders, failed pipelines keep the old ones
+    pub fn reload_shaders(&mut self, changed: &[String]) -> anyhow::Result<()> {
+        let uses = |shaders: [&str; 2]| shaders.iter().any(|shader| changed.iter().any(|name| name == shader));
+        let mut errors = vec![];
+        if uses(ModelDrawer::SHADERS) || changed.iter().any(|name| name == DrawCommands::SHADER) {
+            errors.extend(self.model_drawer.reload_pipeline(&self.device).err());
+            if let Some(bounding_spheres_drawer) = self.bounding_spheres_drawer.as_mut() {
+                errors.extend(bounding_spheres_drawer.reload_pipeline(&self.device).err());
+            }
+        }
+        if changed.iter().any(|name| name == ShadowPass::SHADER) {
+            errors.extend(self.shadow_pass.reload_pipeline(&self.device).err());
+        }
+        if uses(LightDrawer::SHADERS) {
+            errors.extend(self.light_drawer.reload_pipeline(&self.device).err());
+        }
+        if uses(DebugDrawer::SHADERS) {
+            errors.extend(self.debug_drawer.reload_pipeline(&self.device).err());
+        }
+        if uses(PointCloudDrawer::SHADERS) {
+            errors.extend(self.point_cloud_drawer.reload_pipeline(&self.device).err());
+        }
+        if errors.is_empty() {
+            return Ok(());
+        }
+        let messages: Vec<String> = errors.iter().map(|e| format!("{:#}", e)).collect();
+        anyhow::bail!("{}", messages.join("\n"))
+    }
+
+    pub fn add_point_cloud(&mut self, point_cloud: &PointCloud) {
+        self.point_cloud_drawer.add_point_cloud(point_cloud, &self.device);
+    }
+
+    pub fn add_octree(&mut self, octree: Octree) {
+        self.point_cloud_drawer.add_octree(octree);
+    }
+
+    /// Streams octree nodes for the current camera, it has to be called after the view projection update
+    pub fn update_octrees(&mut self, camera: &Camera, projection: &Projection) {
+        self.point_cloud_drawer.set_point_budget(self.gui.program_state.program().point_budget());
+        self.point_cloud_drawer.update_octrees(
+            &Frustum::from_matrix(&self.uniforms.view_proj),
+            camera.position.to_vec(),
+            projection.calc_pixels_per_unit(self.surface_config.height),
+            &self.device,
+        );
     }
 
     pub fn render(&mut self, window: &Window) {
         let frame = self
             .surface
+            .as_ref()
+            .expect("Rendering to a window without a surface")
             .get_current_texture()
             .expect("Timeout getting texture");
         let mut encoder = self
@@ -193,61 +360,22 @@ impl RenderingState {
         let view = &frame
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+        self.draw_scene(&mut encoder, view);
+
+        let mut staging_belt = wgpu::util::StagingBelt::new(5 * 1024);
+        if self.show_gui {
+            self.gui.renderer.with_primitives(|backend, primitive| {
+                backend.present(
+                    &self.device,
+                    &mut staging_belt,
+                    &mut encoder,
                     view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: {
-                            let [r, g, b, a] = self
-                                .gui
-                                .program_state
-                                .program()
-                                .background_color()
-                                .into_linear();
-                            wgpu::LoadOp::Clear(wgpu::Color {
-                                r: r as f64,
-                                g: g as f64,
-                                b: b as f64,
-                                a: a as f64,
-                            })
-                        },
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(0),
-                        store: true,
-                    }),
-                }),
+                    primitive,
+                    &self.viewport,
+                    &self.gui.debug.overlay(),
+                )
             });
-            self.model_drawer.draw(&mut render_pass);
-            self.debug_drawer.draw(&mut render_pass);
-            if let Some(bounding_spheres_drawer) = &self.bounding_spheres_drawer {
-                bounding_spheres_drawer.draw(&mut render_pass);
-            }
         }
-
-        let mut staging_belt = wgpu::util::StagingBelt::new(5 * 1024);
-        self.gui.renderer.with_primitives(|backend, primitive| {
-            backend.present(
-                &mut self.device,
-                &mut staging_belt,
-                &mut encoder,
-                view,
-                primitive,
-                &self.viewport,
-                &self.gui.debug.overlay(),
-            )
-        });
         staging_belt.finish();
 
         // todo event to remove window from here?
@@ -256,18 +384,218 @@ impl RenderingState {
         frame.present();
     }
 
+    /// Applies queued gui messages
+    pub fn update_gui(&mut self, clipboard: &mut Clipboard) {
+        if self.gui.program_state.is_queue_empty() {
+            return;
+        }
+        let _ = self.gui.program_state.update(
+            self.viewport.logical_size(),
+            conversion::cursor_position(
+                self.gui.cursor_position,
+                self.viewport.scale_factor(),
+            ),
+            &mut self.gui.renderer,
+            &Theme::default(),
+            &iced_winit::renderer::Style::default(),
+            clipboard,
+            &mut self.gui.debug,
+        );
+    }
+
+    /// True while octree nodes requested for the current view are still loading
+    pub fn is_streaming(&self) -> bool {
+        self.point_cloud_drawer.has_pending_nodes()
+    }
+
+    /// Renders the scene without the gui into an offscreen texture and reads it back
+    pub fn render_to_image(&mut self) -> RgbaImage {
+        let (width, height) = (self.surface_config.width, self.surface_config.height);
+        let texture = self.device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("offscreen target"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: OFFSCREEN_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        });
+        // rows of a texture to buffer copy must be aligned to 256 bytes
+        let unpadded_bytes_per_row = width * 4;
+        let padded_bytes_per_row = unpadded_bytes_per_row.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+            * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let output_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("offscreen readback buffer"),
+            size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Offscreen Encoder"),
+            });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.draw_scene(&mut encoder, &view);
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &output_buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(iter::once(encoder.finish()));
+
+        let slice = output_buffer.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.expect("Failed to map the readback buffer"));
+        self.device.poll(wgpu::Maintain::Wait);
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        for row in slice.get_mapped_range().chunks_exact(padded_bytes_per_row as usize) {
+            // bgra to rgba
+            for bgra in row[..unpadded_bytes_per_row as usize].chunks_exact(4) {
+                pixels.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+            }
+        }
+        output_buffer.unmap();
+        RgbaImage::from_raw(width, height, pixels).unwrap()
+    }
+
+    // models, point clouds, debug lines and bounding spheres, everything except the gui
+    fn draw_scene(&mut self, encoder: &mut wgpu::CommandEncoder, view: &wgpu::TextureView) {
+        self.point_cloud_drawer.update_settings(
+            (self.surface_config.width, self.surface_config.height),
+            self.gui.program_state.program().point_size(),
+            &self.queue,
+        );
+        self.model_drawer.set_lod_colors(self.gui.program_state.program().lod_colors(), &self.device);
+        self.model_drawer.prepare_draws(encoder);
+        if let Some(bounding_spheres_drawer) = &self.bounding_spheres_drawer {
+            bounding_spheres_drawer.prepare_draws(encoder);
+        }
+        self.shadow_pass.render(encoder, &self.shadow_maps, &self.model_drawer, &self.queue);
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Render pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: {
+                        let [r, g, b, a] = self
+                            .gui
+                            .program_state
+                            .program()
+                            .background_color()
+                            .into_linear();
+                        wgpu::LoadOp::Clear(wgpu::Color {
+                            r: r as f64,
+                            g: g as f64,
+                            b: b as f64,
+                            a: a as f64,
+                        })
+                    },
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &self.depth_texture_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(0),
+                    store: true,
+                }),
+            }),
+        });
+        self.model_drawer.draw(&mut render_pass);
+        self.point_cloud_drawer.draw(&mut render_pass);
+        self.debug_drawer.draw(&mut render_pass);
+        if let Some(bounding_spheres_drawer) = &self.bounding_spheres_drawer {
+            bounding_spheres_drawer.draw(&mut render_pass);
+        }
+        if self.show_gui {
+            self.light_drawer.draw(&mut render_pass);
+        }
+    }
+
     pub fn add_line(&mut self, start: SimpleVertex, end: SimpleVertex) {
         self.debug_drawer.add_line(start, end, &self.queue);
     }
 }
 
+// bounding spheres are stored in the same scene manager as regular models, but drawn by a separate drawer
+fn get_drawer_mut<'a>(
+    model_drawer: &'a mut ModelDrawer,
+    bounding_spheres_drawer: &'a mut Option<ModelDrawer>,
+    model_id: usize,
+) -> &'a mut ModelDrawer {
+    match bounding_spheres_drawer {
+        Some(drawer) if drawer.has_model(model_id) => drawer,
+        _ => model_drawer,
+    }
+}
+
+/// Builds a pipeline from the compiled vertex and fragment shaders with the given permutation defines,
+/// validation errors are returned instead of panicking so that a reload can keep the old pipeline
+pub fn create_render_pipeline(
+    device: &wgpu::Device,
+    render_pipeline_layout: &wgpu::PipelineLayout,
+    shaders: [&str; 2],
+    defines: &[&str],
+    vertex_buffer_layouts: &[wgpu::VertexBufferLayout],
+    topology: wgpu::PrimitiveTopology,
+    label: &str,
+) -> anyhow::Result<wgpu::RenderPipeline> {
+    let [vs_name, fs_name] = shaders.map(|name| shader::variant_name(name, defines));
+    let vs_source = shader::read_spirv(&vs_name)?;
+    let fs_source = shader::read_spirv(&fs_name)?;
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let vs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(&vs_name),
+        source: wgpu::util::make_spirv(&vs_source),
+    });
+    let fs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(&fs_name),
+        source: wgpu::util::make_spirv(&fs_source),
+    });
+    let pipeline = build_render_pipeline(
+        device,
+        render_pipeline_layout,
+        [vs_module, fs_module],
+        vertex_buffer_layouts,
+        topology,
+        defines.contains(&shader::ALPHA_BLEND),
+        label,
+    );
+    match futures::executor::block_on(device.pop_error_scope()) {
+        Some(error) => anyhow::bail!("Failed to build {} from {} and {}: {}", label, vs_name, fs_name, error),
+        None => Ok(pipeline),
+    }
+}
+
 pub fn build_render_pipeline(
     device: &wgpu::Device,
     render_pipeline_layout: &wgpu::PipelineLayout,
-    vs_module: wgpu::ShaderModule,
-    fs_module: wgpu::ShaderModule,
-    vertex_buffer_layout: wgpu::VertexBufferLayout,
+    [vs_module, fs_module]: [wgpu::ShaderModule; 2],
+    vertex_buffer_layouts: &[wgpu::VertexBufferLayout],
     topology: wgpu::PrimitiveTopology,
+    // blended surfaces are tested against the depth buffer but don't write to it
+    blended: bool,
     label: &str,
 ) -> wgpu::RenderPipeline {
     device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
@@ -276,16 +604,17 @@ pub fn build_render_pipeline(
         vertex: wgpu::VertexState {
             module: &vs_module,
             entry_point: "main",
-            buffers: &[vertex_buffer_layout],
+            buffers: vertex_buffer_layouts,
         },
         fragment: Some(wgpu::FragmentState {
             module: &fs_module,
             entry_point: "main",
             targets: &[Some(wgpu::ColorTargetState {
                 format: wgpu::TextureFormat::Bgra8UnormSrgb,
-                blend: Some(wgpu::BlendState {
-                    color: wgpu::BlendComponent::REPLACE,
-                    alpha: wgpu::BlendComponent::REPLACE,
+                blend: Some(if blended {
+                    wgpu::BlendState::ALPHA_BLENDING
+                } else {
+                    wgpu::BlendState::REPLACE
                 }),
                 write_mask: wgpu::ColorWrites::ALL,
             })],
@@ -300,7 +629,7 @@ pub fn build_render_pipeline(
         },
         depth_stencil: Some(wgpu::DepthStencilState {
             format: texture::DEPTH_FORMAT,
-            depth_write_enabled: true,
+            depth_write_enabled: !blended,
             depth_compare: wgpu::CompareFunction::Less,
             stencil: wgpu::StencilState::default(),
             bias: Default::default(),
@@ -313,3 +642,28 @@ pub fn build_render_pipeline(
         multiview: None,
     })
 }
+
+/// Builds a pipeline from a compiled compute shader, validation errors are returned like in create_render_pipeline
+pub fn create_compute_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &str,
+    label: &str,
+) -> anyhow::Result<wgpu::ComputePipeline> {
+    let source = shader::read_spirv(shader)?;
+    device.push_error_scope(wgpu::ErrorFilter::Validation);
+    let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some(shader),
+        source: wgpu::util::make_spirv(&source),
+    });
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        module: &module,
+        entry_point: "main",
+    });
+    match futures::executor::block_on(device.pop_error_scope()) {
+        Some(error) => anyhow::bail!("Failed to build {} from {}: {}", label, shader, error),
+        None => Ok(pipeline),
+    }
+}
@@ -0,0 +1,477 @@
+use crate::camera::{Camera, Projection, OPENGL_TO_WGPU_MATRIX};
+use crate::lighting::{Light, LightKind};
+use crate::model::{ModelVertex, Vertex};
+use crate::renderer::light::MAX_LIGHTS;
+use crate::renderer::model::{ModelDrawer, RawVisibleInstance};
+use crate::{shader, texture};
+use cgmath::prelude::*;
+use cgmath::{ortho, perspective, Deg, Matrix4, Point3, Vector3, Vector4};
+use iced_wgpu::wgpu;
+use iced_winit::futures;
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use std::ops::Range;
+
+/// Shadow maps of all lights share one depth texture, each of them takes a square of it
+const ATLAS_SIZE: u32 = 4096;
+/// The buffers have a fixed size, so bind groups made from them never have to be recreated
+pub const MAX_SHADOW_MAPS: usize = 32;
+const MIN_RESOLUTION: u32 = 128;
+/// Directional lights split the view into cascades, the nearer ones get more texels per unit
+const NUM_OF_CASCADES: usize = 3;
+/// How far from the camera directional lights cast shadows
+const SHADOW_DISTANCE: f32 = 100.0;
+// blend of logarithmic and uniform cascade splits, 1 is fully logarithmic
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+// casters between the light and a cascade have to be in its depth range too
+const CASTER_MARGIN: f32 = 50.0;
+const SPOT_NEAR: f32 = 0.1;
+// dynamic uniform offsets must be aligned to min_uniform_buffer_offset_alignment, 256 at most
+const VIEW_ALIGNMENT: u64 = 256;
+
+/// Shadow map in the std430 layout of include/shadow.glsl
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct RawShadow {
+    view_proj: Matrix4<f32>,
+    /// Offset and size of the map in the atlas, in texture coordinates
+    rect: [f32; 4],
+    bias: f32,
+    _padding: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for RawShadow {}
+unsafe impl bytemuck::Zeroable for RawShadow {}
+
+struct ShadowMap {
+    light: Light,
+    /// Always 0 for spot lights
+    cascade: usize,
+    /// x, y and size in texels of the atlas
+    rect: (u32, u32, u32),
+    view_proj: Matrix4<f32>,
+}
+
+impl ShadowMap {
+    fn to_raw(&self) -> RawShadow {
+        let (x, y, size) = self.rect;
+        let atlas_size = ATLAS_SIZE as f32;
+        RawShadow {
+            view_proj: self.view_proj,
+            rect: [x as f32 / atlas_size, y as f32 / atlas_size, size as f32 / atlas_size, size as f32 / atlas_size],
+            bias: self.light.shadow.map_or(0.0, |shadow| shadow.bias),
+            _padding: [0.0; 3],
+        }
+    }
+}
+
+/// Depth maps of the shadow casting lights packed into one atlas, see include/shadow.glsl.
+/// Lit drawers make their bind groups from it, ShadowPass renders into it
+pub struct ShadowMaps {
+    atlas_view: wgpu::TextureView,
+    buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    maps: Vec<ShadowMap>,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device) -> ShadowMaps {
+        let atlas = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("shadow atlas"),
+            size: wgpu::Extent3d {
+                width: ATLAS_SIZE,
+                height: ATLAS_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: texture::DEPTH_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        });
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow buffer"),
+            size: (MAX_SHADOW_MAPS * std::mem::size_of::<RawShadow>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+            ],
+            label: Some("shadow_bind_group_layout"),
+        });
+        ShadowMaps {
+            atlas_view: atlas.create_view(&wgpu::TextureViewDescriptor::default()),
+            buffer,
+            bind_group_layout,
+            maps: vec![],
+        }
+    }
+
+    pub fn create_bind_group(&self, device: &wgpu::Device) -> wgpu::BindGroup {
+        // linear filtering of a comparison sampler blends the results of the 4 nearest texels
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&self.atlas_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("shadow_bind_group"),
+        })
+    }
+
+    /// Packs the maps of all shadow casting lights into the atlas and returns the map indices of every light.
+    /// Lights whose maps don't fit cast no shadows
+    pub fn set_lights(&mut self, lights: &[Light]) -> Vec<Option<Range<usize>>> {
+        let lights: Vec<&Light> = lights.iter().take(MAX_LIGHTS).collect();
+        // (light index, cascade), the largest maps go first, so every shelf is as high as its first map
+        let mut requests: Vec<(usize, usize)> = vec![];
+        for (index, light) in lights.iter().enumerate() {
+            requests.extend((0..num_of_maps(light)).map(|cascade| (index, cascade)));
+        }
+        requests.sort_by_key(|(index, _)| std::cmp::Reverse(resolution(lights[*index])));
+        let mut rects = HashMap::new();
+        let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+        for request in requests {
+            let size = resolution(lights[request.0]);
+            if x + size > ATLAS_SIZE {
+                x = 0;
+                y += shelf_height;
+                shelf_height = 0;
+            }
+            if y + size > ATLAS_SIZE {
+                continue;
+            }
+            rects.insert(request, (x, y, size));
+            x += size;
+            shelf_height = shelf_height.max(size);
+        }
+
+        self.maps.clear();
+        let mut shadow_maps = vec![];
+        for (index, light) in lights.iter().enumerate() {
+            let num_of_maps = num_of_maps(light);
+            let light_rects: Option<Vec<(u32, u32, u32)>> =
+                (0..num_of_maps).map(|cascade| rects.get(&(index, cascade)).copied()).collect();
+            match light_rects {
+                Some(light_rects) if num_of_maps > 0 && self.maps.len() + num_of_maps <= MAX_SHADOW_MAPS => {
+                    let start = self.maps.len();
+                    self.maps.extend(light_rects.into_iter().enumerate().map(|(cascade, rect)| ShadowMap {
+                        light: **light,
+                        cascade,
+                        rect,
+                        view_proj: Matrix4::identity(),
+                    }));
+                    shadow_maps.push(Some(start..self.maps.len()));
+                }
+                _ => {
+                    if num_of_maps > 0 {
+                        log::warn!("No room for the shadow maps of light {}, lower the resolution of some shadows", index);
+                    }
+                    shadow_maps.push(None);
+                }
+            }
+        }
+        shadow_maps
+    }
+
+    /// Fits the maps to the current view, cascades of directional lights follow the camera
+    pub fn update(&mut self, camera: &Camera, projection: &Projection, queue: &wgpu::Queue) {
+        if self.maps.is_empty() {
+            return;
+        }
+        let splits = cascade_splits(projection);
+        for map in self.maps.iter_mut() {
+            map.view_proj = match map.light.kind {
+                LightKind::Directional => {
+                    let slice = projection.with_depth_range(splits[map.cascade], splits[map.cascade + 1]);
+                    cascade_view_proj(&map.light, camera, &slice, map.rect.2)
+                }
+                _ => spot_view_proj(&map.light),
+            };
+        }
+        let raw_shadows: Vec<RawShadow> = self.maps.iter().map(ShadowMap::to_raw).collect();
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&raw_shadows));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+}
+
+fn num_of_maps(light: &Light) -> usize {
+    if !light.casts_shadows() {
+        return 0;
+    }
+    match light.kind {
+        LightKind::Directional => NUM_OF_CASCADES,
+        _ => 1,
+    }
+}
+
+fn resolution(light: &Light) -> u32 {
+    light.shadow.map_or(0, |shadow| shadow.resolution.clamp(MIN_RESOLUTION, ATLAS_SIZE))
+}
+
+/// View distances where cascades start and end
+fn cascade_splits(projection: &Projection) -> [f32; NUM_OF_CASCADES + 1] {
+    let near = projection.znear;
+    let far = projection.zfar.min(SHADOW_DISTANCE);
+    let mut splits = [near; NUM_OF_CASCADES + 1];
+    for (i, split) in splits.iter_mut().enumerate().skip(1) {
+        let t = i as f32 / NUM_OF_CASCADES as f32;
+        let logarithmic = near * (far / near).powf(t);
+        let uniform = near + (far - near) * t;
+        *split = CASCADE_SPLIT_LAMBDA * logarithmic + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform;
+    }
+    splits
+}
+
+fn cascade_view_proj(light: &Light, camera: &Camera, slice: &Projection, resolution: u32) -> Matrix4<f32> {
+    let inverse = (slice.calc_matrix() * camera.calc_view_matrix())
+        .invert()
+        .unwrap_or_else(Matrix4::identity);
+    let mut corners = vec![];
+    for x in [-1.0, 1.0] {
+        for y in [-1.0, 1.0] {
+            for z in [0.0, 1.0] {
+                let corner = inverse * Vector4::new(x, y, z, 1.0);
+                corners.push(corner.truncate() / corner.w);
+            }
+        }
+    }
+    let center = corners.iter().fold(Vector3::zero(), |sum, corner| sum + corner) / corners.len() as f32;
+    // a sphere around the slice keeps the size of the map while the camera turns
+    let radius = corners.iter().map(|corner| (corner - center).magnitude()).fold(0.0, f32::max).ceil();
+    let direction = light.normalized_direction();
+    let view = Matrix4::look_to_rh(Point3::origin(), direction, up_vector(direction));
+    let center = view.transform_point(Point3::from_vec(center));
+    // moving the map by whole texels keeps shadow edges still while the camera moves
+    let texel = 2.0 * radius / resolution as f32;
+    let x = (center.x / texel).floor() * texel;
+    let y = (center.y / texel).floor() * texel;
+    let projection = ortho(
+        x - radius,
+        x + radius,
+        y - radius,
+        y + radius,
+        -center.z - radius - CASTER_MARGIN,
+        -center.z + radius,
+    );
+    OPENGL_TO_WGPU_MATRIX * projection * view
+}
+
+fn spot_view_proj(light: &Light) -> Matrix4<f32> {
+    let outer_angle = match light.kind {
+        LightKind::Spot { outer_angle, .. } => outer_angle,
+        _ => Deg(45.0),
+    };
+    let direction = light.normalized_direction();
+    let view = Matrix4::look_to_rh(Point3::from_vec(light.position), direction, up_vector(direction));
+    let far = if light.range > 0.0 { light.range } else { SHADOW_DISTANCE };
+    // the cone fits into the frustum, it can't be projected when it's close to 180 degrees wide
+    let fovy = Deg((outer_angle.0 * 2.0).min(170.0));
+    OPENGL_TO_WGPU_MATRIX * perspective(fovy, 1.0, SPOT_NEAR, far) * view
+}
+
+fn up_vector(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    }
+}
+
+/// Renders the depth of the models into the shadow maps, it runs before the main pass
+pub struct ShadowPass {
+    render_pipeline: wgpu::RenderPipeline,
+    pipeline_layout: wgpu::PipelineLayout,
+    // view projection of every map at a VIEW_ALIGNMENT offset
+    view_buffer: wgpu::Buffer,
+    view_bind_group: wgpu::BindGroup,
+}
+
+impl ShadowPass {
+    pub const SHADER: &'static str = "shadow.vert";
+
+    /// Models are drawn with the instance bind groups of the model drawer
+    pub fn new(device: &wgpu::Device, model_drawer: &ModelDrawer) -> ShadowPass {
+        let view_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("shadow_view_bind_group_layout"),
+        });
+        let view_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow view buffer"),
+            size: MAX_SHADOW_MAPS as u64 * VIEW_ALIGNMENT,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let view_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &view_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &view_buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(std::mem::size_of::<Matrix4<f32>>() as u64),
+                }),
+            }],
+            label: Some("shadow_view_bind_group"),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow pipeline"),
+            bind_group_layouts: &[model_drawer.uniform_bind_group_layout(), &view_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let render_pipeline = ShadowPass::create_render_pipeline(device, &pipeline_layout)
+            .unwrap_or_else(|e| panic!("{:?}", e));
+        ShadowPass {
+            render_pipeline,
+            pipeline_layout,
+            view_buffer,
+            view_bind_group,
+        }
+    }
+
+    /// Rebuilds the pipeline from the compiled shader, the old one stays on errors
+    pub fn reload_pipeline(&mut self, device: &wgpu::Device) -> anyhow::Result<()> {
+        self.render_pipeline = ShadowPass::create_render_pipeline(device, &self.pipeline_layout)?;
+        Ok(())
+    }
+
+    // depth only, there is no fragment shader
+    fn create_render_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout) -> anyhow::Result<wgpu::RenderPipeline> {
+        let vs_source = shader::read_spirv(ShadowPass::SHADER)?;
+        device.push_error_scope(wgpu::ErrorFilter::Validation);
+        let vs_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(ShadowPass::SHADER),
+            source: wgpu::util::make_spirv(&vs_source),
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow_render_pipeline"),
+            layout: Some(layout),
+            vertex: wgpu::VertexState {
+                module: &vs_module,
+                entry_point: "main",
+                buffers: &[ModelVertex::desc(), RawVisibleInstance::desc()],
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                front_face: wgpu::FrontFace::Ccw,
+                // open meshes cast shadows from both sides
+                cull_mode: None,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                // against acne on surfaces at grazing angles, the per light bias handles the rest
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+        match futures::executor::block_on(device.pop_error_scope()) {
+            Some(error) => anyhow::bail!("Failed to build shadow_render_pipeline from {}: {}", ShadowPass::SHADER, error),
+            None => Ok(pipeline),
+        }
+    }
+
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        shadow_maps: &ShadowMaps,
+        model_drawer: &ModelDrawer,
+        queue: &wgpu::Queue,
+    ) {
+        if shadow_maps.is_empty() {
+            return;
+        }
+        for (i, map) in shadow_maps.maps.iter().enumerate() {
+            let view_proj: [[f32; 4]; 4] = map.view_proj.into();
+            queue.write_buffer(&self.view_buffer, i as u64 * VIEW_ALIGNMENT, bytemuck::cast_slice(&view_proj));
+        }
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &shadow_maps.atlas_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        render_pass.set_pipeline(&self.render_pipeline);
+        for (i, map) in shadow_maps.maps.iter().enumerate() {
+            let (x, y, size) = map.rect;
+            render_pass.set_viewport(x as f32, y as f32, size as f32, size as f32, 0.0, 1.0);
+            render_pass.set_scissor_rect(x, y, size, size);
+            render_pass.set_bind_group(1, &self.view_bind_group, &[(i as u64 * VIEW_ALIGNMENT) as u32]);
+            model_drawer.draw_depth(&mut render_pass);
+        }
+    }
+}
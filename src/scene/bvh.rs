@@ -0,0 +1,316 @@
+use crate::scene::picking::Ray;
+use cgmath::{Matrix4, Vector3};
+
+// leaves with up to this many primitives are not split any further
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    /// Contains nothing, growing it by any point gives a box around that point only
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points<'a, I: IntoIterator<Item = &'a Vector3<f32>>>(points: I) -> Aabb {
+        let mut aabb = Aabb::empty();
+        for point in points {
+            aabb.grow(*point);
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn grow(&mut self, point: Vector3<f32>) {
+        self.min = Vector3::new(self.min.x.min(point.x), self.min.y.min(point.y), self.min.z.min(point.z));
+        self.max = Vector3::new(self.max.x.max(point.x), self.max.y.max(point.y), self.max.z.max(point.z));
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        let mut aabb = *self;
+        aabb.grow(other.min);
+        aabb.grow(other.max);
+        aabb
+    }
+
+    pub fn centroid(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x && self.max.x >= other.min.x
+            && self.min.y <= other.max.y && self.max.y >= other.min.y
+            && self.min.z <= other.max.z && self.max.z >= other.min.z
+    }
+
+    /// Box around all 8 transformed corners, it's not tight for rotated boxes but always contains the object
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let mut aabb = Aabb::empty();
+        for i in 0..8 {
+            let corner = Vector3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+            aabb.grow((matrix * corner.extend(1.0)).truncate());
+        }
+        aabb
+    }
+
+    /// Slab test, returns the distance along the ray to the box entry point or 0 if the ray starts inside
+    pub fn intersect_ray(&self, ray: &Ray) -> Option<f32> {
+        let mut near = f32::NEG_INFINITY;
+        let mut far = f32::INFINITY;
+        for axis in 0..3 {
+            let (origin, direction) = (ray.origin[axis], ray.direction[axis]);
+            if direction == 0.0 {
+                // parallel to the slab, 0 * inf would give NaN for rays in its planes
+                if origin < self.min[axis] || origin > self.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (self.min[axis] - origin) / direction;
+            let t2 = (self.max[axis] - origin) / direction;
+            near = near.max(t1.min(t2));
+            far = far.min(t1.max(t2));
+        }
+        if near > far || far < 0.0 {
+            None
+        } else {
+            Some(near.max(0.0))
+        }
+    }
+
+    fn longest_axis(&self) -> usize {
+        let size = self.max - self.min;
+        if size.x >= size.y && size.x >= size.z {
+            0
+        } else if size.y >= size.z {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+struct Node {
+    bounds: Aabb,
+    // for leaves it's the index of the first primitive in Bvh::primitives, for inner nodes it's the right child
+    // index, the left child always goes right after its parent
+    offset: usize,
+    // 0 for inner nodes
+    count: usize,
+}
+
+/// Bounding volume hierarchy over primitives given by their bounding boxes.
+/// It stores only primitive indices, the primitives themselves are tested by the caller
+#[derive(Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    primitives: Vec<usize>,
+    primitive_bounds: Vec<Aabb>,
+}
+
+impl Bvh {
+    pub fn build(bounds: Vec<Aabb>) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(bounds.len() * 2),
+            primitives: (0..bounds.len()).collect(),
+            primitive_bounds: bounds,
+        };
+        if !bvh.primitives.is_empty() {
+            bvh.build_node(0, bvh.primitives.len());
+        }
+        bvh
+    }
+
+    /// Builds a bvh over triangles given by vertex positions and triangle list indices
+    pub fn from_triangles(positions: &[Vector3<f32>], indices: &[u32]) -> Bvh {
+        let bounds: Vec<Aabb> = indices
+            .chunks_exact(3)
+            .map(|triangle| Aabb::from_points(triangle.iter().map(|i| &positions[*i as usize])))
+            .collect();
+        Bvh::build(bounds)
+    }
+
+    fn build_node(&mut self, start: usize, end: usize) -> usize {
+        let bounds = &self.primitive_bounds;
+        let node_index = self.nodes.len();
+        let mut node_bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for primitive in self.primitives[start..end].iter() {
+            node_bounds = node_bounds.union(&bounds[*primitive]);
+            centroid_bounds.grow(bounds[*primitive].centroid());
+        }
+        self.nodes.push(Node {
+            bounds: node_bounds,
+            offset: start,
+            count: end - start,
+        });
+        if end - start <= MAX_LEAF_SIZE {
+            return node_index;
+        }
+
+        let axis = centroid_bounds.longest_axis();
+        let middle = (start + end) / 2;
+        self.primitives[start..end].select_nth_unstable_by(middle - start, |a, b| {
+            bounds[*a].centroid()[axis].total_cmp(&bounds[*b].centroid()[axis])
+        });
+        self.build_node(start, middle);
+        let right = self.build_node(middle, end);
+        self.nodes[node_index].offset = right;
+        self.nodes[node_index].count = 0;
+        node_index
+    }
+
+    /// Finds the nearest primitive hit by the ray. `intersect` is called only for primitives whose
+    /// bounding boxes are closer than the nearest hit found so far and returns the distance to the primitive
+    pub fn raycast<F: FnMut(usize) -> Option<f32>>(&self, ray: &Ray, mut intersect: F) -> Option<(usize, f32)> {
+        let mut nearest: Option<(usize, f32)> = None;
+        let mut stack = match self.nodes.first() {
+            Some(root) if root.bounds.intersect_ray(ray).is_some() => vec![0],
+            _ => return None,
+        };
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            match node.bounds.intersect_ray(ray) {
+                Some(distance) if nearest.is_none_or(|(_, nearest)| distance <= nearest) => {}
+                _ => continue,
+            }
+            if node.count > 0 {
+                for primitive in self.primitives[node.offset..node.offset + node.count].iter() {
+                    if let Some(distance) = intersect(*primitive) {
+                        if nearest.is_none_or(|(_, nearest)| distance < nearest) {
+                            nearest = Some((*primitive, distance));
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let left = node_index + 1;
+            let right = node.offset;
+            let left_distance = self.nodes[left].bounds.intersect_ray(ray);
+            let right_distance = self.nodes[right].bounds.intersect_ray(ray);
+            // the closer child goes on top of the stack, so far nodes are often skipped
+            match (left_distance, right_distance) {
+                (Some(l), Some(r)) if l <= r => stack.extend([right, left]),
+                (Some(_), Some(_)) => stack.extend([left, right]),
+                (Some(_), None) => stack.push(left),
+                (None, Some(_)) => stack.push(right),
+                (None, None) => {}
+            }
+        }
+        nearest
+    }
+
+    /// Returns all primitives whose bounding boxes intersect the box
+    pub fn query_aabb(&self, aabb: &Aabb) -> Vec<usize> {
+        let mut result = vec![];
+        let mut stack = if self.nodes.is_empty() { vec![] } else { vec![0] };
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if !node.bounds.intersects(aabb) {
+                continue;
+            }
+            if node.count > 0 {
+                result.extend(
+                    self.primitives[node.offset..node.offset + node.count]
+                        .iter()
+                        .filter(|primitive| self.primitive_bounds[**primitive].intersects(aabb)),
+                );
+            } else {
+                stack.extend([node_index + 1, node.offset]);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box(x: f32, y: f32, z: f32) -> Aabb {
+        Aabb {
+            min: Vector3::new(x, y, z),
+            max: Vector3::new(x + 1.0, y + 1.0, z + 1.0),
+        }
+    }
+
+    #[test]
+    fn intersect_ray_hits_from_outside_and_inside() {
+        let aabb = unit_box(0.0, 0.0, 0.0);
+        let ray = Ray::new(Vector3::new(0.5, 0.5, -2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(aabb.intersect_ray(&ray), Some(2.0));
+        let inside = Ray::new(Vector3::new(0.5, 0.5, 0.5), Vector3::new(1.0, 1.0, 0.0));
+        assert_eq!(aabb.intersect_ray(&inside), Some(0.0));
+        let behind = Ray::new(Vector3::new(0.5, 0.5, 2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(aabb.intersect_ray(&behind), None);
+    }
+
+    #[test]
+    fn intersect_ray_parallel_to_a_slab() {
+        let aabb = unit_box(0.0, 0.0, 0.0);
+        // x and y directions are zero and the origin lies in the planes of the box faces
+        let on_faces = Ray::new(Vector3::new(0.0, 1.0, -2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(aabb.intersect_ray(&on_faces), Some(2.0));
+        let beside = Ray::new(Vector3::new(1.5, 0.5, -2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(aabb.intersect_ray(&beside), None);
+    }
+
+    #[test]
+    fn raycast_returns_the_nearest_primitive() {
+        let bounds: Vec<Aabb> = (0..100).map(|i| unit_box((i % 10) as f32 * 2.0, 0.0, (i / 10) as f32 * 2.0)).collect();
+        let bvh = Bvh::build(bounds.clone());
+        let mut tested = 0;
+        for column in 0..10 {
+            let ray = Ray::new(Vector3::new(column as f32 * 2.0 + 0.5, 0.5, -1.0), Vector3::new(0.0, 0.0, 1.0));
+            let hit = bvh.raycast(&ray, |primitive| {
+                tested += 1;
+                bounds[primitive].intersect_ray(&ray)
+            });
+            assert_eq!(hit, Some((column, 1.0)));
+        }
+        // far boxes are skipped once the nearest one is found
+        assert!(tested < 100, "{} primitives tested", tested);
+
+        let miss = Ray::new(Vector3::new(0.5, 5.0, -1.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(bvh.raycast(&miss, |_| panic!("no box is hit")), None);
+        assert_eq!(Bvh::default().raycast(&miss, |_| Some(0.0)), None);
+    }
+
+    #[test]
+    fn query_aabb_returns_overlapping_primitives() {
+        let bounds: Vec<Aabb> = (0..100).map(|i| unit_box((i % 10) as f32 * 2.0, 0.0, (i / 10) as f32 * 2.0)).collect();
+        let bvh = Bvh::build(bounds);
+        // touches boxes 0 and 1 of the first row and 10 and 11 of the second one
+        let mut found = bvh.query_aabb(&Aabb {
+            min: Vector3::new(0.5, 0.5, 0.5),
+            max: Vector3::new(2.5, 0.5, 2.5),
+        });
+        found.sort_unstable();
+        assert_eq!(found, [0, 1, 10, 11]);
+        // in the gap between the rows
+        assert!(bvh.query_aabb(&Aabb {
+            min: Vector3::new(0.0, 0.0, 1.2),
+            max: Vector3::new(20.0, 1.0, 1.8),
+        })
+        .is_empty());
+        assert!(Bvh::default().query_aabb(&unit_box(0.0, 0.0, 0.0)).is_empty());
+    }
+}
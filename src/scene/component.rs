@@ -0,0 +1,191 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/// Display name of an object, gltf node names end up here
+#[derive(Clone)]
+pub struct Name(pub String);
+
+/// Free form labels for grouping objects, scene files keep them
+#[derive(Clone, Default)]
+pub struct Tags(pub Vec<String>);
+
+/// Draws an instance of the model at the object's world transform. Instance slots are handed out
+/// by the manager, so only its create_object and create_bounding_volume make one
+#[derive(Copy, Clone)]
+pub struct MeshRenderer {
+    pub(in crate::scene) model_id: usize,
+    pub(in crate::scene) instance_id: usize,
+}
+
+impl MeshRenderer {
+    pub fn model_id(&self) -> usize {
+        self.model_id
+    }
+
+    /// Index of the instance among the instances of the model, see Manager::remove_object
+    pub fn instance_id(&self) -> usize {
+        self.instance_id
+    }
+}
+
+/// Marks the bounding sphere drawn around its parent, picking and scene files skip it
+#[derive(Copy, Clone)]
+pub struct BoundingVolume;
+
+// type erased storage, so components of a removed object are dropped without knowing their types
+trait Storage {
+    fn remove_object(&mut self, object_id: usize);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: 'static> Storage for HashMap<usize, T> {
+    fn remove_object(&mut self, object_id: usize) {
+        self.remove(&object_id);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// Components of objects by their type and object id. Any 'static type can be a component,
+/// so features and users attach their own data without new registries in the manager.
+/// Transforms are fields of `Object`, the hierarchy needs them for every object
+#[derive(Default)]
+pub struct Components {
+    storages: HashMap<TypeId, Box<dyn Storage>>,
+}
+
+impl Components {
+    /// Returns the component of the same type the object had before
+    pub fn insert<T: 'static>(&mut self, object_id: usize, component: T) -> Option<T> {
+        self.storages
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(HashMap::<usize, T>::new()))
+            .as_any_mut()
+            .downcast_mut::<HashMap<usize, T>>()
+            .unwrap()
+            .insert(object_id, component)
+    }
+
+    pub fn get<T: 'static>(&self, object_id: usize) -> Option<&T> {
+        self.storage::<T>()?.get(&object_id)
+    }
+
+    /// Drops components of every type of the object
+    pub fn remove_object(&mut self, object_id: usize) {
+        for storage in self.storages.values_mut() {
+            storage.remove_object(object_id);
+        }
+    }
+
+    /// Ids of objects with every component of the query together with the components, ordered by creation
+    pub fn query<'a, Q: Query<'a>>(&'a self) -> Vec<(usize, Q)> {
+        let mut object_ids = Q::candidates(self);
+        object_ids.sort_unstable();
+        object_ids
+            .into_iter()
+            .filter_map(|object_id| Some((object_id, Q::fetch(self, object_id)?)))
+            .collect()
+    }
+
+    fn storage<T: 'static>(&self) -> Option<&HashMap<usize, T>> {
+        self.storages.get(&TypeId::of::<T>())?.as_any().downcast_ref()
+    }
+}
+
+/// Components a query asks for, `&T` or tuples of them like `(&MeshRenderer, &Name)`
+pub trait Query<'a>: Sized {
+    /// Objects that may have all of the components, the ones with the first component of a tuple,
+    /// so the rarest component should go first
+    fn candidates(components: &'a Components) -> Vec<usize>;
+    fn fetch(components: &'a Components, object_id: usize) -> Option<Self>;
+}
+
+impl<'a, T: 'static> Query<'a> for &'a T {
+    fn candidates(components: &'a Components) -> Vec<usize> {
+        match components.storage::<T>() {
+            Some(storage) => storage.keys().copied().collect(),
+            None => vec![],
+        }
+    }
+
+    fn fetch(components: &'a Components, object_id: usize) -> Option<Self> {
+        components.get(object_id)
+    }
+}
+
+macro_rules! impl_tuple_query {
+    ($first:ident $(, $rest:ident)*) => {
+        impl<'a, $first: Query<'a>, $($rest: Query<'a>),*> Query<'a> for ($first, $($rest),*) {
+            fn candidates(components: &'a Components) -> Vec<usize> {
+                $first::candidates(components)
+            }
+
+            fn fetch(components: &'a Components, object_id: usize) -> Option<Self> {
+                Some(($first::fetch(components, object_id)?, $($rest::fetch(components, object_id)?),*))
+            }
+        }
+    };
+}
+
+impl_tuple_query!(A, B);
+impl_tuple_query!(A, B, C);
+impl_tuple_query!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_replaces_the_component_of_the_same_type() {
+        let mut components = Components::default();
+        assert!(components.insert(1, Name("a".to_string())).is_none());
+        assert_eq!(components.insert(1, Name("b".to_string())).unwrap().0, "a");
+        components.insert(1, Tags(vec!["t".to_string()]));
+        assert_eq!(components.get::<Name>(1).unwrap().0, "b");
+        assert_eq!(components.get::<Tags>(1).unwrap().0, ["t"]);
+        assert!(components.get::<Name>(2).is_none());
+    }
+
+    #[test]
+    fn remove_object_drops_components_of_every_type() {
+        let mut components = Components::default();
+        components.insert(1, Name("a".to_string()));
+        components.insert(1, 5u32);
+        components.insert(2, Name("b".to_string()));
+        components.remove_object(1);
+        assert!(components.get::<Name>(1).is_none());
+        assert!(components.get::<u32>(1).is_none());
+        assert_eq!(components.get::<Name>(2).unwrap().0, "b");
+    }
+
+    #[test]
+    fn query_returns_objects_with_every_component_in_creation_order() {
+        let mut components = Components::default();
+        for object_id in [7, 3, 5] {
+            components.insert(object_id, object_id as u32);
+        }
+        components.insert(5, Name("five".to_string()));
+        components.insert(3, Name("three".to_string()));
+        components.insert(3, Tags::default());
+
+        let ids: Vec<usize> = components.query::<&u32>().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, [3, 5, 7]);
+
+        let named: Vec<(usize, u32, String)> = components
+            .query::<(&u32, &Name)>()
+            .into_iter()
+            .map(|(id, (value, name))| (id, *value, name.0.clone()))
+            .collect();
+        assert_eq!(named, [(3, 3, "three".to_string()), (5, 5, "five".to_string())]);
+
+        assert_eq!(components.query::<(&Name, &u32, &Tags)>().len(), 1);
+        assert!(components.query::<(&u32, &BoundingVolume)>().is_empty());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::json::{self, Value};
+use crate::lighting::{Light, LightKind, Shadow};
+use crate::model::ModelSource;
+use crate::scene::manager::Transform;
+use anyhow::*;
+use cgmath::{Deg, Point3, Quaternion, Vector3};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Every migration upgrades a scene from version `i + 1` to `i + 2`, add one here when the format changes
+const MIGRATIONS: &[fn(Value) -> Result<Value>] = &[];
+pub const SCENE_VERSION: usize = MIGRATIONS.len() + 1;
+
+/// Everything needed to rebuild a scene, stored as json. Model paths in files are relative to the scene file
+pub struct SceneDescription {
+    pub models: Vec<ModelSource>,
+    pub objects: Vec<ObjectDescription>,
+    pub lights: Vec<Light>,
+    pub camera: CameraDescription,
+    /// sRGB with alpha, like the gui colors
+    pub background_color: [f32; 4],
+}
+
+pub struct ObjectDescription {
+    /// Index in SceneDescription::models
+    pub model: usize,
+    /// Relative to the parent
+    pub transform: Transform,
+    /// Index in SceneDescription::objects
+    pub parent: Option<usize>,
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Copy, Clone)]
+pub struct CameraDescription {
+    pub position: Point3<f32>,
+    pub yaw: Deg<f32>,
+    pub pitch: Deg<f32>,
+}
+
+impl SceneDescription {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<SceneDescription> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        let scene = migrate(json::parse(&source)?).with_context(|| format!("Failed to migrate {}", path.display()))?;
+        from_json(&scene, path.parent().unwrap()).with_context(|| format!("Invalid scene {}", path.display()))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let scene = self.to_json(path.parent().unwrap());
+        fs::write(path, json::to_string_pretty(&scene)).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn to_json(&self, base_dir: &Path) -> Value {
+        let models = self
+            .models
+            .iter()
+            .map(|model| {
+                let path = relative_path(&model.path, base_dir);
+                match model.mesh {
+                    Some(mesh) => json::object([("path", path.into()), ("mesh", mesh.into())]),
+                    None => json::object([("path", path.into())]),
+                }
+            })
+            .collect::<Vec<_>>();
+        let objects = self
+            .objects
+            .iter()
+            .map(|object| {
+                let transform = &object.transform;
+                let mut fields = vec![
+                    ("model", object.model.into()),
+                    ("position", vector_to_json(transform.position)),
+                    // the same order as in gltf
+                    (
+                        "rotation",
+                        [transform.rotation.v.x, transform.rotation.v.y, transform.rotation.v.z, transform.rotation.s].into(),
+                    ),
+                    ("scale", vector_to_json(transform.scale)),
+                ];
+                if let Some(parent) = object.parent {
+                    fields.push(("parent", parent.into()));
+                }
+                if let Some(name) = &object.name {
+                    fields.push(("name", name.as_str().into()));
+                }
+                if !object.tags.is_empty() {
+                    fields.push(("tags", Value::Array(object.tags.iter().map(|tag| tag.as_str().into()).collect())));
+                }
+                Value::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+            })
+            .collect::<Vec<_>>();
+        let lights = self
+            .lights
+            .iter()
+            .map(light_to_json)
+            .collect::<Vec<_>>();
+        json::object([
+            ("version", SCENE_VERSION.into()),
+            ("models", models.into()),
+            ("objects", objects.into()),
+            ("lights", lights.into()),
+            (
+                "camera",
+                json::object([
+                    ("position", [self.camera.position.x, self.camera.position.y, self.camera.position.z].into()),
+                    ("yaw", self.camera.yaw.0.into()),
+                    ("pitch", self.camera.pitch.0.into()),
+                ]),
+            ),
+            ("background_color", self.background_color.into()),
+        ])
+    }
+}
+
+fn migrate(mut scene: Value) -> Result<Value> {
+    let version = scene
+        .get("version")
+        .and_then(Value::as_usize)
+        .ok_or_else(|| anyhow!("Scene version is missing"))?;
+    if version == 0 || version > SCENE_VERSION {
+        bail!("Unsupported scene version {}, the latest one is {}", version, SCENE_VERSION);
+    }
+    for migration in MIGRATIONS[version - 1..].iter() {
+        scene = migration(scene)?;
+    }
+    Ok(scene)
+}
+
+fn from_json(scene: &Value, base_dir: &Path) -> Result<SceneDescription> {
+    let mut models = vec![];
+    for model in array(scene, "models") {
+        let path = model
+            .get("path")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow!("Model path is missing"))?;
+        let mesh = match model.get("mesh") {
+            Some(mesh) => Some(mesh.as_usize().ok_or_else(|| anyhow!("Invalid mesh index of {}", path))?),
+            None => None,
+        };
+        models.push(ModelSource {
+            path: base_dir.join(path),
+            mesh,
+        });
+    }
+
+    let mut objects = vec![];
+    let num_of_objects = array(scene, "objects").len();
+    for object in array(scene, "objects") {
+        let model = object
+            .get("model")
+            .and_then(Value::as_usize)
+            .filter(|model| *model < models.len())
+            .ok_or_else(|| anyhow!("Invalid object model {:?}", object.get("model")))?;
+        let parent = match object.get("parent") {
+            Some(parent) => Some(
+                parent
+                    .as_usize()
+                    .filter(|parent| *parent < num_of_objects)
+                    .ok_or_else(|| anyhow!("Invalid object parent {:?}", parent))?,
+            ),
+            None => None,
+        };
+        let rotation = get_f32_array::<4>(object, "rotation").unwrap_or([0.0, 0.0, 0.0, 1.0]);
+        objects.push(ObjectDescription {
+            model,
+            parent,
+            transform: Transform {
+                position: get_f32_array(object, "position").unwrap_or([0.0; 3]).into(),
+                rotation: Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]),
+                scale: get_f32_array(object, "scale").unwrap_or([1.0; 3]).into(),
+            },
+            name: object.get("name").and_then(Value::as_str).map(String::from),
+            tags: array(object, "tags").iter().filter_map(Value::as_str).map(String::from).collect(),
+        });
+    }
+
+    // a chain of parents longer than the number of objects goes around in a circle
+    for (index, object) in objects.iter().enumerate() {
+        let (mut parent, mut depth) = (object.parent, 0);
+        while let Some(parent_index) = parent {
+            if depth == objects.len() {
+                bail!("Object {} is its own ancestor", index);
+            }
+            parent = objects[parent_index].parent;
+            depth += 1;
+        }
+    }
+
+    let mut lights = vec![];
+    for light in array(scene, "lights") {
+        lights.push(light_from_json(light)?);
+    }
+
+    let default_camera = CameraDescription::default();
+    let camera = match scene.get("camera") {
+        Some(camera) => CameraDescription {
+            position: get_f32_array(camera, "position")
+                .map(Point3::from)
+                .unwrap_or(default_camera.position),
+            yaw: camera.get("yaw").and_then(Value::as_f32).map(Deg).unwrap_or(default_camera.yaw),
+            pitch: camera.get("pitch").and_then(Value::as_f32).map(Deg).unwrap_or(default_camera.pitch),
+        },
+        None => default_camera,
+    };
+
+    Ok(SceneDescription {
+        models,
+        objects,
+        lights,
+        camera,
+        background_color: get_f32_array(scene, "background_color").unwrap_or([0.0, 0.0, 0.0, 1.0]),
+    })
+}
+
+impl Default for CameraDescription {
+    // the same as CameraState::new
+    fn default() -> Self {
+        CameraDescription {
+            position: Point3::new(10.0, 0.0, -25.0),
+            yaw: Deg(90.0),
+            pitch: Deg(0.0),
+        }
+    }
+}
+
+fn light_to_json(light: &Light) -> Value {
+    let mut fields = vec![
+        ("position", vector_to_json(light.position)),
+        ("color", vector_to_json(light.color)),
+        ("intensity", light.intensity.into()),
+    ];
+    match light.kind {
+        LightKind::Point => {
+            fields.push(("type", "point".into()));
+            fields.push(("range", light.range.into()));
+        }
+        LightKind::Directional => {
+            fields.push(("type", "directional".into()));
+            fields.push(("direction", vector_to_json(light.direction)));
+        }
+        LightKind::Spot { inner_angle, outer_angle } => {
+            fields.push(("type", "spot".into()));
+            fields.push(("range", light.range.into()));
+            fields.push(("direction", vector_to_json(light.direction)));
+            fields.push(("inner_angle", inner_angle.0.into()));
+            fields.push(("outer_angle", outer_angle.0.into()));
+        }
+    }
+    if let Some(shadow) = light.shadow {
+        let shadow_fields = [("bias", shadow.bias.into()), ("resolution", (shadow.resolution as usize).into())];
+        fields.push(("shadow", Value::Object(shadow_fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())));
+    }
+    Value::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+}
+
+// everything but the position of point and spot lights is optional
+fn light_from_json(light: &Value) -> Result<Light> {
+    let kind = match light.get("type").and_then(Value::as_str).unwrap_or("point") {
+        "point" => LightKind::Point,
+        "directional" => LightKind::Directional,
+        "spot" => LightKind::Spot {
+            inner_angle: Deg(light.get("inner_angle").and_then(Value::as_f32).unwrap_or(20.0)),
+            outer_angle: Deg(light.get("outer_angle").and_then(Value::as_f32).unwrap_or(30.0)),
+        },
+        kind => bail!("Unknown light type {}", kind),
+    };
+    let position = match (get_f32_array(light, "position"), kind) {
+        (Some(position), _) => position.into(),
+        (None, LightKind::Directional) => Vector3::new(0.0, 0.0, 0.0),
+        (None, _) => bail!("Light position is missing"),
+    };
+    Ok(Light {
+        kind,
+        position,
+        direction: get_f32_array(light, "direction").unwrap_or([0.0, -1.0, 0.0]).into(),
+        color: get_f32_array(light, "color").unwrap_or([1.0; 3]).into(),
+        intensity: light.get("intensity").and_then(Value::as_f32).unwrap_or(1.0),
+        range: light.get("range").and_then(Value::as_f32).unwrap_or(0.0),
+        // lights without the shadow object don't cast shadows
+        shadow: light.get("shadow").map(|shadow| Shadow {
+            bias: shadow.get("bias").and_then(Value::as_f32).unwrap_or(Shadow::default().bias),
+            resolution: shadow
+                .get("resolution")
+                .and_then(Value::as_usize)
+                .map_or(Shadow::default().resolution, |resolution| resolution as u32),
+        }),
+    })
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value.get(key).and_then(Value::as_array).map(Vec::as_slice).unwrap_or(&[])
+}
+
+fn get_f32_array<const N: usize>(value: &Value, key: &str) -> Option<[f32; N]> {
+    value.get(key).and_then(Value::as_f32_array::<N>)
+}
+
+fn vector_to_json(vector: Vector3<f32>) -> Value {
+    [vector.x, vector.y, vector.z].into()
+}
+
+// keeps scenes movable together with their models, paths outside of the scene directory become absolute
+fn relative_path(path: &Path, base_dir: &Path) -> String {
+    let path: PathBuf = match path.strip_prefix(base_dir).ok() {
+        Some(relative) => relative.to_path_buf(),
+        None => fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+    };
+    path.to_string_lossy().replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<SceneDescription> {
+        from_json(&json::parse(source)?, Path::new("/scenes"))
+    }
+
+    #[test]
+    fn objects_keep_parents_names_and_tags() {
+        let scene = parse(
+            r#"{"version": 1, "models": [{"path": "a.obj"}], "objects": [
+                {"model": 0, "parent": 1, "position": [1, 2, 3], "name": "child", "tags": ["x", "y"]},
+                {"model": 0}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(scene.models[0].path, Path::new("/scenes/a.obj"));
+        let child = &scene.objects[0];
+        assert_eq!(child.parent, Some(1));
+        assert_eq!(child.transform.position, Vector3::new(1.0, 2.0, 3.0));
+        assert_eq!(child.name.as_deref(), Some("child"));
+        assert_eq!(child.tags, ["x", "y"]);
+        assert_eq!(scene.objects[1].parent, None);
+
+        let saved = from_json(&scene.to_json(Path::new("/scenes")), Path::new("/scenes")).unwrap();
+        assert_eq!(saved.objects[0].parent, Some(1));
+        assert_eq!(saved.objects[0].name.as_deref(), Some("child"));
+        assert_eq!(saved.objects[0].tags, ["x", "y"]);
+        assert!(saved.objects[1].name.is_none());
+    }
+
+    #[test]
+    fn rejects_broken_hierarchies() {
+        let objects = |objects: &str| {
+            parse(&format!(r#"{{"version": 1, "models": [{{"path": "a.obj"}}], "objects": [{}]}}"#, objects))
+        };
+        assert!(objects(r#"{"model": 0, "parent": 2}, {"model": 0}"#).is_err());
+        assert!(objects(r#"{"model": 0, "parent": 0}"#).is_err());
+        assert!(objects(r#"{"model": 0, "parent": 1}, {"model": 0, "parent": 2}, {"model": 0, "parent": 0}"#).is_err());
+        assert!(objects(r#"{"model": 0, "parent": 1}, {"model": 0, "parent": 2}, {"model": 0}"#).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(migrate(json::parse(r#"{"version": 0}"#).unwrap()).is_err());
+        assert!(migrate(json::parse(&format!(r#"{{"version": {}}}"#, SCENE_VERSION + 1)).unwrap()).is_err());
+        assert!(migrate(json::parse(r#"{"models": []}"#).unwrap()).is_err());
+    }
+}
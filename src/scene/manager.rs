@@ -1,30 +1,38 @@
-use crate::model::Model;
+use crate::lighting::Light;
+use crate::model::{self, Model, ModelSource};
 use crate::app::IndexDriver;
-use std::collections::HashMap;
-use cgmath::{Matrix4, Vector3, Quaternion};
-
-pub const NUM_INSTANCES_PER_ROW: u32 = 5;
-pub const NUM_ROWS: u32 = 5;
-pub const INSTANCE_DISPLACEMENT: Vector3<f32> = Vector3::new(
-    NUM_INSTANCES_PER_ROW as f32 * 0.5,
-    0.0,
-    NUM_ROWS as f32 * 0.5,
-);
+use crate::scene::bvh::{Aabb, Bvh};
+use crate::scene::component::{BoundingVolume, Components, MeshRenderer, Name, Query, Tags};
+use crate::scene::description::{ObjectDescription, SceneDescription};
+use crate::scene::picking::{self, Hit, Ray};
+use anyhow::*;
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, One, SquareMatrix, Vector3, Quaternion, Zero};
 
+/// Everything else an object has, a model instance, a light and so on, is a component, see Manager::add_component
 pub struct Object {
     pub(crate) id: usize,
-    pub model_id: usize,
-    pub instance_id: usize,
-    pub transform: Transform
+    /// Relative to the parent, root objects are placed in the world
+    pub transform: Transform,
+    parent: Option<usize>,
+    children: Vec<usize>,
+    // parent world matrix times the local transform, refreshed by Manager::update_world_transforms
+    world: Matrix4<f32>,
 }
 
 impl Object {
-    pub fn get_raw_transform(&self) -> RawTransform {
-        RawTransform {
-            transform: Matrix4::from_translation(self.transform.position)
-                * Matrix4::from(self.transform.rotation)
-                * Matrix4::from_nonuniform_scale(self.transform.scale.x, self.transform.scale.y, self.transform.scale.z)
-        }
+    pub fn parent(&self) -> Option<usize> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[usize] {
+        &self.children
+    }
+
+    pub fn world_matrix(&self) -> Matrix4<f32> {
+        self.world
     }
 }
 
@@ -35,20 +43,94 @@ pub struct Transform {
     pub scale: Vector3<f32>,
 }
 
+impl Default for Transform {
+    /// The identity, objects are placed at their parent's origin
+    fn default() -> Self {
+        Transform {
+            position: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Transform {
+    pub fn calc_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.position)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// Splits an affine matrix without shear into translation, rotation and scale
+    pub fn from_matrix(matrix: &Matrix4<f32>) -> Transform {
+        let mut scale = Vector3::new(matrix.x.truncate().magnitude(), matrix.y.truncate().magnitude(), matrix.z.truncate().magnitude());
+        let mut rotation = Matrix3::from_cols(matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate());
+        if rotation.determinant() < 0.0 {
+            scale.x = -scale.x;
+        }
+        for (i, s) in [scale.x, scale.y, scale.z].into_iter().enumerate() {
+            if s != 0.0 {
+                rotation[i] /= s;
+            }
+        }
+        Transform {
+            position: matrix.w.truncate(),
+            rotation: Quaternion::from(rotation).normalize(),
+            scale,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct RawTransform {
     transform: Matrix4<f32>,
+    normal: [[f32; 4]; 3],
+}
+
+impl RawTransform {
+    /// The world matrix of an instance with its normal matrix
+    pub fn new(transform: Matrix4<f32>) -> RawTransform {
+        // inverting on the gpu for every vertex is slow, a zero scale has no normals anyway
+        let normal = Matrix3::from_cols(transform.x.truncate(), transform.y.truncate(), transform.z.truncate())
+            .invert()
+            .unwrap_or_else(Matrix3::identity)
+            .transpose();
+        RawTransform {
+            transform,
+            // mat3 columns are padded to vec4 in std430
+            normal: [normal.x.extend(0.0).into(), normal.y.extend(0.0).into(), normal.z.extend(0.0).into()],
+        }
+    }
 }
 
 unsafe impl bytemuck::Pod for RawTransform {}
 unsafe impl bytemuck::Zeroable for RawTransform {}
 
+
 pub struct Manager {
     index_driver: IndexDriver,
     model_registry: HashMap<usize, Model>,
     object_registry: HashMap<usize, Object>,
+    // object ids by model and instance slot, see MeshRenderer::instance_id
     model_instances: HashMap<usize, Vec<usize>>,
+    // model space boxes, they are transformed by object transforms to build the scene bvh
+    model_bounds: HashMap<usize, Aabb>,
+    bvh: Bvh,
+    // object ids by bvh primitive index
+    bvh_objects: Vec<usize>,
+    // the bvh is rebuilt lazily on the next query after any object was added, removed or moved
+    bvh_dirty: bool,
+    // the renderer uploads lights again after any light was added, removed, changed or moved
+    lights_dirty: bool,
+    // objects whose local transform or parent changed, their subtrees get new world matrices
+    // on the next update_world_transforms
+    dirty_objects: HashSet<usize>,
+    // objects that got a new world matrix since the last update_world_transforms, queries update
+    // world matrices too, so the renderer learns about those moves later
+    moved_objects: HashSet<usize>,
+    // mesh renderers, lights, names, tags, user data and so on
+    components: Components,
 }
 
 impl Manager {
@@ -58,11 +140,20 @@ impl Manager {
             model_registry: HashMap::new(),
             object_registry: HashMap::new(),
             model_instances: HashMap::new(),
+            model_bounds: HashMap::new(),
+            bvh: Bvh::default(),
+            bvh_objects: vec![],
+            bvh_dirty: false,
+            lights_dirty: false,
+            dirty_objects: HashSet::new(),
+            moved_objects: HashSet::new(),
+            components: Components::default(),
         }
     }
 
     pub fn add_model(&mut self, model: Model) -> usize {
         let model_id = model.id;
+        self.model_bounds.insert(model_id, model.calc_aabb());
         self.model_registry.insert(model_id, model);
         self.model_instances.insert(model_id, vec![]);
         model_id
@@ -73,34 +164,753 @@ impl Manager {
     }
 
     pub fn get_model_ids(&self) -> Vec<usize> {
-        self.model_registry.iter().map(|(_, m)| m.id).collect()
+        self.model_registry.values().map(|m| m.id).collect()
     }
 
+    /// Creates a root object with an instance of the model
     pub fn create_object(&mut self, model_id: usize, transform: Transform) -> usize {
-        let id = self.index_driver.next_id();
-        let instance_id = match self.model_instances.get(&model_id) {
-            Some(instances) => instances.len(),
-            None => 0,
+        let object_id = self.index_driver.next_id();
+        self.insert_object(object_id, transform, None);
+        self.add_mesh_renderer(object_id, model_id);
+        object_id
+    }
+
+    /// Creates a child drawing the model as a sphere of the radius around the parent's origin.
+    /// It only follows the position and the largest scale of the parent, so it stays a sphere
+    /// around rotated and non-uniformly scaled parents
+    pub fn create_bounding_volume(&mut self, parent_id: usize, model_id: usize, radius: f32) -> usize {
+        let object_id = self.index_driver.next_id();
+        self.components.insert(object_id, BoundingVolume);
+        let transform = Transform {
+            scale: Vector3::new(radius, radius, radius),
+            ..Default::default()
         };
+        self.insert_object(object_id, transform, Some(parent_id));
+        self.add_mesh_renderer(object_id, model_id);
+        object_id
+    }
+
+    // components that change how the world matrix is calculated have to be added before
+    fn insert_object(&mut self, id: usize, transform: Transform, parent: Option<usize>) {
+        let parent_world = match parent {
+            Some(parent_id) => {
+                let parent = self.object_registry.get_mut(&parent_id).unwrap();
+                parent.children.push(id);
+                parent.world
+            }
+            None => Matrix4::identity(),
+        };
+        let world = calc_world(&self.components, id, parent_world, &transform);
         let object = Object {
             id,
-            model_id,
-            instance_id,
             transform,
+            parent,
+            children: vec![],
+            world,
         };
         self.object_registry.insert(object.id, object);
-        self.model_instances.get_mut(&model_id).unwrap().push(id);
-        id
+    }
+
+    // the instance goes into the next free slot of the model
+    fn add_mesh_renderer(&mut self, object_id: usize, model_id: usize) {
+        let instances = self.model_instances.get_mut(&model_id).unwrap();
+        self.components.insert(object_id, MeshRenderer { model_id, instance_id: instances.len() });
+        instances.push(object_id);
+        self.bvh_dirty = true;
+    }
+
+    /// Sets the local transform, world matrices of the object and its descendants are updated
+    /// by the next update_world_transforms
+    pub fn set_transform(&mut self, object_id: usize, transform: Transform) -> &Object {
+        let object = self.object_registry.get_mut(&object_id).unwrap();
+        object.transform = transform;
+        self.dirty_objects.insert(object_id);
+        object
+    }
+
+    /// Moves the object under another parent, or to the root with None. The local transform is
+    /// recalculated, so the object stays where it is in the world
+    pub fn set_parent(&mut self, object_id: usize, parent_id: Option<usize>) -> Result<()> {
+        if !self.object_registry.contains_key(&object_id) {
+            bail!("No object {}", object_id);
+        }
+        let parent_world = match parent_id {
+            Some(parent_id) => {
+                if !self.object_registry.contains_key(&parent_id) {
+                    bail!("No parent object {}", parent_id);
+                }
+                if parent_id == object_id || self.descendants(object_id).contains(&parent_id) {
+                    bail!("Object {} can't be a child of its own descendant {}", object_id, parent_id);
+                }
+                self.calc_world_matrix(parent_id)
+            }
+            None => Matrix4::identity(),
+        };
+        let inverse = parent_world
+            .invert()
+            .ok_or_else(|| anyhow!("Parent {:?} has a zero scale", parent_id))?;
+        let world = self.calc_world_matrix(object_id);
+
+        let object = self.object_registry.get_mut(&object_id).unwrap();
+        let old_parent_id = std::mem::replace(&mut object.parent, parent_id);
+        self.set_transform(object_id, Transform::from_matrix(&(inverse * world)));
+        if let Some(old_parent) = old_parent_id.and_then(|id| self.object_registry.get_mut(&id)) {
+            old_parent.children.retain(|child_id| *child_id != object_id);
+        }
+        if let Some(parent_id) = parent_id {
+            self.object_registry.get_mut(&parent_id).unwrap().children.push(object_id);
+        }
+        Ok(())
+    }
+
+    /// Recalculates world matrices below every changed object, returns ids of all objects that got a new one
+    /// since the last call, their instances have to be written again with `RenderingState::update_instance`
+    pub fn update_world_transforms(&mut self) -> Vec<usize> {
+        self.apply_transforms();
+        self.moved_objects.drain().collect()
+    }
+
+    fn apply_transforms(&mut self) {
+        let dirty_objects = std::mem::take(&mut self.dirty_objects);
+        let mut updated = vec![];
+        for object_id in dirty_objects.iter() {
+            // a changed ancestor updates the whole subtree anyway
+            if self.ancestors(*object_id).iter().any(|id| dirty_objects.contains(id)) {
+                continue;
+            }
+            let object = match self.object_registry.get(object_id) {
+                Some(object) => object,
+                None => continue,
+            };
+            let parent_world = match object.parent {
+                Some(parent_id) => self.object_registry.get(&parent_id).unwrap().world,
+                None => Matrix4::identity(),
+            };
+            let mut stack = vec![(*object_id, parent_world)];
+            while let Some((id, parent_world)) = stack.pop() {
+                let object = self.object_registry.get_mut(&id).unwrap();
+                object.world = calc_world(&self.components, id, parent_world, &object.transform);
+                stack.extend(object.children.iter().map(|child_id| (*child_id, object.world)));
+                updated.push(id);
+            }
+        }
+        if !updated.is_empty() {
+            self.bvh_dirty = true;
+        }
+        if updated.iter().any(|object_id| self.components.get::<Light>(*object_id).is_some()) {
+            self.lights_dirty = true;
+        }
+        self.moved_objects.extend(updated);
+    }
+
+    /// Children, grandchildren and so on, every object before its own children
+    pub fn descendants(&self, object_id: usize) -> Vec<usize> {
+        let mut descendants = vec![];
+        let mut stack: Vec<usize> = match self.object_registry.get(&object_id) {
+            Some(object) => object.children.iter().rev().copied().collect(),
+            None => vec![],
+        };
+        while let Some(id) = stack.pop() {
+            descendants.push(id);
+            stack.extend(self.object_registry.get(&id).unwrap().children.iter().rev());
+        }
+        descendants
+    }
+
+    /// The parent, its parent and so on up to the root
+    pub fn ancestors(&self, object_id: usize) -> Vec<usize> {
+        let mut ancestors = vec![];
+        let mut parent = self.object_registry.get(&object_id).and_then(Object::parent);
+        while let Some(id) = parent {
+            ancestors.push(id);
+            parent = self.object_registry.get(&id).and_then(Object::parent);
+        }
+        ancestors
+    }
+
+    // from local transforms, cached world matrices may be waiting for update_world_transforms
+    fn calc_world_matrix(&self, object_id: usize) -> Matrix4<f32> {
+        let mut path = self.ancestors(object_id);
+        path.reverse();
+        path.push(object_id);
+        path.into_iter().fold(Matrix4::identity(), |parent_world, id| {
+            let object = self.object_registry.get(&id).unwrap();
+            calc_world(&self.components, id, parent_world, &object.transform)
+        })
+    }
+
+    // takes the removed object out of its parent, its children become roots that stay in place
+    fn unlink(&mut self, object: &Object, world: Matrix4<f32>) {
+        if let Some(parent) = object.parent.and_then(|id| self.object_registry.get_mut(&id)) {
+            parent.children.retain(|child_id| *child_id != object.id);
+        }
+        for child_id in object.children.iter() {
+            if let Some(child) = self.object_registry.get_mut(child_id) {
+                child.parent = None;
+                child.transform = Transform::from_matrix(&(world * child.transform.calc_matrix()));
+            }
+        }
+        self.dirty_objects.remove(&object.id);
+        self.moved_objects.remove(&object.id);
+        if self.components.get::<Light>(object.id).is_some() {
+            self.lights_dirty = true;
+        }
+        self.components.remove_object(object.id);
+    }
+
+    /// Attaches a component to the object, returns the one of the same type it had before
+    pub fn add_component<T: 'static>(&mut self, object_id: usize, component: T) -> Result<Option<T>> {
+        if !self.object_registry.contains_key(&object_id) {
+            bail!("No object {}", object_id);
+        }
+        if TypeId::of::<T>() == TypeId::of::<Light>() {
+            self.lights_dirty = true;
+        }
+        Ok(self.components.insert(object_id, component))
+    }
+
+    pub fn get_component<T: 'static>(&self, object_id: usize) -> Option<&T> {
+        self.components.get(object_id)
+    }
+
+    /// Objects with every component of the query, ordered by creation, e.g. `query::<(&MeshRenderer, &Name)>()`
+    pub fn query<'a, Q: Query<'a>>(&'a self) -> Vec<(&'a Object, Q)> {
+        self.components
+            .query::<Q>()
+            .into_iter()
+            .map(|(object_id, components)| (self.object_registry.get(&object_id).unwrap(), components))
+            .collect()
+    }
+
+    /// Removes the object and frees its instance slot. The last instance of the same model is moved
+    /// into the freed slot (swap-remove), so instance ids always stay in 0..num_of_instances.
+    /// Children of the object are kept as roots, see App::remove_object for removing whole subtrees
+    pub fn remove_object(&mut self, object_id: usize) -> Option<Object> {
+        if !self.object_registry.contains_key(&object_id) {
+            return None;
+        }
+        let world = self.calc_world_matrix(object_id);
+        let mesh_renderer = self.components.get::<MeshRenderer>(object_id).copied();
+        let object = self.object_registry.remove(&object_id).unwrap();
+        self.unlink(&object, world);
+        if let Some(MeshRenderer { model_id, instance_id }) = mesh_renderer {
+            let instances = self.model_instances.get_mut(&model_id).unwrap();
+            instances.swap_remove(instance_id);
+            if let Some(moved_id) = instances.get(instance_id) {
+                self.components.insert(*moved_id, MeshRenderer { model_id, instance_id });
+            }
+            self.bvh_dirty = true;
+        }
+        Some(object)
+    }
+
+    /// Removes the model together with all of its instances
+    pub fn remove_model(&mut self, model_id: usize) -> Option<Model> {
+        let model = self.model_registry.remove(&model_id)?;
+        self.model_bounds.remove(&model_id);
+        for object_id in self.model_instances.remove(&model_id).unwrap_or_default() {
+            let world = self.calc_world_matrix(object_id);
+            let object = self.object_registry.remove(&object_id).unwrap();
+            self.unlink(&object, world);
+        }
+        self.bvh_dirty = true;
+        Some(model)
+    }
+
+    /// Creates a root object with the light, the light's position and direction are in the object space,
+    /// so lights follow their parents like any other object
+    pub fn add_light(&mut self, light: Light) -> usize {
+        let object_id = self.index_driver.next_id();
+        self.insert_object(object_id, Transform::default(), None);
+        self.components.insert(object_id, light);
+        self.lights_dirty = true;
+        object_id
+    }
+
+    pub fn get_light(&self, object_id: usize) -> Option<&Light> {
+        self.components.get(object_id)
+    }
+
+    /// Changing a light, the renderer picks it up on the next frame
+    pub fn set_light(&mut self, object_id: usize, light: Light) {
+        if self.components.get::<Light>(object_id).is_some() {
+            self.components.insert(object_id, light);
+            self.lights_dirty = true;
+        }
+    }
+
+    /// Removes the object of the light
+    pub fn remove_light(&mut self, object_id: usize) -> Option<Light> {
+        let light = *self.components.get::<Light>(object_id)?;
+        self.remove_object(object_id);
+        Some(light)
+    }
+
+    /// Object ids of all lights, ordered by creation
+    pub fn get_light_ids(&self) -> Vec<usize> {
+        self.components.query::<&Light>().into_iter().map(|(object_id, _)| object_id).collect()
+    }
+
+    /// Lights in the world space ordered by creation, so the order on the gpu and in scene files is stable
+    pub fn get_lights(&self) -> Vec<Light> {
+        self.query::<&Light>()
+            .into_iter()
+            .map(|(object, light)| Light {
+                position: (object.world * light.position.extend(1.0)).truncate(),
+                direction: (object.world * light.direction.extend(0.0)).truncate(),
+                ..*light
+            })
+            .collect()
+    }
+
+    /// Returns true once after lights were changed
+    pub fn take_lights_dirty(&mut self) -> bool {
+        std::mem::replace(&mut self.lights_dirty, false)
+    }
+
+    /// Loads models of the scene and creates its objects next to the existing ones, returns ids of the new models.
+    /// All models are loaded first, so a broken file leaves the manager untouched
+    pub fn populate(&mut self, scene: &SceneDescription, loader: &mut model::Loader) -> Result<Vec<usize>> {
+        // every gltf file is loaded once, its meshes are taken out one by one
+        let mut gltf_models: HashMap<PathBuf, Vec<Option<Model>>> = HashMap::new();
+        let mut models = vec![];
+        for source in scene.models.iter() {
+            let model = match source.mesh {
+                None => loader.load(&source.path)?,
+                Some(mesh) => {
+                    if !gltf_models.contains_key(&source.path) {
+                        let gltf_scene = loader.load_gltf(&source.path)?;
+                        gltf_models.insert(source.path.clone(), gltf_scene.models.into_iter().map(Some).collect());
+                    }
+                    gltf_models
+                        .get_mut(&source.path)
+                        .unwrap()
+                        .get_mut(mesh)
+                        .and_then(Option::take)
+                        .ok_or_else(|| anyhow!("{} has no mesh {} or it's used twice", source.path.display(), mesh))?
+                }
+            };
+            models.push(model);
+        }
+        let model_ids: Vec<usize> = models.into_iter().map(|model| self.add_model(model)).collect();
+        let mut object_ids = vec![];
+        for object in scene.objects.iter() {
+            let object_id = self.create_object(model_ids[object.model], object.transform.clone());
+            if let Some(name) = &object.name {
+                self.components.insert(object_id, Name(name.clone()));
+            }
+            if !object.tags.is_empty() {
+                self.components.insert(object_id, Tags(object.tags.clone()));
+            }
+            object_ids.push(object_id);
+        }
+        // parents may come after their children, transforms in files are already relative to them
+        for (object, object_id) in scene.objects.iter().zip(object_ids.iter()) {
+            if let Some(parent) = object.parent {
+                self.object_registry.get_mut(object_id).unwrap().parent = Some(object_ids[parent]);
+                self.object_registry.get_mut(&object_ids[parent]).unwrap().children.push(*object_id);
+            }
+        }
+        for object_id in object_ids.iter() {
+            let world = self.calc_world_matrix(*object_id);
+            self.object_registry.get_mut(object_id).unwrap().world = world;
+        }
+        Ok(model_ids)
+    }
+
+    /// Model sources and objects for a scene file, helper models like bounding spheres are skipped by the caller.
+    /// Lights are saved separately, see get_lights. Objects whose parent isn't saved become roots in place
+    pub fn describe(&self, skipped_model_ids: &[usize]) -> (Vec<ModelSource>, Vec<ObjectDescription>) {
+        let mut model_ids: Vec<usize> = self
+            .model_registry
+            .keys()
+            .filter(|model_id| !skipped_model_ids.contains(model_id))
+            .copied()
+            .collect();
+        model_ids.sort_unstable();
+        let models = model_ids.iter().map(|model_id| self.get_model(*model_id).source.clone()).collect();
+        let saved: Vec<(usize, &Object)> = model_ids
+            .iter()
+            .enumerate()
+            .flat_map(|(model_index, model_id)| self.get_model_instances(*model_id).into_iter().map(move |object| (model_index, object)))
+            .collect();
+        let indices: HashMap<usize, usize> = saved.iter().enumerate().map(|(index, (_, object))| (object.id, index)).collect();
+        let objects = saved
+            .iter()
+            .map(|(model_index, object)| {
+                let parent = object.parent.and_then(|parent_id| indices.get(&parent_id).copied());
+                let transform = match (object.parent, parent) {
+                    (Some(_), None) => Transform::from_matrix(&self.calc_world_matrix(object.id)),
+                    _ => object.transform.clone(),
+                };
+                ObjectDescription {
+                    model: *model_index,
+                    transform,
+                    parent,
+                    name: self.get_component::<Name>(object.id).map(|name| name.0.clone()),
+                    tags: self.get_component::<Tags>(object.id).map_or(vec![], |tags| tags.0.clone()),
+                }
+            })
+            .collect();
+        (models, objects)
+    }
+
+    /// Returns the nearest object hit by the ray
+    pub fn raycast(&mut self, ray: &Ray) -> Option<Hit> {
+        self.update_bvh();
+        let mut nearest: Option<Hit> = None;
+        self.bvh.raycast(ray, |primitive| {
+            let object_id = self.bvh_objects[primitive];
+            let object = self.object_registry.get(&object_id)?;
+            let mesh_renderer = self.components.get::<MeshRenderer>(object_id)?;
+            let model = self.model_registry.get(&mesh_renderer.model_id)?;
+            let hit = picking::intersect_object(ray, object, model)?;
+            if nearest.is_none_or(|nearest| hit.distance < nearest.distance) {
+                nearest = Some(hit);
+            }
+            Some(hit.distance)
+        });
+        nearest
+    }
+
+    /// Returns ids of objects whose world space bounding boxes intersect the box
+    pub fn query_aabb(&mut self, aabb: &Aabb) -> Vec<usize> {
+        self.update_bvh();
+        self.bvh.query_aabb(aabb).into_iter().map(|primitive| self.bvh_objects[primitive]).collect()
+    }
+
+    // moved objects get their world matrices first, so queries always see the current scene
+    fn update_bvh(&mut self) {
+        self.apply_transforms();
+        if !self.bvh_dirty {
+            return;
+        }
+        // objects without a model instance have nothing to hit, bounding spheres are never picked
+        let (bvh_objects, bounds): (Vec<usize>, Vec<Aabb>) = self
+            .query::<&MeshRenderer>()
+            .into_iter()
+            .filter(|(object, _)| self.components.get::<BoundingVolume>(object.id).is_none())
+            .map(|(object, mesh_renderer)| (object.id, self.calc_world_bounds(object, mesh_renderer.model_id)))
+            .unzip();
+        self.bvh_objects = bvh_objects;
+        self.bvh = Bvh::build(bounds);
+        self.bvh_dirty = false;
+    }
+
+    /// World space box of the object's model, None for objects without one
+    pub fn get_world_bounds(&self, object_id: usize) -> Option<Aabb> {
+        let object = self.object_registry.get(&object_id)?;
+        let mesh_renderer = self.components.get::<MeshRenderer>(object_id)?;
+        Some(self.calc_world_bounds(object, mesh_renderer.model_id))
+    }
+
+    fn calc_world_bounds(&self, object: &Object, model_id: usize) -> Aabb {
+        self.model_bounds.get(&model_id).unwrap().transform(&object.world)
+    }
+
+    pub fn get_object(&self, object_id: usize) -> Option<&Object> {
+        self.object_registry.get(&object_id)
+    }
+
+    /// Returns the object that currently occupies the instance slot
+    pub fn get_model_instance(&self, model_id: usize, instance_id: usize) -> Option<&Object> {
+        let object_id = self.model_instances.get(&model_id)?.get(instance_id)?;
+        self.object_registry.get(object_id)
     }
 
     pub fn get_model_instances(&self, model_id: usize) -> Vec<&Object> {
         let obj_ids = self.model_instances.get(&model_id).unwrap();
         obj_ids.iter().map(|id| self.object_registry.get(id).unwrap()).collect()
     }
+}
+
+// the parent world matrix times the local transform
+fn calc_world(components: &Components, object_id: usize, parent_world: Matrix4<f32>, transform: &Transform) -> Matrix4<f32> {
+    let parent_world = match components.get::<BoundingVolume>(object_id) {
+        Some(_) => {
+            let scale = parent_world.x.truncate().magnitude().max(parent_world.y.truncate().magnitude()).max(parent_world.z.truncate().magnitude());
+            Matrix4::from_translation(parent_world.w.truncate()) * Matrix4::from_scale(scale)
+        }
+        None => parent_world,
+    };
+    parent_world * transform.calc_matrix()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Rotation3};
+
+    // ids come from the model loader outside of tests
+    fn next_model_id(manager: &Manager) -> usize {
+        manager.model_registry.keys().max().map_or(0, |id| id + 1)
+    }
+
+    fn add_empty_model(manager: &mut Manager) -> usize {
+        manager.add_model(Model {
+            id: next_model_id(manager),
+            label: String::from("empty"),
+            meshes: vec![],
+            materials: vec![],
+            source: ModelSource {
+                path: PathBuf::from("empty.obj"),
+                mesh: None,
+            },
+        })
+    }
+
+    // a 2x2 square in the xy plane around the origin
+    fn add_square_model(manager: &mut Manager) -> usize {
+        let positions = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].map(|(x, y)| Vector3::new(x, y, 0.0));
+        let indices = vec![0, 1, 2, 0, 2, 3];
+        manager.add_model(Model {
+            id: next_model_id(manager),
+            label: String::from("square"),
+            meshes: vec![model::Mesh {
+                name: String::from("square"),
+                vertices: positions.iter().map(|position| model::ModelVertex { position: *position, ..Default::default() }).collect(),
+                bvh: Bvh::from_triangles(&positions, &indices),
+                indices,
+                material_id: 0,
+                has_tex_coords: false,
+                lods: vec![],
+            }],
+            materials: vec![],
+            source: ModelSource {
+                path: PathBuf::from("square.obj"),
+                mesh: None,
+            },
+        })
+    }
+
+    fn translation(x: f32, y: f32, z: f32) -> Transform {
+        Transform {
+            position: Vector3::new(x, y, z),
+            ..Default::default()
+        }
+    }
+
+    // an object placed relative to the parent, scene files make them with their parent ids
+    fn create_child(manager: &mut Manager, parent_id: usize, model_id: usize, transform: Transform) -> usize {
+        let object_id = manager.index_driver.next_id();
+        manager.insert_object(object_id, transform, Some(parent_id));
+        manager.add_mesh_renderer(object_id, model_id);
+        object_id
+    }
+
+    fn position(manager: &Manager, object_id: usize) -> Vector3<f32> {
+        manager.get_object(object_id).unwrap().world_matrix().w.truncate()
+    }
+
+    #[test]
+    fn set_parent_rejects_cycles() {
+        let mut manager = Manager::new();
+        let model_id = add_empty_model(&mut manager);
+        let root = manager.create_object(model_id, Transform::default());
+        let child = create_child(&mut manager, root, model_id, Transform::default());
+        let grandchild = create_child(&mut manager, child, model_id, Transform::default());
+        assert!(manager.set_parent(root, Some(root)).is_err());
+        assert!(manager.set_parent(root, Some(grandchild)).is_err());
+        assert!(manager.set_parent(child, Some(grandchild)).is_err());
+        assert!(manager.set_parent(grandchild, Some(grandchild + 1)).is_err());
+        assert_eq!(manager.ancestors(grandchild), [child, root]);
+
+        manager.set_parent(grandchild, Some(root)).unwrap();
+        assert_eq!(manager.descendants(root), [child, grandchild]);
+        assert!(manager.get_object(child).unwrap().children().is_empty());
+    }
+
+    #[test]
+    fn world_transforms_follow_parents() {
+        let mut manager = Manager::new();
+        let model_id = add_empty_model(&mut manager);
+        let root = manager.create_object(model_id, translation(1.0, 0.0, 0.0));
+        let child = create_child(&mut manager, root, model_id, translation(0.0, 1.0, 0.0));
+        let grandchild = create_child(&mut manager, child, model_id, translation(0.0, 0.0, 1.0));
+        let other = manager.create_object(model_id, translation(5.0, 0.0, 0.0));
+        assert_eq!(position(&manager, grandchild), Vector3::new(1.0, 1.0, 1.0));
+
+        manager.set_transform(root, Transform {
+            rotation: Quaternion::from_angle_y(Deg(90.0)),
+            ..translation(2.0, 0.0, 0.0)
+        });
+        let mut updated = manager.update_world_transforms();
+        updated.sort_unstable();
+        assert_eq!(updated, [root, child, grandchild]);
+        let moved = position(&manager, grandchild);
+        assert!((moved - Vector3::new(3.0, 1.0, 0.0)).magnitude() < 1e-5, "{:?}", moved);
+
+        // reparenting keeps the world pose
+        manager.set_parent(grandchild, Some(other)).unwrap();
+        manager.update_world_transforms();
+        assert!((position(&manager, grandchild) - moved).magnitude() < 1e-5);
+        manager.set_transform(other, translation(6.0, 0.0, 0.0));
+        manager.update_world_transforms();
+        assert!((position(&manager, grandchild) - (moved + Vector3::new(1.0, 0.0, 0.0))).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn bounding_volumes_stay_spheres() {
+        let mut manager = Manager::new();
+        let model_id = add_empty_model(&mut manager);
+        let object = manager.create_object(model_id, Transform {
+            rotation: Quaternion::from_angle_z(Deg(30.0)),
+            scale: Vector3::new(1.0, 3.0, 2.0),
+            ..translation(1.0, 2.0, 3.0)
+        });
+        let sphere = manager.create_bounding_volume(object, model_id, 2.0);
+        let world = manager.get_object(sphere).unwrap().world_matrix();
+        assert_eq!(world, Matrix4::from_translation(Vector3::new(1.0, 2.0, 3.0)) * Matrix4::from_scale(6.0));
+    }
+
+    #[test]
+    fn deep_hierarchies_dont_recurse() {
+        let mut manager = Manager::new();
+        let model_id = add_empty_model(&mut manager);
+        let mut leaf = manager.create_object(model_id, translation(0.0, 1.0, 0.0));
+        for _ in 0..100_000 {
+            leaf = create_child(&mut manager, leaf, model_id, translation(0.0, 1.0, 0.0));
+        }
+        manager.set_parent(leaf, None).unwrap();
+        assert_eq!(manager.get_object(leaf).unwrap().transform.position, Vector3::new(0.0, 100_001.0, 0.0));
+    }
+
+    #[test]
+    fn add_component_needs_an_object() {
+        let mut manager = Manager::new();
+        let light_id = manager.add_light(Light::point(Vector3::zero(), Vector3::new(1.0, 1.0, 1.0)));
+        assert!(manager.add_component(light_id, Name("lamp".to_string())).unwrap().is_none());
+        assert!(manager.add_component(light_id + 1, Name("nothing".to_string())).is_err());
+        let named: Vec<usize> = manager.query::<(&Light, &Name)>().iter().map(|(object, _)| object.id).collect();
+        assert_eq!(named, [light_id]);
+    }
+
+    #[test]
+    fn lights_are_objects() {
+        let mut manager = Manager::new();
+        let first = manager.add_light(Light::point(Vector3::new(1.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0)));
+        let second = manager.add_light(Light::point(Vector3::zero(), Vector3::new(1.0, 1.0, 1.0)));
+        assert!(manager.take_lights_dirty());
+        assert!(!manager.take_lights_dirty());
+
+        // the light position is in the object space
+        manager.set_parent(first, Some(second)).unwrap();
+        manager.set_transform(second, Transform {
+            position: Vector3::new(0.0, 2.0, 0.0),
+            ..Default::default()
+        });
+        manager.update_world_transforms();
+        assert!(manager.take_lights_dirty());
+        let positions: Vec<Vector3<f32>> = manager.get_lights().iter().map(|light| light.position).collect();
+        assert_eq!(positions, [Vector3::new(1.0, 2.0, 0.0), Vector3::new(0.0, 2.0, 0.0)]);
+
+        assert!(manager.remove_light(second).is_some());
+        assert!(manager.take_lights_dirty());
+        assert_eq!(manager.get_light_ids(), [first]);
+        assert!(manager.get_object(second).is_none());
+    }
+
+    #[test]
+    fn remove_object_moves_the_last_instance_into_the_free_slot() {
+        let mut manager = Manager::new();
+        let model_id = add_empty_model(&mut manager);
+        let objects: Vec<usize> = (0..3).map(|_| manager.create_object(model_id, Transform::default())).collect();
+        assert!(manager.remove_object(objects[1]).is_some());
+        assert!(manager.remove_object(objects[1]).is_none());
+
+        let moved = manager.get_component::<MeshRenderer>(objects[2]).unwrap();
+        assert_eq!(moved.instance_id(), 1);
+        assert_eq!(manager.get_model_instance(model_id, 1).unwrap().id, objects[2]);
+        assert_eq!(manager.get_model_instance(model_id, 0).unwrap().id, objects[0]);
+        assert!(manager.get_model_instance(model_id, 2).is_none());
+        assert_eq!(manager.get_model_instances(model_id).len(), 2);
+        assert!(manager.get_component::<MeshRenderer>(objects[1]).is_none());
+
+        // removing the last instance moves nothing
+        manager.remove_object(objects[2]);
+        assert_eq!(manager.get_component::<MeshRenderer>(objects[0]).unwrap().instance_id(), 0);
+        assert_eq!(manager.get_model_instances(model_id).len(), 1);
+    }
+
+    #[test]
+    fn remove_model_removes_all_of_its_objects() {
+        let mut manager = Manager::new();
+        let removed_model = add_square_model(&mut manager);
+        let kept_model = add_square_model(&mut manager);
+        let removed: Vec<usize> = (0..3).map(|_| manager.create_object(removed_model, Transform::default())).collect();
+        let kept = manager.create_object(kept_model, translation(5.0, 0.0, 0.0));
+        let child = create_child(&mut manager, removed[0], kept_model, Transform::default());
+        let ray = Ray::new(Vector3::new(0.5, 0.5, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(removed.contains(&manager.raycast(&ray).unwrap().object_id));
+
+        assert!(manager.remove_model(removed_model).is_some());
+        assert!(!manager.get_model_ids().contains(&removed_model));
+        for object_id in removed.iter() {
+            assert!(manager.get_object(*object_id).is_none());
+            assert!(manager.get_component::<MeshRenderer>(*object_id).is_none());
+        }
+        // instances of other models stay, children of removed objects become roots
+        let remaining: Vec<usize> = manager.query::<&MeshRenderer>().iter().map(|(object, _)| object.id).collect();
+        assert_eq!(remaining, [kept, child]);
+        assert_eq!(manager.get_object(child).unwrap().parent, None);
+        assert_eq!(manager.get_model_instance(kept_model, 1).unwrap().id, child);
+        assert_eq!(manager.raycast(&ray).unwrap().object_id, child);
+        assert!(manager.remove_model(removed_model).is_none());
+    }
+
+    #[test]
+    fn raycast_finds_the_nearest_object_where_it_was_moved() {
+        let mut manager = Manager::new();
+        let model_id = add_square_model(&mut manager);
+        let far = manager.create_object(model_id, translation(0.0, 0.0, 5.0));
+        let near = manager.create_object(model_id, translation(0.0, 0.0, 2.0));
+        // drawn as a square twice as large as the near one
+        manager.create_bounding_volume(near, model_id, 2.0);
+        let ray = Ray::new(Vector3::new(0.5, 0.5, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        let hit = manager.raycast(&ray).unwrap();
+        assert_eq!(hit.object_id, near);
+        assert!((hit.distance - 7.0).abs() < 1e-5);
+        assert_eq!(hit.normal, Vector3::new(0.0, 0.0, -1.0));
+        let only_bounding_volume = Ray::new(Vector3::new(1.5, 1.5, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!(manager.raycast(&only_bounding_volume).is_none());
+
+        // no update_world_transforms in between, the query applies the move itself
+        manager.set_transform(near, translation(3.0, 0.0, 2.0));
+        assert_eq!(manager.raycast(&ray).unwrap().object_id, far);
+        let moved = Ray::new(Vector3::new(3.5, 0.5, -5.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(manager.raycast(&moved).unwrap().object_id, near);
+        // the renderer still learns about the move
+        assert!(manager.update_world_transforms().contains(&near));
+        let miss = Ray::new(Vector3::new(0.5, 0.5, -5.0), Vector3::new(0.0, 1.0, 0.0));
+        assert!(manager.raycast(&miss).is_none());
+    }
+
+    #[test]
+    fn query_aabb_returns_overlapping_objects() {
+        let mut manager = Manager::new();
+        let model_id = add_square_model(&mut manager);
+        let origin = manager.create_object(model_id, Transform::default());
+        let right = manager.create_object(model_id, translation(3.0, 0.0, 0.0));
+        let far = manager.create_object(model_id, translation(10.0, 0.0, 0.0));
+        manager.create_bounding_volume(origin, model_id, 5.0);
+        let aabb = Aabb {
+            min: Vector3::new(0.5, -0.5, -0.5),
+            max: Vector3::new(2.5, 0.5, 0.5),
+        };
+        let mut found = manager.query_aabb(&aabb);
+        found.sort_unstable();
+        assert_eq!(found, [origin, right]);
 
-    pub fn get_objects_by_ids(&self, ids: &[usize]) -> Vec<&Object> {
-        ids.iter().map(|id| {
-            self.object_registry.get(id).unwrap()
-        }).collect()
+        manager.set_transform(far, translation(1.5, 0.0, 0.0));
+        manager.remove_object(right);
+        let mut found = manager.query_aabb(&aabb);
+        found.sort_unstable();
+        assert_eq!(found, [origin, far]);
+        assert!(manager
+            .query_aabb(&Aabb {
+                min: Vector3::new(20.0, 20.0, 20.0),
+                max: Vector3::new(21.0, 21.0, 21.0),
+            })
+            .is_empty());
     }
 }
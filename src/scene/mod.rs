@@ -1 +1,5 @@
+pub mod bvh;
+pub mod component;
+pub mod description;
 pub mod manager;
+pub mod picking;
@@ -0,0 +1,212 @@
+use crate::model::Model;
+use crate::scene::manager::Object;
+use cgmath::{InnerSpace, Matrix, Matrix3, Matrix4, SquareMatrix, Vector3};
+
+// triangles that are almost parallel to the ray are ignored
+const EPSILON: f32 = 1e-7;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: Vector3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    pub fn new(origin: Vector3<f32>, direction: Vector3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: direction.normalize(),
+        }
+    }
+
+    pub fn at(&self, distance: f32) -> Vector3<f32> {
+        self.origin + self.direction * distance
+    }
+
+    /// The result is not normalized on purpose, so distances along the transformed ray
+    /// stay the same as distances along the original one
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Ray {
+        let origin = matrix * self.origin.extend(1.0);
+        let direction = matrix * self.direction.extend(0.0);
+        Ray {
+            origin: origin.truncate() / origin.w,
+            direction: direction.truncate(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Hit {
+    pub object_id: usize,
+    pub distance: f32,
+    /// world space
+    pub point: Vector3<f32>,
+    /// world space, always faces the ray origin
+    pub normal: Vector3<f32>,
+    pub mesh_index: usize,
+    /// index of the triangle inside the mesh, its vertices are indices[3 * triangle_index..3 * triangle_index + 3]
+    pub triangle_index: usize,
+}
+
+/// Tests the ray against the object's mesh triangles, the ray is in world space
+pub fn intersect_object(ray: &Ray, object: &Object, model: &Model) -> Option<Hit> {
+    let transform = object.world_matrix();
+    let inverse = transform.invert()?;
+    let local_ray = ray.transform(&inverse);
+    let mut nearest: Option<(f32, usize, usize, Vector3<f32>)> = None;
+    for (mesh_index, mesh) in model.meshes.iter().enumerate() {
+        let hit = mesh.bvh.raycast(&local_ray, |triangle_index| {
+            let (v0, v1, v2) = mesh.get_triangle(triangle_index);
+            intersect_triangle(&local_ray, v0, v1, v2)
+        });
+        if let Some((triangle_index, distance)) = hit {
+            if nearest.is_none_or(|(nearest, ..)| distance < nearest) {
+                let (v0, v1, v2) = mesh.get_triangle(triangle_index);
+                nearest = Some((distance, mesh_index, triangle_index, (v1 - v0).cross(v2 - v0)));
+            }
+        }
+    }
+    let (distance, mesh_index, triangle_index, local_normal) = nearest?;
+    // normals have to be transformed with the inverse transpose to stay perpendicular on scaled objects
+    let normal_matrix = Matrix3::from_cols(
+        inverse.x.truncate(),
+        inverse.y.truncate(),
+        inverse.z.truncate(),
+    ).transpose();
+    let mut normal = (normal_matrix * local_normal).normalize();
+    if normal.dot(ray.direction) > 0.0 {
+        normal = -normal;
+    }
+    Some(Hit {
+        object_id: object.id,
+        distance,
+        point: ray.at(distance),
+        normal,
+        mesh_index,
+        triangle_index,
+    })
+}
+
+/// Möller–Trumbore intersection, both faces of a triangle are hit
+pub fn intersect_triangle(ray: &Ray, v0: Vector3<f32>, v1: Vector3<f32>, v2: Vector3<f32>) -> Option<f32> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+    let p = ray.direction.cross(edge2);
+    let determinant = edge1.dot(p);
+    if determinant.abs() < EPSILON {
+        return None;
+    }
+    let inverse_determinant = 1.0 / determinant;
+    let t = ray.origin - v0;
+    let u = t.dot(p) * inverse_determinant;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = t.cross(edge1);
+    let v = ray.direction.dot(q) * inverse_determinant;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = edge2.dot(q) * inverse_determinant;
+    if distance > EPSILON {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{self, ModelSource};
+    use crate::scene::bvh::Bvh;
+    use crate::scene::manager::{Manager, Transform};
+    use cgmath::{Deg, Quaternion, Rotation3};
+    use std::path::PathBuf;
+
+    fn triangle() -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        (Vector3::new(0.0, 0.0, 0.0), Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0))
+    }
+
+    fn assert_close(a: Vector3<f32>, b: Vector3<f32>) {
+        assert!((a - b).magnitude() < 1e-4, "{:?} is not {:?}", a, b);
+    }
+
+    #[test]
+    fn intersect_triangle_hits_both_faces() {
+        let (v0, v1, v2) = triangle();
+        let front = Ray::new(Vector3::new(0.25, 0.25, 2.0), Vector3::new(0.0, 0.0, -1.0));
+        assert!((intersect_triangle(&front, v0, v1, v2).unwrap() - 2.0).abs() < 1e-6);
+        let back = Ray::new(Vector3::new(0.25, 0.25, -3.0), Vector3::new(0.0, 0.0, 1.0));
+        assert!((intersect_triangle(&back, v0, v1, v2).unwrap() - 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn intersect_triangle_misses() {
+        let (v0, v1, v2) = triangle();
+        // past the hypotenuse
+        let beside = Ray::new(Vector3::new(0.75, 0.75, 2.0), Vector3::new(0.0, 0.0, -1.0));
+        assert_eq!(intersect_triangle(&beside, v0, v1, v2), None);
+        // in the plane of the triangle, straight through it
+        let parallel = Ray::new(Vector3::new(-1.0, 0.25, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(intersect_triangle(&parallel, v0, v1, v2), None);
+        let parallel_above = Ray::new(Vector3::new(-1.0, 0.25, 1.0), Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(intersect_triangle(&parallel_above, v0, v1, v2), None);
+        // the triangle is behind the origin of the ray
+        let away = Ray::new(Vector3::new(0.25, 0.25, 2.0), Vector3::new(0.0, 0.0, 1.0));
+        assert_eq!(intersect_triangle(&away, v0, v1, v2), None);
+    }
+
+    #[test]
+    fn intersect_object_uses_the_world_transform() {
+        // a 2x2 square in the xy plane around the origin
+        let positions = [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].map(|(x, y)| Vector3::new(x, y, 0.0));
+        let indices = vec![0, 1, 2, 0, 2, 3];
+        let mut manager = Manager::new();
+        let model_id = manager.add_model(Model {
+            id: 0,
+            label: String::from("square"),
+            meshes: vec![model::Mesh {
+                name: String::from("square"),
+                vertices: positions.iter().map(|position| model::ModelVertex { position: *position, ..Default::default() }).collect(),
+                bvh: Bvh::from_triangles(&positions, &indices),
+                indices,
+                material_id: 0,
+                has_tex_coords: false,
+                lods: vec![],
+            }],
+            materials: vec![],
+            source: ModelSource {
+                path: PathBuf::from("square.obj"),
+                mesh: None,
+            },
+        });
+        // turned to face the x axis, 4 units wide along z and 6 units high
+        let object_id = manager.create_object(model_id, Transform {
+            position: Vector3::new(5.0, 0.0, 0.0),
+            rotation: Quaternion::from_angle_y(Deg(90.0)),
+            scale: Vector3::new(2.0, 3.0, 1.0),
+        });
+        let object = manager.get_object(object_id).unwrap();
+        let model = manager.get_model(model_id);
+
+        let ray = Ray::new(Vector3::new(0.0, 2.5, 1.5), Vector3::new(1.0, 0.0, 0.0));
+        let hit = intersect_object(&ray, object, model).unwrap();
+        assert_eq!(hit.object_id, object_id);
+        assert!((hit.distance - 5.0).abs() < 1e-4);
+        assert_close(hit.point, Vector3::new(5.0, 2.5, 1.5));
+        assert_close(hit.normal, Vector3::new(-1.0, 0.0, 0.0));
+        assert_eq!(hit.mesh_index, 0);
+
+        // from the other side the normal turns around
+        let back = Ray::new(Vector3::new(8.0, -2.5, -1.5), Vector3::new(-1.0, 0.0, 0.0));
+        let hit = intersect_object(&back, object, model).unwrap();
+        assert!((hit.distance - 3.0).abs() < 1e-4);
+        assert_close(hit.normal, Vector3::new(1.0, 0.0, 0.0));
+        assert!(hit.normal.dot(back.direction) < 0.0);
+
+        // above the scaled square
+        let above = Ray::new(Vector3::new(0.0, 3.5, 0.0), Vector3::new(1.0, 0.0, 0.0));
+        assert!(intersect_object(&above, object, model).is_none());
+    }
+}
@@ -1,59 +1,149 @@
-use shaderc;
+use anyhow::*;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
 use std::fs;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
-const SPV_PATH: &str = "src/shader/spv";
+#[cfg(feature = "shader-hot-reload")]
+mod compiler;
+mod variant;
 
-pub fn compile_shaders(dir: &str) {
-    let dir = Path::new(dir);
-    compile_all(dir);
+pub use variant::variant_name;
+
+/// Compiled by build.rs from src/shader, by variant name
+const EMBEDDED: &[(&str, &[u8])] = include!(concat!(env!("OUT_DIR"), "/shaders.rs"));
+// relative to the shader directory
+#[cfg(feature = "shader-hot-reload")]
+const INCLUDE_DIR: &str = "include";
+// sources compiled on their own, includes are .glsl
+const SOURCE_EXTENSIONS: [&str; 3] = ["vert", "frag", "comp"];
+// sources are edited by hand, checking twice a second is enough
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// permutation defines, sources list the ones they support
+pub const HAS_NORMAL_MAP: &str = "HAS_NORMAL_MAP";
+pub const HAS_TEXCOORDS: &str = "HAS_TEXCOORDS";
+pub const SHADOWS: &str = "SHADOWS";
+// blended pipelines don't write depth, see renderer::render::create_render_pipeline
+pub const ALPHA_BLEND: &str = "ALPHA_BLEND";
+// debug coloring by level of detail
+pub const LOD_COLORS: &str = "LOD_COLORS";
+
+// shaders compiled at runtime from the override directory, they take precedence over the embedded ones
+static OVERRIDES: Mutex<BTreeMap<String, Vec<u8>>> = Mutex::new(BTreeMap::new());
+
+/// Compiles every source in `dir` to override the embedded shaders with, used during development
+pub fn compile_shaders<P: AsRef<Path>>(dir: P) -> Result<()> {
+    let dir = dir.as_ref();
+    for file_path in find_files(dir, &SOURCE_EXTENSIONS)? {
+        compile_shader(&file_path, dir)?;
+    }
+    Ok(())
 }
 
-fn compile_all(dir: &Path) {
-    for entry in fs::read_dir(dir).unwrap() {
-        let entry = entry.unwrap();
-        let file_path = entry.path();
-        if file_path.is_dir() {
-            compile_all(&file_path);
-        } else {
-            if file_path.extension().unwrap() != "spv" {
-                compile_shader(&file_path);
+/// Reads a compiled shader variant, e.g. "shader.vert" or "shader.frag[HAS_NORMAL_MAP]", see variant_name
+pub fn read_spirv(variant: &str) -> Result<Vec<u8>> {
+    if let Some(spirv) = OVERRIDES.lock().unwrap().get(variant) {
+        return Ok(spirv.clone());
+    }
+    EMBEDDED
+        .iter()
+        .find(|(embedded, _)| *embedded == variant)
+        .map(|(_, spirv)| spirv.to_vec())
+        .ok_or_else(|| anyhow!("Unknown shader {}", variant))
+}
+
+/// Compiles all permutations of a source into overrides of the embedded shaders,
+/// the previous ones stay when compilation fails
+#[cfg(feature = "shader-hot-reload")]
+pub fn compile_shader(file_path: &Path, shader_dir: &Path) -> Result<()> {
+    let variants = compiler::compile_variants(file_path, &shader_dir.join(INCLUDE_DIR)).map_err(|e| anyhow!(e))?;
+    OVERRIDES.lock().unwrap().extend(variants);
+    Ok(())
+}
+
+#[cfg(not(feature = "shader-hot-reload"))]
+pub fn compile_shader(file_path: &Path, _shader_dir: &Path) -> Result<()> {
+    bail!(
+        "Can't compile {}, pointz was built without the shader-hot-reload feature",
+        file_path.display()
+    )
+}
+
+/// The file name of a source, which is also the name compiled shaders are read by
+pub fn shader_name(file_path: &Path) -> String {
+    file_path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// Polls modification times of the shader sources and includes, there is no file watching crate in the tree
+pub struct ShaderWatcher {
+    dir: PathBuf,
+    modified: HashMap<PathBuf, SystemTime>,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new<P: AsRef<Path>>(dir: P) -> ShaderWatcher {
+        let mut watcher = ShaderWatcher {
+            dir: dir.as_ref().to_path_buf(),
+            modified: HashMap::new(),
+            last_poll: Instant::now(),
+        };
+        // sources were just compiled, only later changes matter
+        watcher.changed_files();
+        watcher
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Sources to recompile since the last poll, at most one poll per POLL_INTERVAL.
+    /// A changed include recompiles every source, includes aren't tracked per source
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return vec![];
+        }
+        self.last_poll = Instant::now();
+        let changed = self.changed_files();
+        if changed.iter().any(|path| path.extension() == Some(OsStr::new("glsl"))) {
+            return find_files(&self.dir, &SOURCE_EXTENSIONS).unwrap_or_default();
+        }
+        changed
+    }
+
+    fn changed_files(&mut self) -> Vec<PathBuf> {
+        // editors may replace files while saving, they are picked up on the next poll
+        let files = find_files(&self.dir, &["vert", "frag", "comp", "glsl"]).unwrap_or_default();
+        let mut changed = vec![];
+        for path in files {
+            let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()).ok() {
+                Some(modified) => modified,
+                None => continue,
+            };
+            if self.modified.insert(path.clone(), modified) != Some(modified) {
+                changed.push(path);
             }
         }
+        changed
     }
 }
 
-fn compile_shader(file_path: &Path) {
-    let out = format!(
-        "{}/{}.spv",
-        SPV_PATH,
-        file_path.file_name().and_then(OsStr::to_str).unwrap()
-    );
-    let shader_type = get_shader_king(file_path.extension().and_then(OsStr::to_str).unwrap());
-    let compiler = shaderc::Compiler::new().unwrap();
-    let mut options = shaderc::CompileOptions::new().unwrap();
-    options.add_macro_definition("EP", Some("main"));
-    let source = fs::read_to_string(file_path).expect("file doesn't exist");
-    let frag = compiler
-        .compile_into_spirv(
-            &source,
-            shader_type,
-            file_path.to_str().unwrap(),
-            "main",
-            Some(&options),
-        )
-        .unwrap();
-    let mut file = File::create(out).unwrap();
-    file.write_all(frag.as_binary_u8()).unwrap();
-}
-
-fn get_shader_king(extension: &str) -> shaderc::ShaderKind {
-    match extension {
-        "frag" => shaderc::ShaderKind::Fragment,
-        "vert" => shaderc::ShaderKind::Vertex,
-        _ => panic!("Unsupported shader extension provided"),
+fn find_files(dir: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let file_path = entry?.path();
+        if file_path.is_dir() {
+            files.extend(find_files(&file_path, extensions)?);
+        } else if file_path
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|extension| extensions.contains(&extension))
+        {
+            files.push(file_path);
+        }
     }
+    Ok(files)
 }
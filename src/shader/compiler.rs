@@ -0,0 +1,82 @@
+// Shared by build.rs, which embeds the shaders, and by the runtime overrides of shader::compile_shader,
+// so it only depends on shaderc and std
+
+use super::variant::variant_name;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+/// Marks a source as compiled once per subset of the listed defines, e.g. `// permutations: HAS_NORMAL_MAP SHADOWS`
+const PERMUTATIONS_DIRECTIVE: &str = "// permutations:";
+
+pub fn shader_kind(path: &Path) -> Option<shaderc::ShaderKind> {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("frag") => Some(shaderc::ShaderKind::Fragment),
+        Some("vert") => Some(shaderc::ShaderKind::Vertex),
+        Some("comp") => Some(shaderc::ShaderKind::Compute),
+        _ => None,
+    }
+}
+
+/// Every subset of the features the source declares, the empty one included
+pub fn permutations(source: &str) -> Vec<Vec<&str>> {
+    let features: Vec<&str> = source
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix(PERMUTATIONS_DIRECTIVE))
+        .flat_map(str::split_whitespace)
+        .collect();
+    (0..1usize << features.len())
+        .map(|mask| {
+            features
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, feature)| *feature)
+                .collect()
+        })
+        .collect()
+}
+
+/// Compiles every permutation of a source into (variant name, SPIR-V) pairs. `#include "file"` is resolved
+/// relative to the including file and `#include <file>` relative to `include_dir`
+pub fn compile_variants(path: &Path, include_dir: &Path) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let kind = shader_kind(path).ok_or_else(|| format!("Unsupported shader extension of {}", path.display()))?;
+    let name = path
+        .file_name()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| format!("Invalid shader path {}", path.display()))?;
+    let source = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let compiler = shaderc::Compiler::new().ok_or("Failed to create a shader compiler")?;
+    let mut variants = vec![];
+    for defines in permutations(&source) {
+        let mut options = shaderc::CompileOptions::new().ok_or("Failed to create shader compile options")?;
+        options.add_macro_definition("EP", Some("main"));
+        for define in defines.iter() {
+            options.add_macro_definition(define, None);
+        }
+        options.set_include_callback(|requested, include_type, requesting, _depth| {
+            let include_path = match include_type {
+                shaderc::IncludeType::Relative => Path::new(requesting).parent().unwrap_or(Path::new("")).join(requested),
+                shaderc::IncludeType::Standard => include_dir.join(requested),
+            };
+            let content = fs::read_to_string(&include_path)
+                .map_err(|e| format!("Failed to include {}: {}", include_path.display(), e))?;
+            Ok(shaderc::ResolvedInclude {
+                resolved_name: include_path.to_string_lossy().into_owned(),
+                content,
+            })
+        });
+        // the diagnostics are "<file>:<line>: error: ...", the defines tell which variant failed
+        let binary = compiler
+            .compile_into_spirv(&source, kind, &path.to_string_lossy(), "main", Some(&options))
+            .map_err(|e| {
+                if defines.is_empty() {
+                    e.to_string()
+                } else {
+                    format!("{}\n(compiled with {})", e, defines.join(", "))
+                }
+            })?;
+        variants.push((variant_name(name, &defines), binary.as_binary_u8().to_vec()));
+    }
+    Ok(variants)
+}
@@ -0,0 +1,16 @@
+#ifndef INSTANCES_GLSL
+#define INSTANCES_GLSL
+
+// transforms of the model instances, see scene::manager::RawTransform
+struct Instance {
+    mat4 model;
+    // inverse transpose of the model matrix, it keeps normals perpendicular on scaled instances
+    mat3 normal;
+};
+
+layout(set=0, binding=1)
+buffer readonly Instances {
+    Instance s_instances[];
+};
+
+#endif
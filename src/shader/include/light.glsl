@@ -0,0 +1,55 @@
+#ifndef LIGHT_GLSL
+#define LIGHT_GLSL
+
+// define it before the include when the lights are in another bind group
+#ifndef LIGHT_SET
+#define LIGHT_SET 2
+#endif
+
+#define LIGHT_POINT 0u
+#define LIGHT_DIRECTIONAL 1u
+#define LIGHT_SPOT 2u
+
+// the same layout as lighting::RawLight
+struct Light {
+    vec3 position;
+    uint kind;
+    vec3 direction;
+    float range;
+    vec3 color;
+    float intensity;
+    float inner_cos;
+    float outer_cos;
+    // index of the first map in include/shadow.glsl, -1 without shadows
+    int first_shadow;
+    uint num_of_shadows;
+};
+
+layout(set=LIGHT_SET, binding=0)
+readonly buffer Lights {
+    uint light_count;
+    Light lights[];
+};
+
+// color arriving at the world space position, light_dir is set to the direction towards the light
+vec3 light_radiance(Light light, vec3 position, out vec3 light_dir) {
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        light_dir = -light.direction;
+        return light.color * light.intensity;
+    }
+    vec3 to_light = light.position - position;
+    float dist = length(to_light);
+    light_dir = to_light / max(dist, 0.0001);
+    float attenuation = 1.0;
+    if (light.range > 0.0) {
+        // smooth window that reaches zero at the range
+        float ratio = dist / light.range;
+        attenuation = pow(clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0), 2.0);
+    }
+    if (light.kind == LIGHT_SPOT) {
+        attenuation *= smoothstep(light.outer_cos, light.inner_cos, dot(-light_dir, light.direction));
+    }
+    return light.color * light.intensity * attenuation;
+}
+
+#endif
@@ -0,0 +1,55 @@
+#ifndef SHADOW_GLSL
+#define SHADOW_GLSL
+
+// needs the Light struct
+#include "light.glsl"
+
+#ifndef SHADOW_SET
+#define SHADOW_SET 3
+#endif
+
+// the same layout as renderer::shadow::RawShadow
+struct Shadow {
+    mat4 view_proj;
+    // offset and size of the map in the atlas
+    vec4 rect;
+    float bias;
+};
+
+layout(set=SHADOW_SET, binding=0)
+readonly buffer Shadows {
+    Shadow shadows[];
+};
+layout(set=SHADOW_SET, binding=1) uniform texture2D t_shadow_atlas;
+layout(set=SHADOW_SET, binding=2) uniform samplerShadow s_shadow;
+
+// 3x3 percentage closer filtering, the samples stay inside the map
+float sample_shadow(vec4 rect, vec2 uv, float depth) {
+    vec2 texel = 1.0 / (vec2(textureSize(sampler2DShadow(t_shadow_atlas, s_shadow), 0)) * rect.zw);
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec2 sample_uv = clamp(uv + vec2(x, y) * texel, texel * 0.5, 1.0 - texel * 0.5);
+            lit += texture(sampler2DShadow(t_shadow_atlas, s_shadow), vec3(rect.xy + sample_uv * rect.zw, depth));
+        }
+    }
+    return lit / 9.0;
+}
+
+// 1 when the world space position is lit by the light, 0 in its shadow.
+// Cascades are ordered from the nearest, the first one that covers the position is used
+float shadow_factor(Light light, vec3 position, vec3 normal) {
+    for (uint i = 0u; i < light.num_of_shadows; i++) {
+        Shadow shadow = shadows[uint(light.first_shadow) + i];
+        vec4 clip = shadow.view_proj * vec4(position + normal * shadow.bias, 1.0);
+        vec3 ndc = clip.xyz / clip.w;
+        vec2 uv = ndc.xy * vec2(0.5, -0.5) + 0.5;
+        if (any(lessThan(uv, vec2(0.0))) || any(greaterThan(uv, vec2(1.0))) || ndc.z < 0.0 || ndc.z > 1.0) {
+            continue;
+        }
+        return sample_shadow(shadow.rect, uv, ndc.z);
+    }
+    return 1.0;
+}
+
+#endif
@@ -0,0 +1,11 @@
+#ifndef UNIFORMS_GLSL
+#define UNIFORMS_GLSL
+
+// camera, the same buffer for every drawer
+layout(set=0, binding=0)
+uniform Uniforms {
+    mat4 u_view_proj;
+    vec3 u_view_position;
+};
+
+#endif
@@ -0,0 +1,10 @@
+#ifndef VISIBLE_INSTANCES_GLSL
+#define VISIBLE_INSTANCES_GLSL
+
+// instances that passed frustum culling with their levels of detail, see renderer::model::RawVisibleInstance.
+// They are per instance attributes rather than a buffer indexed by gl_InstanceIndex,
+// which doesn't include the first instance of the draw on gl
+layout(location=5) in uint a_instance;
+layout(location=6) in uint a_lod;
+
+#endif
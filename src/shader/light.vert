@@ -4,24 +4,18 @@ layout(location=0) in vec3 a_position;
 
 layout(location=0) out vec4 v_color;
 
-layout(set=0, binding=0)
-uniform Uniforms {
-    mat4 u_view_proj;
-    vec3 u_view_position;
-};
+#include "include/uniforms.glsl"
 
-layout(set=1, binding=0)
-uniform Light {
-    vec3 u_position;
-    vec3 u_color;
-};
+#define LIGHT_SET 1
+#include "include/light.glsl"
 
 // Let's keep our light smaller than our other objects
-float scale = 0.25;
+const float SCALE = 0.25;
 
+// one instance per light
 void main() {
-    vec3 v_position = a_position * scale + u_position;
-    gl_Position = u_view_proj * vec4(v_position, 1);
+    Light light = lights[gl_InstanceIndex];
+    gl_Position = u_view_proj * vec4(a_position * SCALE + light.position, 1.0);
 
-    v_color = vec4(u_color, 1.0);
+    v_color = vec4(light.color, 1.0);
 }
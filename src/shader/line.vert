@@ -2,11 +2,7 @@
 
 layout(location=0) in vec3 pos;
 
-layout(set=0, binding=0)
-uniform Uniforms {
-    mat4 u_view_proj;
-    vec3 u_view_position;
-};
+#include "include/uniforms.glsl"
 
 void main() {
     gl_Position = u_view_proj * vec4(pos, 1.0);
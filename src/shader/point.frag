@@ -0,0 +1,14 @@
+#version 450
+
+layout(location=0) in vec4 v_color;
+layout(location=1) in vec2 v_corner;
+
+layout(location=0) out vec4 f_color;
+
+void main() {
+    // round points
+    if (dot(v_corner, v_corner) > 1.0) {
+        discard;
+    }
+    f_color = v_color;
+}
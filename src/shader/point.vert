@@ -0,0 +1,32 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec4 a_color;
+
+layout(location=0) out vec4 v_color;
+layout(location=1) out vec2 v_corner;
+
+#include "include/uniforms.glsl"
+
+layout(set=0, binding=1)
+uniform PointSettings {
+    vec2 u_viewport_size;
+    float u_point_size;
+};
+
+// every point is drawn as a quad of two triangles
+const vec2 CORNERS[6] = vec2[6](
+    vec2(-1.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0),
+    vec2(-1.0, -1.0), vec2(1.0, 1.0), vec2(-1.0, 1.0)
+);
+
+void main() {
+    vec2 corner = CORNERS[gl_VertexIndex];
+    vec4 clip_position = u_view_proj * vec4(a_position, 1.0);
+    // point size is in pixels, multiplying by w keeps it the same after the perspective division
+    clip_position.xy += corner * u_point_size / u_viewport_size * clip_position.w;
+    gl_Position = clip_position;
+
+    v_color = vec4(pow(a_color.rgb, vec3(2.2)), a_color.a);
+    v_corner = corner;
+}
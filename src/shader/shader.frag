@@ -1,9 +1,23 @@
 #version 450
+// permutations: HAS_NORMAL_MAP HAS_TEXCOORDS SHADOWS ALPHA_BLEND LOD_COLORS
 
 layout(location=0) in vec2 v_tex_coords;
 layout(location=1) in vec3 v_position;
-layout(location=2) in vec3 v_light_position;
-layout(location=3) in vec3 v_view_position;
+layout(location=2) in vec3 v_normal;
+layout(location=3) in vec3 v_tangent;
+layout(location=4) in vec3 v_bitangent;
+#ifdef LOD_COLORS
+layout(location=5) flat in uint v_lod;
+
+// full detail, then every level of simplify::MAX_LODS
+const vec3 LOD_COLORS_BY_LEVEL[5] = vec3[](
+    vec3(1.0, 1.0, 1.0),
+    vec3(0.2, 0.9, 0.2),
+    vec3(0.2, 0.5, 1.0),
+    vec3(1.0, 0.8, 0.1),
+    vec3(1.0, 0.2, 0.2)
+);
+#endif
 
 layout(location=0) out vec4 f_color;
 
@@ -13,45 +27,119 @@ layout(set = 1, binding = 1) uniform sampler s_diffuse;
 layout(set = 1, binding = 2) uniform texture2D t_normal;
 layout(set = 1, binding = 3) uniform sampler s_normal;
 
-layout(set=0, binding=0)
-uniform Uniforms {
-    mat4 u_view_proj;
-    vec3 u_view_position;
+// factors multiply the maps, see model::RawMaterial
+layout(set = 1, binding = 4) uniform Material {
+    vec4 m_base_color;
+    vec3 m_emissive;
+    float m_metallic;
+    vec3 m_specular;
+    float m_roughness;
+    float m_ior;
+    float m_alpha_cutoff;
+    float m_normal_scale;
 };
 
-layout(set = 2, binding = 0)
-uniform Light {
-    vec3 light_position;
-    vec3 light_color;
-};
-
-
-void main() {
-    mat3 coordinate_system = mat3(
-        vec3(1, 0, 0), // x axis (right)
-        vec3(0, 1, 0), // y axis (up)
-        vec3(0, 0, 1)  // z axis (forward)
-    );
+// roughness in g, metallic in b like in gltf
+layout(set = 1, binding = 5) uniform texture2D t_metallic_roughness;
+layout(set = 1, binding = 6) uniform sampler s_metallic_roughness;
 
+layout(set = 1, binding = 7) uniform texture2D t_emissive;
+layout(set = 1, binding = 8) uniform sampler s_emissive;
 
-    vec4 object_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
-    vec4 object_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords);
+#include "include/uniforms.glsl"
 
-    float ambient_strength = 0.1;
-    vec3 ambient_color = light_color * ambient_strength;
+#include "include/light.glsl"
+#ifdef SHADOWS
+#include "include/shadow.glsl"
+#endif
 
-    vec3 normal = normalize(object_normal.rgb);
-    vec3 light_dir = normalize(v_light_position - v_position);
+const float PI = 3.14159265359;
+// there is no image based lighting, it keeps the unlit sides from being black
+const float AMBIENT_STRENGTH = 0.03;
+// below it the specular highlight gets smaller than a pixel
+const float MIN_ROUGHNESS = 0.04;
 
-    vec3 view_dir = normalize(v_view_position - v_position);
-    vec3 half_dir = normalize(view_dir + light_dir);
+// GGX / Trowbridge-Reitz
+float distribution(float n_dot_h, float roughness) {
+    float a2 = roughness * roughness * roughness * roughness;
+    float d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
 
-    float specular_strength = pow(max(dot(normal, half_dir), 0.0), 32);
-    vec3 specular_color = specular_strength * light_color;
+// Smith with Schlick-GGX for direct lights
+float geometry(float n_dot_v, float n_dot_l, float roughness) {
+    float k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    return n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+}
 
-    float diffuse_strength = max(dot(normal, light_dir), 0.0);
-    vec3 diffuse_color = light_color * diffuse_strength;
+vec3 fresnel(float cos_theta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
 
-    vec3 result = (ambient_color + diffuse_color + specular_color) * object_color.xyz;
-    f_color = vec4(result, object_color.a);
+void main() {
+    // every texture is sampled before the discard, implicit lods need uniform control flow
+    vec4 base_color = m_base_color * texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+    vec4 metallic_roughness = texture(sampler2D(t_metallic_roughness, s_metallic_roughness), v_tex_coords);
+    vec3 emissive = m_emissive * texture(sampler2D(t_emissive, s_emissive), v_tex_coords).rgb;
+#if defined(HAS_NORMAL_MAP) && defined(HAS_TEXCOORDS)
+    vec4 object_normal = texture(sampler2D(t_normal, s_normal), v_tex_coords);
+#endif
+    if (base_color.a < m_alpha_cutoff) {
+        discard;
+    }
+    float metallic = clamp(m_metallic * metallic_roughness.b, 0.0, 1.0);
+    float roughness = clamp(m_roughness * metallic_roughness.g, MIN_ROUGHNESS, 1.0);
+
+#if defined(HAS_NORMAL_MAP) && defined(HAS_TEXCOORDS)
+    mat3 tangent_matrix = mat3(
+        normalize(v_tangent),
+        normalize(v_bitangent),
+        normalize(v_normal)
+    );
+    // stored as 0..1 for -1..1
+    vec3 tangent_normal = (object_normal.rgb * 2.0 - 1.0) * vec3(m_normal_scale, m_normal_scale, 1.0);
+    vec3 normal = normalize(tangent_matrix * tangent_normal);
+#else
+    vec3 normal = normalize(v_normal);
+#endif
+
+    vec3 view_dir = normalize(u_view_position - v_position);
+    float n_dot_v = max(dot(normal, view_dir), 1e-4);
+
+    // dielectrics reflect by their index of refraction, metals tint reflections with the base color
+    float dielectric_f0 = pow((m_ior - 1.0) / (m_ior + 1.0), 2.0);
+    vec3 f0 = mix(min(vec3(dielectric_f0) * m_specular, vec3(1.0)), base_color.rgb, metallic);
+
+    vec3 result = vec3(0.0);
+    for (uint i = 0u; i < light_count; i++) {
+        vec3 light_dir;
+        vec3 radiance = light_radiance(lights[i], v_position, light_dir);
+#ifdef SHADOWS
+        radiance *= shadow_factor(lights[i], v_position, normalize(v_normal));
+#endif
+        result += lights[i].color * lights[i].intensity * AMBIENT_STRENGTH * base_color.rgb;
+
+        float n_dot_l = max(dot(normal, light_dir), 0.0);
+        if (n_dot_l <= 0.0) {
+            continue;
+        }
+        vec3 half_dir = normalize(view_dir + light_dir);
+        vec3 f = fresnel(max(dot(half_dir, view_dir), 0.0), f0);
+        vec3 specular = distribution(max(dot(normal, half_dir), 0.0), roughness)
+            * geometry(n_dot_v, n_dot_l, roughness) * f / (4.0 * n_dot_v * n_dot_l);
+        vec3 diffuse = (1.0 - f) * (1.0 - metallic) * base_color.rgb / PI;
+        // scaled by pi, so a light of intensity 1 fully lights a white surface facing it
+        result += (diffuse + specular) * radiance * n_dot_l * PI;
+    }
+    result += emissive;
+#ifdef LOD_COLORS
+    // lit from the camera, so the shape is still readable
+    result = LOD_COLORS_BY_LEVEL[min(v_lod, 4u)] * (0.3 + 0.7 * n_dot_v);
+#endif
+
+#ifdef ALPHA_BLEND
+    f_color = vec4(result, base_color.a);
+#else
+    f_color = vec4(result, 1.0);
+#endif
 }
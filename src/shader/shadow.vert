@@ -0,0 +1,17 @@
+#version 450
+
+layout(location=0) in vec3 a_position;
+
+// the instances of the model bind group, its camera uniforms are replaced by the view of the shadow map
+#include "include/instances.glsl"
+// every instance of the model is listed, a_lod is always 0
+#include "include/visible_instances.glsl"
+
+layout(set=1, binding=0)
+uniform ShadowView {
+    mat4 u_shadow_view_proj;
+};
+
+void main() {
+    gl_Position = u_shadow_view_proj * s_instances[a_instance].model * vec4(a_position, 1.0);
+}
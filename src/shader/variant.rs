@@ -0,0 +1,12 @@
+// Shared by build.rs and the binary, which reads the embedded shaders by these names, so it only depends on std
+
+/// The name a compiled variant is stored under, e.g. "shader.frag[HAS_NORMAL_MAP,HAS_TEXCOORDS]"
+pub fn variant_name(name: &str, defines: &[&str]) -> String {
+    if defines.is_empty() {
+        return name.to_string();
+    }
+    let mut defines = defines.to_vec();
+    defines.sort_unstable();
+    defines.dedup();
+    format!("{}[{}]", name, defines.join(","))
+}
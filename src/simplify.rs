@@ -0,0 +1,322 @@
+// Quadric edge collapse simplification for mesh levels of detail. Vertices are collapsed into their
+// neighbours, so every level indexes the vertices of the full mesh and only the indices are stored per level
+use crate::model::ModelVertex;
+use cgmath::{InnerSpace, Vector3};
+use std::collections::HashMap;
+
+/// Levels after the full mesh at most, each one has about half of the triangles of the previous one
+pub const MAX_LODS: usize = 4;
+// smaller meshes are cheap enough at any distance
+const MIN_LOD_TRIANGLES: usize = 64;
+// a level has to drop at least a quarter of the triangles of the previous one to be worth drawing
+const MIN_REDUCTION: f32 = 0.75;
+// collapses that move the surface further than this fraction of the mesh size are never made
+const MAX_ERROR: f64 = 0.05;
+
+/// Index lists of the levels after the full mesh, coarser ones last. Meshes with seams or borders
+/// everywhere can't be simplified much and get fewer levels
+pub fn generate_lods(vertices: &[ModelVertex], indices: &[u32]) -> Vec<Vec<u32>> {
+    let mut lods: Vec<Vec<u32>> = vec![];
+    while lods.len() < MAX_LODS {
+        let previous = lods.last().map(Vec::as_slice).unwrap_or(indices);
+        if previous.len() / 3 < MIN_LOD_TRIANGLES {
+            break;
+        }
+        let lod = simplify(vertices, previous, previous.len() / 2);
+        if lod.len() as f32 > previous.len() as f32 * MIN_REDUCTION {
+            break;
+        }
+        lods.push(lod);
+    }
+    lods
+}
+
+/// Collapses edges with the smallest quadric error until at most `target_index_count` indices are left
+/// or no collapse keeps the surface close enough. Vertices on borders and on attribute seams stay in place,
+/// so there are no cracks between triangles with different texture coordinates or normals
+pub fn simplify(vertices: &[ModelVertex], indices: &[u32], target_index_count: usize) -> Vec<u32> {
+    let positions: Vec<Vector3<f64>> = vertices.iter().map(|vertex| vertex.position.cast().unwrap()).collect();
+    let locked = find_locked(vertices, indices);
+    let max_error = {
+        let (min, max) = positions.iter().fold(
+            (Vector3::new(f64::MAX, f64::MAX, f64::MAX), Vector3::new(f64::MIN, f64::MIN, f64::MIN)),
+            |(min, max), p| (min.zip(*p, f64::min), max.zip(*p, f64::max)),
+        );
+        let size = (max - min).magnitude() * MAX_ERROR;
+        size * size
+    };
+
+    let mut quadrics = vec![Quadric::default(); vertices.len()];
+    // triangles around every vertex, a collapsed vertex hands its triangles over
+    let mut adjacency: Vec<Vec<usize>> = vec![vec![]; vertices.len()];
+    for (triangle, corners) in indices.chunks_exact(3).enumerate() {
+        let [a, b, c] = [corners[0], corners[1], corners[2]].map(|i| positions[i as usize]);
+        let normal = (b - a).cross(c - a);
+        let area = normal.magnitude();
+        if area > 0.0 {
+            // area weighted, so small triangles don't hold large flat regions in place
+            let quadric = Quadric::from_plane(normal / area, a, area);
+            for corner in corners {
+                quadrics[*corner as usize].add(&quadric);
+            }
+        }
+        for corner in corners {
+            adjacency[*corner as usize].push(triangle);
+        }
+    }
+
+    let mut remap: Vec<u32> = (0..vertices.len() as u32).collect();
+    let mut index_count = indices.len();
+    while index_count > target_index_count {
+        let mut candidates = vec![];
+        for corners in indices.chunks_exact(3) {
+            let corners = [corners[0], corners[1], corners[2]].map(|i| find(&remap, i));
+            if is_degenerate(corners) {
+                continue;
+            }
+            for (a, b) in [(corners[0], corners[1]), (corners[1], corners[2]), (corners[2], corners[0])] {
+                for (from, to) in [(a, b), (b, a)] {
+                    if !locked[from as usize] {
+                        let error = quadrics[from as usize].error(positions[to as usize]);
+                        if error <= max_error {
+                            candidates.push((error, from, to));
+                        }
+                    }
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // errors change around collapsed vertices, they are collapsed again in the next pass
+        let mut touched = vec![false; vertices.len()];
+        // each collapse removes about two triangles, a pass stops well before the target
+        let max_collapses = (index_count - target_index_count) / 6 + 1;
+        let mut collapses = 0;
+        for (_, from, to) in candidates {
+            if collapses >= max_collapses || index_count <= target_index_count {
+                break;
+            }
+            let (from_index, to_index) = (from as usize, to as usize);
+            if touched[from_index] || touched[to_index] || flips(indices, &remap, &adjacency[from_index], &positions, from, to) {
+                continue;
+            }
+            let removed = adjacency[from_index]
+                .iter()
+                .filter(|triangle| {
+                    let corners = triangle_corners(indices, &remap, **triangle);
+                    !is_degenerate(corners) && corners.contains(&to)
+                })
+                .count();
+            remap[from_index] = to;
+            let quadric = quadrics[from_index];
+            quadrics[to_index].add(&quadric);
+            let triangles = std::mem::take(&mut adjacency[from_index]);
+            adjacency[to_index].extend(triangles);
+            touched[from_index] = true;
+            touched[to_index] = true;
+            index_count -= removed * 3;
+            collapses += 1;
+        }
+        if collapses == 0 {
+            break;
+        }
+    }
+
+    let mut simplified = Vec::with_capacity(index_count);
+    for triangle in 0..indices.len() / 3 {
+        let corners = triangle_corners(indices, &remap, triangle);
+        if !is_degenerate(corners) {
+            simplified.extend_from_slice(&corners);
+        }
+    }
+    simplified
+}
+
+// vertices on mesh borders and vertices that share a position with another one, they have a seam in texture
+// coordinates or normals there
+fn find_locked(vertices: &[ModelVertex], indices: &[u32]) -> Vec<bool> {
+    let mut locked = vec![false; vertices.len()];
+    let mut by_position: HashMap<[u32; 3], usize> = HashMap::new();
+    for (i, vertex) in vertices.iter().enumerate() {
+        let key = [vertex.position.x.to_bits(), vertex.position.y.to_bits(), vertex.position.z.to_bits()];
+        if let Some(other) = by_position.insert(key, i) {
+            locked[other] = true;
+            locked[i] = true;
+        }
+    }
+    // a border edge belongs to one triangle only
+    let mut edges: HashMap<(u32, u32), usize> = HashMap::new();
+    for corners in indices.chunks_exact(3) {
+        for (a, b) in [(corners[0], corners[1]), (corners[1], corners[2]), (corners[2], corners[0])] {
+            *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+        }
+    }
+    for ((a, b), count) in edges {
+        if count == 1 {
+            locked[a as usize] = true;
+            locked[b as usize] = true;
+        }
+    }
+    locked
+}
+
+fn find(remap: &[u32], mut vertex: u32) -> u32 {
+    while remap[vertex as usize] != vertex {
+        vertex = remap[vertex as usize];
+    }
+    vertex
+}
+
+fn triangle_corners(indices: &[u32], remap: &[u32], triangle: usize) -> [u32; 3] {
+    [0, 1, 2].map(|corner| find(remap, indices[triangle * 3 + corner]))
+}
+
+fn is_degenerate([a, b, c]: [u32; 3]) -> bool {
+    a == b || b == c || c == a
+}
+
+// moving `from` onto `to` must not turn any of the remaining triangles around
+fn flips(indices: &[u32], remap: &[u32], triangles: &[usize], positions: &[Vector3<f64>], from: u32, to: u32) -> bool {
+    triangles.iter().any(|triangle| {
+        let corners = triangle_corners(indices, remap, *triangle);
+        if is_degenerate(corners) || corners.contains(&to) {
+            return false;
+        }
+        let [a, b, c] = corners.map(|i| positions[i as usize]);
+        let [moved_a, moved_b, moved_c] = corners.map(|i| positions[if i == from { to } else { i } as usize]);
+        let normal = (b - a).cross(c - a);
+        let moved_normal = (moved_b - moved_a).cross(moved_c - moved_a);
+        normal.dot(moved_normal) <= 0.0
+    })
+}
+
+/// Weighted sum of squared distances to a set of planes, the symmetric 4x4 matrix is stored as its upper triangle
+#[derive(Copy, Clone, Default)]
+struct Quadric {
+    a: [f64; 10],
+    weight: f64,
+}
+
+impl Quadric {
+    fn from_plane(normal: Vector3<f64>, point: Vector3<f64>, weight: f64) -> Quadric {
+        let (x, y, z) = (normal.x, normal.y, normal.z);
+        let d = -normal.dot(point);
+        Quadric {
+            a: [x * x, x * y, x * z, x * d, y * y, y * z, y * d, z * z, z * d, d * d].map(|value| value * weight),
+            weight,
+        }
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (a, b) in self.a.iter_mut().zip(other.a.iter()) {
+            *a += b;
+        }
+        self.weight += other.weight;
+    }
+
+    /// Mean squared distance of the point to the planes
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        if self.weight == 0.0 {
+            return 0.0;
+        }
+        let a = &self.a;
+        let error = a[0] * p.x * p.x + 2.0 * a[1] * p.x * p.y + 2.0 * a[2] * p.x * p.z + 2.0 * a[3] * p.x
+            + a[4] * p.y * p.y + 2.0 * a[5] * p.y * p.z + 2.0 * a[6] * p.y
+            + a[7] * p.z * p.z + 2.0 * a[8] * p.z
+            + a[9];
+        (error / self.weight).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // a flat square of size x size quads facing up, with `height` giving the y of every vertex
+    fn grid(size: u32, height: impl Fn(u32, u32) -> f32) -> (Vec<ModelVertex>, Vec<u32>) {
+        let mut vertices = vec![];
+        for z in 0..=size {
+            for x in 0..=size {
+                vertices.push(ModelVertex {
+                    position: Vector3::new(x as f32, height(x, z), z as f32),
+                    ..Default::default()
+                });
+            }
+        }
+        let mut indices = vec![];
+        for z in 0..size {
+            for x in 0..size {
+                let corner = z * (size + 1) + x;
+                let next_row = corner + size + 1;
+                indices.extend_from_slice(&[corner, next_row, corner + 1, corner + 1, next_row, next_row + 1]);
+            }
+        }
+        (vertices, indices)
+    }
+
+    fn normals(vertices: &[ModelVertex], indices: &[u32]) -> Vec<Vector3<f32>> {
+        indices
+            .chunks_exact(3)
+            .map(|corners| {
+                let [a, b, c] = [corners[0], corners[1], corners[2]].map(|i| vertices[i as usize].position);
+                (b - a).cross(c - a)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flat_grids_lose_triangles_but_keep_their_border_and_orientation() {
+        let (vertices, indices) = grid(16, |_, _| 0.0);
+        let lods = generate_lods(&vertices, &indices);
+        assert!(!lods.is_empty());
+        let border: HashSet<u32> = (0..vertices.len() as u32)
+            .filter(|i| {
+                let position = vertices[*i as usize].position;
+                position.x == 0.0 || position.x == 16.0 || position.z == 0.0 || position.z == 16.0
+            })
+            .collect();
+        let mut previous = indices.len();
+        for lod in lods.iter() {
+            assert_eq!(lod.len() % 3, 0);
+            assert!(lod.len() as f32 <= previous as f32 * MIN_REDUCTION);
+            previous = lod.len();
+            let used: HashSet<u32> = lod.iter().copied().collect();
+            assert!(border.is_subset(&used));
+            for normal in normals(&vertices, lod) {
+                assert!(normal.y > 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn small_meshes_get_no_levels() {
+        // 32 triangles
+        let (vertices, indices) = grid(4, |_, _| 0.0);
+        assert!(generate_lods(&vertices, &indices).is_empty());
+    }
+
+    #[test]
+    fn rough_surfaces_keep_more_triangles() {
+        let (vertices, indices) = grid(16, |_, _| 0.0);
+        let flat = simplify(&vertices, &indices, 0).len();
+        // isolated bumps, moving or flattening them moves the surface further than the max error
+        let (vertices, indices) = grid(16, |x, z| if x % 2 == 1 && z % 2 == 1 { 8.0 } else { 0.0 });
+        assert!(simplify(&vertices, &indices, 0).len() > flat * 2);
+    }
+
+    #[test]
+    fn vertices_on_seams_are_locked() {
+        let (mut vertices, indices) = grid(2, |_, _| 0.0);
+        // the center vertex gets a twin with other texture coordinates
+        let mut twin = vertices[4];
+        twin.tex_coords = cgmath::Vector2::new(1.0, 1.0);
+        vertices.push(twin);
+        let locked = find_locked(&vertices, &indices);
+        assert!(locked[4] && locked[9]);
+        // the rest is on the border
+        assert!(locked.iter().all(|locked| *locked));
+        let (vertices, indices) = grid(4, |_, _| 0.0);
+        assert!(!find_locked(&vertices, &indices)[12]);
+    }
+}
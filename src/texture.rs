@@ -3,50 +3,182 @@ use iced_wgpu::wgpu;
 use image::{GenericImageView, RgbaImage};
 use std::path::Path;
 
+mod bc;
+mod dds;
+mod ktx2;
+mod pack;
+
+pub use pack::pack_obj_textures;
+
 pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum TextureType {
+    /// Colors in srgb, base color and emissive maps
     Diffuse,
     Normal,
+    /// Linear values that aren't colors, e.g. metallic and roughness
+    Data,
     Depth,
 }
 
+/// Block compressed formats of KTX2 and DDS files, srgb or linear is picked by the texture type like for images
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockFormat {
+    Bc1,
+    Bc2,
+    Bc3,
+    Bc4,
+    Bc5,
+    Bc6h,
+    Bc7,
+    /// Block width and height
+    Astc(u8, u8),
+}
+
+impl BlockFormat {
+    pub fn block_dimensions(self) -> (u32, u32) {
+        match self {
+            BlockFormat::Astc(width, height) => (width as u32, height as u32),
+            _ => (4, 4),
+        }
+    }
+
+    pub fn block_bytes(self) -> usize {
+        match self {
+            BlockFormat::Bc1 | BlockFormat::Bc4 => 8,
+            _ => 16,
+        }
+    }
+
+    /// Bytes of a mip level, partial blocks at the edges take full blocks
+    pub fn level_size(self, width: u32, height: u32) -> usize {
+        let (block_width, block_height) = self.block_dimensions();
+        let blocks_wide = width.max(1).div_ceil(block_width);
+        let blocks_high = height.max(1).div_ceil(block_height);
+        (blocks_wide * blocks_high) as usize * self.block_bytes()
+    }
+
+    /// BC3 keeps the alpha of images with transparent pixels, BC1 is half the size for the rest
+    pub fn for_image(image: &RgbaImage) -> BlockFormat {
+        if image.pixels().any(|pixel| pixel[3] < 255) {
+            BlockFormat::Bc3
+        } else {
+            BlockFormat::Bc1
+        }
+    }
+}
+
+/// Blocks of every mip level as they are uploaded, the largest level first
+#[derive(Clone)]
+pub struct CompressedImage {
+    pub format: BlockFormat,
+    pub levels: Vec<Vec<u8>>,
+}
+
+#[derive(Clone)]
 pub struct Texture {
     pub label: String,
     pub dimensions: (u32, u32),
     pub rgba_image: Option<RgbaImage>,
+    /// Set instead of rgba_image for KTX2 and DDS files
+    pub compressed: Option<CompressedImage>,
     pub type_: TextureType,
 }
 
 impl Texture {
-    pub fn from_image(img: &image::DynamicImage, label: &str, is_normal_map: bool) -> Result<Self> {
+    pub fn from_image(img: &image::DynamicImage, label: &str, type_: TextureType) -> Result<Self> {
         let dimensions = img.dimensions();
         // todo I can store rgba_image in a Texture
         let rgba_image = img.to_rgba8();
-        let type_ = if is_normal_map {
-            TextureType::Normal
-        } else {
-            TextureType::Diffuse
-        };
 
         Ok(Texture {
             label: label.to_string(),
             dimensions,
             rgba_image: Some(rgba_image),
+            compressed: None,
             type_,
         })
     }
 
-    pub fn load<P: AsRef<Path>>(path: P, is_normal_map: bool) -> Result<Texture> {
+    /// Images in any format the image crate reads, KTX2 and DDS textures are recognized by their magic numbers
+    pub fn from_bytes(bytes: &[u8], label: &str, type_: TextureType) -> Result<Texture> {
+        if bytes.starts_with(&ktx2::IDENTIFIER) {
+            return match ktx2::read(bytes)? {
+                ktx2::Ktx2Image::Rgba(image) => Self::from_image(&image::DynamicImage::ImageRgba8(image), label, type_),
+                ktx2::Ktx2Image::Compressed { width, height, image } => Ok(Self::from_compressed(image, width, height, label, type_)),
+            };
+        }
+        if bytes.starts_with(dds::MAGIC) {
+            let (width, height, image) = dds::read(bytes)?;
+            return Ok(Self::from_compressed(image, width, height, label, type_));
+        }
+        let img = image::load_from_memory(bytes)?;
+        Self::from_image(&img, label, type_)
+    }
+
+    fn from_compressed(image: CompressedImage, width: u32, height: u32, label: &str, type_: TextureType) -> Texture {
+        Texture {
+            label: label.to_string(),
+            dimensions: (width, height),
+            rgba_image: None,
+            compressed: Some(image),
+            type_,
+        }
+    }
+
+    /// Decodes the largest level for adapters without support for the format, mipmaps are generated again
+    pub fn decompress(&self) -> Result<Texture> {
+        let compressed = self.compressed.as_ref().ok_or_else(|| anyhow!("{} isn't compressed", self.label))?;
+        let (width, height) = self.dimensions;
+        let image = bc::decode(compressed.format, width, height, &compressed.levels[0])
+            .with_context(|| format!("Failed to decompress {}", self.label))?;
+        Self::from_image(&image::DynamicImage::ImageRgba8(image), &self.label, self.type_)
+    }
+
+    /// 1x1 texture of a single color
+    pub fn from_color(color: [u8; 4], label: &str, type_: TextureType) -> Texture {
+        let img = image::DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, image::Rgba(color)));
+        Self::from_image(&img, label, type_).unwrap()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, type_: TextureType) -> Result<Texture> {
         let path_copy = path.as_ref().to_path_buf();
-        let label = match path_copy.to_str() {
-            Some(l) => l,
-            None => "no_name",
-        };
+        let label = path_copy.to_str().unwrap_or("no_name");
 
-        let img = image::open(path)?;
+        let bytes = std::fs::read(&path_copy)?;
+        if bytes.starts_with(&ktx2::IDENTIFIER) || bytes.starts_with(dds::MAGIC) {
+            return Self::from_bytes(&bytes, label, type_);
+        }
+        // formats without a magic number like tga are known by the extension
+        let mut reader = image::io::Reader::new(std::io::Cursor::new(bytes));
+        if let Some(format) = path_copy.extension().and_then(image::ImageFormat::from_extension) {
+            reader.set_format(format);
+        }
+        let img = reader.with_guessed_format()?.decode()?;
         // it will crash if label is longer then 64
-        Self::from_image(&img, label, is_normal_map)
+        Self::from_image(&img, label, type_)
+    }
+
+    /// Levels of the full mip chain, the image itself included. Compressed files have as many as they store
+    pub fn mip_level_count(&self) -> u32 {
+        if let Some(compressed) = self.compressed.as_ref() {
+            return compressed.levels.len() as u32;
+        }
+        32 - self.dimensions.0.max(self.dimensions.1).max(1).leading_zeros()
+    }
+
+    /// Every level below the full size image, each one halves the previous one down to 1x1.
+    /// It's done on the cpu, textures are uploaded only once when a model is added
+    pub fn mip_chain(&self) -> Vec<RgbaImage> {
+        let mut levels: Vec<RgbaImage> = vec![];
+        if let Some(rgba_image) = self.rgba_image.as_ref() {
+            for _ in 1..self.mip_level_count() {
+                let level = downsample(levels.last().unwrap_or(rgba_image), self.type_);
+                levels.push(level);
+            }
+        }
+        levels
     }
 
     pub fn create_depth_texture(sc_desc: &wgpu::SurfaceConfiguration, label: &str) -> Self {
@@ -54,7 +186,60 @@ impl Texture {
             label: label.to_string(),
             dimensions: (sc_desc.width, sc_desc.height),
             rgba_image: None,
+            compressed: None,
             type_: TextureType::Depth,
         }
     }
 }
+
+/// Averages 2x2 blocks, odd sizes repeat their last row or column
+fn downsample(image: &RgbaImage, type_: TextureType) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    RgbaImage::from_fn((width / 2).max(1), (height / 2).max(1), |x, y| {
+        let mut sum = [0.0f32; 4];
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            let pixel = image.get_pixel((x * 2 + dx).min(width - 1), (y * 2 + dy).min(height - 1));
+            for (channel, value) in pixel.0.iter().enumerate() {
+                let value = *value as f32 / 255.0;
+                // colors are averaged in linear space, otherwise distant textures get darker
+                sum[channel] += if type_ == TextureType::Diffuse && channel < 3 { srgb_to_linear(value) } else { value };
+            }
+        }
+        let mut average = sum.map(|value| value / 4.0);
+        match type_ {
+            TextureType::Diffuse => {
+                for value in average.iter_mut().take(3) {
+                    *value = linear_to_srgb(*value);
+                }
+            }
+            // averaged normals get shorter, they are stored as 0..1 for -1..1
+            TextureType::Normal => {
+                let normal = [0, 1, 2].map(|channel| average[channel] * 2.0 - 1.0);
+                let length = normal.iter().map(|value| value * value).sum::<f32>().sqrt();
+                if length > 0.0 {
+                    for channel in 0..3 {
+                        average[channel] = normal[channel] / length * 0.5 + 0.5;
+                    }
+                }
+            }
+            TextureType::Data | TextureType::Depth => {}
+        }
+        image::Rgba(average.map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8))
+    })
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
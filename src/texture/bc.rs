@@ -0,0 +1,247 @@
+use super::BlockFormat;
+use anyhow::*;
+use image::RgbaImage;
+
+const BLOCK_SIZE: u32 = 4;
+
+/// Decodes the BC1-BC5 formats on the cpu for adapters that can't sample them.
+/// Single channel formats go to red and green like on the gpu, the rest is opaque black
+pub fn decode(format: BlockFormat, width: u32, height: u32, data: &[u8]) -> Result<RgbaImage> {
+    let block_bytes = format.block_bytes();
+    let blocks_wide = width.div_ceil(BLOCK_SIZE);
+    let blocks_high = height.div_ceil(BLOCK_SIZE);
+    if data.len() < (blocks_wide * blocks_high) as usize * block_bytes {
+        bail!("{:?} image of {}x{} is truncated", format, width, height);
+    }
+    let mut image = RgbaImage::new(width, height);
+    for (block_index, block) in data.chunks_exact(block_bytes).take((blocks_wide * blocks_high) as usize).enumerate() {
+        let texels = match format {
+            BlockFormat::Bc1 => decode_color(block, true),
+            BlockFormat::Bc2 => {
+                let mut texels = decode_color(&block[8..], false);
+                let alpha = u64::from_le_bytes(block[..8].try_into().unwrap());
+                for (i, texel) in texels.iter_mut().enumerate() {
+                    texel[3] = ((alpha >> (i * 4)) & 0xF) as u8 * 17;
+                }
+                texels
+            }
+            BlockFormat::Bc3 => {
+                let mut texels = decode_color(&block[8..], false);
+                for (texel, alpha) in texels.iter_mut().zip(decode_channel(&block[..8])) {
+                    texel[3] = alpha;
+                }
+                texels
+            }
+            BlockFormat::Bc4 => decode_channel(block).map(|red| [red, 0, 0, 255]),
+            BlockFormat::Bc5 => {
+                let green = decode_channel(&block[8..]);
+                let mut texels = decode_channel(&block[..8]).map(|red| [red, 0, 0, 255]);
+                for (texel, green) in texels.iter_mut().zip(green) {
+                    texel[1] = green;
+                }
+                texels
+            }
+            _ => bail!("{:?} can't be decoded on the cpu, the adapter has to support it", format),
+        };
+        let block_x = block_index as u32 % blocks_wide * BLOCK_SIZE;
+        let block_y = block_index as u32 / blocks_wide * BLOCK_SIZE;
+        for (i, texel) in texels.iter().enumerate() {
+            let (x, y) = (block_x + i as u32 % BLOCK_SIZE, block_y + i as u32 / BLOCK_SIZE);
+            // blocks at the right and bottom edges stick out of smaller images
+            if x < width && y < height {
+                image.put_pixel(x, y, image::Rgba(*texel));
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// Encodes an image as BC1, or as BC3 when it has transparent pixels, see BlockFormat::for_image.
+/// It fits the endpoints to the bounding box of the block colors, which is fast and good enough for textures
+pub fn encode(format: BlockFormat, image: &RgbaImage) -> Result<Vec<u8>> {
+    let (width, height) = image.dimensions();
+    let blocks_wide = width.div_ceil(BLOCK_SIZE);
+    let blocks_high = height.div_ceil(BLOCK_SIZE);
+    let mut data = Vec::with_capacity((blocks_wide * blocks_high) as usize * format.block_bytes());
+    for block_y in 0..blocks_high {
+        for block_x in 0..blocks_wide {
+            let texels: [[u8; 4]; 16] = std::array::from_fn(|i| {
+                // edge blocks repeat the last row and column
+                let x = (block_x * BLOCK_SIZE + i as u32 % BLOCK_SIZE).min(width - 1);
+                let y = (block_y * BLOCK_SIZE + i as u32 / BLOCK_SIZE).min(height - 1);
+                image.get_pixel(x, y).0
+            });
+            match format {
+                BlockFormat::Bc1 => data.extend(encode_color(&texels)),
+                BlockFormat::Bc3 => {
+                    data.extend(encode_channel(&texels.map(|texel| texel[3])));
+                    data.extend(encode_color(&texels));
+                }
+                _ => bail!("{:?} can't be encoded, only BC1 and BC3 are supported", format),
+            }
+        }
+    }
+    Ok(data)
+}
+
+fn decode_color(block: &[u8], has_alpha: bool) -> [[u8; 4]; 16] {
+    let color0 = u16::from_le_bytes([block[0], block[1]]);
+    let color1 = u16::from_le_bytes([block[2], block[3]]);
+    let palette = color_palette(color0, color1, has_alpha);
+    let indices = u32::from_le_bytes([block[4], block[5], block[6], block[7]]);
+    std::array::from_fn(|i| palette[((indices >> (i * 2)) & 0b11) as usize])
+}
+
+fn color_palette(color0: u16, color1: u16, has_alpha: bool) -> [[u8; 4]; 4] {
+    let [r0, g0, b0] = unpack_565(color0);
+    let [r1, g1, b1] = unpack_565(color1);
+    let mix = |a: u8, b: u8, wa: u32, wb: u32| ((a as u32 * wa + b as u32 * wb) / (wa + wb)) as u8;
+    // BC1 switches to three colors and transparent black when the endpoints are in reverse order
+    if color0 > color1 || !has_alpha {
+        [
+            [r0, g0, b0, 255],
+            [r1, g1, b1, 255],
+            [mix(r0, r1, 2, 1), mix(g0, g1, 2, 1), mix(b0, b1, 2, 1), 255],
+            [mix(r0, r1, 1, 2), mix(g0, g1, 1, 2), mix(b0, b1, 1, 2), 255],
+        ]
+    } else {
+        [
+            [r0, g0, b0, 255],
+            [r1, g1, b1, 255],
+            [mix(r0, r1, 1, 1), mix(g0, g1, 1, 1), mix(b0, b1, 1, 1), 255],
+            [0, 0, 0, 0],
+        ]
+    }
+}
+
+// the alpha block of BC3, also the red and green blocks of BC4 and BC5
+fn decode_channel(block: &[u8]) -> [u8; 16] {
+    let palette = channel_palette(block[0], block[1]);
+    let indices = u64::from_le_bytes([block[2], block[3], block[4], block[5], block[6], block[7], 0, 0]);
+    std::array::from_fn(|i| palette[((indices >> (i * 3)) & 0b111) as usize])
+}
+
+fn channel_palette(value0: u8, value1: u8) -> [u8; 8] {
+    let (value0, value1) = (value0 as u32, value1 as u32);
+    let mut palette = [value0 as u8, value1 as u8, 0, 0, 0, 0, 0, 255];
+    if value0 > value1 {
+        for i in 1..7 {
+            palette[i + 1] = ((value0 * (7 - i as u32) + value1 * i as u32) / 7) as u8;
+        }
+    } else {
+        // six steps, the last two are the extremes
+        for i in 1..5 {
+            palette[i + 1] = ((value0 * (5 - i as u32) + value1 * i as u32) / 5) as u8;
+        }
+    }
+    palette
+}
+
+fn encode_color(texels: &[[u8; 4]; 16]) -> [u8; 8] {
+    let mut min = [255u8; 3];
+    let mut max = [0u8; 3];
+    for texel in texels.iter() {
+        for channel in 0..3 {
+            min[channel] = min[channel].min(texel[channel]);
+            max[channel] = max[channel].max(texel[channel]);
+        }
+    }
+    let (mut color0, mut color1) = (pack_565(max), pack_565(min));
+    if color0 < color1 {
+        std::mem::swap(&mut color0, &mut color1);
+    }
+    let mut block = [0u8; 8];
+    block[..2].copy_from_slice(&color0.to_le_bytes());
+    block[2..4].copy_from_slice(&color1.to_le_bytes());
+    // a single color, every index points to color0
+    if color0 == color1 {
+        return block;
+    }
+    let palette = color_palette(color0, color1, false);
+    let mut indices = 0u32;
+    for (i, texel) in texels.iter().enumerate() {
+        let distance = |color: &[u8; 4]| (0..3).map(|c| (color[c] as i32 - texel[c] as i32).pow(2)).sum::<i32>();
+        let nearest = (0..4).min_by_key(|index| distance(&palette[*index])).unwrap();
+        indices |= (nearest as u32) << (i * 2);
+    }
+    block[4..].copy_from_slice(&indices.to_le_bytes());
+    block
+}
+
+fn encode_channel(values: &[u8; 16]) -> [u8; 8] {
+    let max = *values.iter().max().unwrap();
+    let min = *values.iter().min().unwrap();
+    let mut block = [0u8; 8];
+    block[0] = max;
+    block[1] = min;
+    if max == min {
+        return block;
+    }
+    let palette = channel_palette(max, min);
+    let mut indices = 0u64;
+    for (i, value) in values.iter().enumerate() {
+        let nearest = (0..8).min_by_key(|index| (palette[*index] as i32 - *value as i32).abs()).unwrap();
+        indices |= (nearest as u64) << (i * 3);
+    }
+    block[2..].copy_from_slice(&indices.to_le_bytes()[..6]);
+    block
+}
+
+fn unpack_565(color: u16) -> [u8; 3] {
+    let r = (color >> 11) & 0x1F;
+    let g = (color >> 5) & 0x3F;
+    let b = color & 0x1F;
+    // the high bits are repeated in the low ones, so 0x1F becomes 255
+    [((r << 3) | (r >> 2)) as u8, ((g << 2) | (g >> 4)) as u8, ((b << 3) | (b >> 2)) as u8]
+}
+
+fn pack_565(color: [u8; 3]) -> u16 {
+    let [r, g, b] = color.map(|channel| channel as u32);
+    (((r * 31 + 127) / 255) << 11 | ((g * 63 + 127) / 255) << 5 | ((b * 31 + 127) / 255)) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: [u8; 4], b: [u8; 4], tolerance: u8) {
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert!(a.abs_diff(*b) <= tolerance, "{:?} is not close to {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_bc1_and_bc3() {
+        // 6x5 has partial blocks at the right and bottom edges, the colors are corners of the bounding box of the block
+        let image = RgbaImage::from_fn(6, 5, |x, _| if x < 3 { image::Rgba([255, 255, 0, 255]) } else { image::Rgba([0, 0, 0, 128]) });
+        assert_eq!(BlockFormat::for_image(&image), BlockFormat::Bc3);
+        let data = encode(BlockFormat::Bc3, &image).unwrap();
+        assert_eq!(data.len(), BlockFormat::Bc3.level_size(6, 5));
+        let decoded = decode(BlockFormat::Bc3, 6, 5, &data).unwrap();
+        for (pixel, decoded) in image.pixels().zip(decoded.pixels()) {
+            assert_close(pixel.0, decoded.0, 8);
+        }
+
+        let opaque = RgbaImage::from_pixel(4, 4, image::Rgba([0, 255, 0, 255]));
+        let data = encode(BlockFormat::Bc1, &opaque).unwrap();
+        assert_eq!(data.len(), 8);
+        assert_close(decode(BlockFormat::Bc1, 4, 4, &data).unwrap().get_pixel(3, 3).0, [0, 255, 0, 255], 4);
+    }
+
+    #[test]
+    fn decodes_single_channel_formats_to_red_and_green() {
+        // both endpoints are 200, every index picks the first one
+        let block = [200, 200, 0, 0, 0, 0, 0, 0];
+        let decoded = decode(BlockFormat::Bc4, 4, 4, &block).unwrap();
+        assert_eq!(decoded.get_pixel(1, 2).0, [200, 0, 0, 255]);
+        let decoded = decode(BlockFormat::Bc5, 4, 4, &[block, [50, 50, 0, 0, 0, 0, 0, 0]].concat()).unwrap();
+        assert_eq!(decoded.get_pixel(1, 2).0, [200, 50, 0, 255]);
+    }
+
+    #[test]
+    fn rejects_truncated_and_unsupported_data() {
+        assert!(decode(BlockFormat::Bc1, 8, 4, &[0; 8]).is_err());
+        assert!(decode(BlockFormat::Bc7, 4, 4, &[0; 16]).is_err());
+        assert!(encode(BlockFormat::Bc7, &RgbaImage::new(4, 4)).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use super::{BlockFormat, CompressedImage};
+use anyhow::*;
+
+pub const MAGIC: &[u8; 4] = b"DDS ";
+// magic and the header that follows it
+const HEADER_SIZE: usize = 128;
+// DX10 header after the regular one, it's there when the fourcc is DX10
+const DX10_HEADER_SIZE: usize = 20;
+
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDSCAPS2_VOLUME: u32 = 0x200000;
+
+const DXGI_FORMAT_BC1_UNORM: u32 = 71;
+const DXGI_FORMAT_BC1_UNORM_SRGB: u32 = 72;
+const DXGI_FORMAT_BC2_UNORM: u32 = 74;
+const DXGI_FORMAT_BC2_UNORM_SRGB: u32 = 75;
+const DXGI_FORMAT_BC3_UNORM: u32 = 77;
+const DXGI_FORMAT_BC3_UNORM_SRGB: u32 = 78;
+const DXGI_FORMAT_BC4_UNORM: u32 = 80;
+const DXGI_FORMAT_BC5_UNORM: u32 = 83;
+const DXGI_FORMAT_BC6H_UF16: u32 = 95;
+const DXGI_FORMAT_BC7_UNORM: u32 = 98;
+const DXGI_FORMAT_BC7_UNORM_SRGB: u32 = 99;
+
+/// Reads a block compressed DDS file with its mip levels, returns its size and blocks
+pub fn read(bytes: &[u8]) -> Result<(u32, u32, CompressedImage)> {
+    if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+        bail!("Not a DDS file");
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let (height, width) = (u32_at(12), u32_at(16));
+    let levels = u32_at(28).max(1) as usize;
+    let four_cc = &bytes[84..88];
+    if u32_at(112) & (DDSCAPS2_CUBEMAP | DDSCAPS2_VOLUME) != 0 {
+        bail!("Cube maps and volume DDS textures are not supported");
+    }
+
+    let (format, data_offset) = match four_cc {
+        b"DXT1" => (BlockFormat::Bc1, HEADER_SIZE),
+        b"DXT2" | b"DXT3" => (BlockFormat::Bc2, HEADER_SIZE),
+        b"DXT4" | b"DXT5" => (BlockFormat::Bc3, HEADER_SIZE),
+        b"ATI1" | b"BC4U" => (BlockFormat::Bc4, HEADER_SIZE),
+        b"ATI2" | b"BC5U" => (BlockFormat::Bc5, HEADER_SIZE),
+        b"DX10" => {
+            if bytes.len() < HEADER_SIZE + DX10_HEADER_SIZE {
+                bail!("DDS DX10 header is truncated");
+            }
+            // array textures have more than one image
+            if u32_at(HEADER_SIZE + 12) > 1 {
+                bail!("DDS texture arrays are not supported");
+            }
+            let format = match u32_at(HEADER_SIZE) {
+                DXGI_FORMAT_BC1_UNORM | DXGI_FORMAT_BC1_UNORM_SRGB => BlockFormat::Bc1,
+                DXGI_FORMAT_BC2_UNORM | DXGI_FORMAT_BC2_UNORM_SRGB => BlockFormat::Bc2,
+                DXGI_FORMAT_BC3_UNORM | DXGI_FORMAT_BC3_UNORM_SRGB => BlockFormat::Bc3,
+                DXGI_FORMAT_BC4_UNORM => BlockFormat::Bc4,
+                DXGI_FORMAT_BC5_UNORM => BlockFormat::Bc5,
+                DXGI_FORMAT_BC6H_UF16 => BlockFormat::Bc6h,
+                DXGI_FORMAT_BC7_UNORM | DXGI_FORMAT_BC7_UNORM_SRGB => BlockFormat::Bc7,
+                other => bail!("DXGI format {} is not supported", other),
+            };
+            (format, HEADER_SIZE + DX10_HEADER_SIZE)
+        }
+        // uncompressed files have no fourcc, they are better off as pngs
+        other => bail!("DDS format {:?} is not supported, only block compressed ones are", String::from_utf8_lossy(other)),
+    };
+
+    // levels follow each other without padding, the largest one first
+    let mut offset = data_offset;
+    let mut data = vec![];
+    for level in 0..levels {
+        let length = format.level_size(width >> level, height >> level);
+        let level_data = bytes
+            .get(offset..offset + length)
+            .ok_or_else(|| anyhow!("DDS level {} is out of the file", level))?;
+        data.push(level_data.to_vec());
+        offset += length;
+    }
+    Ok((width, height, CompressedImage { format, levels: data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dds(height: u32, width: u32, levels: u32, four_cc: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(MAGIC);
+        bytes[12..16].copy_from_slice(&height.to_le_bytes());
+        bytes[16..20].copy_from_slice(&width.to_le_bytes());
+        bytes[28..32].copy_from_slice(&levels.to_le_bytes());
+        bytes[84..88].copy_from_slice(four_cc);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn reads_levels_of_block_compressed_files() {
+        // 8x4 is two blocks, 4x2 and 2x1 take one each
+        let data: Vec<u8> = (0..32).collect();
+        let (width, height, image) = read(&dds(4, 8, 3, b"DXT1", &data)).unwrap();
+        assert_eq!((width, height, image.format), (8, 4, BlockFormat::Bc1));
+        assert_eq!(image.levels, vec![data[..16].to_vec(), data[16..24].to_vec(), data[24..].to_vec()]);
+
+        let mut dx10 = vec![0; DX10_HEADER_SIZE];
+        dx10[..4].copy_from_slice(&DXGI_FORMAT_BC7_UNORM.to_le_bytes());
+        dx10.extend_from_slice(&[7; 16]);
+        let (_, _, image) = read(&dds(4, 4, 1, b"DX10", &dx10)).unwrap();
+        assert_eq!(image.format, BlockFormat::Bc7);
+        assert_eq!(image.levels, vec![vec![7; 16]]);
+    }
+
+    #[test]
+    fn rejects_unsupported_and_truncated_files() {
+        assert!(read(&dds(4, 4, 1, b"DXT5", &[0; 15])).is_err());
+        assert!(read(&dds(4, 4, 1, b"\0\0\0\0", &[0; 64])).is_err());
+        assert!(read(&dds(4, 4, 1, b"DX10", &[0; 4])).is_err());
+        assert!(read(b"DDS ").is_err());
+    }
+}
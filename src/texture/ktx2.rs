@@ -0,0 +1,229 @@
+use super::{BlockFormat, CompressedImage};
+use anyhow::*;
+use image::RgbaImage;
+
+pub const IDENTIFIER: [u8; 12] = [0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A];
+// identifier, 9 header fields and the index of the data format descriptor, key values and supercompression data
+const HEADER_SIZE: usize = 80;
+const LEVEL_INDEX_ENTRY_SIZE: usize = 24;
+
+const SUPERCOMPRESSION_NONE: u32 = 0;
+const SUPERCOMPRESSION_BASIS_LZ: u32 = 1;
+// color model of the data format descriptor for UASTC, the other Basis Universal codec
+const COLOR_MODEL_UASTC: u8 = 166;
+
+const VK_FORMAT_UNDEFINED: u32 = 0;
+const VK_FORMAT_R8G8B8A8_UNORM: u32 = 37;
+const VK_FORMAT_R8G8B8A8_SRGB: u32 = 43;
+const VK_FORMAT_BC1_RGB_UNORM: u32 = 131;
+const VK_FORMAT_BC1_RGB_SRGB: u32 = 132;
+const VK_FORMAT_BC1_RGBA_UNORM: u32 = 133;
+const VK_FORMAT_BC1_RGBA_SRGB: u32 = 134;
+const VK_FORMAT_BC2_UNORM: u32 = 135;
+const VK_FORMAT_BC2_SRGB: u32 = 136;
+const VK_FORMAT_BC3_UNORM: u32 = 137;
+const VK_FORMAT_BC3_SRGB: u32 = 138;
+const VK_FORMAT_BC4_UNORM: u32 = 139;
+const VK_FORMAT_BC5_UNORM: u32 = 141;
+const VK_FORMAT_BC6H_UFLOAT: u32 = 143;
+const VK_FORMAT_BC7_UNORM: u32 = 145;
+const VK_FORMAT_BC7_SRGB: u32 = 146;
+// ASTC formats come in unorm and srgb pairs by block size, from 4x4 to 12x12
+const VK_FORMAT_ASTC_4X4_UNORM: u32 = 157;
+const ASTC_BLOCKS: [(u8, u8); 14] = [
+    (4, 4), (5, 4), (5, 5), (6, 5), (6, 6), (8, 5), (8, 6), (8, 8), (10, 5), (10, 6), (10, 8), (10, 10), (12, 10), (12, 12),
+];
+
+// data format descriptor values, see the Khronos Data Format Specification
+const DF_MODEL_RGBSDA: u8 = 1;
+const DF_MODEL_BC1A: u8 = 128;
+const DF_MODEL_BC3: u8 = 130;
+const DF_PRIMARIES_BT709: u8 = 1;
+const DF_TRANSFER_LINEAR: u8 = 1;
+const DF_TRANSFER_SRGB: u8 = 2;
+const DF_CHANNEL_ALPHA: u8 = 15;
+const DF_SAMPLE_LINEAR: u8 = 0x10;
+
+/// What a KTX2 file holds, only 2d textures without supercompression are supported.
+/// Basis Universal files (BasisLZ and UASTC) are rejected, transcoding them to a gpu format is not implemented
+pub enum Ktx2Image {
+    Rgba(RgbaImage),
+    Compressed { width: u32, height: u32, image: CompressedImage },
+}
+
+pub fn read(bytes: &[u8]) -> Result<Ktx2Image> {
+    if bytes.len() < HEADER_SIZE || bytes[..12] != IDENTIFIER {
+        bail!("Not a KTX2 file");
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+    let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize;
+    let vk_format = u32_at(12);
+    let (width, height, depth) = (u32_at(20), u32_at(24), u32_at(28));
+    let (layers, faces, levels) = (u32_at(32), u32_at(36), u32_at(40).max(1) as usize);
+    let supercompression = u32_at(44);
+    let (dfd_offset, dfd_length) = (u32_at(48) as usize, u32_at(52) as usize);
+
+    let color_model = if dfd_length >= 16 && dfd_offset + 16 <= bytes.len() { bytes[dfd_offset + 12] } else { 0 };
+    // todo transcode Basis Universal, ETC1S with BasisLZ and UASTC, to BC7 or RGBA, until then these files are rejected
+    if supercompression == SUPERCOMPRESSION_BASIS_LZ || color_model == COLOR_MODEL_UASTC {
+        bail!("Basis Universal textures have to be transcoded, which this build doesn't support. Pack the source images with pointz pack-textures instead");
+    }
+    if supercompression != SUPERCOMPRESSION_NONE {
+        bail!("KTX2 supercompression scheme {} is not supported", supercompression);
+    }
+    if depth > 1 || layers > 1 || faces > 1 {
+        bail!("Only 2d KTX2 textures are supported, it's {}x{}x{} with {} layers and {} faces", width, height, depth, layers, faces);
+    }
+    if HEADER_SIZE + levels * LEVEL_INDEX_ENTRY_SIZE > bytes.len() {
+        bail!("KTX2 level index is truncated");
+    }
+    let level = |index: usize| -> Result<&[u8]> {
+        let entry = HEADER_SIZE + index * LEVEL_INDEX_ENTRY_SIZE;
+        let (offset, length) = (u64_at(entry), u64_at(entry + 8));
+        bytes.get(offset..offset + length).ok_or_else(|| anyhow!("KTX2 level {} is out of the file", index))
+    };
+
+    let format = match vk_format {
+        VK_FORMAT_R8G8B8A8_UNORM | VK_FORMAT_R8G8B8A8_SRGB => {
+            // the renderer makes its own mipmaps from the full size image
+            let image = RgbaImage::from_raw(width, height, level(0)?.to_vec())
+                .ok_or_else(|| anyhow!("KTX2 image of {}x{} is truncated", width, height))?;
+            return Ok(Ktx2Image::Rgba(image));
+        }
+        VK_FORMAT_BC1_RGB_UNORM | VK_FORMAT_BC1_RGB_SRGB | VK_FORMAT_BC1_RGBA_UNORM | VK_FORMAT_BC1_RGBA_SRGB => BlockFormat::Bc1,
+        VK_FORMAT_BC2_UNORM | VK_FORMAT_BC2_SRGB => BlockFormat::Bc2,
+        VK_FORMAT_BC3_UNORM | VK_FORMAT_BC3_SRGB => BlockFormat::Bc3,
+        VK_FORMAT_BC4_UNORM => BlockFormat::Bc4,
+        VK_FORMAT_BC5_UNORM => BlockFormat::Bc5,
+        VK_FORMAT_BC6H_UFLOAT => BlockFormat::Bc6h,
+        VK_FORMAT_BC7_UNORM | VK_FORMAT_BC7_SRGB => BlockFormat::Bc7,
+        astc if (VK_FORMAT_ASTC_4X4_UNORM..VK_FORMAT_ASTC_4X4_UNORM + ASTC_BLOCKS.len() as u32 * 2).contains(&astc) => {
+            let (block_width, block_height) = ASTC_BLOCKS[((astc - VK_FORMAT_ASTC_4X4_UNORM) / 2) as usize];
+            BlockFormat::Astc(block_width, block_height)
+        }
+        VK_FORMAT_UNDEFINED => bail!("KTX2 file without a format"),
+        other => bail!("KTX2 format {} is not supported", other),
+    };
+    let levels = (0..levels).map(|index| level(index).map(<[u8]>::to_vec)).collect::<Result<_>>()?;
+    Ok(Ktx2Image::Compressed { width, height, image: CompressedImage { format, levels } })
+}
+
+/// Writes a BC1, BC3 or RGBA8 texture with all of its levels, the largest one first
+pub fn write(format: Option<BlockFormat>, width: u32, height: u32, srgb: bool, levels: &[Vec<u8>]) -> Result<Vec<u8>> {
+    let (vk_format, dfd) = match format {
+        None => (if srgb { VK_FORMAT_R8G8B8A8_SRGB } else { VK_FORMAT_R8G8B8A8_UNORM }, rgba_descriptor(srgb)),
+        Some(BlockFormat::Bc1) => (if srgb { VK_FORMAT_BC1_RGB_SRGB } else { VK_FORMAT_BC1_RGB_UNORM }, block_descriptor(DF_MODEL_BC1A, srgb)),
+        Some(BlockFormat::Bc3) => (if srgb { VK_FORMAT_BC3_SRGB } else { VK_FORMAT_BC3_UNORM }, block_descriptor(DF_MODEL_BC3, srgb)),
+        Some(other) => bail!("Writing {:?} textures is not supported", other),
+    };
+    // levels start at multiples of the block size, but at least of 4 bytes
+    let alignment = format.map_or(4, BlockFormat::block_bytes).max(4);
+    let dfd_offset = HEADER_SIZE + levels.len() * LEVEL_INDEX_ENTRY_SIZE;
+    let mut data_end = dfd_offset + dfd.len();
+    // the smallest level is stored first, so streaming readers can show something early
+    let mut offsets = vec![0; levels.len()];
+    for (index, level) in levels.iter().enumerate().rev() {
+        data_end = data_end.next_multiple_of(alignment);
+        offsets[index] = data_end;
+        data_end += level.len();
+    }
+
+    let mut bytes = Vec::with_capacity(data_end);
+    bytes.extend_from_slice(&IDENTIFIER);
+    // type size is 1 for byte formats and blocks
+    for value in [vk_format, 1, width, height, 0, 0, 1, levels.len() as u32, SUPERCOMPRESSION_NONE] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    // no key values and no supercompression data
+    for value in [dfd_offset as u32, dfd.len() as u32, 0, 0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes.extend_from_slice(&[0; 16]);
+    for (level, offset) in levels.iter().zip(offsets.iter()) {
+        for value in [*offset, level.len(), level.len()] {
+            bytes.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+    }
+    bytes.extend_from_slice(&dfd);
+    for (index, level) in levels.iter().enumerate().rev() {
+        bytes.resize(offsets[index], 0);
+        bytes.extend_from_slice(level);
+    }
+    Ok(bytes)
+}
+
+fn rgba_descriptor(srgb: bool) -> Vec<u8> {
+    // alpha is linear even in srgb textures
+    let alpha = if srgb { DF_CHANNEL_ALPHA | DF_SAMPLE_LINEAR } else { DF_CHANNEL_ALPHA };
+    let samples = [(0, 7, 0, 255), (8, 7, 1, 255), (16, 7, 2, 255), (24, 7, alpha, 255)];
+    descriptor(DF_MODEL_RGBSDA, srgb, (1, 1), 4, &samples)
+}
+
+fn block_descriptor(color_model: u8, srgb: bool) -> Vec<u8> {
+    // channel 0 is the color and 15 the alpha of block formats
+    match color_model {
+        DF_MODEL_BC3 => descriptor(color_model, srgb, (4, 4), 16, &[(0, 63, DF_CHANNEL_ALPHA, u32::MAX), (64, 63, 0, u32::MAX)]),
+        _ => descriptor(color_model, srgb, (4, 4), 8, &[(0, 63, 0, u32::MAX)]),
+    }
+}
+
+// samples are bit offset, bit length - 1, channel and upper value
+fn descriptor(color_model: u8, srgb: bool, block: (u8, u8), block_bytes: u8, samples: &[(u16, u8, u8, u32)]) -> Vec<u8> {
+    let block_size = 24 + samples.len() * 16;
+    let mut bytes = Vec::with_capacity(4 + block_size);
+    bytes.extend_from_slice(&(4 + block_size as u32).to_le_bytes());
+    // Khronos vendor and the basic descriptor type
+    bytes.extend_from_slice(&0u32.to_le_bytes());
+    bytes.extend_from_slice(&2u16.to_le_bytes());
+    bytes.extend_from_slice(&(block_size as u16).to_le_bytes());
+    let transfer = if srgb { DF_TRANSFER_SRGB } else { DF_TRANSFER_LINEAR };
+    bytes.extend_from_slice(&[color_model, DF_PRIMARIES_BT709, transfer, 0]);
+    bytes.extend_from_slice(&[block.0 - 1, block.1 - 1, 0, 0]);
+    bytes.extend_from_slice(&[block_bytes, 0, 0, 0, 0, 0, 0, 0]);
+    for (bit_offset, bit_length, channel, upper) in samples {
+        bytes.extend_from_slice(&bit_offset.to_le_bytes());
+        bytes.extend_from_slice(&[*bit_length, *channel, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&upper.to_le_bytes());
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_it_writes() {
+        let levels = vec![vec![1; 32], vec![2; 8]];
+        let bytes = write(Some(BlockFormat::Bc1), 8, 4, true, &levels).unwrap();
+        let Ktx2Image::Compressed { width, height, image } = read(&bytes).unwrap() else {
+            panic!("not a compressed image");
+        };
+        assert_eq!((width, height, image.format), (8, 4, BlockFormat::Bc1));
+        assert_eq!(image.levels, levels);
+
+        let bytes = write(None, 1, 1, false, &[vec![1, 2, 3, 4]]).unwrap();
+        let Ktx2Image::Rgba(image) = read(&bytes).unwrap() else {
+            panic!("not an rgba image");
+        };
+        assert_eq!(image.get_pixel(0, 0).0, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rejects_basis_universal_and_broken_files() {
+        let mut basis_lz = write(Some(BlockFormat::Bc3), 4, 4, false, &[vec![0; 16]]).unwrap();
+        basis_lz[44..48].copy_from_slice(&SUPERCOMPRESSION_BASIS_LZ.to_le_bytes());
+        assert!(read(&basis_lz).is_err_and(|e| e.to_string().contains("Basis Universal")));
+
+        let mut uastc = write(Some(BlockFormat::Bc3), 4, 4, false, &[vec![0; 16]]).unwrap();
+        let dfd_offset = u32::from_le_bytes(uastc[48..52].try_into().unwrap()) as usize;
+        uastc[dfd_offset + 12] = COLOR_MODEL_UASTC;
+        assert!(read(&uastc).is_err_and(|e| e.to_string().contains("Basis Universal")));
+
+        let bytes = write(Some(BlockFormat::Bc1), 4, 4, false, &[vec![0; 8]]).unwrap();
+        assert!(read(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read(&bytes[..HEADER_SIZE - 1]).is_err());
+        assert!(write(Some(BlockFormat::Bc7), 4, 4, false, &[vec![0; 16]]).is_err());
+    }
+}
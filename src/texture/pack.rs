@@ -0,0 +1,90 @@
+use super::{bc, ktx2, BlockFormat, Texture, TextureType};
+use anyhow::*;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Copies an OBJ model into the output directory with its textures converted to KTX2 files with mipmaps.
+/// Colors and data maps are BC1 or BC3, normal maps stay uncompressed because blocks show on them.
+/// The MTL files are rewritten to point to the new textures, returns the number of converted textures
+pub fn pack_obj_textures(input: &Path, output: &Path) -> Result<usize> {
+    let folder = input.parent().unwrap_or_else(|| Path::new(""));
+    let obj = fs::read_to_string(input).with_context(|| format!("Failed to read {}", input.display()))?;
+    fs::create_dir_all(output).with_context(|| format!("Failed to create {}", output.display()))?;
+    let file_name = input.file_name().ok_or_else(|| anyhow!("{} is not a file", input.display()))?;
+    fs::write(output.join(file_name), &obj)?;
+
+    // textures shared by materials are converted once, by their source path
+    let mut packed: HashMap<String, String> = HashMap::new();
+    for line in obj.lines() {
+        let Some(mtl_name) = line.trim().strip_prefix("mtllib ") else {
+            continue;
+        };
+        let mtl_name = mtl_name.trim();
+        let mtl = fs::read_to_string(folder.join(mtl_name)).with_context(|| format!("Failed to read {}", mtl_name))?;
+        let mut packed_mtl = String::with_capacity(mtl.len());
+        for line in mtl.lines() {
+            packed_mtl.push_str(&pack_mtl_line(line, folder, output, &mut packed)?);
+            packed_mtl.push('\n');
+        }
+        let mtl_output = output.join(relative(mtl_name)?);
+        if let Some(parent) = mtl_output.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&mtl_output, packed_mtl).with_context(|| format!("Failed to write {}", mtl_output.display()))?;
+    }
+    Ok(packed.len())
+}
+
+// texture maps end with the file name, options like "-bm 0.5" come before it
+fn pack_mtl_line(line: &str, folder: &Path, output: &Path, packed: &mut HashMap<String, String>) -> Result<String> {
+    let mut parts: Vec<&str> = line.split_whitespace().collect();
+    let type_ = match parts.first().copied() {
+        Some("map_Kd" | "map_Ke") => TextureType::Diffuse,
+        Some("map_Bump" | "map_bump" | "bump" | "norm") => TextureType::Normal,
+        Some("map_Pr" | "map_Pm" | "map_Ks" | "map_Ns" | "map_d") => TextureType::Data,
+        _ => return Ok(line.to_string()),
+    };
+    let Some(source) = parts.pop().filter(|_| !parts.is_empty()) else {
+        return Ok(line.to_string());
+    };
+    if !packed.contains_key(source) {
+        let target = relative(source)?.with_extension("ktx2");
+        pack_texture(&folder.join(source), &output.join(&target), type_)?;
+        // mtl paths use forward slashes on every platform
+        packed.insert(source.to_string(), target.to_string_lossy().replace('\\', "/"));
+    }
+    parts.push(&packed[source]);
+    Ok(parts.join(" "))
+}
+
+fn pack_texture(source: &Path, target: &Path, type_: TextureType) -> Result<()> {
+    let texture = Texture::load(source, type_).with_context(|| format!("Failed to load {}", source.display()))?;
+    // already compressed textures are converted again from their largest level
+    let texture = if texture.compressed.is_some() { texture.decompress()? } else { texture };
+    let image = texture.rgba_image.as_ref().unwrap();
+    let levels = std::iter::once(image.clone()).chain(texture.mip_chain());
+    let format = match type_ {
+        TextureType::Normal => None,
+        _ => Some(BlockFormat::for_image(image)),
+    };
+    let levels: Vec<Vec<u8>> = match format {
+        Some(format) => levels.map(|level| bc::encode(format, &level)).collect::<Result<_>>()?,
+        None => levels.map(|level| level.into_raw()).collect(),
+    };
+    let (width, height) = texture.dimensions;
+    let bytes = ktx2::write(format, width, height, type_ == TextureType::Diffuse, &levels)?;
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(target, bytes).with_context(|| format!("Failed to write {}", target.display()))
+}
+
+// files outside of the model's folder can't be placed next to it in the output
+fn relative(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if path.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)) {
+        bail!("{} is outside of the model's folder", path.display());
+    }
+    Ok(path.to_path_buf())
+}
@@ -0,0 +1,192 @@
+//! Renders reference scenes with a software adapter and compares them to resources/golden,
+//! `POINTZ_UPDATE_GOLDEN=1 cargo test --test golden_images` replaces the stored images instead
+
+#[path = "../src/exit_code.rs"]
+mod exit_code;
+
+use image::{Rgba, RgbaImage};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const RESOURCES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources");
+/// Reference images are stored next to the scenes they show
+const REFERENCE_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/resources/golden");
+/// Actual and diff images end up here
+const OUTPUT_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/golden");
+const UPDATE_ENV: &str = "POINTZ_UPDATE_GOLDEN";
+
+const SIZE: &str = "320x240";
+/// CIE76 color difference a pixel may have, about two just noticeable differences
+const MAX_DELTA_E: f32 = 5.0;
+/// Share of pixels that may exceed MAX_DELTA_E, covers rasterization differences on edges
+const MAX_FAILED_PIXELS: f32 = 0.002;
+
+struct Case {
+    name: &'static str,
+    /// Relative to the resources directory, the default scene when empty
+    paths: &'static [&'static str],
+    /// x,y,z,yaw,pitch
+    camera: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "default-scene",
+        paths: &[],
+        camera: "10,0,-25,90,0",
+    },
+    Case {
+        name: "default-scene-from-above",
+        paths: &[],
+        camera: "10,40,-10,90,-60",
+    },
+    Case {
+        name: "penguin",
+        paths: &["penguin.obj"],
+        camera: "0,0.5,-4,90,0",
+    },
+    Case {
+        name: "cube",
+        paths: &["cube.obj"],
+        camera: "3,3,-3,135,-35",
+    },
+];
+
+enum Rendered {
+    Image(PathBuf),
+    NoAdapter,
+}
+
+#[test]
+fn golden_images() {
+    let update = std::env::var_os(UPDATE_ENV).is_some();
+    fs::create_dir_all(OUTPUT_DIR).unwrap();
+    if update {
+        fs::create_dir_all(REFERENCE_DIR).unwrap();
+    }
+
+    let mut failures = vec![];
+    for case in CASES {
+        let actual_path = match render(case) {
+            Rendered::Image(path) => path,
+            Rendered::NoAdapter => {
+                // hardware adapters differ too much between machines for stored references
+                eprintln!("skipped, no software adapter");
+                return;
+            }
+        };
+        let reference_path = Path::new(REFERENCE_DIR).join(format!("{}.png", case.name));
+        if update {
+            fs::copy(&actual_path, &reference_path).unwrap();
+            println!("{}: updated", case.name);
+            continue;
+        }
+
+        let actual = image::open(&actual_path).unwrap().to_rgba8();
+        let reference = match image::open(&reference_path) {
+            Ok(reference) => reference.to_rgba8(),
+            Err(e) => {
+                failures.push(format!(
+                    "{}: can't read reference {} ({}), run with {}=1 to create it",
+                    case.name,
+                    reference_path.display(),
+                    e,
+                    UPDATE_ENV
+                ));
+                continue;
+            }
+        };
+        if reference.dimensions() != actual.dimensions() {
+            failures.push(format!(
+                "{}: reference is {:?} but the image is {:?}, see {}",
+                case.name,
+                reference.dimensions(),
+                actual.dimensions(),
+                actual_path.display()
+            ));
+            continue;
+        }
+        let (failed_pixels, diff) = compare(&reference, &actual);
+        if failed_pixels > MAX_FAILED_PIXELS {
+            let diff_path = Path::new(OUTPUT_DIR).join(format!("{}.diff.png", case.name));
+            diff.save(&diff_path).unwrap();
+            failures.push(format!(
+                "{}: {:.2}% of pixels differ, see {} and {}",
+                case.name,
+                failed_pixels * 100.0,
+                actual_path.display(),
+                diff_path.display()
+            ));
+        }
+    }
+    assert!(failures.is_empty(), "{} of {} golden images differ\n{}", failures.len(), CASES.len(), failures.join("\n"));
+}
+
+fn render(case: &Case) -> Rendered {
+    let output = Path::new(OUTPUT_DIR).join(format!("{}.actual.png", case.name));
+    let status = Command::new(env!("CARGO_BIN_EXE_pointz"))
+        .args(["--software", "--backend", "all", "--no-gui", "--size", SIZE])
+        .args(["--camera", case.camera, "--resources", RESOURCES_DIR])
+        .arg("--output")
+        .arg(&output)
+        .arg("--")
+        .args(case.paths.iter().map(|path| Path::new(RESOURCES_DIR).join(path)))
+        .status()
+        .expect("Failed to start pointz");
+    if status.code() == Some(exit_code::NO_ADAPTER) {
+        return Rendered::NoAdapter;
+    }
+    assert!(status.success(), "Failed to render {}", case.name);
+    Rendered::Image(output)
+}
+
+/// Share of pixels over MAX_DELTA_E and an image with them in red over a dimmed reference
+fn compare(reference: &RgbaImage, actual: &RgbaImage) -> (f32, RgbaImage) {
+    let mut diff = RgbaImage::new(reference.width(), reference.height());
+    let mut failed = 0;
+    for ((expected, actual), diff) in reference.pixels().zip(actual.pixels()).zip(diff.pixels_mut()) {
+        if delta_e(expected, actual) > MAX_DELTA_E {
+            failed += 1;
+            *diff = Rgba([255, 0, 0, 255]);
+        } else {
+            let [r, g, b, _] = expected.0;
+            let gray = ((r as u32 + g as u32 + b as u32) / 12) as u8;
+            *diff = Rgba([gray, gray, gray, 255]);
+        }
+    }
+    (failed as f32 / (reference.width() * reference.height()).max(1) as f32, diff)
+}
+
+/// CIE76, the euclidean distance in L*a*b*, alpha is blended over black first
+fn delta_e(a: &Rgba<u8>, b: &Rgba<u8>) -> f32 {
+    let [l1, a1, b1] = to_lab(a);
+    let [l2, a2, b2] = to_lab(b);
+    ((l1 - l2).powi(2) + (a1 - a2).powi(2) + (b1 - b2).powi(2)).sqrt()
+}
+
+fn to_lab(pixel: &Rgba<u8>) -> [f32; 3] {
+    let alpha = pixel.0[3] as f32 / 255.0;
+    let [r, g, b] = [0, 1, 2].map(|i| srgb_to_linear(pixel.0[i] as f32 / 255.0) * alpha);
+    // sRGB to XYZ, normalized by the D65 white point
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}